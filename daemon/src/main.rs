@@ -1,23 +1,25 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::io::{self, BufRead, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::thread;
 use std::hash::{Hash, Hasher};
 use std::collections::hash_map::DefaultHasher;
-use std::time::{Duration, Instant, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Result};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tempfile::{NamedTempFile, TempDir};
+use tempfile::NamedTempFile;
 use url::Url;
 use walkdir::WalkDir;
+use xxhash_rust::xxh3::xxh3_64;
 use threadpool::ThreadPool;
 
+#[allow(non_snake_case)]
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
 struct Settings {
     #[serde(default)]
@@ -31,6 +33,8 @@ struct Settings {
     #[serde(default)]
     quickChecks: String,
     #[serde(default)]
+    checks: String,
+    #[serde(default)]
     maxDiagnosticsPerFile: u32,
     #[serde(default)]
     maxFixesPerFile: u32,
@@ -39,9 +43,165 @@ struct Settings {
     #[serde(default)]
     daemonCacheDir: String,
     #[serde(default)]
+    daemonCacheScope: String,
+    #[serde(default)]
+    disableCacheSnapshot: bool,
+    #[serde(default)]
+    remoteCacheUrl: String,
+    #[serde(default)]
+    remoteCacheToken: String,
+    #[serde(default)]
+    remoteCacheWrite: bool,
+    #[serde(default)]
+    remoteCacheTimeoutMs: u64,
+    #[serde(default)]
     perFileTimeoutMs: u64,
     #[serde(default)]
+    retryCount: u32,
+    #[serde(default)]
+    retryBackoffMs: u64,
+    #[serde(default)]
+    retryChecks: String,
+    #[serde(default)]
+    baselinePath: String,
+    #[serde(default)]
+    disableBaseline: bool,
+    #[serde(default)]
     publishDiagnosticsThrottleMs: u64,
+    #[serde(default)]
+    redactPatterns: Vec<String>,
+    #[serde(default)]
+    disableDefaultRedaction: bool,
+    #[serde(default)]
+    compilerLaunchers: Vec<String>,
+    #[serde(default)]
+    duplicateEntryPolicy: String,
+    #[serde(default)]
+    flagInference: String,
+    #[serde(default)]
+    includeGlobs: Vec<String>,
+    #[serde(default)]
+    excludeGlobs: Vec<String>,
+    #[serde(default)]
+    dropFlags: Vec<String>,
+    #[serde(default)]
+    disableDefaultFlagDrops: bool,
+    #[serde(default)]
+    keepCheckAliases: bool,
+    #[serde(default)]
+    triggerOnOpen: String,
+    #[serde(default)]
+    triggerOnSave: String,
+    #[serde(default)]
+    triggerOnType: String,
+    #[serde(default)]
+    triggerDebounceMs: u64,
+    #[serde(default)]
+    rateLimitPerMethod: f64,
+    #[serde(default)]
+    rateLimitPerFile: f64,
+    #[serde(default)]
+    rateLimitBurst: u32,
+    #[serde(default)]
+    deltaResyncInterval: u32,
+    #[serde(default)]
+    singleInstance: bool,
+    #[serde(default)]
+    janitorIntervalMs: u64,
+    #[serde(default)]
+    compileDbWatchIntervalMs: u64,
+    #[serde(default)]
+    checkOptions: BTreeMap<String, String>,
+    #[serde(default)]
+    checkOptionsOverrides: Vec<CheckOptionsOverride>,
+    #[serde(default)]
+    disableInstantiationGrouping: bool,
+    #[serde(default)]
+    memoryCacheMaxDiagnostics: u64,
+    #[serde(default)]
+    cacheMaxEntries: u64,
+    #[serde(default)]
+    cacheMaxBytes: u64,
+    #[serde(default)]
+    cacheWarming: bool,
+    #[serde(default)]
+    cacheWarmingWorkers: u32,
+    #[serde(default)]
+    enableAlphaChecks: bool,
+    #[serde(default)]
+    debounceMs: u64,
+    #[serde(default)]
+    clangFormatPath: String,
+    #[serde(default)]
+    analyzers: Vec<String>,
+    #[serde(default)]
+    cppcheckPath: String,
+    #[serde(default)]
+    cppcheckArgs: Vec<String>,
+    #[serde(default)]
+    deepTimeoutMs: u64,
+    #[serde(default)]
+    externalAnalyzers: Vec<ExternalAnalyzer>,
+    #[serde(default)]
+    enableCheckProfile: bool,
+    #[serde(default)]
+    diffBaseRef: String,
+    #[serde(default)]
+    precompiledPreamble: bool,
+    #[serde(default)]
+    clangPath: String,
+    #[serde(default)]
+    clangApplyReplacementsPath: String,
+    #[serde(default)]
+    formatAfterFix: bool,
+    #[serde(default)]
+    ignoredChecks: Vec<String>,
+    #[serde(default)]
+    headerFilter: String,
+    #[serde(default)]
+    excludeHeaderFilter: String,
+    #[serde(default)]
+    severityOverrides: BTreeMap<String, String>,
+    #[serde(default)]
+    minimumSeverity: String,
+    #[serde(default)]
+    fixableChecks: Vec<String>,
+    #[serde(default)]
+    unfixableChecks: Vec<String>,
+    #[serde(default)]
+    suppressionFixes: bool,
+}
+
+/// A lint tool run on each analyzed file next to clang-tidy. `command` is
+/// the argv, with `${file}`, `${root}`, `${compileCommands}` and
+/// `${compileDir}` substituted; `format` says how to read its output.
+#[derive(Debug, Clone, Deserialize, Serialize, Default, Hash)]
+struct ExternalAnalyzer {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    command: Vec<String>,
+    /// `regex` (default), `sarif` or `json`.
+    #[serde(default)]
+    format: String,
+    /// For `regex`: named groups `line` and `message`, optionally `file`,
+    /// `column`, `severity` and `code`.
+    #[serde(default)]
+    pattern: String,
+    /// Globs (relative to the workspace root or absolute); empty runs the
+    /// tool on every file.
+    #[serde(default)]
+    files: Vec<String>,
+}
+
+/// `CheckOptions` applied on top of `checkOptions` for files matching any of
+/// `paths` (globs relative to the workspace root, `**` crosses directories).
+#[derive(Debug, Clone, Deserialize, Serialize, Default, Hash)]
+struct CheckOptionsOverride {
+    #[serde(default)]
+    paths: Vec<String>,
+    #[serde(default)]
+    options: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -55,6 +215,172 @@ struct JsonRpcResponse<T> {
 struct JsonRpcError {
     code: i32,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+/// Error raised by request handlers that need a specific JSON-RPC error code or
+/// structured `data`; any other error is reported as a generic server error.
+#[derive(Debug)]
+struct RpcError {
+    code: i32,
+    message: String,
+    data: Option<Value>,
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const RATE_LIMITED: i32 = -32001;
+const INSTANCE_RUNNING: i32 = -32002;
+const FILE_MODIFIED: i32 = -32003;
+const UNAUTHORIZED: i32 = -32004;
+const REQUEST_SUPERSEDED: i32 = -32800;
+
+/// `-32602 Invalid params` naming the offending parameter in `data.field`.
+fn invalid_params(field: &str, message: String) -> anyhow::Error {
+    RpcError {
+        code: INVALID_PARAMS,
+        message,
+        data: Some(serde_json::json!({ "field": field })),
+    }
+    .into()
+}
+
+/// The file on disk no longer matches the analysis a fix came from.
+fn file_modified(file_uri: &str) -> anyhow::Error {
+    RpcError {
+        code: FILE_MODIFIED,
+        message: "File changed since it was analyzed; re-run analyzeFile".to_string(),
+        data: Some(serde_json::json!({ "reason": "fileModified", "fileUri": file_uri })),
+    }
+    .into()
+}
+
+/// The cached analysis of `file_path` when the file on disk is still the text
+/// it was made from; fix offsets are only valid for that text.
+fn analysis_on_disk(state: &AppState, file_uri: &str, file_path: &Path) -> Result<CacheEntry> {
+    state
+        .cache
+        .lock()
+        .unwrap()
+        .get(file_path)
+        .filter(|entry| entry.matches_file(file_path, file_signature(file_path)))
+        .ok_or_else(|| file_modified(file_uri))
+}
+
+/// `checks` parameter: check name globs selecting which fixes to apply.
+fn check_filters(params: &Value) -> Vec<Regex> {
+    params
+        .get("checks")
+        .and_then(|v| v.as_array())
+        .map(|list| list.iter().filter_map(|c| c.as_str()).filter_map(glob_to_regex).collect())
+        .unwrap_or_default()
+}
+
+/// `includeBehaviorChanging` parameter: bulk fix operations leave out fixes
+/// classified `behaviorChanging` unless it is set.
+fn include_behavior_changing(params: &Value) -> bool {
+    params.get("includeBehaviorChanging").and_then(|v| v.as_bool()).unwrap_or(false)
+}
+
+fn required_str<'a>(params: &'a Value, field: &str) -> Result<&'a str> {
+    match params.get(field) {
+        None | Some(Value::Null) => Err(invalid_params(field, format!("Missing required parameter `{field}`"))),
+        Some(value) => value
+            .as_str()
+            .ok_or_else(|| invalid_params(field, format!("Parameter `{field}` must be a string"))),
+    }
+}
+
+/// The required `fileUri` parameter and the local path it names.
+fn file_uri_param(params: &Value) -> Result<(&str, PathBuf)> {
+    let file_uri = required_str(params, "fileUri")?;
+    let file_path = uri_to_path(file_uri)
+        .ok_or_else(|| invalid_params("fileUri", format!("Parameter `fileUri` is not a file URI: {file_uri}")))?;
+    Ok((file_uri, file_path))
+}
+
+#[derive(Debug)]
+struct PendingMessage {
+    id: Option<Value>,
+    method: String,
+    params: Value,
+}
+
+/// Messages read from the client that have not been dispatched yet. A newer
+/// `analyzeFile` for a file replaces any queued request for the same file.
+#[derive(Default)]
+struct PendingQueue {
+    items: Mutex<VecDeque<PendingMessage>>,
+    ready: Condvar,
+    closed: AtomicBool,
+}
+
+impl PendingQueue {
+    /// Enqueues `message` and returns the queued requests it supersedes.
+    fn push(&self, message: PendingMessage) -> Vec<PendingMessage> {
+        let mut items = self.items.lock().unwrap();
+        let mut superseded = Vec::new();
+        if message.method == "analyzeFile" {
+            let uri = message.params.get("fileUri").cloned();
+            let mut kept = VecDeque::with_capacity(items.len());
+            for queued in items.drain(..) {
+                if queued.method == "analyzeFile" && queued.id.is_some() && queued.params.get("fileUri").cloned() == uri {
+                    superseded.push(queued);
+                } else {
+                    kept.push_back(queued);
+                }
+            }
+            *items = kept;
+        }
+        items.push_back(message);
+        self.ready.notify_one();
+        superseded
+    }
+
+    fn pop(&self) -> Option<PendingMessage> {
+        let mut items = self.items.lock().unwrap();
+        loop {
+            if let Some(message) = items.pop_front() {
+                return Some(message);
+            }
+            if self.closed.load(Ordering::SeqCst) {
+                return None;
+            }
+            items = self.ready.wait(items).unwrap();
+        }
+    }
+
+    fn close(&self) {
+        let _items = self.items.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+        self.ready.notify_all();
+    }
+}
+
+fn to_json_rpc_error(err: &anyhow::Error) -> JsonRpcError {
+    match err.downcast_ref::<RpcError>() {
+        Some(rpc) => JsonRpcError {
+            code: rpc.code,
+            message: rpc.message.clone(),
+            data: rpc.data.clone(),
+        },
+        None => JsonRpcError {
+            code: -32000,
+            message: err.to_string(),
+            data: None,
+        },
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -77,1271 +403,11364 @@ struct Notification<T> {
     params: T,
 }
 
+/// Secret patterns that are always redacted unless `disableDefaultRedaction` is set:
+/// `-D` defines whose name looks like a credential, inline credential assignments,
+/// and the user name component of home directory paths.
+const DEFAULT_REDACT_PATTERNS: &[(&str, &str)] = &[
+    (
+        r#"(?i)(-D\s*\w*(?:KEY|TOKEN|SECRET|PASSWORD|PASSWD|CREDENTIALS?)\w*=)(?:"[^"]*"|'[^']*'|\S+)"#,
+        "${1}<redacted>",
+    ),
+    (
+        r"(?i)\b((?:authorization|bearer|api[_-]?key|access[_-]?token|password|secret)\s*[:=]\s*)\S+",
+        "${1}<redacted>",
+    ),
+    (r"(/home/|/Users/|[A-Za-z]:\\Users\\)[^/\\\s]+", "${1}<user>"),
+];
+
+#[derive(Debug, Default)]
+struct Redactor {
+    rules: Vec<(Regex, String)>,
+}
+
+impl Redactor {
+    fn from_settings(settings: &Settings) -> (Self, Vec<String>) {
+        let mut rules = Vec::new();
+        let mut errors = Vec::new();
+        if !settings.disableDefaultRedaction {
+            for (pattern, replacement) in DEFAULT_REDACT_PATTERNS {
+                rules.push((Regex::new(pattern).unwrap(), replacement.to_string()));
+            }
+        }
+        for pattern in &settings.redactPatterns {
+            match Regex::new(pattern) {
+                Ok(re) => rules.push((re, "<redacted>".to_string())),
+                Err(err) => errors.push(format!("Invalid redaction pattern {pattern:?}: {err}")),
+            }
+        }
+        (Redactor { rules }, errors)
+    }
+
+    fn redact(&self, text: &str) -> String {
+        let mut out = text.to_string();
+        for (re, replacement) in &self.rules {
+            if re.is_match(&out) {
+                out = re.replace_all(&out, replacement.as_str()).into_owned();
+            }
+        }
+        out
+    }
+}
+
+/// Everything the daemon emits goes through this sink so that free-form text
+/// (logs, reports) can be redacted before it leaves the process.
+struct Output {
+    writer: Mutex<Box<dyn Write + Send>>,
+    redactor: Mutex<Arc<Redactor>>,
+    /// Replies use `Content-Length` headers once the client is known to send them.
+    header_framed: AtomicBool,
+}
+
+/// How messages are delimited on stdin. `Auto` settles on the first message:
+/// a `Content-Length` header selects LSP-style framing, anything else NDJSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    Auto,
+    Ndjson,
+    Header,
+}
+
 #[derive(Clone)]
 struct AppState {
     settings: Arc<Mutex<Settings>>,
     root_dir: Arc<Mutex<Option<PathBuf>>>,
     compile_commands: Arc<Mutex<Option<PathBuf>>>,
     compile_index: Arc<Mutex<Option<Arc<CompileCommandsIndex>>>>,
-    stdout: Arc<Mutex<io::Stdout>>,
+    stdout: Arc<Output>,
     cancel_map: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>,
-    cache: Arc<Mutex<HashMap<PathBuf, CacheEntry>>>,
+    /// `$/progress` token (as JSON text) to the `runId` it reports on.
+    progress_tokens: Arc<Mutex<HashMap<String, String>>>,
+    /// Shared by every session of the process, like `scheduler` and
+    /// `in_flight`; entries carry the settings hash they were produced under.
+    cache: Arc<Mutex<SpillStore<CacheEntry>>>,
+    client: Arc<Mutex<ClientOptions>>,
+    tool_versions: Arc<Mutex<HashMap<String, Option<String>>>>,
+    latest: Arc<Mutex<SpillStore<Vec<RpcDiagnostic>>>>,
+    documents: Arc<Mutex<HashMap<PathBuf, OpenDocument>>>,
+    rate_limits: Arc<Mutex<HashMap<String, TokenBucket>>>,
+    project_runs: Arc<Mutex<HashMap<String, ProjectRunFiles>>>,
+    published: Arc<Mutex<HashMap<String, PublishedSet>>>,
+    versions: Arc<Mutex<HashMap<PathBuf, i64>>>,
+    instance_lock: Arc<Mutex<Option<InstanceLock>>>,
+    fix_sessions: Arc<Mutex<FixSessions>>,
+    /// Serializes fix writes so two writes to a file cannot interleave, and
+    /// keeps the journal to revert them.
+    fix_writes: Arc<Mutex<FixJournal>>,
+    resources: Arc<Mutex<ResourceStats>>,
+    include_graph: Arc<Mutex<Option<Arc<IncludeGraph>>>>,
+    /// False for `--socket`/`--listen` sessions, which share the process.
+    owns_process: bool,
+    /// Unique within the process; identifies the session in the workspace lock.
+    session_id: u64,
+    parent_pid: Arc<Mutex<Option<u32>>>,
+    scheduler: Arc<Scheduler>,
+    in_flight: Arc<InFlightFiles>,
+    active_runs: Arc<ActiveRuns>,
+    /// Set by `shutdown`; afterwards only `exit` is accepted.
+    shutting_down: Arc<AtomicBool>,
+    /// Disk cache entries removed to stay within `cacheMaxBytes`.
+    disk_cache_evictions: Arc<AtomicU64>,
+    /// Shared lock on the disk cache directory while this daemon uses it; see
+    /// `recover_cache_dir`.
+    cache_dir_lock: Arc<Mutex<Option<std::fs::File>>>,
+    remote_cache: Arc<RemoteCache>,
+    cache_warming: Arc<CacheWarming>,
+    /// Files `analyze_document` found not analyzable, so repeated requests
+    /// skip the compile database lookup until it changes.
+    skipped_files: Arc<Mutex<HashMap<PathBuf, SkipVerdict>>>,
+    /// Findings in project headers from the latest analysis of each
+    /// translation unit, which project runs publish merged per header.
+    header_findings: Arc<Mutex<HashMap<PathBuf, Vec<RpcDiagnostic>>>>,
+    diagnostic_stats: Arc<Mutex<DiagnosticStats>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Position {
-    line: usize,
-    character: usize,
+/// State shared by all `--socket`/`--listen` sessions of a process; a stdio
+/// daemon has exactly one session.
+#[derive(Clone, Default)]
+struct SharedState {
+    tool_versions: Arc<Mutex<HashMap<String, Option<String>>>>,
+    cache: Arc<Mutex<SpillStore<CacheEntry>>>,
+    scheduler: Arc<Scheduler>,
+    in_flight: Arc<InFlightFiles>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Range {
-    start: Position,
-    end: Position,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Priority {
+    /// `analyzeFile` and document triggers: the file the user is looking at.
+    Interactive,
+    /// `analyzeProject` files.
+    Batch,
+    /// Cache warming: only runs on slots nobody else is waiting for.
+    Background,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct TextEdit {
-    range: Range,
-    #[serde(rename = "newText")]
-    new_text: String,
+/// Admission control for clang-tidy runs, shared by all analysis paths.
+/// All priorities share one cap of `limit` running processes. Interactive
+/// runs take the next free slot; batch runs only start while no interactive
+/// run is waiting, so a project scan yields that slot to the editor. Background runs
+/// likewise yield to waiting batch runs. Concurrent project runs share the
+/// batch slots: a free slot goes to the waiting run that holds the fewest.
+#[derive(Debug, Default)]
+struct Scheduler {
+    slots: Mutex<SchedulerSlots>,
+    changed: Condvar,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Fix {
-    title: String,
-    edits: Vec<TextEdit>,
+#[derive(Debug, Default)]
+struct SchedulerSlots {
+    limit: usize,
+    interactive_running: usize,
+    interactive_waiting: usize,
+    batch_running: usize,
+    batch_waiting: usize,
+    background_running: usize,
+    background_waiting: usize,
+    /// Batch slots held and awaited per project run.
+    batch_runs: HashMap<String, RunSlots>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct RpcDiagnostic {
-    range: Range,
-    severity: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    code: Option<String>,
-    message: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    fixes: Option<Vec<Fix>>,
+#[derive(Debug, Default)]
+struct RunSlots {
+    running: usize,
+    waiting: usize,
 }
 
-const DISK_CACHE_VERSION: u32 = 1;
+impl SchedulerSlots {
+    /// Whether another run waiting for a batch slot holds fewer than `run`.
+    fn batch_run_behind(&self, run: &str) -> bool {
+        let running = self.batch_runs.get(run).map_or(0, |slots| slots.running);
+        self.batch_runs.iter().any(|(other, slots)| other != run && slots.waiting > 0 && slots.running < running)
+    }
+}
 
-#[derive(Debug, Clone)]
-struct CacheEntry {
-    mtime: u64,
-    size: u64,
-    settings_hash: u64,
-    diagnostics: Vec<RpcDiagnostic>,
+struct SchedulerPermit<'a> {
+    scheduler: &'a Scheduler,
+    priority: Priority,
+    run: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct DiskCacheEntry {
-    version: u32,
-    filePath: String,
-    mtime: u64,
-    size: u64,
-    settingsHash: u64,
-    diagnostics: Vec<RpcDiagnostic>,
+impl Drop for SchedulerPermit<'_> {
+    fn drop(&mut self) {
+        let mut slots = self.scheduler.slots.lock().unwrap();
+        match self.priority {
+            Priority::Interactive => slots.interactive_running -= 1,
+            Priority::Batch => {
+                slots.batch_running -= 1;
+                if let Some(run) = slots.batch_runs.get_mut(&self.run) {
+                    run.running -= 1;
+                    if run.running == 0 && run.waiting == 0 {
+                        slots.batch_runs.remove(&self.run);
+                    }
+                }
+            }
+            Priority::Background => slots.background_running -= 1,
+        }
+        self.scheduler.changed.notify_all();
+    }
 }
 
-#[derive(Debug, Clone)]
-struct InternalDiagnostic {
-    file: PathBuf,
-    range: Range,
-    severity: String,
-    code: Option<String>,
-    message: String,
-    fixes: Vec<Fix>,
+impl Scheduler {
+    fn set_limit(&self, max_workers: u32) {
+        self.slots.lock().unwrap().limit = max_workers.max(1) as usize;
+        self.changed.notify_all();
+    }
+
+    fn acquire(&self, priority: Priority) -> SchedulerPermit<'_> {
+        self.acquire_for(priority, "")
+    }
+
+    /// Like `acquire`, accounting batch slots to project run `run`.
+    fn acquire_for(&self, priority: Priority, run: &str) -> SchedulerPermit<'_> {
+        let mut slots = self.slots.lock().unwrap();
+        let limit = |slots: &SchedulerSlots| slots.limit.max(1);
+        let shared = |slots: &SchedulerSlots| slots.interactive_running + slots.batch_running + slots.background_running;
+        match priority {
+            Priority::Interactive => {
+                slots.interactive_waiting += 1;
+                while shared(&slots) >= limit(&slots) {
+                    slots = self.changed.wait(slots).unwrap();
+                }
+                slots.interactive_waiting -= 1;
+                slots.interactive_running += 1;
+            }
+            Priority::Batch => {
+                slots.batch_waiting += 1;
+                slots.batch_runs.entry(run.to_string()).or_default().waiting += 1;
+                while slots.interactive_waiting > 0 || shared(&slots) >= limit(&slots) || slots.batch_run_behind(run) {
+                    slots = self.changed.wait(slots).unwrap();
+                }
+                slots.batch_waiting -= 1;
+                slots.batch_running += 1;
+                let slots = slots.batch_runs.entry(run.to_string()).or_default();
+                slots.waiting -= 1;
+                slots.running += 1;
+            }
+            Priority::Background => {
+                slots.background_waiting += 1;
+                while slots.interactive_waiting + slots.batch_waiting > 0 || shared(&slots) >= limit(&slots) {
+                    slots = self.changed.wait(slots).unwrap();
+                }
+                slots.background_waiting -= 1;
+                slots.background_running += 1;
+            }
+        }
+        SchedulerPermit { scheduler: self, priority, run: run.to_string() }
+    }
+
+    fn status(&self) -> Value {
+        let slots = self.slots.lock().unwrap();
+        serde_json::json!({
+            "limit": slots.limit.max(1),
+            "interactiveRunning": slots.interactive_running,
+            "interactiveWaiting": slots.interactive_waiting,
+            "batchRunning": slots.batch_running,
+            "batchWaiting": slots.batch_waiting,
+            "backgroundRunning": slots.background_running,
+            "backgroundWaiting": slots.background_waiting,
+            "batchRuns": slots
+                .batch_runs
+                .iter()
+                .map(|(run, slots)| (run.clone(), serde_json::json!({ "running": slots.running, "waiting": slots.waiting })))
+                .collect::<serde_json::Map<String, Value>>(),
+        })
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct FixesFile {
-    #[serde(rename = "Diagnostics", default)]
-    diagnostics: Vec<FixDiagnostic>,
+/// Progress of the background pass started by `cacheWarming`. Pausing holds
+/// back files that have not started yet.
+#[derive(Debug, Default)]
+struct CacheWarming {
+    progress: Mutex<WarmingProgress>,
+    changed: Condvar,
 }
 
-#[derive(Debug, Deserialize)]
-struct FixDiagnostic {
-    #[serde(rename = "DiagnosticName")]
-    diagnostic_name: Option<String>,
-    #[serde(rename = "DiagnosticMessage")]
-    diagnostic_message: Option<FixMessage>,
-    #[serde(rename = "Message")]
-    message: Option<String>,
-    #[serde(rename = "FilePath")]
-    file_path: Option<String>,
-    #[serde(rename = "FileOffset")]
-    file_offset: Option<usize>,
-    #[serde(rename = "Replacements")]
-    replacements: Option<Vec<FixReplacement>>,
+#[derive(Debug, Default)]
+struct WarmingProgress {
+    running: bool,
+    paused: bool,
+    done: usize,
+    total: usize,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct FixMessage {
-    #[serde(rename = "Message")]
-    message: Option<String>,
-    #[serde(rename = "FilePath")]
-    file_path: Option<String>,
-    #[serde(rename = "FileOffset")]
-    file_offset: Option<usize>,
-    #[serde(rename = "Replacements")]
-    replacements: Option<Vec<FixReplacement>>,
+impl CacheWarming {
+    fn set_paused(&self, paused: bool) {
+        self.progress.lock().unwrap().paused = paused;
+        self.changed.notify_all();
+    }
+
+    /// Blocks while paused; false once `cancel` is set.
+    fn wait_resumed(&self, cancel: &AtomicBool) -> bool {
+        let mut progress = self.progress.lock().unwrap();
+        while progress.paused && !cancel.load(Ordering::Relaxed) {
+            progress = self.changed.wait_timeout(progress, Duration::from_millis(200)).unwrap().0;
+        }
+        !cancel.load(Ordering::Relaxed)
+    }
+
+    fn status(&self) -> Value {
+        let progress = self.progress.lock().unwrap();
+        let state = match (progress.running, progress.paused) {
+            (false, _) => "idle",
+            (true, true) => "paused",
+            (true, false) => "running",
+        };
+        serde_json::json!({ "state": state, "done": progress.done, "total": progress.total })
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct FixReplacement {
-    #[serde(rename = "FilePath", default)]
-    file_path: String,
-    #[serde(rename = "Offset", default)]
-    offset: usize,
-    #[serde(rename = "Length", default)]
-    length: usize,
-    #[serde(rename = "ReplacementText", default)]
-    replacement_text: String,
+/// Work started on behalf of the client that may still write to it: project
+/// runs, pooled requests and document-trigger analyses. `shutdown` waits for
+/// the count to drop to zero.
+#[derive(Debug, Default)]
+struct ActiveRuns {
+    count: Mutex<usize>,
+    idle: Condvar,
 }
 
-#[derive(Debug, Deserialize)]
-struct CompileCommand {
-    file: String,
-    directory: String,
-    #[serde(default)]
-    command: Option<String>,
-    #[serde(default)]
-    arguments: Option<Vec<String>>,
+struct ActiveRunGuard {
+    runs: Arc<ActiveRuns>,
+}
+
+impl Drop for ActiveRunGuard {
+    fn drop(&mut self) {
+        let mut count = self.runs.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.runs.idle.notify_all();
+        }
+    }
+}
+
+impl ActiveRuns {
+    fn enter(self: &Arc<Self>) -> ActiveRunGuard {
+        *self.count.lock().unwrap() += 1;
+        ActiveRunGuard { runs: self.clone() }
+    }
+
+    /// Waits until no run is active or `timeout` passes; true when idle.
+    fn wait_idle(&self, timeout: Duration) -> bool {
+        let count = self.count.lock().unwrap();
+        let (count, _) = self.idle.wait_timeout_while(count, timeout, |count| *count > 0).unwrap();
+        *count == 0
+    }
+}
+
+const DEFAULT_SHUTDOWN_TIMEOUT_MS: u64 = 5000;
+
+/// `#include` edges between project files, resolved with each translation
+/// unit's include paths. Built from the compile database it records.
+#[derive(Debug, Default)]
+struct IncludeGraph {
+    index_path: PathBuf,
+    index_mtime: u64,
+    includes: HashMap<PathBuf, Vec<PathBuf>>,
+    includers: HashMap<PathBuf, Vec<PathBuf>>,
+    /// Number of translation units that reach each header.
+    fan_in: HashMap<PathBuf, usize>,
+}
+
+/// Resource usage of one clang-tidy process as reported by the OS. Not
+/// available on platforms without `wait4`.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceUsage {
+    peak_rss_kb: u64,
+    user_cpu_ms: u64,
+    system_cpu_ms: u64,
+}
+
+/// Most recent clang-tidy invocation for a file.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InvocationUsage {
+    mode: String,
+    wall_ms: u64,
+    #[serde(flatten)]
+    usage: Option<ResourceUsage>,
+}
+
+/// Aggregated clang-tidy resource usage since the daemon started.
+#[derive(Debug, Default)]
+struct ResourceStats {
+    invocations: u64,
+    wall_ms: u64,
+    user_cpu_ms: u64,
+    system_cpu_ms: u64,
+    peak_rss_kb: u64,
+    files: HashMap<PathBuf, InvocationUsage>,
+    /// Per-check timings of the latest profiled run of each file.
+    check_profiles: HashMap<PathBuf, HashMap<String, CheckTiming>>,
+    /// Timings summed over the files of recent project runs, oldest first.
+    run_profiles: VecDeque<RunProfile>,
+}
+
+/// Time clang-tidy spent in one check (`--enable-check-profile`).
+#[derive(Debug, Clone, Copy, Default)]
+struct CheckTiming {
+    wall_ms: f64,
+    user_ms: f64,
+    system_ms: f64,
+    files: usize,
+}
+
+impl CheckTiming {
+    fn add(&mut self, other: &CheckTiming) {
+        self.wall_ms += other.wall_ms;
+        self.user_ms += other.user_ms;
+        self.system_ms += other.system_ms;
+        self.files += other.files;
+    }
 }
 
 #[derive(Debug)]
-struct CompileCommandsIndex {
+struct RunProfile {
+    run_id: String,
+    files: usize,
+    checks: HashMap<String, CheckTiming>,
+}
+
+const RUN_PROFILE_HISTORY: usize = 10;
+
+/// Fix writes kept for `revertLastFix` / `revertRun`, and the pre-edit text
+/// they may hold in total; the oldest are forgotten first.
+const FIX_JOURNAL_ENTRIES: usize = 20;
+const FIX_JOURNAL_BYTES: usize = 64 * 1024 * 1024;
+
+/// Pre-edit contents of the files recent fix writes changed, newest last.
+#[derive(Debug, Default)]
+struct FixJournal {
+    next_id: u64,
+    entries: VecDeque<JournalEntry>,
+    bytes: usize,
+}
+
+/// The files one `applyFix`, `applyAllFixesInFile` or `applyFixesProject`
+/// run wrote.
+#[derive(Debug)]
+struct JournalEntry {
+    run_id: String,
+    files: Vec<JournalFile>,
+}
+
+#[derive(Debug)]
+struct JournalFile {
+    path: PathBuf,
+    before: String,
+    /// xxh3 hash of the written text, like `file_content_hash`; a revert is
+    /// refused once the file no longer matches it.
+    after: u64,
+}
+
+impl FixJournal {
+    /// Id for a single-file fix write, `applied-<n>`.
+    fn next_run_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("applied-{}", self.next_id)
+    }
+
+    fn record(&mut self, run_id: &str, files: Vec<JournalFile>) {
+        if files.is_empty() {
+            return;
+        }
+        self.bytes += files.iter().map(|file| file.before.len()).sum::<usize>();
+        self.entries.push_back(JournalEntry { run_id: run_id.to_string(), files });
+        while self.entries.len() > FIX_JOURNAL_ENTRIES || (self.bytes > FIX_JOURNAL_BYTES && self.entries.len() > 1) {
+            if let Some(oldest) = self.entries.pop_front() {
+                self.bytes -= oldest.files.iter().map(|file| file.before.len()).sum::<usize>();
+            }
+        }
+    }
+
+    /// Forgets `path` in the entry of `run_id`, for a file the run ended up
+    /// not writing; an entry left without files is forgotten too.
+    fn forget_file(&mut self, run_id: &str, path: &Path) {
+        let Some(index) = self.entries.iter().rposition(|entry| entry.run_id == run_id) else {
+            return;
+        };
+        let entry = &mut self.entries[index];
+        if let Some(position) = entry.files.iter().position(|file| file.path == path) {
+            self.bytes -= entry.files.remove(position).before.len();
+        }
+        if entry.files.is_empty() {
+            self.entries.remove(index);
+        }
+    }
+
+    /// Forgets the entry of a write that failed, unless one of its files kept
+    /// the new text (a restore failed too); that one stays revertable.
+    fn discard(&mut self, run_id: &str) {
+        let Some(index) = self.entries.iter().rposition(|entry| entry.run_id == run_id) else {
+            return;
+        };
+        let restored = self.entries[index]
+            .files
+            .iter()
+            .all(|file| file_content_hash(&file.path) == Some(xxh3_64(file.before.as_bytes())));
+        if restored {
+            let entry = self.entries.remove(index).expect("journal entry");
+            self.bytes -= entry.files.iter().map(|file| file.before.len()).sum::<usize>();
+        }
+    }
+
+    /// Restores the files of the newest entry with `run_id` (the newest entry
+    /// when `None`) and forgets it. Nothing is written when one of its files
+    /// changed after the fix was applied. Files that already hold their
+    /// pre-edit text, never written or restored by an earlier attempt, are
+    /// skipped, and the entry is kept when the restore fails, so it can be
+    /// retried.
+    fn revert(&mut self, run_id: Option<&str>) -> Result<Value> {
+        let index = match run_id {
+            Some(run_id) => self
+                .entries
+                .iter()
+                .rposition(|entry| entry.run_id == run_id)
+                .ok_or_else(|| invalid_params("runId", format!("No applied fixes recorded for run `{run_id}`")))?,
+            None => self.entries.len().checked_sub(1).context("No applied fixes to revert")?,
+        };
+        let entry = &self.entries[index];
+        let uri = |path: &Path| Url::from_file_path(path).map(|u| u.to_string()).unwrap_or_default();
+        let mut restores: Vec<(PathBuf, String, String)> = Vec::new();
+        for file in &entry.files {
+            let current = std::fs::read_to_string(&file.path).unwrap_or_default();
+            let hash = xxh3_64(current.as_bytes());
+            if hash == xxh3_64(file.before.as_bytes()) {
+                continue;
+            }
+            if hash != file.after {
+                return Err(RpcError {
+                    code: FILE_MODIFIED,
+                    message: "File changed since the fix was applied".to_string(),
+                    data: Some(serde_json::json!({ "reason": "fileModified", "fileUri": uri(&file.path) })),
+                }
+                .into());
+            }
+            restores.push((file.path.clone(), current, file.before.clone()));
+        }
+        write_files_atomic(&restores)?;
+        let entry = self.entries.remove(index).expect("journal entry");
+        self.bytes -= entry.files.iter().map(|file| file.before.len()).sum::<usize>();
+        Ok(serde_json::json!({
+            "runId": entry.run_id,
+            "revertedFiles": entry.files.iter().map(|file| uri(&file.path)).collect::<Vec<_>>(),
+        }))
+    }
+}
+
+#[derive(Debug, Default)]
+struct FixSessions {
+    next_id: u64,
+    sessions: HashMap<String, FixSession>,
+}
+
+/// A fix session nobody has touched for this long is dropped.
+const FIX_SESSION_IDLE_TTL: Duration = Duration::from_secs(30 * 60);
+
+impl FixSessions {
+    fn drop_idle(&mut self) {
+        let now = Instant::now();
+        self.sessions.retain(|_, session| now.duration_since(session.used) < FIX_SESSION_IDLE_TTL);
+    }
+}
+
+/// Review-fixes walk over one file. `text` is the document with all accepted
+/// fixes applied; pending edits are kept as byte ranges into it.
+#[derive(Debug)]
+struct FixSession {
+    /// The file the session walks; closing its document ends the session.
+    file_path: PathBuf,
+    used: Instant,
+    text: String,
+    pending: VecDeque<SessionFix>,
+    current: Option<SessionFix>,
+    accepted: usize,
+    skipped: usize,
+    conflicting: usize,
+}
+
+#[derive(Debug, Clone)]
+struct SessionFix {
+    diagnostic_id: String,
+    code: Option<String>,
+    message: String,
+    title: String,
+    safety: Option<FixSafety>,
+    /// (start, end, replacement), byte offsets into `FixSession::text`.
+    edits: Vec<(usize, usize, String)>,
+}
+
+/// Lock file marking the daemon that serves a workspace. Removed on drop.
+#[derive(Debug)]
+struct InstanceLock {
     path: PathBuf,
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Contents of a workspace lock file.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InstanceInfo {
+    pid: u32,
+    /// Distinguishes the `--socket`/`--listen` sessions of one process.
+    #[serde(default)]
+    session_id: u64,
+    root_uri: String,
+    started_at: u64,
+}
+
+/// Files an active project run has scheduled but not finished yet.
+#[derive(Debug, Default)]
+struct ProjectRunFiles {
+    mode: String,
+    pending: HashSet<PathBuf>,
+}
+
+/// Removes a run's flag from `cancel_map` when the run ends.
+struct CancelEntryGuard {
+    map: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>,
+    run_id: String,
+    flag: Arc<AtomicBool>,
+}
+
+impl Drop for CancelEntryGuard {
+    fn drop(&mut self) {
+        let mut map = self.map.lock().unwrap();
+        if map.get(&self.run_id).is_some_and(|flag| Arc::ptr_eq(flag, &self.flag)) {
+            map.remove(&self.run_id);
+        }
+    }
+}
+
+/// Removes a project run from `project_runs` when its thread exits.
+struct ProjectRunGuard {
+    runs: Arc<Mutex<HashMap<String, ProjectRunFiles>>>,
+    run_id: String,
+}
+
+impl Drop for ProjectRunGuard {
+    fn drop(&mut self) {
+        self.runs.lock().unwrap().remove(&self.run_id);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket is back at capacity. A full bucket behaves like a
+    /// missing one, so it is dropped then.
+    full_at: Instant,
+}
+
+/// Editor buffer state tracked from document lifecycle notifications.
+#[derive(Debug, Clone, Default)]
+struct OpenDocument {
+    content: Option<String>,
+    version: Option<i64>,
+    generation: u64,
+}
+
+/// Result of `analyze_document`. `stale` is set when a newer document version
+/// was seen while clang-tidy was running.
+struct DocumentAnalysis {
+    diagnostics: Vec<RpcDiagnostic>,
+    stale: bool,
+    /// Why the file was not analyzed, e.g. it has no compile command.
+    skipped: Option<String>,
+    /// Source file whose compile command was borrowed (`flagInference`).
+    flags_from: Option<PathBuf>,
+    /// Merged findings of the headers the analysis touched, by header URI,
+    /// ready to publish.
+    headers: Vec<(String, Vec<RpcDiagnostic>)>,
+}
+
+/// A file found not analyzable against one version of the compile database.
+#[derive(Debug, Clone)]
+struct SkipVerdict {
+    compile_commands: PathBuf,
     mtime: u64,
-    files: Vec<PathBuf>,
-    file_set: HashSet<PathBuf>,
-    commands: HashMap<PathBuf, CompileCommandEntry>,
+    reason: String,
+}
+
+/// Options negotiated with the client at `initialize`.
+#[derive(Debug, Clone, Default)]
+struct ClientOptions {
+    fix_format: FixFormat,
+    diagnostics_delta: bool,
+    work_done_progress: bool,
+}
+
+/// Diagnostics last published for a URI, used to compute deltas and to
+/// resend the set in full.
+#[derive(Debug, Default)]
+struct PublishedSet {
+    diagnostics: Vec<RpcDiagnostic>,
+    version: u64,
+    since_full: u32,
+}
+
+const DEFAULT_DELTA_RESYNC_INTERVAL: u32 = 20;
+
+/// Progress of one long-running request, sent as LSP `$/progress` on the
+/// client's `workDoneToken` when it opted in, as the older `progress`
+/// notification keyed by `runId` otherwise.
+#[derive(Clone)]
+struct ProgressReporter {
+    stdout: Arc<Output>,
+    run_id: String,
+    token: Value,
+    lsp: bool,
+    tokens: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl ProgressReporter {
+    /// Registers the request's token (`workDoneToken`, else `runId`) so that
+    /// `window/workDoneProgress/cancel` can find the run.
+    fn new(state: &AppState, run_id: &str, params: &Value) -> Self {
+        let token = params.get("workDoneToken").cloned().unwrap_or_else(|| Value::String(run_id.to_string()));
+        state.progress_tokens.lock().unwrap().insert(token.to_string(), run_id.to_string());
+        ProgressReporter {
+            stdout: state.stdout.clone(),
+            run_id: run_id.to_string(),
+            token,
+            lsp: state.client.lock().unwrap().work_done_progress,
+            tokens: state.progress_tokens.clone(),
+        }
+    }
+
+    fn begin(&self, title: &str, message: String, cancellable: bool) {
+        if self.lsp {
+            self.send(serde_json::json!({
+                "kind": "begin",
+                "title": title,
+                "message": message,
+                "cancellable": cancellable,
+                "percentage": 0,
+            }));
+        } else {
+            self.send_legacy("begin", message, None);
+        }
+    }
+
+    fn report(&self, message: String, percent: Option<u32>) {
+        self.report_details(message, percent, serde_json::Map::new());
+    }
+
+    /// Like `report`, with extra fields (run timing, active files) added to
+    /// the payload in both progress forms.
+    fn report_details(&self, message: String, percent: Option<u32>, details: serde_json::Map<String, Value>) {
+        let mut value = if self.lsp {
+            let mut value = serde_json::json!({ "kind": "report", "message": message });
+            if let Some(percent) = percent {
+                value["percentage"] = serde_json::json!(percent);
+            }
+            value
+        } else {
+            self.legacy_params("report", message, percent)
+        };
+        if let Some(fields) = value.as_object_mut() {
+            fields.extend(details);
+        }
+        if self.lsp {
+            self.send(value);
+        } else {
+            let _ = send_notification(&self.stdout, "progress", value);
+        }
+    }
+
+    fn end(&self, message: &str) {
+        self.tokens.lock().unwrap().remove(&self.token.to_string());
+        if self.lsp {
+            self.send(serde_json::json!({ "kind": "end", "message": message }));
+        } else {
+            self.send_legacy("end", message.to_string(), None);
+        }
+    }
+
+    fn send(&self, value: Value) {
+        let _ = send_notification(&self.stdout, "$/progress", serde_json::json!({ "token": self.token, "value": value }));
+    }
+
+    fn send_legacy(&self, kind: &str, message: String, percent: Option<u32>) {
+        let params = self.legacy_params(kind, message, percent);
+        let _ = send_notification(&self.stdout, "progress", params);
+    }
+
+    fn legacy_params(&self, kind: &str, message: String, percent: Option<u32>) -> Value {
+        let mut params = serde_json::json!({ "runId": self.run_id, "kind": kind, "message": message });
+        if let Some(percent) = percent {
+            params["percent"] = serde_json::json!(percent);
+        }
+        params
+    }
+}
+
+/// Weight of the newest file in the moving average of per-file wall time.
+const PROGRESS_AVERAGE_WEIGHT: f64 = 0.2;
+/// Longest gap between progress reports of a project run while files are
+/// still being analyzed.
+const PROGRESS_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Files in flight and a moving average of per-file wall time, for the
+/// timing details of project run progress reports.
+struct RunClock {
+    started: Instant,
+    active: Vec<PathBuf>,
+    average_ms: Option<f64>,
+    last_report: Instant,
+}
+
+impl RunClock {
+    fn new(started: Instant) -> Self {
+        RunClock {
+            started,
+            active: Vec::new(),
+            average_ms: None,
+            last_report: Instant::now(),
+        }
+    }
+
+    fn start(&mut self, file: &Path) {
+        self.active.push(file.to_path_buf());
+    }
+
+    fn finish(&mut self, file: &Path, wall_ms: u64) {
+        if let Some(pos) = self.active.iter().position(|f| f == file) {
+            self.active.remove(pos);
+        }
+        let wall_ms = wall_ms as f64;
+        self.average_ms = Some(match self.average_ms {
+            Some(avg) => avg + PROGRESS_AVERAGE_WEIGHT * (wall_ms - avg),
+            None => wall_ms,
+        });
+    }
+
+    fn due(&self) -> bool {
+        self.last_report.elapsed() >= PROGRESS_REPORT_INTERVAL
+    }
+
+    /// `elapsedMs`, `etaMs` (once a file has finished) and `activeFiles`, for
+    /// `remaining` files shared by `workers` workers.
+    fn details(&mut self, remaining: usize, workers: usize) -> serde_json::Map<String, Value> {
+        self.last_report = Instant::now();
+        let mut details = serde_json::Map::new();
+        details.insert("elapsedMs".to_string(), serde_json::json!(self.started.elapsed().as_millis() as u64));
+        if let Some(avg) = self.average_ms {
+            let eta = avg * remaining as f64 / workers.clamp(1, remaining.max(1)) as f64;
+            details.insert("etaMs".to_string(), serde_json::json!(eta.round() as u64));
+        }
+        let active: Vec<Value> = self
+            .active
+            .iter()
+            .filter_map(|f| Url::from_file_path(f).ok())
+            .map(|u| Value::String(u.to_string()))
+            .collect();
+        details.insert("activeFiles".to_string(), Value::Array(active));
+        details
+    }
+}
+
+/// Sends `publishDiagnostics`, as a delta against the previous set for the URI
+/// when the client opted in.
+#[derive(Clone)]
+struct Publisher {
+    stdout: Arc<Output>,
+    published: Arc<Mutex<HashMap<String, PublishedSet>>>,
+    delta: bool,
+    resync_interval: u32,
+    stats: Arc<Mutex<DiagnosticStats>>,
+    /// Whether publishes also count towards their run in `stats`.
+    track_runs: bool,
+}
+
+impl Publisher {
+    fn from_state(state: &AppState) -> Self {
+        let interval = state.settings.lock().unwrap().deltaResyncInterval;
+        Publisher {
+            stdout: state.stdout.clone(),
+            published: state.published.clone(),
+            delta: state.client.lock().unwrap().diagnostics_delta,
+            resync_interval: if interval == 0 { DEFAULT_DELTA_RESYNC_INTERVAL } else { interval },
+            stats: state.diagnostic_stats.clone(),
+            track_runs: false,
+        }
+    }
+
+    /// A publisher whose publishes are also kept as per-run statistics, for
+    /// `analyzeProject` and `analyzeChangedFiles`.
+    fn tracking_runs(self) -> Self {
+        Publisher { track_runs: true, ..self }
+    }
+
+    fn publish(&self, run_id: &str, file_uri: &str, document_version: Option<i64>, diags: &[RpcDiagnostic]) -> Result<()> {
+        if let Some(file_path) = uri_to_path(file_uri) {
+            self.stats.lock().unwrap().record(self.track_runs.then_some(run_id), &file_path, diags);
+        }
+        if !self.delta {
+            let mut params = serde_json::json!({
+                "runId": run_id,
+                "fileUri": file_uri,
+                "diagnostics": diags,
+            });
+            if let Some(version) = document_version {
+                params["documentVersion"] = serde_json::json!(version);
+            }
+            return send_notification(&self.stdout, "publishDiagnostics", params);
+        }
+        self.publish_versioned(run_id, file_uri, document_version, diags, false).map(|_| ())
+    }
+
+    /// Publishes the set last sent for `file_uri` again in full, for a delta
+    /// client that lost track of it. Returns the new version, or `None` when
+    /// nothing was published for the URI.
+    fn resync(&self, run_id: &str, file_uri: &str) -> Result<Option<u64>> {
+        let Some(diags) = self.published.lock().unwrap().get(file_uri).map(|entry| entry.diagnostics.clone()) else {
+            return Ok(None);
+        };
+        let version = self.publish_versioned(run_id, file_uri, None, &diags, true)?;
+        Ok(Some(version))
+    }
+
+    /// Publishes in delta mode and returns the URI's new version.
+    fn publish_versioned(&self, run_id: &str, file_uri: &str, document_version: Option<i64>, diags: &[RpcDiagnostic], full: bool) -> Result<u64> {
+        let mut published = self.published.lock().unwrap();
+        let entry = published.entry(file_uri.to_string()).or_default();
+        let base_version = entry.version;
+        entry.version += 1;
+        let full = full || base_version == 0 || entry.since_full + 1 >= self.resync_interval;
+        let mut params = if full {
+            entry.since_full = 0;
+            serde_json::json!({
+                "runId": run_id,
+                "fileUri": file_uri,
+                "version": entry.version,
+                "diagnostics": diags,
+            })
+        } else {
+            entry.since_full += 1;
+            // The id covers position, check and message only; a diagnostic
+            // whose range, severity, fixes or tags changed is sent again,
+            // removed and re-added under the same id.
+            let content = |d: &RpcDiagnostic| xxh3_64(serde_json::to_string(d).unwrap_or_default().as_bytes());
+            let previous: HashMap<&str, u64> = entry.diagnostics.iter().map(|d| (d.id.as_str(), content(d))).collect();
+            let current: HashMap<&str, u64> = diags.iter().map(|d| (d.id.as_str(), content(d))).collect();
+            let added: Vec<&RpcDiagnostic> =
+                diags.iter().filter(|d| previous.get(d.id.as_str()) != current.get(d.id.as_str())).collect();
+            let removed: Vec<&str> = entry
+                .diagnostics
+                .iter()
+                .map(|d| d.id.as_str())
+                .filter(|id| previous.get(id) != current.get(id))
+                .collect();
+            serde_json::json!({
+                "runId": run_id,
+                "fileUri": file_uri,
+                "version": entry.version,
+                "delta": {"baseVersion": base_version, "added": added, "removed": removed},
+            })
+        };
+        if let Some(version) = document_version {
+            params["documentVersion"] = serde_json::json!(version);
+        }
+        entry.diagnostics = diags.to_vec();
+        let version = entry.version;
+        drop(published);
+        send_notification(&self.stdout, "publishDiagnostics", params)?;
+        Ok(version)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum FixFormat {
+    #[default]
+    Edits,
+    WorkspaceEdit,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct Position {
+    line: usize,
+    character: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct Range {
+    start: Position,
+    end: Position,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct TextEdit {
+    range: Range,
+    #[serde(rename = "newText")]
+    new_text: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Fix {
+    title: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    edits: Vec<TextEdit>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    edit: Option<WorkspaceEdit>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    safety: Option<FixSafety>,
+    /// Other fixes of the same result whose edits overlap this one's; applying
+    /// both would corrupt the text.
+    #[serde(rename = "conflictsWith", default, skip_serializing_if = "Vec::is_empty")]
+    conflicts_with: Vec<FixRef>,
+}
+
+/// Points at a fix by its diagnostic and its index in `fixes`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct FixRef {
+    diagnostic_id: String,
+    fix_index: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum FixSafety {
+    Safe,
+    Cosmetic,
+    BehaviorChanging,
+}
+
+/// Per-check fix metadata. Patterns ending in `*` match by prefix; the first
+/// matching row wins, so specific checks must come before their group.
+const CHECK_FIX_METADATA: &[(&str, &str, FixSafety)] = &[
+    ("modernize-use-nullptr", "quickfix", FixSafety::Safe),
+    ("modernize-use-override", "quickfix", FixSafety::Safe),
+    ("modernize-use-bool-literals", "quickfix", FixSafety::Safe),
+    ("modernize-use-using", "refactor.rewrite", FixSafety::Cosmetic),
+    ("modernize-use-trailing-return-type", "refactor.rewrite", FixSafety::Cosmetic),
+    ("modernize-use-auto", "refactor.rewrite", FixSafety::Cosmetic),
+    ("modernize-pass-by-value", "refactor.rewrite", FixSafety::BehaviorChanging),
+    ("modernize-loop-convert", "refactor.rewrite", FixSafety::BehaviorChanging),
+    ("modernize-use-emplace", "refactor.rewrite", FixSafety::BehaviorChanging),
+    ("modernize-*", "refactor.rewrite", FixSafety::Safe),
+    ("readability-braces-around-statements", "refactor.rewrite", FixSafety::Cosmetic),
+    ("readability-identifier-naming", "refactor.rewrite", FixSafety::BehaviorChanging),
+    ("readability-*", "refactor.rewrite", FixSafety::Cosmetic),
+    ("google-readability-*", "refactor.rewrite", FixSafety::Cosmetic),
+    ("llvm-include-order", "refactor.rewrite", FixSafety::Cosmetic),
+    ("llvm-namespace-comment", "refactor.rewrite", FixSafety::Cosmetic),
+    ("misc-unused-*", "quickfix", FixSafety::Safe),
+    ("misc-include-cleaner", "quickfix", FixSafety::BehaviorChanging),
+    ("performance-unnecessary-value-param", "refactor.rewrite", FixSafety::BehaviorChanging),
+];
+
+/// Alias checks and the canonical check they forward to, so the same finding
+/// reported under both names collapses into one diagnostic.
+const CHECK_ALIASES: &[(&str, &str)] = &[
+    ("cert-arr39-c", "bugprone-sizeof-expression"),
+    ("cert-con36-c", "bugprone-spuriously-wake-up-functions"),
+    ("cert-con54-cpp", "bugprone-spuriously-wake-up-functions"),
+    ("cert-ctr56-cpp", "bugprone-pointer-arithmetic-on-polymorphic-object"),
+    ("cert-dcl03-c", "misc-static-assert"),
+    ("cert-dcl16-c", "readability-uppercase-literal-suffix"),
+    ("cert-dcl37-c", "bugprone-reserved-identifier"),
+    ("cert-dcl51-cpp", "bugprone-reserved-identifier"),
+    ("cert-dcl54-cpp", "misc-new-delete-overloads"),
+    ("cert-dcl59-cpp", "google-build-namespaces"),
+    ("cert-err09-cpp", "misc-throw-by-value-catch-by-reference"),
+    ("cert-err33-c", "bugprone-unused-return-value"),
+    ("cert-err61-cpp", "misc-throw-by-value-catch-by-reference"),
+    ("cert-exp42-c", "bugprone-suspicious-memory-comparison"),
+    ("cert-fio38-c", "misc-non-copyable-objects"),
+    ("cert-flp37-c", "bugprone-suspicious-memory-comparison"),
+    ("cert-int09-c", "readability-enum-initial-value"),
+    ("cert-msc24-c", "bugprone-unsafe-functions"),
+    ("cert-msc30-c", "cert-msc50-cpp"),
+    ("cert-msc32-c", "cert-msc51-cpp"),
+    ("cert-msc33-c", "bugprone-unsafe-functions"),
+    ("cert-oop11-cpp", "performance-move-constructor-init"),
+    ("cert-oop54-cpp", "bugprone-unhandled-self-assignment"),
+    ("cert-pos44-c", "bugprone-bad-signal-to-kill-thread"),
+    ("cert-sig30-c", "bugprone-signal-handler"),
+    ("cert-str34-c", "bugprone-signed-char-misuse"),
+    ("cppcoreguidelines-avoid-c-arrays", "modernize-avoid-c-arrays"),
+    ("cppcoreguidelines-avoid-magic-numbers", "readability-magic-numbers"),
+    ("cppcoreguidelines-c-copy-assignment-signature", "misc-unconventional-assign-operator"),
+    ("cppcoreguidelines-explicit-virtual-functions", "modernize-use-override"),
+    ("cppcoreguidelines-macro-to-enum", "modernize-macro-to-enum"),
+    ("cppcoreguidelines-narrowing-conversions", "bugprone-narrowing-conversions"),
+    ("cppcoreguidelines-noexcept-destructor", "performance-noexcept-destructor"),
+    ("cppcoreguidelines-noexcept-move-operations", "performance-noexcept-move-constructor"),
+    ("cppcoreguidelines-noexcept-swap", "performance-noexcept-swap"),
+    ("cppcoreguidelines-non-private-member-variables-in-classes", "misc-non-private-member-variables-in-classes"),
+    ("cppcoreguidelines-use-default-member-init", "modernize-use-default-member-init"),
+    ("fuchsia-header-anon-namespaces", "google-build-namespaces"),
+    ("google-readability-braces-around-statements", "readability-braces-around-statements"),
+    ("google-readability-function-size", "readability-function-size"),
+    ("google-readability-namespace-comments", "llvm-namespace-comment"),
+    ("hicpp-avoid-c-arrays", "modernize-avoid-c-arrays"),
+    ("hicpp-avoid-goto", "cppcoreguidelines-avoid-goto"),
+    ("hicpp-braces-around-statements", "readability-braces-around-statements"),
+    ("hicpp-deprecated-headers", "modernize-deprecated-headers"),
+    ("hicpp-explicit-conversions", "google-explicit-constructor"),
+    ("hicpp-function-size", "readability-function-size"),
+    ("hicpp-invalid-access-moved", "bugprone-use-after-move"),
+    ("hicpp-member-init", "cppcoreguidelines-pro-type-member-init"),
+    ("hicpp-move-const-arg", "performance-move-const-arg"),
+    ("hicpp-named-parameter", "readability-named-parameter"),
+    ("hicpp-new-delete-operators", "misc-new-delete-overloads"),
+    ("hicpp-no-array-decay", "cppcoreguidelines-pro-bounds-array-to-pointer-decay"),
+    ("hicpp-no-malloc", "cppcoreguidelines-no-malloc"),
+    ("hicpp-noexcept-move", "performance-noexcept-move-constructor"),
+    ("hicpp-special-member-functions", "cppcoreguidelines-special-member-functions"),
+    ("hicpp-static-assert", "misc-static-assert"),
+    ("hicpp-undelegated-constructor", "bugprone-undelegated-constructor"),
+    ("hicpp-uppercase-literal-suffix", "readability-uppercase-literal-suffix"),
+    ("hicpp-use-auto", "modernize-use-auto"),
+    ("hicpp-use-emplace", "modernize-use-emplace"),
+    ("hicpp-use-equals-default", "modernize-use-equals-default"),
+    ("hicpp-use-equals-delete", "modernize-use-equals-delete"),
+    ("hicpp-use-noexcept", "modernize-use-noexcept"),
+    ("hicpp-use-nullptr", "modernize-use-nullptr"),
+    ("hicpp-use-override", "modernize-use-override"),
+    ("hicpp-vararg", "cppcoreguidelines-pro-type-vararg"),
+    ("llvm-else-after-return", "readability-else-after-return"),
+    ("llvm-qualified-auto", "readability-qualified-auto"),
+];
+
+/// Maps a reported check code to its canonical check. clang-tidy joins the names
+/// with commas when several enabled aliases fire at once; the first one wins.
+fn canonical_check(code: &str) -> String {
+    let first = code.split(',').map(str::trim).find(|c| !c.is_empty()).unwrap_or(code);
+    CHECK_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == first)
+        .map(|(_, canonical)| canonical.to_string())
+        .unwrap_or_else(|| first.to_string())
+}
+
+fn normalize_check_codes(diags: &mut [InternalDiagnostic]) {
+    for diag in diags.iter_mut() {
+        if let Some(code) = diag.code.as_mut() {
+            *code = canonical_check(code);
+        }
+    }
+}
+
+/// Code action kind of the `NOLINT` fixes added by `suppressionFixes`. They
+/// silence a finding instead of fixing it, so bulk operations skip them.
+const SUPPRESSION_FIX_KIND: &str = "quickfix.suppress";
+
+fn is_suppression_fix(fix: &Fix) -> bool {
+    fix.kind.as_deref() == Some(SUPPRESSION_FIX_KIND)
+}
+
+/// Appends two fixes to every clang-tidy finding that suppress it: a
+/// `// NOLINT(check)` at the end of its line and a `// NOLINTNEXTLINE(check)`
+/// line above it, indented like the line. Errors cannot be suppressed, and
+/// cppcheck or external analyzer findings do not know `NOLINT`. On a line
+/// continued with `\`, such as a multi-line macro, a comment would swallow
+/// the continuation, so `NOLINT` goes on the last line and `NOLINTNEXTLINE`
+/// above the first one.
+fn add_suppression_fixes(diags: &mut [InternalDiagnostic], text: &str) {
+    let line_starts = build_line_starts(text);
+    let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+    let line_text = |line: usize| {
+        line_starts
+            .get(line)
+            .map(|&start| text[start..].split('\n').next().unwrap_or_default().trim_end_matches('\r'))
+    };
+    let continued = |line: usize| line_text(line).is_some_and(|text| text.ends_with('\\'));
+    for diag in diags.iter_mut() {
+        let Some(check) = diag.code.as_deref() else {
+            continue;
+        };
+        if check == "clang-diagnostic-error" || check.starts_with("cppcheck-") || check.contains(':') {
+            continue;
+        }
+        if line_starts.get(diag.range.start.line).is_none() {
+            continue;
+        }
+        let mut first = diag.range.start.line;
+        while first > 0 && continued(first - 1) {
+            first -= 1;
+        }
+        let mut last = diag.range.start.line;
+        while continued(last) && line_text(last + 1).is_some() {
+            last += 1;
+        }
+        let first_line = line_text(first).unwrap_or_default();
+        let indent = &first_line[..first_line.len() - first_line.trim_start().len()];
+        let last_line = line_text(last).unwrap_or_default();
+        let line_end = offset_range(text, &line_starts, line_starts[last] + last_line.len(), 0);
+        let line_start = Position { line: first, character: 0 };
+        let suppression = |title: String, range: Range, new_text: String| Fix {
+            title,
+            edits: vec![TextEdit { range, new_text }],
+            edit: None,
+            kind: Some(SUPPRESSION_FIX_KIND.to_string()),
+            safety: Some(FixSafety::Safe),
+            conflicts_with: Vec::new(),
+        };
+        diag.fixes.push(suppression(
+            format!("Suppress {check} on this line (NOLINT)"),
+            line_end,
+            format!(" // NOLINT({check})"),
+        ));
+        diag.fixes.push(suppression(
+            format!("Suppress {check} on this line (NOLINTNEXTLINE)"),
+            Range { start: line_start.clone(), end: line_start },
+            format!("{indent}// NOLINTNEXTLINE({check}){newline}"),
+        ));
+    }
+}
+
+fn classify_fix(check: Option<&str>) -> (&'static str, FixSafety) {
+    if let Some(check) = check {
+        for (pattern, kind, safety) in CHECK_FIX_METADATA {
+            let matches = match pattern.strip_suffix('*') {
+                Some(prefix) => check.starts_with(prefix),
+                None => check == *pattern,
+            };
+            if matches {
+                return (kind, *safety);
+            }
+        }
+    }
+    ("quickfix", FixSafety::BehaviorChanging)
+}
+
+/// LSP `WorkspaceEdit` restricted to the `documentChanges` form.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct WorkspaceEdit {
+    #[serde(rename = "documentChanges", default)]
+    document_changes: Vec<DocumentChange>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum DocumentChange {
+    Edit(TextDocumentEdit),
+    Resource(ResourceOperation),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct TextDocumentEdit {
+    #[serde(rename = "textDocument")]
+    text_document: VersionedTextDocumentIdentifier,
+    edits: Vec<TextEdit>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct VersionedTextDocumentIdentifier {
+    uri: String,
+    version: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum ResourceOperation {
+    Create {
+        uri: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        options: Option<ResourceOptions>,
+    },
+    Rename {
+        #[serde(rename = "oldUri")]
+        old_uri: String,
+        #[serde(rename = "newUri")]
+        new_uri: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        options: Option<ResourceOptions>,
+    },
+    Delete {
+        uri: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        options: Option<ResourceOptions>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct ResourceOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    overwrite: Option<bool>,
+    #[serde(rename = "ignoreIfExists", default, skip_serializing_if = "Option::is_none")]
+    ignore_if_exists: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recursive: Option<bool>,
+    #[serde(rename = "ignoreIfNotExists", default, skip_serializing_if = "Option::is_none")]
+    ignore_if_not_exists: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct RpcDiagnostic {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    id: String,
+    range: Range,
+    severity: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fixes: Option<Vec<Fix>>,
+    #[serde(rename = "codeDescription", default, skip_serializing_if = "Option::is_none")]
+    code_description: Option<CodeDescription>,
+    #[serde(rename = "relatedInformation", default, skip_serializing_if = "Option::is_none")]
+    related_information: Option<Vec<RelatedInformation>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    experimental: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
+    #[serde(rename = "inferredFlags", default, skip_serializing_if = "Option::is_none")]
+    inferred_flags: Option<bool>,
+    /// Header the finding is in, for findings outside the analyzed file;
+    /// cleared when they are published to the header.
+    #[serde(rename = "fileUri", default, skip_serializing_if = "Option::is_none")]
+    file_uri: Option<String>,
+    /// Number of translation units that reported a header finding.
+    #[serde(rename = "tuCount", default, skip_serializing_if = "Option::is_none")]
+    tu_count: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct CodeDescription {
+    href: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct Location {
+    uri: String,
+    range: Range,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct RelatedInformation {
+    location: Location,
+    message: String,
+}
+
+/// Location attached to an `InternalDiagnostic`, converted to
+/// `RelatedInformation` when the diagnostic is published.
+#[derive(Debug, Clone, PartialEq)]
+struct RelatedNote {
+    file: PathBuf,
+    range: Range,
+    message: String,
+}
+
+const DISK_CACHE_VERSION: u32 = 7;
+
+const DEFAULT_MEMORY_CACHE_MAX_DIAGNOSTICS: usize = 200_000;
+
+/// Per-file values kept in memory while their total size stays under a
+/// diagnostics budget. Least recently used files beyond it are written to a
+/// per-process spill directory and read back on access.
+#[derive(Debug)]
+struct SpillStore<V> {
+    entries: HashMap<PathBuf, (V, u64)>,
+    order: BTreeMap<u64, PathBuf>,
+    /// Spilled paths with the tick they were last used at.
+    spilled: HashMap<PathBuf, u64>,
+    tick: u64,
+    weight: usize,
+    limit: usize,
+    /// Most entries kept, in memory and spilled together; 0 is unlimited.
+    max_entries: usize,
+    /// Entries dropped to stay within `max_entries`.
+    evictions: u64,
+    spill_dir: Option<tempfile::TempDir>,
+}
+
+/// Size of a stored value in diagnostics, for `SpillStore` budgeting.
+trait StoreWeight {
+    fn weight(&self) -> usize;
+}
+
+impl StoreWeight for CacheEntry {
+    fn weight(&self) -> usize {
+        self.diagnostics.len() + 1
+    }
+}
+
+impl StoreWeight for Vec<RpcDiagnostic> {
+    fn weight(&self) -> usize {
+        self.len() + 1
+    }
+}
+
+impl<V> Default for SpillStore<V> {
+    fn default() -> Self {
+        SpillStore {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            spilled: HashMap::new(),
+            tick: 0,
+            weight: 0,
+            limit: DEFAULT_MEMORY_CACHE_MAX_DIAGNOSTICS,
+            max_entries: 0,
+            evictions: 0,
+            spill_dir: None,
+        }
+    }
+}
+
+impl<V: Clone + Serialize + serde::de::DeserializeOwned + StoreWeight> SpillStore<V> {
+    fn set_limit(&mut self, limit: u64) {
+        self.limit = if limit == 0 { DEFAULT_MEMORY_CACHE_MAX_DIAGNOSTICS } else { limit as usize };
+        self.spill_excess();
+    }
+
+    fn set_max_entries(&mut self, max_entries: u64) {
+        self.max_entries = max_entries as usize;
+        self.evict_excess();
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len() + self.spilled.len()
+    }
+
+    fn get(&mut self, path: &Path) -> Option<V> {
+        if self.spilled.contains_key(path) {
+            let value = self
+                .spill_path(path)
+                .and_then(|file| std::fs::read(file).ok())
+                .and_then(|data| serde_json::from_slice::<V>(&data).ok());
+            self.spilled.remove(path);
+            let value = value?;
+            self.insert(path.to_path_buf(), value.clone());
+            return Some(value);
+        }
+        let tick = self.next_tick();
+        let (value, last_used) = self.entries.get_mut(path)?;
+        self.order.remove(last_used);
+        *last_used = tick;
+        self.order.insert(tick, path.to_path_buf());
+        Some(value.clone())
+    }
+
+    fn contains_key(&self, path: &Path) -> bool {
+        self.entries.contains_key(path) || self.spilled.contains_key(path)
+    }
+
+    fn insert(&mut self, path: PathBuf, value: V) {
+        self.remove(&path);
+        let tick = self.next_tick();
+        self.weight += value.weight();
+        self.order.insert(tick, path.clone());
+        self.entries.insert(path, (value, tick));
+        self.evict_excess();
+        self.spill_excess();
+    }
+
+    fn remove(&mut self, path: &Path) -> bool {
+        if self.spilled.remove(path).is_some() {
+            if let Some(file) = self.spill_path(path) {
+                let _ = std::fs::remove_file(file);
+            }
+            return true;
+        }
+        match self.entries.remove(path) {
+            Some((value, last_used)) => {
+                self.weight -= value.weight();
+                self.order.remove(&last_used);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&Path) -> bool) {
+        let doomed: Vec<PathBuf> = self
+            .entries
+            .keys()
+            .chain(self.spilled.keys())
+            .filter(|path| !keep(path))
+            .cloned()
+            .collect();
+        for path in doomed {
+            self.remove(&path);
+        }
+    }
+
+    /// Every entry, spilled ones included, without changing their order.
+    fn snapshot(&self) -> Vec<(PathBuf, V)> {
+        let mut all: Vec<(PathBuf, V)> = self.entries.iter().map(|(path, (value, _))| (path.clone(), value.clone())).collect();
+        for path in self.spilled.keys() {
+            let value = self
+                .spill_path(path)
+                .and_then(|file| std::fs::read(file).ok())
+                .and_then(|data| serde_json::from_slice::<V>(&data).ok());
+            if let Some(value) = value {
+                all.push((path.clone(), value));
+            }
+        }
+        all
+    }
+
+    /// Deletes the spill directory; spilled entries are forgotten.
+    fn discard_spill(&mut self) {
+        self.spilled.clear();
+        self.spill_dir.take();
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn spill_path(&self, path: &Path) -> Option<PathBuf> {
+        let dir = self.spill_dir.as_ref()?;
+        Some(dir.path().join(format!("{:016x}.json", cache_key_for_path(path))))
+    }
+
+    /// Drops the least recently used entries until at most `max_entries`
+    /// remain. Spilled entries are always older than those in memory, so they
+    /// go first. The most recent entry always stays.
+    fn evict_excess(&mut self) {
+        while self.max_entries > 0 && self.len() > self.max_entries.max(1) {
+            let oldest_spilled = self.spilled.iter().min_by_key(|(_, tick)| **tick).map(|(path, _)| path.clone());
+            let path = match oldest_spilled {
+                Some(path) => path,
+                None => match self.order.first_key_value() {
+                    Some((_, path)) => path.clone(),
+                    None => break,
+                },
+            };
+            self.remove(&path);
+            self.evictions += 1;
+        }
+    }
+
+    /// Moves the least recently used entries out of memory until the budget
+    /// is met. The most recent entry always stays. Entries that cannot be
+    /// written are dropped.
+    fn spill_excess(&mut self) {
+        while self.weight > self.limit && self.entries.len() > 1 {
+            let Some((_, path)) = self.order.pop_first() else {
+                break;
+            };
+            let Some((value, last_used)) = self.entries.remove(&path) else {
+                continue;
+            };
+            self.weight -= value.weight();
+            if self.spill_dir.is_none() {
+                self.spill_dir = tempfile::Builder::new().prefix(&temp_artifact_prefix()).tempdir().ok();
+            }
+            let written = match (self.spill_path(&path), serde_json::to_vec(&value)) {
+                (Some(file), Ok(data)) => std::fs::write(file, data).is_ok(),
+                _ => false,
+            };
+            if written {
+                self.spilled.insert(path, last_used);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    /// mtime (ns) and size when the entry was made or last matched; a fast
+    /// pre-check before `content_hash`.
+    mtime: u64,
+    size: u64,
+    content_hash: u64,
+    settings_hash: u64,
+    diagnostics: Vec<RpcDiagnostic>,
+    /// Content hashes of the other files the fixes edit, taken when they were
+    /// made; `applyFix` refuses to edit a file that no longer matches.
+    #[serde(default)]
+    fix_files: Vec<(PathBuf, u64)>,
+}
+
+impl CacheEntry {
+    /// Whether the entry was made from the current content of `path`: its
+    /// mtime and size (`stamp`) are unchanged, or else the content hashes the
+    /// same, e.g. after a checkout that only touched the mtime.
+    fn matches_file(&self, path: &Path, stamp: Option<(u64, u64)>) -> bool {
+        stamp == Some((self.mtime, self.size)) || file_content_hash(path) == Some(self.content_hash)
+    }
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize, Serialize)]
+struct DiskCacheEntry {
+    version: u32,
+    filePath: String,
+    mtime: u64,
+    size: u64,
+    contentHash: u64,
+    settingsHash: u64,
+    diagnostics: Vec<RpcDiagnostic>,
+    #[serde(default)]
+    fixFiles: Vec<(PathBuf, u64)>,
+}
+
+const CACHE_JOURNAL_FILE: &str = "journal.log";
+/// Every daemon using a cache directory holds a shared lock on this file, so
+/// journal recovery runs only when no other daemon may be writing.
+const CACHE_LOCK_FILE: &str = "daemons.lock";
+
+/// One line of the disk cache journal. Writes log `begin`/`commit` around the
+/// atomic rename, removals log `evict`/`removed`; recovery undoes anything
+/// left half-done.
+#[derive(Debug, Deserialize, Serialize)]
+struct CacheJournalRecord {
+    op: String,
+    file: String,
+}
+
+#[derive(Debug, Default)]
+struct CacheRecovery {
+    kept: usize,
+    incomplete: usize,
+    unreadable: usize,
+    evictions: usize,
+    temp_files: usize,
+}
+
+#[derive(Debug, Clone)]
+struct InternalDiagnostic {
+    file: PathBuf,
+    range: Range,
+    severity: String,
+    code: Option<String>,
+    message: String,
+    fixes: Vec<Fix>,
+    related: Vec<RelatedNote>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FixesFile {
+    #[serde(rename = "Diagnostics", default)]
+    diagnostics: Vec<FixDiagnostic>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FixDiagnostic {
+    #[serde(rename = "DiagnosticName")]
+    diagnostic_name: Option<String>,
+    #[serde(rename = "DiagnosticMessage")]
+    diagnostic_message: Option<FixMessage>,
+    #[serde(rename = "Message")]
+    message: Option<String>,
+    #[serde(rename = "FilePath")]
+    file_path: Option<String>,
+    #[serde(rename = "FileOffset")]
+    file_offset: Option<usize>,
+    #[serde(rename = "Replacements")]
+    replacements: Option<Vec<FixReplacement>>,
+}
+
+impl FixDiagnostic {
+    /// Message, file, offset and replacements, from `DiagnosticMessage` when
+    /// present (clang-tidy 9+) or the older top-level fields.
+    fn parts(&self) -> (String, String, usize, Vec<FixReplacement>) {
+        if let Some(dm) = self.diagnostic_message.as_ref() {
+            (
+                dm.message.clone().or(self.message.clone()).unwrap_or_default(),
+                dm.file_path.clone().or(self.file_path.clone()).unwrap_or_default(),
+                dm.file_offset.or(self.file_offset).unwrap_or(0),
+                dm.replacements.clone().or(self.replacements.clone()).unwrap_or_default(),
+            )
+        } else {
+            (
+                self.message.clone().unwrap_or_default(),
+                self.file_path.clone().unwrap_or_default(),
+                self.file_offset.unwrap_or(0),
+                self.replacements.clone().unwrap_or_default(),
+            )
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct FixMessage {
+    #[serde(rename = "Message")]
+    message: Option<String>,
+    #[serde(rename = "FilePath")]
+    file_path: Option<String>,
+    #[serde(rename = "FileOffset")]
+    file_offset: Option<usize>,
+    #[serde(rename = "Replacements")]
+    replacements: Option<Vec<FixReplacement>>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct FixReplacement {
+    #[serde(rename = "FilePath", default)]
+    file_path: String,
+    #[serde(rename = "Offset", default)]
+    offset: usize,
+    #[serde(rename = "Length", default)]
+    length: usize,
+    #[serde(rename = "ReplacementText", default)]
+    replacement_text: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct CompileCommand {
+    file: String,
+    directory: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    arguments: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+}
+
+/// Files of a compile database, keyed by their path as the database names it
+/// (made absolute and lexically normalized), so building the index does no
+/// file system IO. Canonical paths are resolved lazily, see `key_for`.
+#[derive(Debug)]
+struct CompileCommandsIndex {
+    path: PathBuf,
+    mtime: u64,
+    files: Vec<PathBuf>,
+    file_set: HashSet<PathBuf>,
+    commands: HashMap<PathBuf, CompileCommandEntry>,
+    by_name: HashMap<std::ffi::OsString, Vec<PathBuf>>,
+    /// Canonical paths of files whose key differs from them.
+    aliases: Mutex<HashMap<PathBuf, PathBuf>>,
+    /// Set once the background pass canonicalized every file.
+    canonicalized: AtomicBool,
+}
+
+impl CompileCommandsIndex {
+    /// The key of `file_path` in the index. Paths the database names match
+    /// without IO. Others are canonicalized and matched against the files
+    /// canonicalized so far, and, until the background pass is done, against
+    /// the files of the same name, which are canonicalized on the spot.
+    fn key_for(&self, file_path: &Path) -> Option<PathBuf> {
+        let normalized = normalize_path(file_path);
+        if self.file_set.contains(&normalized) {
+            return Some(normalized);
+        }
+        let canonical = std::fs::canonicalize(file_path).ok()?;
+        if self.file_set.contains(&canonical) {
+            return Some(canonical);
+        }
+        if let Some(key) = self.aliases.lock().unwrap().get(&canonical) {
+            return Some(key.clone());
+        }
+        if self.canonicalized.load(Ordering::Acquire) {
+            return None;
+        }
+        for candidate in self.by_name.get(canonical.file_name()?)? {
+            let Ok(resolved) = std::fs::canonicalize(candidate) else {
+                continue;
+            };
+            let found = resolved == canonical;
+            if resolved != *candidate {
+                self.aliases.lock().unwrap().insert(resolved, candidate.clone());
+            }
+            if found {
+                return Some(candidate.clone());
+            }
+        }
+        None
+    }
+
+    fn entry(&self, file_path: &Path) -> Option<&CompileCommandEntry> {
+        self.commands.get(&self.key_for(file_path)?)
+    }
+}
+
+/// Makes `path` absolute against the working directory and resolves `.` and
+/// `..` without touching the file system.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    let absolute;
+    let path = if path.is_absolute() {
+        path
+    } else {
+        absolute = std::env::current_dir().unwrap_or_default().join(path);
+        &absolute
+    };
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push(component);
+                }
+            }
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+const CANONICALIZE_BATCH: usize = 256;
+
+/// Canonicalizes the files of `index` on a background thread, recording the
+/// ones whose canonical path differs. Stops early once the index is dropped.
+fn canonicalize_compile_index(index: &Arc<CompileCommandsIndex>) {
+    let files = index.files.clone();
+    let index = Arc::downgrade(index);
+    thread::spawn(move || {
+        for batch in files.chunks(CANONICALIZE_BATCH) {
+            let Some(index) = index.upgrade() else {
+                return;
+            };
+            let resolved: Vec<(PathBuf, PathBuf)> = batch
+                .iter()
+                .filter_map(|file| Some((std::fs::canonicalize(file).ok()?, file.clone())))
+                .filter(|(canonical, file)| canonical != file)
+                .collect();
+            index.aliases.lock().unwrap().extend(resolved);
+        }
+        if let Some(index) = index.upgrade() {
+            index.canonicalized.store(true, Ordering::Release);
+        }
+    });
+}
+
+#[derive(Debug, Clone)]
+struct CompileCommandEntry {
+    file: String,
+    directory: String,
+    command: Option<String>,
+    arguments: Option<Vec<String>>,
+    output: Option<String>,
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("bench") {
+        return run_bench_cli(&args[1..]);
+    }
+    if let Some(command @ ("export-cache" | "import-cache")) = args.first().map(String::as_str) {
+        return run_cache_archive_cli(command, &args[1..]);
+    }
+    let mut framing = Framing::Auto;
+    let mut socket: Option<PathBuf> = None;
+    let mut listen: Option<String> = None;
+    let mut auth_token: Option<Arc<str>> = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--stdio" => {}
+            "--framing" => {
+                framing = match iter.next().map(String::as_str) {
+                    Some("auto") => Framing::Auto,
+                    Some("ndjson") => Framing::Ndjson,
+                    Some("header") => Framing::Header,
+                    other => anyhow::bail!("--framing expects auto, ndjson or header, got {other:?}"),
+                }
+            }
+            "--socket" => socket = Some(PathBuf::from(iter.next().context("--socket expects a path")?)),
+            "--listen" => listen = Some(iter.next().context("--listen expects an address")?.clone()),
+            "--token-file" => {
+                let path = iter.next().context("--token-file expects a path")?;
+                let token = std::fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?;
+                let token = token.trim();
+                if token.is_empty() {
+                    anyhow::bail!("--token-file {path} is empty");
+                }
+                auth_token = Some(Arc::from(token));
+            }
+            other => anyhow::bail!("unknown argument {other}"),
+        }
+    }
+
+    let shared = SharedState::default();
+    if let Some(path) = socket {
+        return serve_socket(&path, framing, shared, auth_token);
+    }
+    if let Some(addr) = listen {
+        return serve_tcp(&addr, framing, shared, auth_token);
+    }
+    let reader = Box::new(io::BufReader::new(io::stdin()));
+    serve_connection(reader, Box::new(io::stdout()), framing, shared, None, true)
+}
+
+/// Listens on a Unix domain socket and serves every client on its own thread.
+/// Each connection is its own session with its own settings, documents and
+/// runs; the result cache, worker slots and in-flight analyses are `shared`.
+#[cfg(unix)]
+fn serve_socket(
+    path: &Path,
+    framing: Framing,
+    shared: SharedState,
+    auth_token: Option<Arc<str>>,
+) -> Result<()> {
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            anyhow::bail!("another daemon is already listening on {}", path.display());
+        }
+        std::fs::remove_file(path).with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path).with_context(|| format!("Failed to bind {}", path.display()))?;
+    eprintln!("clang-tidy-daemon listening on {}", path.display());
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Failed to accept connection: {err}");
+                continue;
+            }
+        };
+        match stream.try_clone() {
+            Ok(read_half) => spawn_connection(read_half, stream, framing, shared.clone(), auth_token.clone()),
+            Err(err) => eprintln!("Failed to set up connection: {err}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn serve_socket(
+    _path: &Path,
+    _framing: Framing,
+    _shared: SharedState,
+    _auth_token: Option<Arc<str>>,
+) -> Result<()> {
+    anyhow::bail!("--socket is only supported on Unix")
+}
+
+/// Accepts JSON-RPC clients over TCP, one session per connection as with
+/// `--socket`. A session can run any binary through its settings, so an
+/// address other hosts can reach is refused unless `--token-file` is given.
+fn serve_tcp(
+    addr: &str,
+    framing: Framing,
+    shared: SharedState,
+    auth_token: Option<Arc<str>>,
+) -> Result<()> {
+    let listener = std::net::TcpListener::bind(addr).with_context(|| format!("Failed to listen on {addr}"))?;
+    let local = listener.local_addr()?;
+    if !local.ip().is_loopback() && auth_token.is_none() {
+        anyhow::bail!("{local} is reachable from other hosts; pass --token-file to require an auth token");
+    }
+    eprintln!("clang-tidy-daemon listening on {local}");
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Failed to accept connection: {err}");
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+        match stream.try_clone() {
+            Ok(read_half) => spawn_connection(read_half, stream, framing, shared.clone(), auth_token.clone()),
+            Err(err) => eprintln!("Failed to set up connection: {err}"),
+        }
+    }
+    Ok(())
+}
+
+fn spawn_connection(
+    read_half: impl Read + Send + 'static,
+    write_half: impl Write + Send + 'static,
+    framing: Framing,
+    shared: SharedState,
+    auth_token: Option<Arc<str>>,
+) {
+    thread::spawn(move || {
+        let reader = Box::new(io::BufReader::new(read_half));
+        if let Err(err) = serve_connection(reader, Box::new(write_half), framing, shared, auth_token, false) {
+            eprintln!("Connection closed: {err}");
+        }
+    });
+}
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Runs one client session over a reader/writer pair until the client closes
+/// its side: requests are read on a separate thread and handled in order.
+/// With `auth_token`, the first message must carry it as `params.authToken`
+/// or the connection is closed.
+fn serve_connection(
+    mut reader: Box<dyn BufRead + Send>,
+    writer: Box<dyn Write + Send>,
+    mut framing: Framing,
+    shared: SharedState,
+    auth_token: Option<Arc<str>>,
+    owns_process: bool,
+) -> Result<()> {
+    let stdout = Arc::new(Output {
+        writer: Mutex::new(writer),
+        redactor: Mutex::new(Arc::new(Redactor::from_settings(&Settings::default()).0)),
+        header_framed: AtomicBool::new(framing == Framing::Header),
+    });
+
+    // Checked before the session starts, so a rejected connection closes at once.
+    let first = match auth_token {
+        Some(expected) => {
+            let Ok(Some(message)) = read_message(&mut reader, &mut framing) else {
+                return Ok(());
+            };
+            if framing == Framing::Header {
+                stdout.header_framed.store(true, Ordering::Relaxed);
+            }
+            let value = serde_json::from_str::<Value>(&message).unwrap_or(Value::Null);
+            let token = value.pointer("/params/authToken").and_then(|v| v.as_str());
+            if !token.is_some_and(|token| tokens_equal(token, &expected)) {
+                let id = value.get("id").cloned().unwrap_or(Value::Null);
+                write_protocol_error(&stdout, id, UNAUTHORIZED, "Unauthorized: missing or wrong `authToken`".to_string())?;
+                return Ok(());
+            }
+            Some(message)
+        }
+        None => None,
+    };
+
+    let state = AppState {
+        settings: Arc::new(Mutex::new(Settings::default())),
+        root_dir: Arc::new(Mutex::new(None)),
+        compile_commands: Arc::new(Mutex::new(None)),
+        compile_index: Arc::new(Mutex::new(None)),
+        stdout: stdout.clone(),
+        cancel_map: Arc::new(Mutex::new(HashMap::new())),
+        progress_tokens: Arc::new(Mutex::new(HashMap::new())),
+        cache: shared.cache,
+        client: Arc::new(Mutex::new(ClientOptions::default())),
+        tool_versions: shared.tool_versions,
+        latest: Arc::new(Mutex::new(SpillStore::default())),
+        documents: Arc::new(Mutex::new(HashMap::new())),
+        rate_limits: Arc::new(Mutex::new(HashMap::new())),
+        project_runs: Arc::new(Mutex::new(HashMap::new())),
+        published: Arc::new(Mutex::new(HashMap::new())),
+        versions: Arc::new(Mutex::new(HashMap::new())),
+        instance_lock: Arc::new(Mutex::new(None)),
+        fix_sessions: Arc::new(Mutex::new(FixSessions::default())),
+        fix_writes: Arc::new(Mutex::new(FixJournal::default())),
+        resources: Arc::new(Mutex::new(ResourceStats::default())),
+        include_graph: Arc::new(Mutex::new(None)),
+        owns_process,
+        session_id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+        parent_pid: Arc::new(Mutex::new(None)),
+        scheduler: shared.scheduler,
+        in_flight: shared.in_flight,
+        active_runs: Arc::new(ActiveRuns::default()),
+        shutting_down: Arc::new(AtomicBool::new(false)),
+        disk_cache_evictions: Arc::new(AtomicU64::new(0)),
+        cache_dir_lock: Arc::new(Mutex::new(None)),
+        remote_cache: Arc::new(RemoteCache::default()),
+        cache_warming: Arc::new(CacheWarming::default()),
+        skipped_files: Arc::new(Mutex::new(HashMap::new())),
+        header_findings: Arc::new(Mutex::new(HashMap::new())),
+        diagnostic_stats: Arc::new(Mutex::new(DiagnosticStats::default())),
+    };
+
+    let closed = Arc::new(AtomicBool::new(false));
+    let janitor_state = state.clone();
+    let janitor_closed = closed.clone();
+    thread::spawn(move || loop {
+        let interval = janitor_state.settings.lock().unwrap().janitorIntervalMs;
+        thread::sleep(Duration::from_millis(if interval == 0 { DEFAULT_JANITOR_INTERVAL_MS } else { interval }));
+        if janitor_closed.load(Ordering::Relaxed) {
+            break;
+        }
+        run_janitor(&janitor_state);
+        // Kept current for a daemon that is killed before it can save at exit.
+        let settings = janitor_state.settings.lock().unwrap().clone();
+        save_cache_snapshot(&janitor_state, &settings);
+    });
+
+    let watcher_state = state.clone();
+    let watcher_closed = closed.clone();
+    thread::spawn(move || {
+        let mut pending = None;
+        loop {
+            let interval = watcher_state.settings.lock().unwrap().compileDbWatchIntervalMs;
+            thread::sleep(Duration::from_millis(if interval == 0 { DEFAULT_COMPILE_DB_WATCH_INTERVAL_MS } else { interval }));
+            if watcher_closed.load(Ordering::Relaxed) {
+                break;
+            }
+            poll_compile_db(&watcher_state, &mut pending);
+        }
+    });
+
+    let queue = Arc::new(PendingQueue::default());
+    let reader_queue = queue.clone();
+    let reader_stdout = stdout.clone();
+    let reader_versions = state.versions.clone();
+    thread::spawn(move || {
+        let mut first = first;
+        loop {
+            let read = match first.take() {
+                Some(message) => Ok(Some(message)),
+                None => read_message(&mut reader, &mut framing),
+            };
+            let line = match read {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                    let _ = write_protocol_error(&reader_stdout, Value::Null, PARSE_ERROR, format!("Parse error: {err}"));
+                    continue;
+                }
+                Err(_) => break,
+            };
+            if framing == Framing::Header {
+                reader_stdout.header_framed.store(true, Ordering::Relaxed);
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let value: Value = match serde_json::from_str(&line) {
+                Ok(v) => v,
+                Err(err) => {
+                    let _ = write_protocol_error(&reader_stdout, Value::Null, PARSE_ERROR, format!("Parse error: {err}"));
+                    continue;
+                }
+            };
+
+            let Some(method) = value.get("method").and_then(|m| m.as_str()).map(|s| s.to_string()) else {
+                // Responses to server messages carry no method and need no reply.
+                let is_response = value.get("result").is_some() || value.get("error").is_some();
+                if !is_response {
+                    let id = value.get("id").cloned().unwrap_or(Value::Null);
+                    let _ = write_protocol_error(&reader_stdout, id, INVALID_REQUEST, "Invalid Request: missing method".to_string());
+                }
+                continue;
+            };
+            let message = PendingMessage {
+                id: value.get("id").cloned(),
+                method,
+                params: value.get("params").cloned().unwrap_or(Value::Null),
+            };
+            note_document_version(&reader_versions, &message.method, &message.params);
+            for superseded in reader_queue.push(message) {
+                let _ = write_superseded(&reader_stdout, superseded);
+            }
+        }
+        reader_queue.close();
+    });
+
+    let result = handle_messages(&queue, &state);
+    closed.store(true, Ordering::Relaxed);
+    for flag in state.cancel_map.lock().unwrap().values() {
+        flag.store(true, Ordering::Relaxed);
+    }
+    // The client closed the stream or sent `exit` without `shutdown`.
+    if !state.shutting_down.load(Ordering::Relaxed) {
+        let settings = state.settings.lock().unwrap().clone();
+        save_cache_snapshot(&state, &settings);
+    }
+    state.instance_lock.lock().unwrap().take();
+    if state.owns_process {
+        state.cache.lock().unwrap().discard_spill();
+    }
+    state.latest.lock().unwrap().discard_spill();
+    // LSP convention: `exit` without a prior `shutdown` is an abnormal exit.
+    if state.owns_process && matches!(result, Ok(true)) {
+        let _ = state.stdout.writer.lock().unwrap().flush();
+        std::process::exit(if state.shutting_down.load(Ordering::Relaxed) { 0 } else { 1 });
+    }
+    result.map(|_| ())
+}
+
+/// Stops accepting work, cancels project runs and waits up to `timeout` for
+/// active runs to finish (killing clang-tidy children past the deadline when
+/// the daemon owns the process), then compacts the disk cache and releases
+/// the workspace lock.
+fn shutdown(state: &AppState, timeout: Duration) -> Value {
+    state.shutting_down.store(true, Ordering::Relaxed);
+    let cancelled = {
+        let map = state.cancel_map.lock().unwrap();
+        for flag in map.values() {
+            flag.store(true, Ordering::Relaxed);
+        }
+        state.project_runs.lock().unwrap().len()
+    };
+    let drained = state.active_runs.wait_idle(timeout);
+    if !drained && state.owns_process {
+        kill_child_processes();
+    }
+    let settings = state.settings.lock().unwrap().clone();
+    // Every cache write has either committed or been abandoned by now, so the
+    // journal can be compacted as on the next start.
+    if drained {
+        recover_cache_dir(state, &settings);
+    }
+    save_cache_snapshot(state, &settings);
+    state.cache_dir_lock.lock().unwrap().take();
+    state.instance_lock.lock().unwrap().take();
+    state.latest.lock().unwrap().discard_spill();
+    // Other `--socket`/`--listen` sessions still use the process's memory
+    // cache, preamble PCHs and sanitized compile databases; the janitor
+    // removes them once the process is gone.
+    if state.owns_process {
+        state.cache.lock().unwrap().discard_spill();
+        let _ = std::fs::remove_dir_all(preamble_dir());
+        let mut dbs = sanitized_compile_dbs().lock().unwrap();
+        let _ = std::fs::remove_dir_all(sanitized_db_root());
+        dbs.clear();
+    }
+    serde_json::json!({ "drained": drained, "cancelledRuns": cancelled })
+}
+
+/// Requests that may run for a long time. They are handled on a worker pool
+/// and answered when done, possibly out of order; everything else runs on the
+/// dispatch thread in arrival order, so `cancel`, `initialize` and document
+/// or configuration notifications are never stuck behind an analysis.
+const ASYNC_METHODS: &[&str] = &[
+    "analyzeFile",
+    "createRepro",
+    "benchmark",
+    "getIncluders",
+    "getIncludees",
+    "formatFile",
+    "formatRange",
+    "applyFix",
+    "applyAllFixesInFile",
+    "applyFixesProject",
+    "exportCache",
+    "importCache",
+    "validateCompileDb",
+    "analyzeChangedFiles",
+    "revertLastFix",
+    "revertRun",
+];
+
+/// `analyzeFile` requests being handled on the worker pools, by `fileUri`.
+/// Shared by all sessions, so a file is analyzed by one run at a time.
+#[derive(Default)]
+struct InFlightFiles {
+    files: Mutex<HashMap<String, InFlightRun>>,
+}
+
+/// A queued `analyzeFile` request with the session that sent it.
+struct InFlightRequest {
+    message: PendingMessage,
+    state: AppState,
+}
+
+/// The analysis running for one file: requests of the same session identical
+/// to it (same mode and content) are attached and answered with its result.
+/// Each session's newest different request waits for its turn, in arrival
+/// order; a request from another session waits too and is then usually
+/// served from the shared cache.
+struct InFlightRun {
+    session_id: u64,
+    fingerprint: u64,
+    attached: Vec<InFlightRequest>,
+    /// At most one request per session.
+    waiting: VecDeque<InFlightRequest>,
+    /// Requests that have replaced or joined `waiting`, so a debouncing
+    /// worker can tell whether the burst is still going.
+    arrivals: u64,
+}
+
+impl InFlightRun {
+    /// Makes `request` its session's waiting request, returning the one it
+    /// replaces.
+    fn wait(&mut self, request: InFlightRequest) -> Option<InFlightRequest> {
+        let session_id = request.state.session_id;
+        match self.waiting.iter_mut().find(|waiting| waiting.state.session_id == session_id) {
+            Some(waiting) => Some(std::mem::replace(waiting, request)),
+            None => {
+                self.waiting.push_back(request);
+                None
+            }
+        }
+    }
+
+    /// Starts `request` as the running analysis.
+    fn start(&mut self, request: &InFlightRequest) {
+        self.session_id = request.state.session_id;
+        self.fingerprint = analysis_fingerprint(&request.message.params);
+    }
+}
+
+/// Releases a file's `InFlightRun` if the worker handling it unwinds, failing
+/// the requests attached to or waiting on it, so later requests for the file
+/// are not stuck behind a run that will never finish.
+struct InFlightGuard {
+    in_flight: Arc<InFlightFiles>,
+    key: String,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        // On a normal exit the worker has already handed the entry on or
+        // removed it, and a newer request may own the key by now.
+        if !thread::panicking() {
+            return;
+        }
+        let mut files = self.in_flight.files.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.in_flight.files.clear_poison();
+        let Some(run) = files.remove(&self.key) else {
+            return;
+        };
+        drop(files);
+        for request in run.attached.into_iter().chain(run.waiting) {
+            let err = anyhow::anyhow!("Analysis failed: the worker handling this file stopped");
+            let _ = respond(&request.state.stdout, request.message.id.unwrap_or(Value::Null), Err(err));
+        }
+    }
+}
+
+/// Identity of an `analyzeFile` request for coalescing: mode and line range
+/// plus the buffer content, or the file signature when analyzing the file on
+/// disk.
+fn analysis_fingerprint(params: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    params.get("mode").and_then(|v| v.as_str()).unwrap_or("full").hash(&mut hasher);
+    params.get("lineRange").map(|v| v.to_string()).hash(&mut hasher);
+    params.get("settingsOverride").map(|v| v.to_string()).hash(&mut hasher);
+    match params.get("fileContent").and_then(|v| v.as_str()) {
+        Some(content) => content.hash(&mut hasher),
+        None => params
+            .get("fileUri")
+            .and_then(|v| v.as_str())
+            .and_then(uri_to_path)
+            .and_then(|path| file_signature(&path))
+            .hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
+/// Holds `request` as its session's waiting request for `key` until no newer
+/// request has arrived for `debounce_ms`, then returns the session's newest
+/// one. Requests replaced meanwhile are answered as superseded by
+/// `dispatch_async`.
+fn debounce_request(in_flight: &InFlightFiles, key: &str, request: InFlightRequest, debounce_ms: u64) -> InFlightRequest {
+    let session_id = request.state.session_id;
+    let mut seen = {
+        let mut files = in_flight.files.lock().unwrap();
+        let run = files.get_mut(key).expect("in-flight run registered at dispatch");
+        // A newer request of the session may have arrived since this one was
+        // taken off the queue.
+        if run.waiting.iter().any(|waiting| waiting.state.session_id == session_id) {
+            let _ = write_superseded(&request.state.stdout, request.message);
+        } else {
+            run.waiting.push_back(request);
+        }
+        run.arrivals
+    };
+    loop {
+        thread::sleep(Duration::from_millis(debounce_ms));
+        let mut files = in_flight.files.lock().unwrap();
+        let run = files.get_mut(key).expect("in-flight run registered at dispatch");
+        if run.arrivals == seen {
+            let position = run.waiting.iter().position(|waiting| waiting.state.session_id == session_id);
+            let request = position.and_then(|i| run.waiting.remove(i)).expect("debounced request is waiting");
+            run.start(&request);
+            return request;
+        }
+        seen = run.arrivals;
+    }
+}
+
+/// Handles messages until the queue closes or `exit` arrives; returns whether
+/// it was `exit`.
+fn handle_messages(queue: &PendingQueue, state: &AppState) -> Result<bool> {
+    let stdout = &state.stdout;
+    let workers = thread::available_parallelism().map(|n| n.get()).unwrap_or(1).max(2);
+    let pool = ThreadPool::new(workers);
+    let mut exit = false;
+    while let Some(message) = queue.pop() {
+        if message.method == "exit" {
+            exit = true;
+            break;
+        }
+        if state.shutting_down.load(Ordering::Relaxed) {
+            if let Some(id) = message.id {
+                write_protocol_error(stdout, id, INVALID_REQUEST, format!("Server is shutting down; `{}` rejected", message.method))?;
+            }
+            continue;
+        }
+        if message.id.is_some() && (ASYNC_METHODS.contains(&message.method.as_str()) || is_project_dry_run(&message)) {
+            dispatch_async(message, state, &pool)?;
+            continue;
+        }
+        let PendingMessage { id, method, params } = message;
+        if let Some(id) = id {
+            respond(stdout, id, handle_request(&method, params, state))?;
+        } else {
+            handle_notification(&method, params, state)?;
+        }
+    }
+    pool.join();
+    Ok(exit)
+}
+
+/// An `analyzeProject` with `dryRun` answers with the selected files, which
+/// takes a walk over the compile database and the cache.
+fn is_project_dry_run(message: &PendingMessage) -> bool {
+    message.method == "analyzeProject" && message.params.get("dryRun").and_then(|v| v.as_bool()).unwrap_or(false)
+}
+
+/// Runs `message` on the pool. A request identical to the analysis its
+/// session is running for the file shares that run's result; other requests
+/// for the file wait until it finishes, so results are produced in request
+/// order, and a waiting request is superseded by a newer one from the same
+/// session.
+fn dispatch_async(message: PendingMessage, state: &AppState, pool: &ThreadPool) -> Result<()> {
+    let file_key = (message.method == "analyzeFile")
+        .then(|| message.params.get("fileUri").and_then(|v| v.as_str()).map(|s| s.to_string()))
+        .flatten();
+    let request = InFlightRequest { message, state: state.clone() };
+    if let Some(key) = file_key.as_ref() {
+        let fingerprint = analysis_fingerprint(&request.message.params);
+        let mut files = state.in_flight.files.lock().unwrap();
+        if let Some(run) = files.get_mut(key) {
+            let session_waiting = run.waiting.iter().any(|waiting| waiting.state.session_id == state.session_id);
+            if run.session_id == state.session_id && run.fingerprint == fingerprint && !session_waiting {
+                run.attached.push(request);
+            } else {
+                run.arrivals += 1;
+                if let Some(superseded) = run.wait(request) {
+                    write_superseded(&state.stdout, superseded.message)?;
+                }
+            }
+            return Ok(());
+        }
+        files.insert(key.clone(), InFlightRun {
+            session_id: state.session_id,
+            fingerprint,
+            attached: Vec::new(),
+            waiting: VecDeque::new(),
+            arrivals: 0,
+        });
+    }
+
+    let in_flight = state.in_flight.clone();
+    let mut active = Some(state.active_runs.enter());
+    pool.execute(move || {
+        let _guard = file_key.clone().map(|key| InFlightGuard { in_flight: in_flight.clone(), key });
+        let mut next = Some(request);
+        while let Some(mut request) = next.take() {
+            // Waiting requests may come from other sessions; each counts
+            // towards the `shutdown` of its own.
+            let _active = active.take().unwrap_or_else(|| request.state.active_runs.enter());
+            let debounce = request.state.settings.lock().unwrap().debounceMs;
+            if let Some(key) = file_key.as_ref().filter(|_| debounce > 0) {
+                request = debounce_request(&in_flight, key, request, debounce);
+            }
+            let InFlightRequest { message: PendingMessage { id, method, params }, state } = request;
+            let result = handle_request(&method, params, &state);
+            let Some(key) = file_key.as_ref() else {
+                let _ = respond(&state.stdout, id.unwrap_or(Value::Null), result);
+                continue;
+            };
+            let attached = {
+                let mut files = in_flight.files.lock().unwrap();
+                let run = files.get_mut(key).expect("in-flight run registered at dispatch");
+                let attached = std::mem::take(&mut run.attached);
+                next = run.waiting.pop_front();
+                match next.as_ref() {
+                    Some(waiting) => run.start(waiting),
+                    None => {
+                        files.remove(key);
+                    }
+                }
+                attached
+            };
+            for other in attached {
+                let shared = match &result {
+                    Ok(value) => Ok(coalesced_result(value, &other.message.params, &other.state)),
+                    Err(err) => {
+                        let JsonRpcError { code, message, data } = to_json_rpc_error(err);
+                        Err(RpcError { code, message, data }.into())
+                    }
+                };
+                let _ = respond(&other.state.stdout, other.message.id.unwrap_or(Value::Null), shared);
+            }
+            let _ = respond(&state.stdout, id.unwrap_or(Value::Null), result);
+        }
+    });
+    Ok(())
+}
+
+/// The shared `analyzeFile` result as an attached request would have
+/// received it: its own `runId` and `version`, staleness for that version.
+fn coalesced_result(result: &Value, params: &Value, state: &AppState) -> Value {
+    let mut result = result.clone();
+    let Some(object) = result.as_object_mut() else {
+        return result;
+    };
+    object.insert("runId".to_string(), params.get("runId").cloned().unwrap_or(Value::String("unknown".to_string())));
+    object.remove("version");
+    object.remove("documentVersion");
+    object.remove("stale");
+    let version = document_version(params);
+    if let Some(version) = version {
+        object.insert("version".to_string(), serde_json::json!(version));
+        object.insert("documentVersion".to_string(), serde_json::json!(version));
+    }
+    let path = params.get("fileUri").and_then(|v| v.as_str()).and_then(uri_to_path);
+    if path.is_some_and(|path| is_stale_version(state, &path, version)) {
+        object.insert("stale".to_string(), Value::Bool(true));
+    }
+    object.insert("coalesced".to_string(), Value::Bool(true));
+    result
+}
+
+fn respond(stdout: &Arc<Output>, id: Value, result: Result<Value>) -> Result<()> {
+    match result {
+        Ok(result) => write_json(stdout, &JsonRpcResponse { jsonrpc: "2.0", id, result }),
+        Err(err) => write_json(stdout, &JsonRpcErrorResponse {
+            jsonrpc: "2.0",
+            id,
+            error: to_json_rpc_error(&err),
+        }),
+    }
+}
+
+/// Compares in time independent of where the strings differ.
+fn tokens_equal(given: &str, expected: &str) -> bool {
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn write_protocol_error(stdout: &Arc<Output>, id: Value, code: i32, message: String) -> Result<()> {
+    write_json(stdout, &JsonRpcErrorResponse {
+        jsonrpc: "2.0",
+        id,
+        error: JsonRpcError { code, message, data: None },
+    })
+}
+
+fn write_superseded(stdout: &Arc<Output>, superseded: PendingMessage) -> Result<()> {
+    let resp = JsonRpcErrorResponse {
+        jsonrpc: "2.0",
+        id: superseded.id.unwrap_or(Value::Null),
+        error: JsonRpcError {
+            code: REQUEST_SUPERSEDED,
+            message: "Superseded by a newer analyzeFile request for the same file".to_string(),
+            data: Some(serde_json::json!({ "reason": "superseded" })),
+        },
+    };
+    write_json(stdout, &resp)
+}
+
+fn handle_request(method: &str, params: Value, state: &AppState) -> Result<Value> {
+    if method == "analyzeFile" || method == "analyzeProject" {
+        check_rate_limit(method, &params, state)?;
+    }
+    match method {
+        "initialize" => {
+            let root_dir = params.get("rootUri").and_then(|v| v.as_str()).and_then(uri_to_path);
+            let settings = params
+                .get("settings")
+                .and_then(|s| serde_json::from_value::<Settings>(s.clone()).ok());
+            if let Some(root) = root_dir.as_deref() {
+                let single_instance = settings.as_ref().is_some_and(|s| s.singleInstance);
+                claim_workspace(state, root, single_instance)?;
+            }
+            if let Some(path) = root_dir {
+                *state.root_dir.lock().unwrap() = Some(path);
+            }
+            if let Some(pid) = params.get("parentPid").and_then(|v| v.as_u64()) {
+                watch_parent(state, pid as u32);
+            }
+            if let Some(parsed) = settings {
+                apply_redaction_settings(state, &parsed);
+                apply_memory_limits(state, &parsed);
+                *state.settings.lock().unwrap() = parsed;
+            }
+            let mut client = ClientOptions::default();
+            if let Some(caps) = params.get("capabilities") {
+                if caps.get("fixFormat").and_then(|v| v.as_str()) == Some("workspaceEdit") {
+                    client.fix_format = FixFormat::WorkspaceEdit;
+                }
+                client.diagnostics_delta = caps.get("diagnosticsDelta").and_then(|v| v.as_bool()).unwrap_or(false);
+                client.work_done_progress = caps.get("workDoneProgress").and_then(|v| v.as_bool()).unwrap_or(false);
+            }
+            *state.client.lock().unwrap() = client;
+            state.published.lock().unwrap().clear();
+            *state.compile_commands.lock().unwrap() = None;
+            *state.compile_index.lock().unwrap() = None;
+            state.skipped_files.lock().unwrap().clear();
+            let settings = state.settings.lock().unwrap().clone();
+            recover_cache_dir(state, &settings);
+            run_janitor(state);
+            restore_cache_snapshot(state, &settings);
+            if settings.cacheWarming {
+                start_cache_warming(state);
+            }
+            let result = serde_json::json!({
+                "server": {"name": "clang-tidy-daemon", "version": "0.1.0"},
+                "capabilities": {
+                    "analyzeFile": true,
+                    "analyzeProject": true,
+                    "cancel": true,
+                    "fixFormats": ["edits", "workspaceEdit"],
+                    "documentSync": true,
+                    "diagnosticsDelta": true,
+                    "workDoneProgress": true,
+                    "format": true,
+                },
+                "pid": std::process::id(),
+            });
+            Ok(result)
+        }
+        "shutdown" => {
+            let timeout = params.get("timeoutMs").and_then(|v| v.as_u64()).unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_MS);
+            Ok(shutdown(state, Duration::from_millis(timeout)))
+        }
+        "ping" => Ok(serde_json::json!({ "ok": true })),
+        "analyzeFile" => {
+            let run_id = params.get("runId").cloned().unwrap_or(Value::String("unknown".to_string()));
+            let (file_uri, file_path) = file_uri_param(&params)?;
+            let mode = params.get("mode").and_then(|v| v.as_str()).unwrap_or("full").to_string();
+            let file_content = params.get("fileContent").and_then(|v| v.as_str()).map(|s| s.to_string());
+            let version = document_version(&params);
+            let line_range = line_range_param(&params)?;
+
+            let settings = run_settings(state, &params)?;
+            let analysis =
+                analyze_document(state, &settings, &file_path, file_uri, &mode, file_content, version, line_range.as_deref())?;
+            // Findings outside the range are unknown, not gone. Findings under
+            // overridden settings do not replace those of the file's settings.
+            let overridden = params.get("settingsOverride").is_some_and(|v| !v.is_null());
+            if !analysis.stale && line_range.is_none() && !overridden {
+                record_latest(&state.latest, &file_path, &analysis.diagnostics);
+                if analysis.skipped.is_none() {
+                    state.diagnostic_stats.lock().unwrap().record(None, &file_path, &analysis.diagnostics);
+                }
+            }
+            if !overridden {
+                let run_label = run_id.as_str().map(str::to_string).unwrap_or_else(|| run_id.to_string());
+                publish_header_findings(state, &Publisher::from_state(state), &run_label, &analysis.headers);
+            }
+            let mut result = serde_json::json!({
+                "runId": run_id,
+                "fileUri": file_uri,
+                "diagnostics": analysis.diagnostics,
+            });
+            if line_range.is_some() {
+                result["partial"] = Value::Bool(true);
+            }
+            if let Some(version) = version {
+                result["version"] = serde_json::json!(version);
+                result["documentVersion"] = serde_json::json!(version);
+            }
+            if analysis.stale {
+                result["stale"] = Value::Bool(true);
+            }
+            if let Some(reason) = analysis.skipped {
+                result["skipped"] = Value::String(reason);
+            }
+            if let Some(donor) = analysis.flags_from.as_ref().and_then(|p| Url::from_file_path(p).ok()) {
+                result["flagsFrom"] = Value::String(donor.to_string());
+            }
+            Ok(result)
+        }
+        "analyzeProject" => {
+            static NEXT_PROJECT_RUN: AtomicU64 = AtomicU64::new(1);
+            let run_id = match params.get("runId").and_then(|v| v.as_str()) {
+                Some(run_id) => run_id.to_string(),
+                None => format!("project-{}", NEXT_PROJECT_RUN.fetch_add(1, Ordering::Relaxed)),
+            };
+            if let Some(token) = params.get("workDoneToken") {
+                if state.progress_tokens.lock().unwrap().contains_key(&token.to_string()) {
+                    return Err(invalid_params("workDoneToken", format!("Progress token {token} is in use by another run")));
+                }
+            }
+            let cancel_flag = Arc::new(AtomicBool::new(false));
+            {
+                let mut cancel_map = state.cancel_map.lock().unwrap();
+                if cancel_map.contains_key(&run_id) {
+                    return Err(invalid_params("runId", format!("A run with id `{run_id}` is still active")));
+                }
+                cancel_map.insert(run_id.clone(), cancel_flag.clone());
+            }
+            // Also clears the flag when the request fails before the run starts.
+            let cancel_entry = CancelEntryGuard { map: state.cancel_map.clone(), run_id: run_id.clone(), flag: cancel_flag.clone() };
+            let mode = params.get("mode").and_then(|v| v.as_str()).unwrap_or("full").to_string();
+            let incremental = params.get("incremental").and_then(|v| v.as_bool()).unwrap_or(true);
+            let batch_size = params.get("batchSize").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+            let settings = run_settings(state, &params)?;
+            let root_dir = state.root_dir.lock().unwrap().clone();
+            let stdout = state.stdout.clone();
+            let compile_commands = resolve_compile_commands_path(&settings, root_dir.as_deref(), state);
+            let cache = state.cache.clone();
+            let remote_cache = state.remote_cache.clone();
+            let fix_format = state.client.lock().unwrap().fix_format;
+            let tool_version = clang_tidy_version(&settings, state);
+            let latest = state.latest.clone();
+            let header_findings = state.header_findings.clone();
+            let project_runs = state.project_runs.clone();
+            let publisher = Publisher::from_state(state).tracking_runs();
+            let resources = state.resources.clone();
+            let include_graph = state.include_graph.clone();
+            let scheduler = state.scheduler.clone();
+            let compile_index = match compile_commands.as_deref() {
+                Some(path) => match get_compile_index(path, state) {
+                    Ok(index) => Some(index),
+                    Err(err) => {
+                        let _ = send_log(&stdout, "error", format!("Failed to load compile_commands.json index: {err}"));
+                        None
+                    }
+                },
+                None => None,
+            };
+
+            let capture_baseline = params.get("captureBaseline").and_then(|v| v.as_bool()).unwrap_or(false);
+            let baseline_file = if capture_baseline {
+                Some(baseline_path(&settings, root_dir.as_deref()).context("captureBaseline needs a workspace root or an absolute baselinePath")?)
+            } else {
+                None
+            };
+            let max_run_duration = params.get("maxRunDurationMs").and_then(|v| v.as_u64()).filter(|ms| *ms > 0);
+            let resume = params.get("resume").and_then(|v| v.as_bool()).unwrap_or(false);
+            let checkpoint = match compile_commands.as_deref() {
+                Some(path) if resume && params.get("files").is_none() => {
+                    load_run_checkpoint(&settings, root_dir.as_deref(), path, &mode, &stdout)
+                }
+                _ => None,
+            };
+            let resumed = checkpoint.as_ref().map(|c| (c.run_id.clone(), c.remaining.len()));
+            let export_fixes = params.get("exportFixes").and_then(|v| v.as_bool()).unwrap_or(false);
+
+            if params.get("dryRun").and_then(|v| v.as_bool()).unwrap_or(false) {
+                let compile_commands = compile_commands.context("compile_commands.json not found")?;
+                let selection = select_project_files(
+                    &params,
+                    &settings,
+                    root_dir.as_deref(),
+                    &compile_commands,
+                    compile_index.as_deref(),
+                    checkpoint,
+                    &mode,
+                    &cache,
+                    incremental && !export_fixes && !capture_baseline,
+                )?;
+                let files: Vec<Value> = selection
+                    .files
+                    .iter()
+                    .filter_map(|f| Url::from_file_path(f).ok())
+                    .map(|u| Value::String(u.to_string()))
+                    .collect();
+                let mut result = serde_json::json!({
+                    "runId": run_id,
+                    "dryRun": true,
+                    "selected": selection.selected,
+                    "excluded": selection.excluded,
+                    "unchanged": selection.unchanged,
+                    "cached": selection.cached.len(),
+                    "totalFiles": files.len(),
+                    "files": files,
+                });
+                if let Some((resumed_run, remaining)) = resumed {
+                    result["resumedFrom"] = Value::String(resumed_run);
+                    result["resumedFiles"] = serde_json::json!(remaining);
+                }
+                return Ok(result);
+            }
+
+            let export_dir = if export_fixes {
+                let requested = params.get("exportFixesDir").and_then(|v| v.as_str()).unwrap_or("");
+                Some(prepare_export_dir(requested, root_dir.as_deref(), &run_id)?)
+            } else {
+                None
+            };
+            let progress = ProgressReporter::new(state, &run_id, &params);
+            let run_id_thread = run_id.clone();
+            let export_dir_thread = export_dir.clone();
+            let baseline_file_result = baseline_file.clone();
+            let active = state.active_runs.enter();
+            let trim_state = state.clone();
+            thread::spawn(move || {
+                let _active = active;
+                let _cancel_entry = cancel_entry;
+                let export_dir = export_dir_thread;
+                let run_id_for_tasks = run_id_thread.clone();
+                progress.begin("clang-tidy", format!("Starting project analysis ({mode})"), true);
+                let started = Instant::now();
+                let deadline = max_run_duration.map(|ms| started + Duration::from_millis(ms));
+
+                let compile_commands = match compile_commands {
+                    Some(p) => p,
+                    None => {
+                        let _ = send_log(&stdout, "error", "compile_commands.json not found".to_string());
+                        progress.end("compile_commands.json not found");
+                        return;
+                    }
+                };
+
+                // Exports and baseline captures need every file; unchanged
+                // ones are served from the cache.
+                let complete = export_dir.is_some() || capture_baseline;
+                let selection = match select_project_files(
+                    &params,
+                    &settings,
+                    root_dir.as_deref(),
+                    &compile_commands,
+                    compile_index.as_deref(),
+                    checkpoint,
+                    &mode,
+                    &cache,
+                    incremental && !complete,
+                ) {
+                    Ok(selection) => selection,
+                    Err(err) => {
+                        let _ = send_log(&stdout, "error", format!("{err:#}"));
+                        progress.end(&err.to_string());
+                        return;
+                    }
+                };
+                if let Some(graph) = selection.graph {
+                    *include_graph.lock().unwrap() = Some(graph);
+                }
+                let mut files = selection.files;
+                let selected = selection.selected;
+                let settings_hash = project_fingerprint(&settings, root_dir.as_deref(), &compile_commands, &mode);
+                let cache_dir = resolve_cache_dir(&settings, root_dir.as_deref(), compile_commands.parent());
+                let mut tally = RunTally { cached: selection.cached.len(), ..RunTally::default() };
+                // The totals cover the files served from the cache as well.
+                let portable_root = portable_cache_root(&settings, root_dir.as_deref());
+                for file_path in &selection.cached {
+                    let Some(mut diags) =
+                        cached_diagnostics(file_path, &cache, settings_hash, cache_dir.as_deref(), portable_root)
+                    else {
+                        continue;
+                    };
+                    diags.retain(|diag| diag.file_uri.is_none());
+                    tally.baselined += apply_baseline(&mut diags, &settings, root_dir.as_deref(), file_path, None, &stdout);
+                    drop_below_minimum_severity(&mut diags, &settings);
+                    tally.count(&diags);
+                }
+                let tally = Arc::new(Mutex::new(tally));
+                let timings_path = file_timings_path(&settings, root_dir.as_deref(), &compile_commands);
+
+                let _run_guard = ProjectRunGuard { runs: project_runs.clone(), run_id: run_id_thread.clone() };
+                let merged = claim_project_files(&project_runs, &run_id_thread, &mode, &mut files, !complete);
+                if merged > 0 {
+                    let _ = send_log(&stdout, "info", format!("{merged} files are already scheduled by another project run; merged"));
+                }
+                // Runs over a given file list, exporting fixes or capturing a
+                // baseline are not resumed.
+                let checkpointer = (params.get("files").is_none() && !complete).then(|| {
+                    Arc::new(RunCheckpointer {
+                        path: run_checkpoint_path(&settings, root_dir.as_deref(), &compile_commands, &mode),
+                        run_id: run_id_thread.clone(),
+                        mode: mode.clone(),
+                        fingerprint: project_fingerprint(&settings, root_dir.as_deref(), &compile_commands, &mode),
+                        order: files.clone(),
+                        runs: project_runs.clone(),
+                        saved: Mutex::new((Instant::now(), 0)),
+                    })
+                });
+                if let Some(checkpointer) = checkpointer.as_ref() {
+                    checkpointer.save(&stdout, 0);
+                }
+
+                let total = files.len();
+                if total == 0 {
+                    let summary = tally.lock().unwrap().summary(&run_id_thread, selected, started, false);
+                    let _ = send_notification(&stdout, "summary", summary);
+                    progress.end(if merged > 0 {
+                        "All files are already scheduled by other project runs"
+                    } else if incremental {
+                        "No changed files to analyze"
+                    } else {
+                        "No files found in compile_commands.json"
+                    });
+                    return;
+                }
+
+                let throttle_ms = settings.publishDiagnosticsThrottleMs;
+                let limiter = Arc::new(Mutex::new(Instant::now() - Duration::from_millis(throttle_ms)));
+
+                let workers = settings.maxWorkers.max(1) as usize;
+                let pool = ThreadPool::new(workers);
+                let done = Arc::new(AtomicUsize::new(0));
+                let exported = Arc::new(AtomicUsize::new(0));
+                let clock = Arc::new(Mutex::new(RunClock::new(started)));
+                let captured: Arc<Mutex<HashMap<PathBuf, Vec<BaselineEntry>>>> = Arc::new(Mutex::new(HashMap::new()));
+                let header_uris: Arc<Mutex<BTreeSet<String>>> = Arc::new(Mutex::new(BTreeSet::new()));
+                let effective_batch = if batch_size == 0 { total } else { batch_size.max(1) };
+                for chunk in files.chunks(effective_batch) {
+                    for file_path in chunk {
+                        let file_path = file_path.clone();
+                        let cancel = cancel_flag.clone();
+                        let stdout = stdout.clone();
+                        let settings = settings.clone();
+                        let root_dir = root_dir.clone();
+                        let compile_commands = compile_commands.clone();
+                        let done = done.clone();
+                        let run_id = run_id_for_tasks.clone();
+                        let cache = cache.clone();
+                        let remote_cache = remote_cache.clone();
+                        let mode = mode.clone();
+                        let limiter = limiter.clone();
+                        let tool_version = tool_version.clone();
+                        let latest = latest.clone();
+                        let project_runs = project_runs.clone();
+                        let publisher = publisher.clone();
+                        let resources = resources.clone();
+                        let export_dir = export_dir.clone();
+                        let exported = exported.clone();
+                        let scheduler = scheduler.clone();
+                        let progress = progress.clone();
+                        let checkpointer = checkpointer.clone();
+                        let tally = tally.clone();
+                        let cache_dir = cache_dir.clone();
+                        let clock = clock.clone();
+                        let captured = captured.clone();
+                        let header_findings = header_findings.clone();
+                        let header_uris = header_uris.clone();
+
+                        pool.execute(move || {
+                            // Deferred files count as done, so the progress
+                            // and its ETA cover only the files that will run.
+                            let advance = || {
+                                let finished = done.fetch_add(1, Ordering::Relaxed) + 1;
+                                let percent = (finished as f64 / total as f64 * 100.0) as u32;
+                                let mut clock = clock.lock().unwrap();
+                                if finished == total || finished.is_multiple_of(10) || clock.due() {
+                                    let details = clock.details(total - finished, workers);
+                                    drop(clock);
+                                    progress.report_details(format!("Analyzed {finished}/{total} files"), Some(percent), details);
+                                }
+                                if let Some(checkpointer) = checkpointer.as_ref() {
+                                    checkpointer.save_due(finished, total, &stdout);
+                                }
+                            };
+                            let out_of_time = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                            let defer = || {
+                                tally.lock().unwrap().deferred += 1;
+                                advance();
+                            };
+                            if cancel.load(Ordering::Relaxed) {
+                                return;
+                            }
+                            if out_of_time() {
+                                defer();
+                                return;
+                            }
+                            let mut permit = scheduler.acquire_for(Priority::Batch, &run_id);
+                            if cancel.load(Ordering::Relaxed) {
+                                return;
+                            }
+                            if out_of_time() {
+                                defer();
+                                return;
+                            }
+
+                            let was_cached = is_cached(
+                                &file_path,
+                                &cache,
+                                settings_hash,
+                                cache_dir.as_deref(),
+                                portable_cache_root(&settings, root_dir.as_deref()),
+                            );
+                            clock.lock().unwrap().start(&file_path);
+                            let run_started = Instant::now();
+                            let mut attempt = 0;
+                            let (analyzed, wall_ms) = loop {
+                                let retry = if attempt > 0 { retry_settings(&settings) } else { None };
+                                let started = Instant::now();
+                                let analyzed = analyze_translation_unit(
+                                    &file_path,
+                                    retry.as_ref().unwrap_or(&settings),
+                                    root_dir.as_deref(),
+                                    Some(&compile_commands),
+                                    mode.as_str(),
+                                    None,
+                                    &cache,
+                                    Some(&remote_cache),
+                                    &resources,
+                                    &|message| {
+                                        let _ = send_log(&stdout, "warn", message);
+                                    },
+                                );
+                                let wall_ms = started.elapsed().as_millis() as u64;
+                                match analyzed {
+                                    Err(err)
+                                        if attempt < settings.retryCount
+                                            && is_retryable(&err)
+                                            && !cancel.load(Ordering::Relaxed)
+                                            && !out_of_time() =>
+                                    {
+                                        attempt += 1;
+                                        let delay = retry_backoff(&settings, attempt);
+                                        let _ = send_log(
+                                            &stdout,
+                                            "warn",
+                                            format!(
+                                                "clang-tidy failed for {}: {err}; retry {attempt}/{} in {} ms",
+                                                file_path.display(),
+                                                settings.retryCount,
+                                                delay.as_millis()
+                                            ),
+                                        );
+                                        // Free the worker slot while backing off.
+                                        drop(permit);
+                                        thread::sleep(delay);
+                                        permit = scheduler.acquire_for(Priority::Batch, &run_id);
+                                    }
+                                    analyzed => break (analyzed, wall_ms),
+                                }
+                            };
+                            drop(permit);
+                            clock.lock().unwrap().finish(&file_path, run_started.elapsed().as_millis() as u64);
+                            let mut diags = match analyzed {
+                                Ok(d) => {
+                                    let (headers, mut d): (Vec<_>, Vec<_>) = d.into_iter().partition(|diag| diag.file_uri.is_some());
+                                    let touched = record_header_findings(&header_findings, &file_path, headers);
+                                    header_uris.lock().unwrap().extend(touched);
+                                    let baselined = if capture_baseline {
+                                        let content = std::fs::read_to_string(&file_path).unwrap_or_default();
+                                        captured.lock().unwrap().insert(file_path.clone(), baseline_entries(&d, &content));
+                                        0
+                                    } else {
+                                        apply_baseline(&mut d, &settings, root_dir.as_deref(), &file_path, None, &stdout)
+                                    };
+                                    drop_below_minimum_severity(&mut d, &settings);
+                                    let mut tally = tally.lock().unwrap();
+                                    tally.record(&file_path, was_cached, wall_ms, &d);
+                                    tally.baselined += baselined;
+                                    if attempt > 0 {
+                                        tally.retried += 1;
+                                    }
+                                    d
+                                }
+                                Err(err) => {
+                                    let _ = send_log(&stdout, "error", format!("clang-tidy failed for {}: {err}", file_path.display()));
+                                    let mut tally = tally.lock().unwrap();
+                                    tally.failed += 1;
+                                    if attempt > 0 && attempt == settings.retryCount {
+                                        tally.exhausted.push(file_path.clone());
+                                    }
+                                    Vec::new()
+                                }
+                            };
+
+                            let file_uri = match Url::from_file_path(&file_path) {
+                                Ok(u) => u.to_string(),
+                                Err(_) => return,
+                            };
+                            record_latest(&latest, &file_path, &diags);
+                            if let Some(dir) = export_dir.as_deref() {
+                                match write_exported_fixes(dir, &file_path, &diags) {
+                                    Ok(true) => {
+                                        exported.fetch_add(1, Ordering::Relaxed);
+                                    }
+                                    Ok(false) => {}
+                                    Err(err) => {
+                                        let _ = send_log(&stdout, "warn", format!("Failed to export fixes for {}: {err}", file_path.display()));
+                                    }
+                                }
+                            }
+                            shape_fixes(&mut diags, fix_format, &file_uri, None);
+                            attach_code_descriptions(&mut diags, tool_version.as_deref());
+
+                            throttle_publish(&limiter, throttle_ms);
+                            let _ = publisher.publish(&run_id, &file_uri, None, &diags);
+
+                            if let Some(run) = project_runs.lock().unwrap().get_mut(&run_id) {
+                                run.pending.remove(&file_path);
+                            }
+
+                            advance();
+                        });
+                    }
+
+                    pool.join();
+                }
+
+                if let Some(checkpointer) = checkpointer.as_ref() {
+                    checkpointer.save(&stdout, total);
+                }
+                // Headers analyzed as files of the run keep their own results.
+                let targets: HashSet<String> = files.iter().filter_map(|f| Url::from_file_path(f).ok()).map(|u| u.to_string()).collect();
+                let mut uris = std::mem::take(&mut *header_uris.lock().unwrap());
+                uris.retain(|uri| !targets.contains(uri));
+                let merged = merge_header_findings(&header_findings.lock().unwrap(), &uris);
+                for (uri, mut diags) in merged {
+                    let Some(header) = uri_to_path(&uri) else {
+                        continue;
+                    };
+                    if capture_baseline {
+                        let content = std::fs::read_to_string(&header).unwrap_or_default();
+                        captured.lock().unwrap().insert(header.clone(), baseline_entries(&diags, &content));
+                    } else {
+                        let baselined = apply_baseline(&mut diags, &settings, root_dir.as_deref(), &header, None, &stdout);
+                        tally.lock().unwrap().baselined += baselined;
+                    }
+                    drop_below_minimum_severity(&mut diags, &settings);
+                    let mut tally = tally.lock().unwrap();
+                    tally.headers += 1;
+                    tally.count(&diags);
+                    drop(tally);
+                    record_latest(&latest, &header, &diags);
+                    attach_code_descriptions(&mut diags, tool_version.as_deref());
+                    throttle_publish(&limiter, throttle_ms);
+                    let _ = publisher.publish(&run_id_thread, &uri, None, &diags);
+                }
+                let cancelled = cancel_flag.load(Ordering::Relaxed);
+                let deferred = tally.lock().unwrap().deferred;
+                if deferred > 0 {
+                    let _ = send_log(
+                        &stdout,
+                        "info",
+                        format!(
+                            "maxRunDurationMs of {} ms used up; {deferred} files left for the next run",
+                            max_run_duration.unwrap_or_default()
+                        ),
+                    );
+                }
+                if let Some(path) = baseline_file.as_deref() {
+                    if cancelled {
+                        let _ = send_log(&stdout, "warn", format!("Run cancelled; baseline {} not updated", path.display()));
+                    } else {
+                        let captured = std::mem::take(&mut *captured.lock().unwrap());
+                        match write_baseline(path, root_dir.as_deref(), captured) {
+                            Ok((files, entries)) => {
+                                let _ = send_log(&stdout, "info", format!("Baseline {} lists {entries} diagnostics in {files} files", path.display()));
+                            }
+                            Err(err) => {
+                                let _ = send_log(&stdout, "error", format!("Failed to write baseline {}: {err}", path.display()));
+                            }
+                        }
+                    }
+                }
+                let tally = tally.lock().unwrap();
+                let _ = send_notification(&stdout, "summary", tally.summary(&run_id_thread, selected, started, cancelled));
+                if !tally.durations.is_empty() {
+                    if let Err(err) = save_file_timings(&timings_path, &mode, &tally.durations) {
+                        let _ = send_log(&stdout, "warn", format!("Failed to save file timings to {}: {err}", timings_path.display()));
+                    }
+                }
+                drop(tally);
+                if settings.enableCheckProfile {
+                    record_run_profile(&resources, &run_id_thread, &files);
+                }
+                trim_disk_cache(&trim_state);
+                if let Some(dir) = export_dir.as_deref() {
+                    let count = exported.load(Ordering::Relaxed);
+                    let _ = send_log(&stdout, "info", format!("Exported fixes for {count} files to {}", dir.display()));
+                }
+                progress.end(if cancel_flag.load(Ordering::Relaxed) {
+                    "Project analysis cancelled"
+                } else if deferred > 0 {
+                    "Project analysis stopped at its time budget"
+                } else {
+                    "Project analysis completed"
+                });
+            });
+
+            let mut result = serde_json::json!({ "runId": run_id });
+            if let Some(dir) = export_dir {
+                result["exportFixesDir"] = Value::String(dir.to_string_lossy().to_string());
+            }
+            if let Some(path) = baseline_file_result {
+                result["baselinePath"] = Value::String(path.to_string_lossy().to_string());
+            }
+            if let Some((resumed_run, remaining)) = resumed {
+                result["resumedFrom"] = Value::String(resumed_run);
+                result["resumedFiles"] = serde_json::json!(remaining);
+            }
+            Ok(result)
+        }
+        "getHover" => {
+            let (file_uri, file_path) = file_uri_param(&params)?;
+            let position: Position = serde_json::from_value(params.get("position").cloned().unwrap_or(Value::Null))
+                .map_err(|err| invalid_params("position", format!("Invalid `position`: {err}")))?;
+            let diags = latest_diagnostics(state, &file_path);
+            let settings = state.settings.lock().unwrap().clone();
+            let tool_version = clang_tidy_version(&settings, state);
+            Ok(build_hover(&file_path, file_uri, &diags, &position, tool_version.as_deref()))
+        }
+        "explainFix" => {
+            let (file_uri, file_path) = file_uri_param(&params)?;
+            let diagnostic_id = required_str(&params, "diagnosticId")?;
+            let fix_index = params.get("fixIndex").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+            let diags = latest_diagnostics(state, &file_path);
+            let diag = diags
+                .iter()
+                .find(|d| d.id == diagnostic_id)
+                .context("Diagnostic not found; re-run analysis")?;
+            let fix = diag
+                .fixes
+                .as_ref()
+                .and_then(|f| f.get(fix_index))
+                .context("Diagnostic has no fix at fixIndex")?;
+            Ok(explain_fix(&file_path, file_uri, diag, fix))
+        }
+        "applyFix" => {
+            let (file_uri, file_path) = file_uri_param(&params)?;
+            let mode = params.get("mode").and_then(|v| v.as_str()).unwrap_or("full").to_string();
+            let mut journal = state.fix_writes.lock().unwrap();
+            let analyzed = analysis_on_disk(state, file_uri, &file_path)?;
+            let fix = match params.get("fix").filter(|v| !v.is_null()) {
+                Some(value) => serde_json::from_value::<Fix>(value.clone())
+                    .map_err(|err| invalid_params("fix", format!("Invalid `fix`: {err}")))?,
+                None => {
+                    let diagnostic_id = required_str(&params, "diagnosticId")?;
+                    let fix_index = params.get("fixIndex").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+                    analyzed
+                        .diagnostics
+                        .iter()
+                        .find(|d| d.id == diagnostic_id)
+                        .context("Diagnostic not found in the analysis of the file on disk; re-run analyzeFile")?
+                        .fixes
+                        .as_ref()
+                        .and_then(|f| f.get(fix_index))
+                        .cloned()
+                        .context("Diagnostic has no fix at fixIndex")?
+                }
+            };
+            let files = fix_file_edits(&fix, file_uri);
+            if files.is_empty() {
+                return Err(invalid_params("fix", "Fix has no edits".to_string()));
+            }
+            let settings = state.settings.lock().unwrap().clone();
+            let root = state.root_dir.lock().unwrap().as_deref().and_then(|root| root.canonicalize().ok());
+            // Every file is edited in memory first so a bad edit leaves all of them untouched.
+            let mut updates: Vec<(PathBuf, String, String)> = Vec::new();
+            for (uri, edits) in &files {
+                let path = uri_to_path(uri).ok_or_else(|| invalid_params("fix", format!("Fix edits a non-file URI: {uri}")))?;
+                if path != file_path {
+                    let inside = path.canonicalize().ok().zip(root.as_ref()).is_some_and(|(path, root)| path.starts_with(root));
+                    if !inside {
+                        return Err(invalid_params("fix", format!("Fix edits a file outside the workspace: {uri}")));
+                    }
+                }
+                let text = std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+                // Offsets in other files are only valid for the text clang-tidy read.
+                if path != file_path && !analyzed.fix_files.contains(&(path.clone(), xxh3_64(text.as_bytes()))) {
+                    return Err(file_modified(uri));
+                }
+                let (updated, edited) = apply_text_edits(&text, edits).map_err(|err| invalid_params("fix", err.to_string()))?;
+                let updated = format_fixed_text(&settings, &path, updated, &edited);
+                updates.push((path, text, updated));
+            }
+            // Journaled first, so whatever a failed write leaves behind can be reverted.
+            let run_id = journal.next_run_id();
+            journal.record(
+                &run_id,
+                updates
+                    .iter()
+                    .map(|(path, before, updated)| JournalFile { path: path.clone(), before: before.clone(), after: xxh3_64(updated.as_bytes()) })
+                    .collect(),
+            );
+            if let Err(err) = write_files_atomic(&updates) {
+                journal.discard(&run_id);
+                return Err(err);
+            }
+            // Reverts and other fixes need not wait for the re-analysis.
+            drop(journal);
+
+            let settings = state.settings.lock().unwrap().clone();
+            let analysis = analyze_document(state, &settings, &file_path, file_uri, &mode, None, None, None)?;
+            record_latest(&state.latest, &file_path, &analysis.diagnostics);
+            Ok(serde_json::json!({
+                "fileUri": file_uri,
+                "runId": run_id,
+                "applied": files.iter().map(|(_, edits)| edits.len()).sum::<usize>(),
+                "changedFiles": files.iter().map(|(uri, _)| uri).collect::<Vec<_>>(),
+                "diagnostics": analysis.diagnostics,
+            }))
+        }
+        "applyAllFixesInFile" => {
+            let (file_uri, file_path) = file_uri_param(&params)?;
+            let mode = params.get("mode").and_then(|v| v.as_str()).unwrap_or("full").to_string();
+            let filters = check_filters(&params);
+            let mut journal = state.fix_writes.lock().unwrap();
+            let analyzed = analysis_on_disk(state, file_uri, &file_path)?;
+            let text = std::fs::read_to_string(&file_path).context("Failed to read file")?;
+            let (accepted, skipped) = resolve_fix_conflicts(collect_fixes(
+                &text,
+                file_uri,
+                &analyzed.diagnostics,
+                &filters,
+                include_behavior_changing(&params),
+            ));
+            let mut edits: Vec<&(usize, usize, String)> = accepted.iter().flat_map(|fix| fix.edits.iter()).collect();
+            edits.sort();
+            edits.dedup();
+            let mut run_id = None;
+            if !edits.is_empty() {
+                let spans: Vec<(usize, usize, &str)> = edits.iter().map(|(start, end, new_text)| (*start, *end, new_text.as_str())).collect();
+                let (updated, edited) = splice_edits(&text, &spans);
+                let settings = state.settings.lock().unwrap().clone();
+                let updated = format_fixed_text(&settings, &file_path, updated, &edited);
+                let id = journal.next_run_id();
+                let after = xxh3_64(updated.as_bytes());
+                journal.record(&id, vec![JournalFile { path: file_path.clone(), before: text, after }]);
+                if let Err(err) = write_file_atomic(&file_path, updated.as_bytes()) {
+                    journal.discard(&id);
+                    return Err(err);
+                }
+                run_id = Some(id);
+            }
+            drop(journal);
+
+            let settings = state.settings.lock().unwrap().clone();
+            let analysis = analyze_document(state, &settings, &file_path, file_uri, &mode, None, None, None)?;
+            record_latest(&state.latest, &file_path, &analysis.diagnostics);
+            let describe = |fix: &SessionFix| {
+                serde_json::json!({ "diagnosticId": fix.diagnostic_id, "code": fix.code, "title": fix.title })
+            };
+            let skipped: Vec<Value> = skipped
+                .iter()
+                .map(|(fix, conflicts_with)| {
+                    let mut entry = describe(fix);
+                    entry["conflictsWith"] = Value::String(conflicts_with.clone());
+                    entry
+                })
+                .collect();
+            Ok(serde_json::json!({
+                "fileUri": file_uri,
+                "runId": run_id,
+                "applied": accepted.iter().map(describe).collect::<Vec<_>>(),
+                "skipped": skipped,
+                "diagnostics": analysis.diagnostics,
+            }))
+        }
+        "revertLastFix" | "revertRun" => {
+            let run_id = match method {
+                "revertRun" => Some(required_str(&params, "runId")?),
+                _ => None,
+            };
+            state.fix_writes.lock().unwrap().revert(run_id)
+        }
+        "verifyConfig" => {
+            let settings = state.settings.lock().unwrap().clone();
+            let root_dir = state.root_dir.lock().unwrap().clone();
+            let config_path = match params.get("configUri").and_then(|v| v.as_str()) {
+                Some(uri) => Some(uri_to_path(uri).ok_or_else(|| invalid_params("configUri", "Invalid `configUri`".to_string()))?),
+                None => {
+                    let compile_commands = resolve_compile_commands_path(&settings, root_dir.as_deref(), state);
+                    let compile_dir = compile_commands.as_deref().and_then(Path::parent);
+                    let anchor = match params.get("fileUri").and_then(|v| v.as_str()) {
+                        Some(_) => file_uri_param(&params)?.1,
+                        None => root_dir.clone().context("No workspace root; pass `fileUri` or `configUri`")?.join("file.cpp"),
+                    };
+                    find_clang_tidy_config(&anchor, root_dir.as_deref(), compile_dir)
+                }
+            };
+            let Some(config_path) = config_path else {
+                return Ok(serde_json::json!({ "configUri": null, "diagnostics": [] }));
+            };
+            let config_uri = Url::from_file_path(&config_path)
+                .map(|u| u.to_string())
+                .map_err(|_| anyhow::anyhow!("Invalid config path"))?;
+            let mut diags = verify_config(&settings, &config_path)?;
+            let fix_format = state.client.lock().unwrap().fix_format;
+            shape_fixes(&mut diags, fix_format, &config_uri, None);
+            Ok(serde_json::json!({ "configUri": config_uri, "diagnostics": diags }))
+        }
+        "validateCompileDb" => {
+            let settings = state.settings.lock().unwrap().clone();
+            let root_dir = state.root_dir.lock().unwrap().clone();
+            let limit = params.get("limit").and_then(|v| v.as_u64()).unwrap_or(DEFAULT_COMPILE_DB_PROBLEM_LIMIT as u64) as usize;
+            let compile_commands = match params.get("compileCommandsUri").and_then(|v| v.as_str()) {
+                Some(uri) => Some(
+                    uri_to_path(uri).ok_or_else(|| invalid_params("compileCommandsUri", "Invalid `compileCommandsUri`".to_string()))?,
+                ),
+                None => resolve_compile_commands_path(&settings, root_dir.as_deref(), state),
+            };
+            let Some(compile_commands) = compile_commands else {
+                return Ok(serde_json::json!({ "compileCommandsUri": null, "entries": 0, "problems": [], "totalProblems": 0 }));
+            };
+            let content = std::fs::read_to_string(&compile_commands)
+                .with_context(|| format!("Failed to read {}", compile_commands.display()))?;
+            let (entries, problems) = validate_compile_db(&content, &settings);
+            Ok(serde_json::json!({
+                "compileCommandsUri": Url::from_file_path(&compile_commands).map(|u| u.to_string()).unwrap_or_default(),
+                "entries": entries,
+                "totalProblems": problems.len(),
+                "problems": problems.iter().take(limit).collect::<Vec<_>>(),
+            }))
+        }
+        "explainCheck" => {
+            let check = required_str(&params, "check")?.trim();
+            if check.is_empty() || check.contains(char::is_whitespace) {
+                return Err(invalid_params("check", "`check` must be a single check name".to_string()));
+            }
+            let settings = state.settings.lock().unwrap().clone();
+            let tool_version = clang_tidy_version(&settings, state);
+            Ok(explain_check(check, tool_version.as_deref()))
+        }
+        "getIncluders" | "getIncludees" => {
+            let (file_uri, file_path) = file_uri_param(&params)?;
+            let file_path = std::fs::canonicalize(&file_path).unwrap_or(file_path);
+            let transitive = params.get("transitive").and_then(|v| v.as_bool()).unwrap_or(false);
+            let graph = current_include_graph(state)?;
+            let edges = if method == "getIncluders" { &graph.includers } else { &graph.includes };
+            let files: Vec<String> = walk_include_edges(edges, &file_path, transitive)
+                .iter()
+                .filter_map(|p| Url::from_file_path(p).ok())
+                .map(|u| u.to_string())
+                .collect();
+            Ok(serde_json::json!({ "fileUri": file_uri, "files": files }))
+        }
+        "startFixSession" => {
+            let (file_uri, file_path) = file_uri_param(&params)?;
+            let text = match params.get("fileContent").and_then(|v| v.as_str()) {
+                Some(content) => content.to_string(),
+                None => std::fs::read_to_string(&file_path).context("Failed to read file")?,
+            };
+            let filters = check_filters(&params);
+            let diags = latest_diagnostics(state, &file_path);
+            let session = start_fix_session(text, file_path, file_uri, &diags, &filters, include_behavior_changing(&params));
+            let total = session.pending.len();
+            let mut sessions = state.fix_sessions.lock().unwrap();
+            sessions.drop_idle();
+            sessions.next_id += 1;
+            let session_id = format!("fix-{}", sessions.next_id);
+            sessions.sessions.insert(session_id.clone(), session);
+            Ok(serde_json::json!({ "sessionId": session_id, "total": total }))
+        }
+        "nextFix" | "acceptFix" | "skipFix" | "endFixSession" => {
+            let session_id = required_str(&params, "sessionId")?;
+            let mut sessions = state.fix_sessions.lock().unwrap();
+            sessions.drop_idle();
+            if method == "endFixSession" {
+                let session = sessions.sessions.remove(session_id).context("Unknown fix session")?;
+                return Ok(serde_json::json!({
+                    "accepted": session.accepted,
+                    "skipped": session.skipped,
+                    "conflicting": session.conflicting,
+                    "remaining": session.pending.len() + usize::from(session.current.is_some()),
+                    "content": session.text,
+                }));
+            }
+            let session = sessions.sessions.get_mut(session_id).context("Unknown fix session")?;
+            session.used = Instant::now();
+            match method {
+                "nextFix" => Ok(next_session_fix(session)),
+                "acceptFix" => accept_session_fix(session),
+                _ => {
+                    session.current.take().context("No current fix; call nextFix first")?;
+                    session.skipped += 1;
+                    Ok(serde_json::json!({ "skipped": true }))
+                }
+            }
+        }
+        "formatFile" | "formatRange" => {
+            let (file_uri, file_path) = file_uri_param(&params)?;
+            let text = match params.get("fileContent").and_then(|v| v.as_str()) {
+                Some(content) => content.to_string(),
+                None => std::fs::read_to_string(&file_path).context("Failed to read file")?,
+            };
+            let line_starts = build_line_starts(&text);
+            let ranges = if method == "formatRange" {
+                let range: Range = serde_json::from_value(params.get("range").cloned().unwrap_or(Value::Null))
+                    .map_err(|err| invalid_params("range", format!("Invalid `range`: {err}")))?;
+                let start = position_to_offset(&text, &line_starts, &range.start);
+                let end = position_to_offset(&text, &line_starts, &range.end).max(start);
+                vec![(start, end - start)]
+            } else {
+                Vec::new()
+            };
+            let settings = state.settings.lock().unwrap().clone();
+            let (replacements, incomplete) = run_clang_format(&settings, &file_path, &text, &ranges, &[])?;
+            let edits: Vec<TextEdit> = replacements
+                .into_iter()
+                .map(|r| TextEdit { range: offset_range(&text, &line_starts, r.offset, r.length), new_text: r.text })
+                .collect();
+            let fix_format = state.client.lock().unwrap().fix_format;
+            let version = document_version(&params);
+            let mut result = serde_json::json!({ "fileUri": file_uri });
+            if params.get("asDiagnostics").and_then(|v| v.as_bool()).unwrap_or(false) {
+                let mut diags = format_diagnostics(&file_path, edits);
+                shape_fixes(&mut diags, fix_format, file_uri, version);
+                result["diagnostics"] = serde_json::to_value(diags)?;
+            } else if fix_format == FixFormat::WorkspaceEdit {
+                let fix = Fix {
+                    title: String::new(),
+                    edits,
+                    edit: None,
+                    kind: None,
+                    safety: None,
+                    conflicts_with: Vec::new(),
+                };
+                result["edit"] = serde_json::to_value(fix_workspace_edit(&fix, file_uri, version))?;
+            } else {
+                result["edits"] = serde_json::to_value(edits)?;
+            }
+            if incomplete {
+                result["incomplete"] = Value::Bool(true);
+            }
+            Ok(result)
+        }
+        "createRepro" => {
+            let (_, file_path) = file_uri_param(&params)?;
+            let mode = params.get("mode").and_then(|v| v.as_str()).unwrap_or("full");
+            let preprocessed = params.get("includePreprocessed").and_then(|v| v.as_bool()).unwrap_or(false);
+            let output_dir = params.get("outputDir").and_then(|v| v.as_str()).map(PathBuf::from);
+            let (path, files) = create_repro(state, &file_path, mode, preprocessed, output_dir)?;
+            Ok(serde_json::json!({
+                "path": path.to_string_lossy(),
+                "files": files,
+            }))
+        }
+        "exportCache" | "importCache" => {
+            let settings = state.settings.lock().unwrap().clone();
+            let root_dir = state.root_dir.lock().unwrap().clone().context("No workspace root")?;
+            let compile_commands = resolve_compile_commands_path(&settings, Some(&root_dir), state)
+                .context("compile_commands.json not found")?;
+            let index = get_compile_index(&compile_commands, state)?;
+            let scope = CacheArchiveScope {
+                settings: &settings,
+                root_dir: &root_dir,
+                compile_commands: &compile_commands,
+                files: &index.files,
+                memory: Some(&state.cache),
+                remote: &state.remote_cache,
+            };
+            if method == "exportCache" {
+                let output_dir = params.get("outputDir").and_then(|v| v.as_str()).map(PathBuf::from);
+                let (path, manifest) = export_cache_archive(&scope, &output_dir.unwrap_or_else(std::env::temp_dir))?;
+                Ok(serde_json::json!({
+                    "path": path.to_string_lossy(),
+                    "commit": manifest.commit,
+                    "entries": manifest.entries,
+                }))
+            } else {
+                let path = required_str(&params, "path")?;
+                let import = import_cache_archive(&scope, Path::new(path))?;
+                Ok(serde_json::json!({
+                    "commit": import.manifest.commit,
+                    "headCommit": head_commit(&root_dir),
+                    "imported": import.imported,
+                    "alreadyCached": import.present,
+                }))
+            }
+        }
+        "analyzeChangedFiles" => {
+            let settings = state.settings.lock().unwrap().clone();
+            let root_dir = state.root_dir.lock().unwrap().clone().context("No workspace root")?;
+            let run_id = params.get("runId").and_then(|v| v.as_str()).unwrap_or("changedFiles").to_string();
+            let mode = params.get("mode").and_then(|v| v.as_str()).unwrap_or("full").to_string();
+            let base = params
+                .get("baseRef")
+                .and_then(|v| v.as_str())
+                .map(str::trim)
+                .filter(|base| !base.is_empty())
+                .unwrap_or(diff_base_ref(&settings))
+                .to_string();
+            let fail_on = params.get("failOn").and_then(|v| v.as_str()).unwrap_or("error").to_string();
+            if !SEVERITY_ORDER.contains(&fail_on.as_str()) {
+                return Err(invalid_params("failOn", format!("Unknown severity `{fail_on}`")));
+            }
+            if !in_git_work_tree(&root_dir) {
+                let _ = send_log(&state.stdout, "warn", format!("{} is not a git work tree; no changed files to analyze", root_dir.display()));
+                return Ok(serde_json::json!({ "runId": run_id, "baseRef": base, "git": false, "files": 0, "passed": true }));
+            }
+            let mut files: Vec<PathBuf> = changed_files(&base, &root_dir)?
+                .into_iter()
+                .filter(|file| file.is_file() && is_c_family_file(file))
+                .collect();
+            files.sort();
+            let progress = ProgressReporter::new(state, &run_id, &params);
+            progress.begin("clang-tidy", format!("Analyzing {} files changed since {base}", files.len()), false);
+            let mut result = analyze_changed_files(state, &run_id, &mode, &files, &fail_on);
+            progress.end("Changed files analyzed");
+            result["runId"] = Value::String(run_id);
+            result["baseRef"] = Value::String(base);
+            result["git"] = Value::Bool(true);
+            Ok(result)
+        }
+        "getFileTimings" => {
+            let settings = state.settings.lock().unwrap().clone();
+            let root_dir = state.root_dir.lock().unwrap().clone();
+            let compile_commands = resolve_compile_commands_path(&settings, root_dir.as_deref(), state)
+                .context("compile_commands.json not found")?;
+            let mode = params.get("mode").and_then(|v| v.as_str()).unwrap_or("full").to_string();
+            let limit = params.get("limit").and_then(|v| v.as_u64()).unwrap_or(20) as usize;
+            let durations = load_file_timings(&file_timings_path(&settings, root_dir.as_deref(), &compile_commands))
+                .modes
+                .remove(&mode)
+                .unwrap_or_default();
+            let mut files: Vec<(PathBuf, u64)> = match params.get("files").and_then(|v| v.as_array()) {
+                Some(list) => file_list_param(list)
+                    .into_iter()
+                    .filter_map(|file| Some((file.clone(), *durations.get(&file)?)))
+                    .collect(),
+                None => durations.into_iter().collect(),
+            };
+            files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            let total = files.len();
+            let files: Vec<Value> = files
+                .into_iter()
+                .take(limit)
+                .map(|(file, wall_ms)| {
+                    let uri = Url::from_file_path(&file).map(|u| u.to_string()).unwrap_or_default();
+                    serde_json::json!({ "fileUri": uri, "wallMs": wall_ms })
+                })
+                .collect();
+            Ok(serde_json::json!({ "mode": mode, "totalFiles": total, "files": files }))
+        }
+        "benchmark" => {
+            let settings = state.settings.lock().unwrap().clone();
+            let root_dir = state.root_dir.lock().unwrap().clone();
+            let compile_commands = resolve_compile_commands_path(&settings, root_dir.as_deref(), state)
+                .context("compile_commands.json not found")?;
+            let index = get_compile_index(&compile_commands, state)?;
+            let run_id = params.get("runId").and_then(|v| v.as_str()).unwrap_or("benchmark").to_string();
+            let sample = params.get("sampleSize").and_then(|v| v.as_u64()).unwrap_or(DEFAULT_BENCH_SAMPLE as u64) as usize;
+            let configs = match params.get("configurations") {
+                Some(list) => serde_json::from_value(list.clone())
+                    .map_err(|err| invalid_params("configurations", format!("Invalid `configurations`: {err}")))?,
+                None => default_bench_configs(&settings),
+            };
+            let progress = ProgressReporter::new(state, &run_id, &params);
+            progress.begin("clang-tidy benchmark", format!("Benchmarking {} configurations", configs.len()), false);
+            let report = run_benchmark(
+                &settings,
+                root_dir.as_deref(),
+                &compile_commands,
+                &sample_files(&index.files, sample),
+                &configs,
+                &|message| progress.report(message, None),
+            );
+            progress.end("Benchmark completed");
+            Ok(report)
+        }
+        "applyFixesProject" => {
+            let settings = state.settings.lock().unwrap().clone();
+            let root_dir = state.root_dir.lock().unwrap().clone();
+            let compile_commands = resolve_compile_commands_path(&settings, root_dir.as_deref(), state)
+                .context("compile_commands.json not found")?;
+            let index = get_compile_index(&compile_commands, state)?;
+            static NEXT_FIX_CAMPAIGN: AtomicU64 = AtomicU64::new(1);
+            let run_id = match params.get("runId").and_then(|v| v.as_str()) {
+                Some(run_id) => run_id.to_string(),
+                None => format!("fixes-{}", NEXT_FIX_CAMPAIGN.fetch_add(1, Ordering::Relaxed)),
+            };
+            let mut files: Vec<PathBuf> = match params.get("files").and_then(|v| v.as_array()) {
+                Some(list) => list
+                    .iter()
+                    .filter_map(|v| v.as_str())
+                    .map(|raw| uri_to_path(raw).unwrap_or_else(|| PathBuf::from(raw)))
+                    .collect(),
+                None => index.files.clone(),
+            };
+            let selected = files.len();
+            files.retain(|file_path| glob_exclusion(&settings, file_path, root_dir.as_deref()).is_none());
+            let excluded = selected - files.len();
+            let replacements_tool = match params.get("applyWith").and_then(|v| v.as_str()).unwrap_or("daemon") {
+                "daemon" => None,
+                "clang-apply-replacements" => Some(clang_apply_replacements_binary(&settings)),
+                other => {
+                    return Err(invalid_params(
+                        "applyWith",
+                        format!("Parameter `applyWith` must be \"daemon\" or \"clang-apply-replacements\", got \"{other}\""),
+                    ))
+                }
+            };
+            let campaign = FixCampaign {
+                run_id: run_id.clone(),
+                filters: check_filters(&params),
+                include_behavior_changing: include_behavior_changing(&params),
+                dry_run: params.get("dryRun").and_then(|v| v.as_bool()).unwrap_or(false),
+                replacements_tool,
+                cancel: Arc::new(AtomicBool::new(false)),
+            };
+            {
+                let mut cancel_map = state.cancel_map.lock().unwrap();
+                if cancel_map.contains_key(&run_id) {
+                    return Err(invalid_params("runId", format!("A run with id `{run_id}` is still active")));
+                }
+                cancel_map.insert(run_id.clone(), campaign.cancel.clone());
+            }
+            let cancel_entry = CancelEntryGuard { map: state.cancel_map.clone(), run_id: run_id.clone(), flag: campaign.cancel.clone() };
+            let progress = ProgressReporter::new(state, &run_id, &params);
+            progress.begin("clang-tidy fixes", format!("Collecting fixes from {} files", files.len()), true);
+            let mut summary = run_fix_campaign(
+                &settings,
+                root_dir.as_deref(),
+                &compile_commands,
+                &files,
+                &campaign,
+                &state.fix_writes,
+                &state.scheduler,
+                &state.resources,
+                &|message, percent| progress.report(message, percent),
+            );
+            drop(cancel_entry);
+            progress.end(if campaign.cancel.load(Ordering::Relaxed) {
+                "Fix campaign cancelled"
+            } else if campaign.dry_run {
+                "Fix campaign dry run completed"
+            } else {
+                "Fix campaign completed"
+            });
+            summary["runId"] = Value::String(run_id);
+            summary["excluded"] = serde_json::json!(excluded);
+            Ok(summary)
+        }
+        "checkProfile" => {
+            let limit = params.get("limit").and_then(|v| v.as_u64()).unwrap_or(20) as usize;
+            let stats = state.resources.lock().unwrap();
+            match params.get("runId").and_then(|v| v.as_str()) {
+                Some(run_id) => {
+                    let run = stats
+                        .run_profiles
+                        .iter()
+                        .find(|run| run.run_id == run_id)
+                        .ok_or_else(|| invalid_params("runId", format!("No check profile for run `{run_id}`")))?;
+                    let mut report = check_profile_report(run.files, &run.checks, limit);
+                    report["runId"] = Value::String(run_id.to_string());
+                    Ok(report)
+                }
+                None => {
+                    let mut checks: HashMap<String, CheckTiming> = HashMap::new();
+                    for profile in stats.check_profiles.values() {
+                        for (check, timing) in profile {
+                            checks.entry(check.clone()).or_default().add(timing);
+                        }
+                    }
+                    let mut report = check_profile_report(stats.check_profiles.len(), &checks, limit);
+                    report["runs"] = stats.run_profiles.iter().map(|run| Value::String(run.run_id.clone())).collect();
+                    Ok(report)
+                }
+            }
+        }
+        "getStatus" => {
+            let limit = params.get("limit").and_then(|v| v.as_u64()).unwrap_or(20) as usize;
+            let resources = resource_status(&state.resources.lock().unwrap(), limit);
+            let (entries, memory_evictions) = {
+                let cache = state.cache.lock().unwrap();
+                (cache.len(), cache.evictions)
+            };
+            Ok(serde_json::json!({
+                "pid": std::process::id(),
+                "resources": resources,
+                "scheduler": state.scheduler.status(),
+                "cache": {
+                    "entries": entries,
+                    "memoryEvictions": memory_evictions,
+                    "diskEvictions": state.disk_cache_evictions.load(Ordering::Relaxed),
+                },
+                "remoteCache": state.remote_cache.status(),
+                "cacheWarming": state.cache_warming.status(),
+            }))
+        }
+        "resyncDiagnostics" => {
+            let (file_uri, _) = file_uri_param(&params)?;
+            let version = Publisher::from_state(state).resync("resync", file_uri)?;
+            Ok(serde_json::json!({ "fileUri": file_uri, "version": version }))
+        }
+        "diagnosticStats" => {
+            let limit = params.get("limit").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+            let directory = match params.get("directory").filter(|v| !v.is_null()) {
+                Some(value) if value.is_string() => file_list_param(std::slice::from_ref(value)).pop(),
+                Some(_) => return Err(invalid_params("directory", "Expected a directory URI or path".to_string())),
+                None => None,
+            };
+            let stats = state.diagnostic_stats.lock().unwrap();
+            let result = match params.get("runId").and_then(|v| v.as_str()) {
+                Some(run_id) => {
+                    let (_, files) = stats
+                        .runs
+                        .iter()
+                        .find(|(id, _)| id == run_id)
+                        .ok_or_else(|| invalid_params("runId", format!("No statistics for run {run_id:?}")))?;
+                    let mut result = DiagnosticStats::report(files, directory.as_deref(), limit);
+                    result["runId"] = Value::String(run_id.to_string());
+                    result
+                }
+                None => {
+                    let mut result = DiagnosticStats::report(&stats.files, directory.as_deref(), limit);
+                    result["runs"] = stats.runs.iter().map(|(id, _)| Value::String(id.clone())).collect();
+                    result
+                }
+            };
+            Ok(result)
+        }
+        "pauseCacheWarming" | "resumeCacheWarming" => {
+            state.cache_warming.set_paused(method == "pauseCacheWarming");
+            Ok(state.cache_warming.status())
+        }
+        "cancel" => {
+            if let Some(run_id) = params.get("runId").and_then(|v| v.as_str()) {
+                let map = state.cancel_map.lock().unwrap();
+                if run_id == "*" {
+                    for (_, flag) in map.iter() {
+                        flag.store(true, Ordering::Relaxed);
+                    }
+                } else if let Some(flag) = map.get(run_id) {
+                    flag.store(true, Ordering::Relaxed);
+                }
+            }
+            Ok(serde_json::json!({}))
+        }
+        _ => Err(RpcError {
+            code: METHOD_NOT_FOUND,
+            message: format!("Method not found: {method}"),
+            data: None,
+        }
+        .into()),
+    }
+}
+
+/// Takes the workspace lock file. When another live daemon holds it, either
+/// refuses (`singleInstance`) or warns that the two instances share the cache.
+fn claim_workspace(state: &AppState, root: &Path, single_instance: bool) -> Result<()> {
+    let root = std::fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+    let mut hasher = DefaultHasher::new();
+    root.to_string_lossy().hash(&mut hasher);
+    let lock_path = std::env::temp_dir().join(format!("clang-tidy-daemon-{:016x}.lock", hasher.finish()));
+
+    let mut current = state.instance_lock.lock().unwrap();
+    if current.as_ref().is_some_and(|lock| lock.path == lock_path) {
+        return Ok(());
+    }
+    current.take();
+
+    let info = InstanceInfo {
+        pid: std::process::id(),
+        session_id: state.session_id,
+        root_uri: Url::from_file_path(&root).map(|u| u.to_string()).unwrap_or_default(),
+        started_at: std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    };
+    for _ in 0..2 {
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+            Ok(mut file) => {
+                file.write_all(serde_json::to_string(&info)?.as_bytes())?;
+                *current = Some(InstanceLock { path: lock_path });
+                return Ok(());
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                let owner = std::fs::read_to_string(&lock_path)
+                    .ok()
+                    .and_then(|text| serde_json::from_str::<InstanceInfo>(&text).ok());
+                match owner {
+                    // Includes another `--socket`/`--listen` session of this process.
+                    Some(owner) if process_alive(owner.pid) => {
+                        if single_instance {
+                            return Err(RpcError {
+                                code: INSTANCE_RUNNING,
+                                message: format!("Another clang-tidy daemon (pid {}) is already serving this workspace", owner.pid),
+                                data: Some(serde_json::json!({
+                                    "reason": "instanceRunning",
+                                    "pid": owner.pid,
+                                    "lockFile": lock_path.to_string_lossy(),
+                                    "startedAt": owner.started_at,
+                                })),
+                            }
+                            .into());
+                        }
+                        let _ = send_log(&state.stdout, "warn", format!(
+                            "Another clang-tidy daemon (pid {}) is serving this workspace; both will analyze and write the disk cache",
+                            owner.pid
+                        ));
+                        return Ok(());
+                    }
+                    _ => {
+                        // Left behind by a daemon that exited without cleanup.
+                        let _ = std::fs::remove_file(&lock_path);
+                    }
+                }
+            }
+            Err(err) => {
+                let _ = send_log(&state.stdout, "warn", format!("Failed to create workspace lock {}: {err}", lock_path.display()));
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Best-effort liveness check: `kill(pid, 0)` on Unix (plus the zombie
+/// state from /proc where it exists), `tasklist` on Windows. When the check
+/// itself fails, the process is assumed alive.
+fn process_alive(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        // Signal 0 only checks for existence; EPERM means it exists but
+        // belongs to another user.
+        let rc = unsafe { libc::kill(pid as libc::pid_t, 0) };
+        let exists = rc == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+        // A zombie still answers signals until it is reaped.
+        let zombie = std::fs::read_to_string(format!("/proc/{pid}/stat"))
+            .ok()
+            .and_then(|stat| stat.rsplit_once(')').map(|(_, rest)| rest.trim_start().starts_with('Z')))
+            .unwrap_or(false);
+        exists && !zombie
+    }
+    #[cfg(windows)]
+    {
+        let output = Command::new("tasklist")
+            .args(["/FI", &format!("PID eq {pid}"), "/FO", "CSV", "/NH"])
+            .stdin(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {
+                String::from_utf8_lossy(&output.stdout).contains(&format!("\"{pid}\""))
+            }
+            _ => true,
+        }
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = pid;
+        true
+    }
+}
+
+const PARENT_WATCH_INTERVAL_MS: u64 = 1000;
+
+/// Watches the editor process given as `parentPid`. When it disappears, a
+/// stdio daemon kills its clang-tidy children and exits; a socket session
+/// only cancels its own project runs, since other clients share the process.
+fn watch_parent(state: &AppState, pid: u32) {
+    let previous = state.parent_pid.lock().unwrap().replace(pid);
+    if previous.is_some() {
+        return;
+    }
+    let state = state.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(PARENT_WATCH_INTERVAL_MS));
+        let Some(pid) = *state.parent_pid.lock().unwrap() else {
+            return;
+        };
+        if process_alive(pid) {
+            continue;
+        }
+        for flag in state.cancel_map.lock().unwrap().values() {
+            flag.store(true, Ordering::Relaxed);
+        }
+        if !state.owns_process {
+            *state.parent_pid.lock().unwrap() = None;
+            eprintln!("Parent process {pid} exited; cancelled the runs of its session");
+            return;
+        }
+        eprintln!("Parent process {pid} exited; shutting down");
+        kill_child_processes();
+        state.instance_lock.lock().unwrap().take();
+        state.cache.lock().unwrap().discard_spill();
+        state.latest.lock().unwrap().discard_spill();
+        std::process::exit(1);
+    });
+}
+
+/// Kills the direct children of this process (the clang-tidy runs in flight).
+/// They are found through /proc on Linux, `pgrep -P` on other Unix systems
+/// and a CIM query through PowerShell on Windows; where none of these work,
+/// the children are left to finish.
+fn kill_child_processes() {
+    for pid in child_pids() {
+        #[cfg(unix)]
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGKILL);
+        }
+        #[cfg(windows)]
+        {
+            let _ = Command::new("taskkill")
+                .args(["/F", "/T", "/PID", &pid.to_string()])
+                .stdin(std::process::Stdio::null())
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .status();
+        }
+        #[cfg(not(any(unix, windows)))]
+        let _ = pid;
+    }
+}
+
+/// Process ids whose parent is this process; see `kill_child_processes`.
+fn child_pids() -> Vec<u32> {
+    let own_pid = std::process::id();
+    if let Ok(entries) = std::fs::read_dir("/proc") {
+        let mut pids = Vec::new();
+        for entry in entries.flatten() {
+            let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else {
+                continue;
+            };
+            let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else {
+                continue;
+            };
+            // The command name in parentheses may contain spaces; the parent
+            // pid is the second field after it.
+            let ppid = stat
+                .rsplit_once(')')
+                .and_then(|(_, rest)| rest.split_whitespace().nth(1))
+                .and_then(|field| field.parse::<u32>().ok());
+            if ppid == Some(own_pid) {
+                pids.push(pid);
+            }
+        }
+        return pids;
+    }
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("powershell");
+        command.args([
+            "-NoProfile",
+            "-Command",
+            &format!("Get-CimInstance Win32_Process -Filter 'ParentProcessId={own_pid}' | ForEach-Object ProcessId"),
+        ]);
+        command
+    } else {
+        let mut command = Command::new("pgrep");
+        command.args(["-P", &own_pid.to_string()]);
+        command
+    };
+    let Ok(output) = command.stdin(std::process::Stdio::null()).stderr(std::process::Stdio::null()).output() else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.trim().parse::<u32>().ok())
+        .filter(|&pid| pid != own_pid)
+        .collect()
+}
+
+/// Token buckets per method and per (method, file). Each request takes one
+/// token; buckets refill at the configured rate up to `rateLimitBurst`.
+fn check_rate_limit(method: &str, params: &Value, state: &AppState) -> Result<()> {
+    let settings = state.settings.lock().unwrap().clone();
+    let mut keys = Vec::new();
+    if settings.rateLimitPerMethod > 0.0 {
+        keys.push((method.to_string(), settings.rateLimitPerMethod));
+    }
+    if settings.rateLimitPerFile > 0.0 {
+        if let Some(uri) = params.get("fileUri").and_then(|v| v.as_str()) {
+            keys.push((format!("{method}:{uri}"), settings.rateLimitPerFile));
+        }
+    }
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    let mut buckets = state.rate_limits.lock().unwrap();
+    let now = Instant::now();
+    buckets.retain(|_, bucket| bucket.full_at > now);
+    let mut retry_after: Option<f64> = None;
+    for (key, rate) in &keys {
+        let capacity = if settings.rateLimitBurst > 0 { settings.rateLimitBurst as f64 } else { rate.max(1.0) };
+        let bucket = buckets.entry(key.clone()).or_insert(TokenBucket { tokens: capacity, updated: now, full_at: now });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+        bucket.full_at = now + Duration::from_secs_f64((capacity - bucket.tokens) / rate);
+        if bucket.tokens < 1.0 {
+            let wait = (1.0 - bucket.tokens) / rate;
+            retry_after = Some(retry_after.map_or(wait, |w: f64| w.max(wait)));
+        }
+    }
+    if let Some(wait) = retry_after {
+        return Err(RpcError {
+            code: RATE_LIMITED,
+            message: format!("{method} rate limit exceeded"),
+            data: Some(serde_json::json!({
+                "reason": "rateLimited",
+                "method": method,
+                "retryAfterMs": (wait * 1000.0).ceil() as u64,
+            })),
+        }
+        .into());
+    }
+    for (key, rate) in &keys {
+        if let Some(bucket) = buckets.get_mut(key) {
+            bucket.tokens -= 1.0;
+            bucket.full_at += Duration::from_secs_f64(1.0 / rate);
+        }
+    }
+    Ok(())
+}
+
+const CHECKPOINT_INTERVAL_FILES: usize = 20;
+/// A checkpoint is saved again once this much time has passed, or once the
+/// files finished since the last save make up this fraction of those left.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+const CHECKPOINT_REMAINING_DIVISOR: usize = 8;
+
+const SUMMARY_TOP_CHECKS: usize = 10;
+const SUMMARY_MAX_EXHAUSTED: usize = 100;
+/// Project runs whose statistics `diagnosticStats` keeps.
+const STATS_MAX_RUNS: usize = 10;
+
+/// Diagnostics per (check, severity); diagnostics without a check count
+/// under an empty check.
+type CheckCounts = BTreeMap<(String, String), usize>;
+
+/// Counts behind `diagnosticStats`: the latest published or returned
+/// diagnostics of every file, and those of the most recent project runs.
+#[derive(Debug, Default)]
+struct DiagnosticStats {
+    files: HashMap<PathBuf, CheckCounts>,
+    /// Oldest first.
+    runs: VecDeque<(String, HashMap<PathBuf, CheckCounts>)>,
+}
+
+impl DiagnosticStats {
+    fn record(&mut self, run_id: Option<&str>, file_path: &Path, diags: &[RpcDiagnostic]) {
+        let mut counts = CheckCounts::new();
+        for diag in diags {
+            *counts.entry((diag.code.clone().unwrap_or_default(), diag.severity.clone())).or_default() += 1;
+        }
+        if let Some(run_id) = run_id {
+            if !self.runs.iter().any(|(id, _)| id == run_id) {
+                if self.runs.len() == STATS_MAX_RUNS {
+                    self.runs.pop_front();
+                }
+                self.runs.push_back((run_id.to_string(), HashMap::new()));
+            }
+            if let Some((_, files)) = self.runs.iter_mut().find(|(id, _)| id == run_id) {
+                files.insert(file_path.to_path_buf(), counts.clone());
+            }
+        }
+        if counts.is_empty() {
+            self.files.remove(file_path);
+        } else {
+            self.files.insert(file_path.to_path_buf(), counts);
+        }
+    }
+
+    /// Result of `diagnosticStats` over `files`, limited to those under
+    /// `directory`, with the `limit` most frequent checks (all with 0).
+    fn report(files: &HashMap<PathBuf, CheckCounts>, directory: Option<&Path>, limit: usize) -> Value {
+        let mut severities: BTreeMap<&str, usize> = SEVERITY_ORDER.iter().map(|s| (*s, 0)).collect();
+        let mut checks: HashMap<&str, BTreeMap<&str, usize>> = HashMap::new();
+        let (mut matched, mut total) = (0, 0);
+        for (file, counts) in files {
+            if directory.is_some_and(|dir| !file.starts_with(dir)) {
+                continue;
+            }
+            if counts.values().any(|count| *count > 0) {
+                matched += 1;
+            }
+            for ((check, severity), count) in counts {
+                total += count;
+                *severities.entry(severity.as_str()).or_default() += count;
+                if !check.is_empty() {
+                    *checks.entry(check.as_str()).or_default().entry(severity.as_str()).or_default() += count;
+                }
+            }
+        }
+        let mut checks: Vec<(&str, usize, BTreeMap<&str, usize>)> =
+            checks.into_iter().map(|(check, by_severity)| (check, by_severity.values().sum(), by_severity)).collect();
+        checks.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        let checks: Vec<Value> = checks
+            .into_iter()
+            .take(if limit == 0 { usize::MAX } else { limit })
+            .map(|(check, count, by_severity)| serde_json::json!({ "check": check, "count": count, "severities": by_severity }))
+            .collect();
+        serde_json::json!({
+            "files": matched,
+            "total": total,
+            "diagnostics": severities,
+            "checks": checks,
+        })
+    }
+}
+const DEFAULT_RETRY_BACKOFF_MS: u64 = 1000;
+
+/// Settings for a retry of a failed file: `retryChecks` goes after the
+/// configured checks, so `-*,...` narrows the set. `None` when unset.
+fn retry_settings(settings: &Settings) -> Option<Settings> {
+    let retry_checks = settings.retryChecks.trim();
+    if retry_checks.is_empty() {
+        return None;
+    }
+    let mut retry = settings.clone();
+    retry.checks = if settings.checks.trim().is_empty() {
+        retry_checks.to_string()
+    } else {
+        format!("{},{retry_checks}", settings.checks)
+    };
+    Some(retry)
+}
+
+/// Delay before retry `attempt` (1-based), doubling from `retryBackoffMs`.
+fn retry_backoff(settings: &Settings, attempt: u32) -> Duration {
+    let base = if settings.retryBackoffMs == 0 { DEFAULT_RETRY_BACKOFF_MS } else { settings.retryBackoffMs };
+    Duration::from_millis(base.saturating_mul(1 << attempt.saturating_sub(1).min(16)))
+}
+
+/// A missing binary or source file fails the same way every time.
+fn is_retryable(err: &anyhow::Error) -> bool {
+    !err.chain().any(|cause| cause.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::NotFound))
+}
+
+/// Outcomes of the files of a project run, for its `summary` notification.
+#[derive(Debug, Default)]
+struct RunTally {
+    analyzed: usize,
+    cached: usize,
+    failed: usize,
+    /// Files that failed at first and succeeded on a retry.
+    retried: usize,
+    /// Failed files that used up `retryCount`.
+    exhausted: Vec<PathBuf>,
+    /// Diagnostics dropped because the baseline lists them.
+    baselined: usize,
+    /// Files not started because `maxRunDurationMs` ran out.
+    deferred: usize,
+    /// Headers whose merged findings were published.
+    headers: usize,
+    severities: BTreeMap<String, usize>,
+    checks: HashMap<String, usize>,
+    /// Wall time of the files clang-tidy ran on.
+    durations: HashMap<PathBuf, u64>,
+}
+
+impl RunTally {
+    fn record(&mut self, file_path: &Path, cached: bool, wall_ms: u64, diags: &[RpcDiagnostic]) {
+        if cached {
+            self.cached += 1;
+        } else {
+            self.analyzed += 1;
+            self.durations.insert(file_path.to_path_buf(), wall_ms);
+        }
+        self.count(diags);
+    }
+
+    fn count(&mut self, diags: &[RpcDiagnostic]) {
+        for diag in diags {
+            *self.severities.entry(diag.severity.clone()).or_default() += 1;
+            if let Some(code) = diag.code.as_ref() {
+                *self.checks.entry(code.clone()).or_default() += 1;
+            }
+        }
+    }
+
+    /// Params of the `summary` notification. Of the `files` the run
+    /// selected, those neither analyzed, cached nor failed were skipped.
+    fn summary(&self, run_id: &str, files: usize, started: Instant, cancelled: bool) -> Value {
+        let mut severities: BTreeMap<&str, usize> = SEVERITY_ORDER.iter().map(|s| (*s, 0)).collect();
+        for (severity, count) in &self.severities {
+            *severities.entry(severity.as_str()).or_default() += count;
+        }
+        let mut checks: Vec<(&String, &usize)> = self.checks.iter().collect();
+        checks.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        let top_checks: Vec<Value> = checks
+            .into_iter()
+            .take(SUMMARY_TOP_CHECKS)
+            .map(|(check, count)| serde_json::json!({ "check": check, "count": count }))
+            .collect();
+        let mut exhausted: Vec<&PathBuf> = self.exhausted.iter().collect();
+        exhausted.sort();
+        let exhausted: Vec<Value> = exhausted
+            .into_iter()
+            .take(SUMMARY_MAX_EXHAUSTED)
+            .filter_map(|f| Url::from_file_path(f).ok())
+            .map(|u| Value::String(u.to_string()))
+            .collect();
+        serde_json::json!({
+            "runId": run_id,
+            "files": files,
+            "analyzed": self.analyzed,
+            "cached": self.cached,
+            "failed": self.failed,
+            "retried": self.retried,
+            "retriesExhausted": exhausted,
+            "baselined": self.baselined,
+            "deferred": self.deferred,
+            "headers": self.headers,
+            "skipped": files.saturating_sub(self.analyzed + self.cached + self.failed),
+            "diagnostics": severities,
+            "topChecks": top_checks,
+            "wallMs": started.elapsed().as_millis() as u64,
+            "cancelled": cancelled,
+        })
+    }
+}
+
+/// Progress of a project run over the compile database, saved in the cache
+/// directory so a run with `resume` can continue it after the daemon was
+/// killed mid-scan.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RunCheckpoint {
+    run_id: String,
+    mode: String,
+    fingerprint: u64,
+    remaining: Vec<PathBuf>,
+}
+
+/// Saves the checkpoint of one project run.
+struct RunCheckpointer {
+    path: PathBuf,
+    run_id: String,
+    mode: String,
+    fingerprint: u64,
+    order: Vec<PathBuf>,
+    runs: Arc<Mutex<HashMap<String, ProjectRunFiles>>>,
+    /// When the last save happened and how many files were finished then.
+    saved: Mutex<(Instant, usize)>,
+}
+
+impl RunCheckpointer {
+    /// Saves after `finished` of `total` files when enough time has passed or
+    /// enough files finished since the last save. Rewriting the whole list on
+    /// a fixed file count would cost time quadratic in the database size.
+    fn save_due(&self, finished: usize, total: usize, stdout: &Arc<Output>) {
+        {
+            let saved = self.saved.lock().unwrap();
+            let since = finished.saturating_sub(saved.1);
+            let remaining = total.saturating_sub(finished);
+            if since < CHECKPOINT_INTERVAL_FILES
+                || (saved.0.elapsed() < CHECKPOINT_INTERVAL && since * CHECKPOINT_REMAINING_DIVISOR < remaining)
+            {
+                return;
+            }
+        }
+        self.save(stdout, finished);
+    }
+
+    /// Records the files still pending in the project runs of this mode,
+    /// which include files merged into other runs, in scheduling order.
+    /// Removes the checkpoint once none are left.
+    fn save(&self, stdout: &Arc<Output>, finished: usize) {
+        let mut saved = self.saved.lock().unwrap();
+        if finished < saved.1 {
+            return;
+        }
+        *saved = (Instant::now(), finished);
+        let pending: HashSet<PathBuf> = self
+            .runs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|run| run.mode == self.mode)
+            .flat_map(|run| run.pending.iter().cloned())
+            .collect();
+        if pending.is_empty() {
+            let _ = std::fs::remove_file(&self.path);
+            return;
+        }
+        let mut remaining: Vec<PathBuf> = self.order.iter().filter(|file| pending.contains(*file)).cloned().collect();
+        let ours: HashSet<&PathBuf> = self.order.iter().collect();
+        let mut merged: Vec<PathBuf> = pending.iter().filter(|file| !ours.contains(file)).cloned().collect();
+        merged.sort();
+        remaining.extend(merged);
+        let checkpoint = RunCheckpoint {
+            run_id: self.run_id.clone(),
+            mode: self.mode.clone(),
+            fingerprint: self.fingerprint,
+            remaining,
+        };
+        let write = || -> Result<()> {
+            let dir = self.path.parent().context("checkpoint path has no parent")?;
+            std::fs::create_dir_all(dir)?;
+            let mut tmp = NamedTempFile::new_in(dir)?;
+            serde_json::to_writer(io::BufWriter::new(tmp.as_file_mut()), &checkpoint)?;
+            tmp.persist(&self.path).map_err(|err| err.error)?;
+            Ok(())
+        };
+        if let Err(err) = write() {
+            let _ = send_log(stdout, "warn", format!("Failed to save the run checkpoint to {}: {err}", self.path.display()));
+        }
+    }
+}
+
+/// Where state about project runs over `compile_commands` is kept: the cache
+/// directory, or without a disk cache the per-user cache directory.
+fn run_state_dir(settings: &Settings, root_dir: Option<&Path>, compile_commands: &Path) -> PathBuf {
+    resolve_cache_dir(settings, root_dir, compile_commands.parent()).unwrap_or_else(|| {
+        os_cache_dir()
+            .map(|dir| dir.join("clang-tidy-daemon"))
+            .unwrap_or_else(std::env::temp_dir)
+    })
+}
+
+/// Where project runs in `mode` over `compile_commands` keep their checkpoint.
+fn run_checkpoint_path(settings: &Settings, root_dir: Option<&Path>, compile_commands: &Path, mode: &str) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    compile_commands.hash(&mut hasher);
+    mode.hash(&mut hasher);
+    run_state_dir(settings, root_dir, compile_commands)
+        .join("runs")
+        .join(format!("{:016x}.json", hasher.finish()))
+}
+
+/// How long analyzing each file took in the last project run that analyzed
+/// it, in milliseconds, by mode.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FileTimings {
+    #[serde(default)]
+    modes: BTreeMap<String, HashMap<PathBuf, u64>>,
+}
+
+fn file_timings_path(settings: &Settings, root_dir: Option<&Path>, compile_commands: &Path) -> PathBuf {
+    run_state_dir(settings, root_dir, compile_commands)
+        .join("timings")
+        .join(format!("{:016x}.json", cache_key_for_path(compile_commands)))
+}
+
+fn load_file_timings(path: &Path) -> FileTimings {
+    std::fs::read(path)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+/// Merges the `durations` a run measured in `mode` into the timings file.
+fn save_file_timings(path: &Path, mode: &str, durations: &HashMap<PathBuf, u64>) -> Result<()> {
+    static WRITE: Mutex<()> = Mutex::new(());
+    let _write = WRITE.lock().unwrap();
+    let mut timings = load_file_timings(path);
+    let known = timings.modes.entry(mode.to_string()).or_default();
+    known.extend(durations.iter().map(|(file, ms)| (file.clone(), *ms)));
+    known.retain(|file, _| file.exists());
+    let dir = path.parent().context("timings path has no parent")?;
+    std::fs::create_dir_all(dir)?;
+    let mut tmp = NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(io::BufWriter::new(tmp.as_file_mut()), &timings)?;
+    tmp.persist(path).map_err(|err| err.error)?;
+    Ok(())
+}
+
+/// Sorts `files` by include `fan_in`, widest first, so diagnostics in shared
+/// headers show up early. Among files with the same fan-in, those that took
+/// longest last time go first, so one large translation unit does not leave
+/// the other workers idle at the end. Files without a timing count as
+/// average; remaining ties keep their order.
+fn order_by_fan_in_and_cost(files: &mut [PathBuf], fan_in: &HashMap<PathBuf, usize>, durations: &HashMap<PathBuf, u64>) {
+    if fan_in.is_empty() && durations.is_empty() {
+        return;
+    }
+    let average = durations.values().sum::<u64>().checked_div(durations.len() as u64).unwrap_or(0);
+    files.sort_by_cached_key(|file| {
+        (
+            std::cmp::Reverse(fan_in.get(file).copied().unwrap_or(0)),
+            std::cmp::Reverse(durations.get(file).copied().unwrap_or(average)),
+        )
+    });
+}
+
+/// The checkpoint to resume, unless there is none or the settings, compile
+/// database or `.clang-tidy` changed since it was saved.
+fn load_run_checkpoint(
+    settings: &Settings,
+    root_dir: Option<&Path>,
+    compile_commands: &Path,
+    mode: &str,
+    stdout: &Arc<Output>,
+) -> Option<RunCheckpoint> {
+    let path = run_checkpoint_path(settings, root_dir, compile_commands, mode);
+    let Some(checkpoint) = read_run_checkpoint(&path) else {
+        let _ = send_log(stdout, "info", "No project run checkpoint to resume; analyzing all files".to_string());
+        return None;
+    };
+    if checkpoint.fingerprint != project_fingerprint(settings, root_dir, compile_commands, mode) {
+        let _ = send_log(
+            stdout,
+            "info",
+            format!("The checkpoint of run `{}` is outdated; analyzing all files", checkpoint.run_id),
+        );
+        return None;
+    }
+    Some(checkpoint)
+}
+
+fn read_run_checkpoint(path: &Path) -> Option<RunCheckpoint> {
+    std::fs::read(path).ok().and_then(|data| serde_json::from_slice(&data).ok())
+}
+
+/// Moves the files an earlier run left pending (stopped at its time budget,
+/// or killed) to the front, keeping their order, so they are not starved by
+/// runs that keep stopping early.
+fn order_checkpoint_files_first(files: &mut [PathBuf], checkpoint: &RunCheckpoint) {
+    let ranks: HashMap<&PathBuf, usize> = checkpoint.remaining.iter().enumerate().map(|(rank, file)| (file, rank)).collect();
+    files.sort_by_key(|file| ranks.get(file).copied().unwrap_or(usize::MAX));
+}
+
+/// Fingerprint of what the results of a project run depend on: settings, the
+/// compile database and the `.clang-tidy` files.
+fn project_fingerprint(settings: &Settings, root_dir: Option<&Path>, compile_commands: &Path, mode: &str) -> u64 {
+    settings_fingerprint(settings, root_dir, Some(compile_commands), mode)
+}
+
+const DEFAULT_BASELINE_FILE: &str = ".clang-tidy-baseline.json";
+const BASELINE_VERSION: u32 = 1;
+
+/// Diagnostics accepted as known, recorded by an `analyzeProject` run with
+/// `captureBaseline`. Files are keyed by their path relative to the workspace
+/// root with `/` separators so the baseline can be committed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Baseline {
+    version: u32,
+    files: BTreeMap<String, Vec<BaselineEntry>>,
+}
+
+/// A baselined diagnostic. `anchor` hashes the trimmed text of its line, so
+/// the entry still matches after edits elsewhere in the file move it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+struct BaselineEntry {
+    check: String,
+    message: String,
+    anchor: String,
+}
+
+struct LoadedBaseline {
+    mtime: u64,
+    baseline: Option<Arc<Baseline>>,
+}
+
+/// `baselinePath` resolved against the workspace root; the default file in
+/// the root when unset.
+fn baseline_path(settings: &Settings, root_dir: Option<&Path>) -> Option<PathBuf> {
+    let configured = settings.baselinePath.trim();
+    let path = Path::new(if configured.is_empty() { DEFAULT_BASELINE_FILE } else { configured });
+    if path.is_absolute() {
+        Some(path.to_path_buf())
+    } else {
+        root_dir.map(|root| root.join(path))
+    }
+}
+
+fn baseline_key(root_dir: Option<&Path>, file_path: &Path) -> String {
+    match root_dir.and_then(|root| file_path.strip_prefix(root).ok()) {
+        Some(relative) => relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/"),
+        None => file_path.to_string_lossy().to_string(),
+    }
+}
+
+/// Baseline entries for the diagnostics of a file with text `content`.
+fn baseline_entries(diags: &[RpcDiagnostic], content: &str) -> Vec<BaselineEntry> {
+    let lines: Vec<&str> = content.lines().collect();
+    diags
+        .iter()
+        .map(|diag| {
+            let line = lines.get(diag.range.start.line).copied().unwrap_or_default();
+            BaselineEntry {
+                check: diag.code.clone().unwrap_or_default(),
+                message: diag.message.clone(),
+                anchor: format!("{:016x}", xxh3_64(line.trim().as_bytes())),
+            }
+        })
+        .collect()
+}
+
+/// The baseline at `path`, read again when its mtime changes. A file that
+/// does not parse is reported once and ignored.
+fn load_baseline(path: &Path, stdout: &Arc<Output>) -> Option<Arc<Baseline>> {
+    static BASELINES: std::sync::OnceLock<Mutex<HashMap<PathBuf, LoadedBaseline>>> = std::sync::OnceLock::new();
+    let mtime = mtime_for_path(path)?;
+    let mut loaded = BASELINES.get_or_init(Default::default).lock().unwrap();
+    if let Some(known) = loaded.get(path).filter(|known| known.mtime == mtime) {
+        return known.baseline.clone();
+    }
+    let baseline = match std::fs::read(path).map_err(anyhow::Error::from).and_then(|data| Ok(serde_json::from_slice::<Baseline>(&data)?)) {
+        Ok(baseline) => Some(Arc::new(baseline)),
+        Err(err) => {
+            let _ = send_log(stdout, "warn", format!("Ignoring baseline {}: {err}", path.display()));
+            None
+        }
+    };
+    loaded.insert(path.to_path_buf(), LoadedBaseline { mtime, baseline: baseline.clone() });
+    baseline
+}
+
+/// Drops diagnostics less severe than `minimumSeverity`. Applied to results
+/// as they are published, so the cache keeps everything and changing the
+/// setting needs no re-analysis. An unknown severity filters nothing.
+fn drop_below_minimum_severity(diags: &mut Vec<RpcDiagnostic>, settings: &Settings) {
+    let rank = |severity: &str| SEVERITY_ORDER.iter().position(|s| *s == severity);
+    let Some(minimum) = rank(&settings.minimumSeverity) else {
+        return;
+    };
+    diags.retain(|d| rank(&d.severity).is_none_or(|severity| severity >= minimum));
+}
+
+/// Drops the diagnostics of `file_path` that the baseline lists, each entry
+/// matching at most one diagnostic. `content` is the analyzed text when it is
+/// not the file on disk. Returns how many were dropped.
+fn apply_baseline(
+    diags: &mut Vec<RpcDiagnostic>,
+    settings: &Settings,
+    root_dir: Option<&Path>,
+    file_path: &Path,
+    content: Option<&str>,
+    stdout: &Arc<Output>,
+) -> usize {
+    if settings.disableBaseline || diags.is_empty() {
+        return 0;
+    }
+    let Some(baseline) = baseline_path(settings, root_dir).and_then(|path| load_baseline(&path, stdout)) else {
+        return 0;
+    };
+    let Some(known) = baseline.files.get(&baseline_key(root_dir, file_path)).filter(|known| !known.is_empty()) else {
+        return 0;
+    };
+    let read;
+    let content = match content {
+        Some(content) => content,
+        None => {
+            read = std::fs::read_to_string(file_path).unwrap_or_default();
+            &read
+        }
+    };
+    let mut remaining: HashMap<&BaselineEntry, usize> = HashMap::new();
+    for entry in known {
+        *remaining.entry(entry).or_default() += 1;
+    }
+    let entries = baseline_entries(diags, content);
+    let mut entries = entries.iter();
+    let before = diags.len();
+    diags.retain(|_| {
+        let entry = entries.next().expect("one entry per diagnostic");
+        match remaining.get_mut(entry).filter(|count| **count > 0) {
+            Some(count) => {
+                *count -= 1;
+                false
+            }
+            None => true,
+        }
+    });
+    before - diags.len()
+}
+
+/// Replaces the baseline entries of the `captured` files, keeping those of
+/// files the run did not analyze while they still exist. Returns the number
+/// of files and entries in the baseline.
+fn write_baseline(path: &Path, root_dir: Option<&Path>, captured: HashMap<PathBuf, Vec<BaselineEntry>>) -> Result<(usize, usize)> {
+    let mut baseline: Baseline = match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).with_context(|| format!("Failed to parse {}", path.display()))?,
+        Err(_) => Baseline::default(),
+    };
+    baseline.version = BASELINE_VERSION;
+    baseline.files.retain(|key, _| {
+        let file = Path::new(key);
+        match root_dir.filter(|_| file.is_relative()) {
+            Some(root) => root.join(file).exists(),
+            None => file.exists(),
+        }
+    });
+    for (file_path, mut entries) in captured {
+        let key = baseline_key(root_dir, &file_path);
+        if entries.is_empty() {
+            baseline.files.remove(&key);
+        } else {
+            entries.sort();
+            baseline.files.insert(key, entries);
+        }
+    }
+    let dir = path.parent().context("baseline path has no parent")?;
+    std::fs::create_dir_all(dir)?;
+    let mut tmp = NamedTempFile::new_in(dir)?;
+    serde_json::to_writer_pretty(io::BufWriter::new(tmp.as_file_mut()), &baseline)?;
+    tmp.persist(path).map_err(|err| err.error)?;
+    Ok((baseline.files.len(), baseline.files.values().map(Vec::len).sum()))
+}
+
+/// Files an `analyzeProject` run analyzes, in scheduling order, and how many
+/// were left out on the way.
+struct ProjectSelection {
+    files: Vec<PathBuf>,
+    /// Files before filtering: the checkpoint's, `files`, or the database's.
+    selected: usize,
+    /// Dropped by `includeGlobs`/`excludeGlobs`.
+    excluded: usize,
+    /// Left out in `changed` mode because they match the base ref.
+    unchanged: usize,
+    /// Left out of an incremental run because their results are cached.
+    cached: Vec<PathBuf>,
+    graph: Option<Arc<IncludeGraph>>,
+}
+
+/// Resolves the files of an `analyzeProject` run from the checkpoint being
+/// resumed, `files` or the compile database, filters them by globs and, in
+/// `changed` mode, by the diff, drops cached ones when `prune_cached`, and
+/// orders the rest for scheduling.
+#[allow(clippy::too_many_arguments)]
+fn select_project_files(
+    params: &Value,
+    settings: &Settings,
+    root_dir: Option<&Path>,
+    compile_commands: &Path,
+    compile_index: Option<&CompileCommandsIndex>,
+    checkpoint: Option<RunCheckpoint>,
+    mode: &str,
+    cache: &Arc<Mutex<SpillStore<CacheEntry>>>,
+    prune_cached: bool,
+) -> Result<ProjectSelection> {
+    let resuming = checkpoint.is_some();
+    let mut files: Vec<PathBuf> = if let Some(checkpoint) = checkpoint {
+        checkpoint.remaining.into_iter().filter(|file| file.exists()).collect()
+    } else if let Some(list) = params.get("files").and_then(|v| v.as_array()) {
+        file_list_param(list)
+    } else if let Some(index) = compile_index {
+        index.files.clone()
+    } else {
+        load_project_files(compile_commands).context("Failed to load compile_commands.json")?
+    };
+
+    let selected = files.len();
+    files.retain(|file_path| glob_exclusion(settings, file_path, root_dir).is_none());
+    let excluded = selected - files.len();
+
+    // Without an explicit list, only files that differ from the base ref can
+    // have changed lines.
+    let before = files.len();
+    if mode == "changed" && params.get("files").is_none() {
+        let dir = root_dir.map(Path::to_path_buf).or_else(|| compile_commands.parent().map(Path::to_path_buf)).unwrap_or_default();
+        let changed = changed_files(diff_base_ref(settings), &dir).context("Failed to list changed files")?;
+        files.retain(|file_path| changed.contains(&std::fs::canonicalize(file_path).unwrap_or_else(|_| file_path.clone())));
+    }
+    let unchanged = before - files.len();
+
+    let settings_hash = project_fingerprint(settings, root_dir, compile_commands, mode);
+    let cache_dir = resolve_cache_dir(settings, root_dir, compile_commands.parent());
+    let mut cached = Vec::new();
+    if prune_cached {
+        let portable_root = portable_cache_root(settings, root_dir);
+        (cached, files) = files
+            .into_iter()
+            .partition(|file_path| is_cached(file_path, cache, settings_hash, cache_dir.as_deref(), portable_root));
+    }
+
+    let graph = compile_index.map(|index| Arc::new(build_include_graph(index, settings)));
+    let fan_in = graph.as_ref().map(|graph| include_fan_in_scores(&files, graph)).unwrap_or_default();
+    let timings_path = file_timings_path(settings, root_dir, compile_commands);
+    let durations = load_file_timings(&timings_path).modes.remove(mode).unwrap_or_default();
+    order_by_fan_in_and_cost(&mut files, &fan_in, &durations);
+    if !resuming && params.get("files").is_none() {
+        let previous = read_run_checkpoint(&run_checkpoint_path(settings, root_dir, compile_commands, mode))
+            .filter(|checkpoint| checkpoint.fingerprint == settings_hash);
+        if let Some(checkpoint) = previous {
+            order_checkpoint_files_first(&mut files, &checkpoint);
+        }
+    }
+    if let Some(list) = params.get("priorityFiles").and_then(|v| v.as_array()) {
+        order_by_priority_files(&mut files, &file_list_param(list));
+    }
+    Ok(ProjectSelection { files, selected, excluded, unchanged, cached, graph })
+}
+
+/// Drops files that another active run with the same mode still has pending
+/// (when `merge` is set) and registers the remainder for `run_id`. Returns how many files were merged.
+fn claim_project_files(
+    runs: &Arc<Mutex<HashMap<String, ProjectRunFiles>>>,
+    run_id: &str,
+    mode: &str,
+    files: &mut Vec<PathBuf>,
+    merge: bool,
+) -> usize {
+    let mut runs = runs.lock().unwrap();
+    let before = files.len();
+    if merge {
+        files.retain(|file| {
+            !runs
+                .iter()
+                .any(|(other_id, other)| other_id != run_id && other.mode == mode && other.pending.contains(file))
+        });
+    }
+    runs.insert(
+        run_id.to_string(),
+        ProjectRunFiles {
+            mode: mode.to_string(),
+            pending: files.iter().cloned().collect(),
+        },
+    );
+    before - files.len()
+}
+
+fn handle_notification(method: &str, params: Value, state: &AppState) -> Result<()> {
+    match method {
+        "configChanged" => {
+            if let Some(s) = params.get("settings") {
+                if let Ok(parsed) = serde_json::from_value::<Settings>(s.clone()) {
+                    apply_redaction_settings(state, &parsed);
+                    apply_memory_limits(state, &parsed);
+                    *state.settings.lock().unwrap() = parsed;
+                    *state.compile_commands.lock().unwrap() = None;
+                    *state.compile_index.lock().unwrap() = None;
+                    state.skipped_files.lock().unwrap().clear();
+                }
+            }
+            send_log(&state.stdout, "info", "Settings updated".to_string())?;
+        }
+        "didOpen" | "didChange" | "didSave" | "didClose" => handle_document_event(method, &params, state),
+        "window/workDoneProgress/cancel" => {
+            let token = params.get("token").map(|t| t.to_string()).unwrap_or_default();
+            let run_id = state.progress_tokens.lock().unwrap().get(&token).cloned();
+            if let Some(flag) = run_id.and_then(|run_id| state.cancel_map.lock().unwrap().get(&run_id).cloned()) {
+                flag.store(true, Ordering::Relaxed);
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Tracks the newest document version seen for each file. Called as messages
+/// arrive, before they wait in the queue, so that an analysis still running
+/// can tell that its input text has been replaced.
+fn note_document_version(versions: &Arc<Mutex<HashMap<PathBuf, i64>>>, method: &str, params: &Value) {
+    if !matches!(method, "analyzeFile" | "didOpen" | "didChange" | "didClose") {
+        return;
+    }
+    let Some(file_path) = params.get("fileUri").and_then(|v| v.as_str()).and_then(uri_to_path) else {
+        return;
+    };
+    let mut versions = versions.lock().unwrap();
+    if method == "didClose" {
+        versions.remove(&file_path);
+        return;
+    }
+    if let Some(version) = document_version(params) {
+        let newest = versions.entry(file_path).or_insert(version);
+        if method == "didOpen" || version > *newest {
+            *newest = version;
+        }
+    }
+}
+
+/// The editor's buffer version for a request, sent as `documentVersion` or
+/// the older `version`.
+fn document_version(params: &Value) -> Option<i64> {
+    params.get("documentVersion").or_else(|| params.get("version")).and_then(|v| v.as_i64())
+}
+
+fn is_stale_version(state: &AppState, file_path: &Path, version: Option<i64>) -> bool {
+    let Some(version) = version else {
+        return false;
+    };
+    state
+        .versions
+        .lock()
+        .unwrap()
+        .get(file_path)
+        .is_some_and(|newest| *newest > version)
+}
+
+/// Runs the analysis pipeline used by `analyzeFile` and returns diagnostics in
+/// the shape negotiated with the client. Callers record the result as latest
+/// unless it came back stale.
+#[allow(clippy::too_many_arguments)]
+fn analyze_document(
+    state: &AppState,
+    settings: &Settings,
+    file_path: &Path,
+    file_uri: &str,
+    mode: &str,
+    file_content: Option<String>,
+    version: Option<i64>,
+    line_range: Option<&[(u32, u32)]>,
+) -> Result<DocumentAnalysis> {
+    let root_dir = state.root_dir.lock().unwrap().clone();
+    if let Some(reason) = glob_exclusion(settings, file_path, root_dir.as_deref()) {
+        return Ok(DocumentAnalysis { diagnostics: Vec::new(), stale: false, skipped: Some(reason), flags_from: None, headers: Vec::new() });
+    }
+    let compile_commands = resolve_compile_commands_path(settings, root_dir.as_deref(), state);
+    if let Some(path) = compile_commands.as_deref() {
+        let mtime = mtime_for_path(path).unwrap_or(0);
+        let known = state.skipped_files.lock().unwrap().get(file_path).cloned();
+        if let Some(verdict) = known.filter(|v| v.compile_commands == path && v.mtime == mtime) {
+            return Ok(DocumentAnalysis {
+                diagnostics: Vec::new(),
+                stale: false,
+                skipped: Some(verdict.reason),
+                flags_from: None,
+                headers: Vec::new(),
+            });
+        }
+    }
+    let compile_index = match compile_commands.as_deref() {
+        Some(path) => match get_compile_index(path, state) {
+            Ok(index) => Some(index),
+            Err(err) => {
+                let _ = send_log(&state.stdout, "warn", format!("Failed to load compile_commands.json index: {err}"));
+                None
+            }
+        },
+        None => None,
+    };
+
+    let mut inferred = None;
+    if let Some(index) = compile_index.as_ref() {
+        if !file_in_index(file_path, index) {
+            inferred = infer_compile_entry(state, settings, index, file_path);
+            if inferred.is_none() {
+                let reason = skip_reason(file_path);
+                state.skipped_files.lock().unwrap().insert(
+                    file_path.to_path_buf(),
+                    SkipVerdict { compile_commands: index.path.clone(), mtime: index.mtime, reason: reason.clone() },
+                );
+                return Ok(DocumentAnalysis { diagnostics: Vec::new(), stale: false, skipped: Some(reason), flags_from: None, headers: Vec::new() });
+            }
+        }
+    }
+    let fix_format = state.client.lock().unwrap().fix_format;
+    let tool_version = clang_tidy_version(settings, state);
+    let permit = state.scheduler.acquire(Priority::Interactive);
+    // A newer version arrived while this one waited for a slot; running
+    // clang-tidy on the superseded text would only produce a stale result.
+    if is_stale_version(state, file_path, version) {
+        return Ok(DocumentAnalysis { diagnostics: Vec::new(), stale: true, skipped: None, flags_from: None, headers: Vec::new() });
+    }
+    // A buffer that matches the file on disk can be served from the cache.
+    let file_content = file_content.filter(|content| file_content_hash(file_path) != Some(xxh3_64(content.as_bytes())));
+    let log = |message| {
+        let _ = send_log(&state.stdout, "warn", message);
+    };
+    let mut diags = if let Some((_, entry)) = inferred.as_ref() {
+        let read;
+        let content = match file_content.as_deref() {
+            Some(content) => content,
+            None => {
+                read = std::fs::read_to_string(file_path).context("Failed to read file")?;
+                &read
+            }
+        };
+        let mut diags = analyze_file_with_content(
+            file_path,
+            content,
+            settings,
+            root_dir.as_deref(),
+            entry,
+            mode,
+            line_range,
+            &state.resources,
+            &log,
+        )?;
+        for diag in diags.iter_mut().filter(|d| d.file_uri.is_none()) {
+            diag.inferred_flags = Some(true);
+        }
+        diags
+    } else if let Some(content) = file_content.as_deref() {
+        compile_index
+            .as_deref()
+            .and_then(|index| find_compile_entry(index, file_path))
+            .context("compile command not found for file")
+            .and_then(|entry| {
+                analyze_file_with_content(
+                    file_path,
+                    content,
+                    settings,
+                    root_dir.as_deref(),
+                    &entry,
+                    mode,
+                    line_range,
+                    &state.resources,
+                    &log,
+                )
+            })
+            .unwrap_or_else(|_| {
+                analyze_translation_unit(
+                    file_path,
+                    settings,
+                    root_dir.as_deref(),
+                    compile_commands.as_deref(),
+                    mode,
+                    line_range,
+                    &state.cache,
+                    Some(&state.remote_cache),
+                    &state.resources,
+                    &log,
+                )
+                .unwrap_or_default()
+            })
+    } else {
+        analyze_translation_unit(
+            file_path,
+            settings,
+            root_dir.as_deref(),
+            compile_commands.as_deref(),
+            mode,
+            line_range,
+            &state.cache,
+            Some(&state.remote_cache),
+            &state.resources,
+            &log,
+        )?
+    };
+    drop(permit);
+    let found_in_headers: Vec<RpcDiagnostic>;
+    (found_in_headers, diags) = diags.into_iter().partition(|d| d.file_uri.is_some());
+    apply_baseline(&mut diags, settings, root_dir.as_deref(), file_path, file_content.as_deref(), &state.stdout);
+    drop_below_minimum_severity(&mut diags, settings);
+    shape_fixes(&mut diags, fix_format, file_uri, version);
+    attach_code_descriptions(&mut diags, tool_version.as_deref());
+    let stale = is_stale_version(state, file_path, version);
+    let mut headers = Vec::new();
+    // Findings outside a line range are unknown, not gone.
+    if !stale && line_range.is_none() {
+        let mut uris = record_header_findings(&state.header_findings, file_path, found_in_headers);
+        // An open header is analyzed on its own.
+        let documents = state.documents.lock().unwrap();
+        uris.retain(|uri| uri_to_path(uri).is_none_or(|path| !documents.contains_key(&path)));
+        drop(documents);
+        for (uri, mut diags) in merge_header_findings(&state.header_findings.lock().unwrap(), &uris) {
+            if let Some(header) = uri_to_path(&uri) {
+                apply_baseline(&mut diags, settings, root_dir.as_deref(), &header, None, &state.stdout);
+            }
+            drop_below_minimum_severity(&mut diags, settings);
+            attach_code_descriptions(&mut diags, tool_version.as_deref());
+            headers.push((uri, diags));
+        }
+    }
+    Ok(DocumentAnalysis {
+        diagnostics: diags,
+        stale,
+        skipped: None,
+        flags_from: inferred.map(|(donor, _)| donor),
+        headers,
+    })
+}
+
+/// Publishes the merged header findings of a `DocumentAnalysis` to the
+/// headers' own URIs and records them as the headers' latest diagnostics.
+fn publish_header_findings(state: &AppState, publisher: &Publisher, run_id: &str, headers: &[(String, Vec<RpcDiagnostic>)]) {
+    for (uri, diags) in headers {
+        if let Some(header) = uri_to_path(uri) {
+            record_latest(&state.latest, &header, diags);
+        }
+        let _ = publisher.publish(run_id, uri, None, diags);
+    }
+}
+
+/// A compile command for a file that has none (a header, a new file),
+/// borrowed from another file as `flagInference` selects: `"sibling"` takes
+/// a source file in the same directory, preferring one with the same stem;
+/// `"includer"` the first translation unit that includes the file; `"off"`
+/// borrows nothing. Empty or `"auto"` tries sibling, then includer. Returns
+/// the source file it came from along with the command.
+fn infer_compile_entry(
+    state: &AppState,
+    settings: &Settings,
+    index: &CompileCommandsIndex,
+    file_path: &Path,
+) -> Option<(PathBuf, CompileCommandEntry)> {
+    let normalized = normalize_path(file_path);
+    let file_path = std::fs::canonicalize(file_path).unwrap_or_else(|_| file_path.to_path_buf());
+    let sibling = || {
+        let siblings: Vec<&PathBuf> = index
+            .files
+            .iter()
+            .filter(|f| f.parent() == file_path.parent() || f.parent() == normalized.parent())
+            .collect();
+        siblings
+            .iter()
+            .find(|f| f.file_stem() == file_path.file_stem())
+            .or(siblings.first())
+            .map(|f| (*f).clone())
+    };
+    let includer = || {
+        let graph = current_include_graph(state).ok()?;
+        walk_include_edges(&graph.includers, &file_path, true)
+            .into_iter()
+            .find(|f| index.key_for(f).is_some())
+    };
+    let donor = match settings.flagInference.trim() {
+        "off" => None,
+        "sibling" => sibling(),
+        "includer" => includer(),
+        _ => sibling().or_else(includer),
+    }?;
+    let donor = index.key_for(&donor)?;
+    let entry = index.commands.get(&donor)?;
+    let mut args = resolve_arguments(entry, settings)?;
+    let target = file_path.to_string_lossy().to_string();
+    if !replace_file_arg(&mut args, &entry.file, &donor.to_string_lossy(), &target) {
+        return None;
+    }
+    let cl = is_cl_driver(&args);
+    if is_header(&file_path) {
+        let language = header_language(&donor);
+        if cl {
+            args.insert(1, if language == "c-header" { "/TC" } else { "/TP" }.to_string());
+        } else if let Some(at) = args.iter().position(|arg| *arg == target) {
+            args.splice(at..at, ["-x".to_string(), language.to_string()]);
+        }
+    }
+    // The file is analyzed from a copy; includes next to it must still resolve.
+    if let Some(dir) = file_path.parent() {
+        args.insert(1, format!("{}{}", if cl { "/I" } else { "-iquote" }, dir.display()));
+    }
+    Some((
+        donor,
+        CompileCommandEntry {
+            file: target,
+            directory: entry.directory.clone(),
+            command: None,
+            arguments: Some(args),
+            output: None,
+        },
+    ))
+}
+
+fn is_header(file_path: &Path) -> bool {
+    file_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| matches!(e.to_ascii_lowercase().as_str(), "h" | "hh" | "hpp" | "hxx" | "h++" | "inl" | "ipp" | "tcc"))
+}
+
+/// Header language matching a source file's, as named by `-x`.
+fn header_language(source: &Path) -> &'static str {
+    match source.extension().and_then(|e| e.to_str()).unwrap_or("") {
+        "c" => "c-header",
+        "m" => "objective-c-header",
+        "mm" => "objective-c++-header",
+        _ => "c++-header",
+    }
+}
+
+/// Why `includeGlobs`/`excludeGlobs` keep a file out of analysis, if they do.
+fn glob_exclusion(settings: &Settings, file_path: &Path, root_dir: Option<&Path>) -> Option<String> {
+    if path_matches_globs(&settings.excludeGlobs, file_path, root_dir) {
+        return Some("Excluded by excludeGlobs".to_string());
+    }
+    if !settings.includeGlobs.is_empty() && !path_matches_globs(&settings.includeGlobs, file_path, root_dir) {
+        return Some("Not matched by includeGlobs".to_string());
+    }
+    None
+}
+
+/// Why a file without a compile_commands.json entry is not analyzed.
+fn skip_reason(file_path: &Path) -> String {
+    if is_header(file_path) {
+        "Header without its own compile_commands.json entry".to_string()
+    } else {
+        "Not in compile_commands.json".to_string()
+    }
+}
+
+/// Mode configured for a document lifecycle trigger, or `None` when the
+/// trigger is off. Empty settings fall back to quick-on-open, full-on-save.
+fn trigger_mode(settings: &Settings, event: &str) -> Option<String> {
+    let (configured, default) = match event {
+        "didOpen" => (&settings.triggerOnOpen, "quick"),
+        "didSave" => (&settings.triggerOnSave, "full"),
+        "didChange" => (&settings.triggerOnType, "off"),
+        _ => return None,
+    };
+    let mode = if configured.trim().is_empty() { default } else { configured.trim() };
+    match mode {
+        "quick" | "full" | "changed" => Some(mode.to_string()),
+        _ => None,
+    }
+}
+
+fn handle_document_event(method: &str, params: &Value, state: &AppState) {
+    let Some(file_uri) = params.get("fileUri").and_then(|v| v.as_str()).map(|s| s.to_string()) else {
+        return;
+    };
+    let Some(file_path) = uri_to_path(&file_uri) else {
+        return;
+    };
+    let content = params.get("fileContent").and_then(|v| v.as_str()).map(|s| s.to_string());
+    let version = document_version(params);
+
+    let generation = {
+        let mut docs = state.documents.lock().unwrap();
+        if method == "didClose" {
+            docs.remove(&file_path);
+            state.published.lock().unwrap().remove(&file_uri);
+            state.fix_sessions.lock().unwrap().sessions.retain(|_, session| session.file_path != file_path);
+            return;
+        }
+        let doc = docs.entry(file_path.clone()).or_default();
+        doc.generation += 1;
+        if version.is_some() {
+            doc.version = version;
+        }
+        if method == "didSave" {
+            doc.content = None;
+        } else if content.is_some() {
+            doc.content = content;
+        }
+        doc.generation
+    };
+
+    let settings = state.settings.lock().unwrap().clone();
+    let Some(mode) = trigger_mode(&settings, method) else {
+        return;
+    };
+    let delay = if method == "didChange" { settings.triggerDebounceMs } else { 0 };
+    let state = state.clone();
+    let trigger = method.to_string();
+    let active = state.active_runs.enter();
+    thread::spawn(move || {
+        let _active = active;
+        if delay > 0 {
+            thread::sleep(Duration::from_millis(delay));
+        }
+        if state.shutting_down.load(Ordering::Relaxed) {
+            return;
+        }
+        let (content, version) = {
+            let docs = state.documents.lock().unwrap();
+            match docs.get(&file_path) {
+                Some(doc) if doc.generation == generation => (doc.content.clone(), doc.version),
+                _ => return,
+            }
+        };
+        let settings = state.settings.lock().unwrap().clone();
+        match analyze_document(&state, &settings, &file_path, &file_uri, &mode, content, version, None) {
+            Ok(analysis) => {
+                // The buffer changed while clang-tidy ran; the newer event
+                // publishes its own results.
+                let current = state.documents.lock().unwrap().get(&file_path).map(|doc| doc.generation) == Some(generation);
+                if analysis.stale || !current {
+                    return;
+                }
+                record_latest(&state.latest, &file_path, &analysis.diagnostics);
+                let publisher = Publisher::from_state(&state);
+                let run_id = format!("{trigger}:{generation}");
+                let _ = publisher.publish(&run_id, &file_uri, version, &analysis.diagnostics);
+                publish_header_findings(&state, &publisher, &run_id, &analysis.headers);
+            }
+            Err(err) => {
+                let _ = send_log(&state.stdout, "error", format!("clang-tidy failed for {}: {err}", file_path.display()));
+            }
+        }
+    });
+}
+
+/// Analyzes a file on disk, through the memory and disk caches. With
+/// `line_range`, only findings on those lines (1-based, inclusive) are
+/// reported; a cached result is narrowed down, and a fresh one is not cached.
+#[allow(clippy::too_many_arguments)]
+fn analyze_file(
+    file_path: &Path,
+    settings: &Settings,
+    root_dir: Option<&Path>,
+    compile_commands: Option<&Path>,
+    mode: &str,
+    line_range: Option<&[(u32, u32)]>,
+    cache: &Arc<Mutex<SpillStore<CacheEntry>>>,
+    remote: Option<&Arc<RemoteCache>>,
+    resources: &Arc<Mutex<ResourceStats>>,
+    log: &dyn Fn(String),
+) -> Result<Vec<RpcDiagnostic>> {
+    let mut diags =
+        analyze_translation_unit(file_path, settings, root_dir, compile_commands, mode, line_range, cache, remote, resources, log)?;
+    diags.retain(|d| d.file_uri.is_none());
+    Ok(diags)
+}
+
+/// `analyze_file`, also returning the findings clang-tidy reported in
+/// project headers, each with its `file_uri`. They are cached with the file.
+#[allow(clippy::too_many_arguments)]
+fn analyze_translation_unit(
+    file_path: &Path,
+    settings: &Settings,
+    root_dir: Option<&Path>,
+    compile_commands: Option<&Path>,
+    mode: &str,
+    line_range: Option<&[(u32, u32)]>,
+    cache: &Arc<Mutex<SpillStore<CacheEntry>>>,
+    remote: Option<&Arc<RemoteCache>>,
+    resources: &Arc<Mutex<ResourceStats>>,
+    log: &dyn Fn(String),
+) -> Result<Vec<RpcDiagnostic>> {
+    let clang_tidy = clang_tidy_binary(settings);
+
+    let compile_dir = compile_commands.and_then(|p| p.parent());
+    let base_dir = compile_dir.or(root_dir);
+
+    let file_sig = file_signature(file_path);
+    let mut settings_hash = settings_fingerprint(settings, root_dir, compile_commands, mode);
+    let cache_dir = resolve_cache_dir(settings, root_dir, compile_dir);
+
+    let changed_lines = if mode == "changed" { changed_line_ranges(settings, file_path, None)? } else { None };
+    if let Some(ranges) = changed_lines.as_deref() {
+        if ranges.is_empty() {
+            return Ok(Vec::new());
+        }
+        // The file can be unchanged on disk while the base ref moves.
+        let mut hasher = DefaultHasher::new();
+        settings_hash.hash(&mut hasher);
+        ranges.hash(&mut hasher);
+        settings_hash = hasher.finish();
+    }
+    let line_filter = intersect_line_ranges(changed_lines, line_range);
+    if line_filter.as_deref().is_some_and(|ranges| ranges.is_empty()) {
+        return Ok(Vec::new());
+    }
+    let narrow = |mut diags: Vec<RpcDiagnostic>| {
+        if let Some(ranges) = line_range {
+            diags.retain(|d| d.file_uri.is_none() && in_line_ranges(d.range.start.line, ranges));
+        }
+        diags
+    };
+
+    let cached = cache.lock().unwrap().get(file_path).filter(|entry| entry.settings_hash == settings_hash);
+    if let Some(mut entry) = cached {
+        if file_sig == Some((entry.mtime, entry.size)) {
+            return Ok(narrow(entry.diagnostics));
+        }
+        if file_content_hash(file_path) == Some(entry.content_hash) {
+            if let Some((mtime, size)) = file_sig {
+                // Same content under a new mtime: remember it so the pre-check hits next time.
+                (entry.mtime, entry.size) = (mtime, size);
+                let diagnostics = entry.diagnostics.clone();
+                cache.lock().unwrap().insert(file_path.to_path_buf(), entry);
+                return Ok(narrow(diagnostics));
+            }
+        }
+    }
+    let content_hash = file_content_hash(file_path);
+    if let (Some((mtime, size)), Some(content_hash), Some(dir)) = (file_sig, content_hash, cache_dir.as_deref()) {
+        if let Some(disk) = read_disk_cache(dir, portable_cache_root(settings, root_dir), file_path, content_hash, settings_hash) {
+            let diags = disk.diagnostics;
+            cache.lock().unwrap().insert(
+                file_path.to_path_buf(),
+                CacheEntry {
+                    mtime,
+                    size,
+                    content_hash,
+                    settings_hash,
+                    diagnostics: diags.clone(),
+                    fix_files: disk.fixFiles,
+                },
+            );
+            return Ok(narrow(diags));
+        }
+    }
+    // Results narrowed to changed lines depend on the local diff.
+    let remote = remote.filter(|r| mode != "changed" && line_filter.is_none() && r.enabled(settings));
+    let remote_key = match (remote, root_dir, content_hash) {
+        (Some(r), Some(root), Some(content_hash)) => {
+            r.key(settings, root, compile_commands, compile_dir, file_path, content_hash, mode).map(|key| (r, root, key))
+        }
+        _ => None,
+    };
+    if let (Some((r, root, key)), Some((mtime, size)), Some(content_hash)) = (&remote_key, file_sig, content_hash) {
+        if let Some(diags) = r.fetch(settings, key, root) {
+            let entry = CacheEntry {
+                mtime,
+                size,
+                content_hash,
+                settings_hash,
+                diagnostics: diags.clone(),
+                fix_files: Vec::new(),
+            };
+            if let Some(dir) = cache_dir.as_deref() {
+                let _ = write_disk_cache(dir, portable_cache_root(settings, root_dir), file_path, &entry);
+            }
+            cache.lock().unwrap().insert(file_path.to_path_buf(), entry);
+            return Ok(diags);
+        }
+    }
+
+    let mut headers = Vec::new();
+    let mut diags = if analyzer_enabled(settings, "clang-tidy") {
+        let temp = new_fixes_file().context("Failed to create temp file for fixes")?;
+
+        let mut cmd = tidy_command(&clang_tidy, file_path, settings, root_dir, compile_dir, temp.path(), mode);
+        if let Some(ranges) = line_filter.as_deref() {
+            add_line_filter(&mut cmd, file_path, ranges);
+        }
+        let profile_dir = add_profile_args(&mut cmd, settings)?;
+        let output = run_measured(&mut cmd, file_timeout_ms(settings, mode), resources, file_path, mode)
+            .context("Failed to run clang-tidy")?;
+        if let Some(crash) = crash_description(&output.status) {
+            // Whatever it printed before dying is incomplete; do not cache it.
+            return Err(anyhow::anyhow!("clang-tidy {crash}"));
+        }
+        if let Some(dir) = profile_dir {
+            record_check_profile(resources, file_path, dir.path());
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let combined = format!("{}\n{}", stdout, stderr);
+
+        let file_text = std::fs::read_to_string(file_path).unwrap_or_default();
+        let mut diags = parse_diagnostics(&combined, base_dir, file_path);
+        byte_columns_to_utf16(&mut diags, file_path, &file_text);
+        if !settings.disableInstantiationGrouping {
+            attach_instantiation_notes(&mut diags);
+        }
+        if mode == "deep" {
+            attach_path_notes(&mut diags);
+        }
+        if !settings.keepCheckAliases {
+            normalize_check_codes(&mut diags);
+        }
+        let fix_diags = parse_fixes(temp.path(), base_dir, file_path);
+
+        if let Ok(mut fixes) = fix_diags {
+            if !settings.keepCheckAliases {
+                normalize_check_codes(&mut fixes);
+            }
+            merge_diagnostics(&mut diags, fixes);
+        }
+        if !settings.disableInstantiationGrouping {
+            group_instantiation_duplicates(&mut diags);
+        }
+        widen_diagnostic_ranges(&mut diags, file_path, &file_text);
+        // Only the changed lines of the file itself matter in a narrowed run.
+        if line_filter.is_none() {
+            headers = header_diagnostics(&diags, file_path, root_dir, settings);
+        }
+        diags
+    } else {
+        Vec::new()
+    };
+    // A cppcheck failure keeps the clang-tidy findings but leaves the result
+    // uncached, so the next analysis tries cppcheck again.
+    let mut complete = true;
+    if analyzer_enabled(settings, "cppcheck") {
+        let found = compile_commands
+            .context("cppcheck requires compile_commands.json")
+            .and_then(|compile_commands| run_cppcheck(settings, compile_commands, file_path, file_path, resources, mode));
+        match found {
+            Ok(found) => diags.extend(found),
+            Err(err) => {
+                log(format!("cppcheck failed for {}: {err:#}", file_path.display()));
+                complete = false;
+            }
+        }
+    }
+    let ctx = AdapterContext {
+        source: file_path,
+        file_path,
+        root_dir,
+        compile_commands,
+        work_dir: compile_dir,
+    };
+    diags.extend(run_external_analyzers(settings, &ctx, resources, mode)?);
+
+    diags.retain(|d| paths_match(&d.file, file_path));
+    if let Some(ranges) = line_filter.as_deref() {
+        diags.retain(|d| in_line_ranges(d.range.start.line, ranges));
+    }
+    drop_ignored_checks(&mut diags, settings);
+    apply_severity_overrides(&mut diags, settings);
+    drop_unfixable(&mut diags, settings);
+    apply_diagnostic_caps(&mut diags, settings.maxDiagnosticsPerFile, settings.maxFixesPerFile);
+    if settings.suppressionFixes {
+        add_suppression_fixes(&mut diags, &std::fs::read_to_string(file_path).unwrap_or_default());
+    }
+    let mut result: Vec<RpcDiagnostic> = diags.into_iter().map(to_rpc_diagnostic).collect();
+    result.extend(headers);
+
+    if let (Some((mtime, size)), Some(content_hash)) = (file_sig.filter(|_| line_range.is_none() && complete), content_hash) {
+        let entry = CacheEntry {
+            mtime,
+            size,
+            content_hash,
+            settings_hash,
+            diagnostics: result.clone(),
+            fix_files: fix_file_hashes(file_path, &result),
+        };
+        if let Some(dir) = cache_dir.as_deref() {
+            let _ = write_disk_cache(dir, portable_cache_root(settings, root_dir), file_path, &entry);
+        }
+        if let Some((r, root, key)) = remote_key.filter(|_| settings.remoteCacheWrite) {
+            r.queue_store(RemoteUpload { settings: settings.clone(), key, root: root.to_path_buf(), diagnostics: result.clone() });
+        }
+        cache.lock().unwrap().insert(file_path.to_path_buf(), entry);
+    }
+
+    Ok(result)
+}
+
+/// clang-tidy invocation for a file on disk, as `analyze_file` runs it.
+fn tidy_command(
+    clang_tidy: &str,
+    file_path: &Path,
+    settings: &Settings,
+    root_dir: Option<&Path>,
+    compile_dir: Option<&Path>,
+    fixes_path: &Path,
+    mode: &str,
+) -> Command {
+    let mut cmd = Command::new(clang_tidy);
+    cmd.arg(file_path);
+    if let Some(dir) = compile_dir {
+        cmd.arg("-p").arg(sanitized_compile_db(dir, settings).unwrap_or_else(|| dir.to_path_buf()));
+        cmd.current_dir(dir);
+    } else if let Some(dir) = root_dir {
+        cmd.current_dir(dir);
+    }
+    cmd.arg("-export-fixes").arg(fixes_path);
+    cmd.arg("--quiet");
+    cmd.arg("-extra-arg=-fno-color-diagnostics");
+    if !settings.disableDefaultFlagDrops {
+        cmd.arg(format!("-extra-arg={UNKNOWN_WARNING_FLAG}"));
+    }
+    let check_options = effective_check_options(settings, file_path, root_dir);
+    if !check_options.is_empty() {
+        cmd.arg(format!("--config={}", inline_check_config(&check_options)));
+    }
+    add_check_args(&mut cmd, settings, mode);
+    for arg in &settings.extraArgs {
+        cmd.arg(arg);
+    }
+    cmd
+}
+
+#[allow(clippy::too_many_arguments)]
+fn analyze_file_with_content(
+    file_path: &Path,
+    content: &str,
+    settings: &Settings,
+    root_dir: Option<&Path>,
+    entry: &CompileCommandEntry,
+    mode: &str,
+    line_range: Option<&[(u32, u32)]>,
+    resources: &Arc<Mutex<ResourceStats>>,
+    log: &dyn Fn(String),
+) -> Result<Vec<RpcDiagnostic>> {
+    let changed_lines = if mode == "changed" { changed_line_ranges(settings, file_path, Some(content))? } else { None };
+    let line_filter = intersect_line_ranges(changed_lines, line_range);
+    if line_filter.as_deref().is_some_and(|ranges| ranges.is_empty()) {
+        return Ok(Vec::new());
+    }
+
+    let temp_dir = tempfile::Builder::new()
+        .prefix(&temp_artifact_prefix())
+        .tempdir()
+        .context("Failed to create temp dir for unsaved buffer")?;
+    let filename = file_path.file_name().unwrap_or_else(|| std::ffi::OsStr::new("file.cpp"));
+    let temp_file = temp_dir.path().join(filename);
+    std::fs::write(&temp_file, content).context("Failed to write temp file content")?;
+
+    let mut args = resolve_arguments(entry, settings).context("compile command missing arguments")?;
+    let original_path = file_path.to_string_lossy().to_string();
+    let temp_path = temp_file.to_string_lossy().to_string();
+    let replaced = replace_file_arg(&mut args, &entry.file, &original_path, &temp_path);
+    if !replaced {
+        return Err(anyhow::anyhow!("compile command does not reference file path"));
+    }
+    // clang only infers cl mode from a compiler name it can split off the
+    // path, which a Windows path on another host is not.
+    if is_cl_driver(&args) && !args.iter().any(|arg| arg.starts_with("--driver-mode=")) {
+        args.insert(1, "--driver-mode=cl".to_string());
+    }
+    let (args, _) = sanitize_compile_args(&args, settings, Path::new(&entry.directory));
+
+    let compile_entry = serde_json::json!({
+        "directory": entry.directory,
+        "file": temp_path,
+        "arguments": args,
+    });
+    let compile_path = temp_dir.path().join("compile_commands.json");
+    std::fs::write(&compile_path, serde_json::to_vec(&vec![compile_entry])?)
+        .context("Failed to write temp compile_commands.json")?;
+
+    let clang_tidy = clang_tidy_binary(settings);
+
+    let base_dir = PathBuf::from(&entry.directory);
+    let base_dir_ref = if base_dir.exists() { Some(base_dir.as_path()) } else { root_dir };
+    let mut config_path = find_clang_tidy_config(file_path, root_dir, base_dir_ref);
+    let check_options = effective_check_options(settings, file_path, root_dir);
+    if !check_options.is_empty() {
+        config_path = Some(write_merged_config(config_path.as_deref(), &check_options, temp_dir.path())?);
+    }
+
+    let mut headers = Vec::new();
+    let mut diags = if analyzer_enabled(settings, "clang-tidy") {
+        let temp = new_fixes_file().context("Failed to create temp file for fixes")?;
+        let tidy_cmd = |pch: Option<&Path>| {
+            let mut cmd = Command::new(&clang_tidy);
+            cmd.arg(&temp_file);
+            cmd.arg("-p").arg(temp_dir.path());
+            if base_dir.exists() {
+                cmd.current_dir(&base_dir);
+            } else if let Some(dir) = root_dir {
+                cmd.current_dir(dir);
+            }
+            cmd.arg("-export-fixes").arg(temp.path());
+            cmd.arg("--quiet");
+            cmd.arg("-extra-arg=-fno-color-diagnostics");
+            if !settings.disableDefaultFlagDrops {
+                cmd.arg(format!("-extra-arg={UNKNOWN_WARNING_FLAG}"));
+            }
+            if let Some(config) = config_path.as_ref() {
+                cmd.arg(format!("--config-file={}", config.display()));
+            }
+            add_check_args(&mut cmd, settings, mode);
+            for arg in &settings.extraArgs {
+                cmd.arg(arg);
+            }
+            if let Some(ranges) = line_filter.as_deref() {
+                add_line_filter(&mut cmd, &temp_file, ranges);
+            }
+            if let Some(pch) = pch {
+                cmd.arg(format!("-extra-arg=-include-pch={}", pch.display()));
+            }
+            cmd
+        };
+        let preamble = if settings.precompiledPreamble {
+            preamble_pch(settings, &entry.directory, &args, &temp_path, file_path, content)
+        } else {
+            None
+        };
+
+        let mut cmd = tidy_cmd(preamble.as_deref());
+        let mut profile_dir = add_profile_args(&mut cmd, settings)?;
+        let mut output = run_measured(&mut cmd, file_timeout_ms(settings, mode), resources, file_path, mode)
+            .context("Failed to run clang-tidy")?;
+        if let Some(pch) = preamble.as_deref().filter(|_| preamble_rejected(&output)) {
+            discard_preamble(pch);
+            cmd = tidy_cmd(None);
+            profile_dir = add_profile_args(&mut cmd, settings)?;
+            output = run_measured(&mut cmd, file_timeout_ms(settings, mode), resources, file_path, mode)
+                .context("Failed to run clang-tidy")?;
+        }
+        if let Some(dir) = profile_dir {
+            record_check_profile(resources, file_path, dir.path());
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let combined = format!("{}\n{}", stdout, stderr);
+
+        let mut diags = parse_diagnostics(&combined, base_dir_ref, &temp_file);
+        byte_columns_to_utf16(&mut diags, &temp_file, content);
+        if !settings.disableInstantiationGrouping {
+            attach_instantiation_notes(&mut diags);
+        }
+        if mode == "deep" {
+            attach_path_notes(&mut diags);
+        }
+        for diag in diags.iter_mut().filter(|d| paths_match(&d.file, &temp_file)) {
+            diag.file = file_path.to_path_buf();
+            for note in diag.related.iter_mut() {
+                if paths_match(&note.file, &temp_file) {
+                    note.file = file_path.to_path_buf();
+                }
+            }
+        }
+        if !settings.keepCheckAliases {
+            normalize_check_codes(&mut diags);
+        }
+        let fix_diags = parse_fixes(temp.path(), base_dir_ref, &temp_file);
+
+        if let Ok(mut fixes) = fix_diags {
+            for fix in fixes.iter_mut() {
+                fix.file = file_path.to_path_buf();
+            }
+            if !settings.keepCheckAliases {
+                normalize_check_codes(&mut fixes);
+            }
+            merge_diagnostics(&mut diags, fixes);
+        }
+        if !settings.disableInstantiationGrouping {
+            group_instantiation_duplicates(&mut diags);
+        }
+        widen_diagnostic_ranges(&mut diags, file_path, content);
+        if line_filter.is_none() {
+            headers = header_diagnostics(&diags, file_path, root_dir, settings);
+        }
+        diags
+    } else {
+        Vec::new()
+    };
+    if analyzer_enabled(settings, "cppcheck") {
+        match run_cppcheck(settings, &compile_path, &temp_file, file_path, resources, mode) {
+            Ok(found) => diags.extend(found),
+            Err(err) => log(format!("cppcheck failed for {}: {err:#}", file_path.display())),
+        }
+    }
+    let ctx = AdapterContext {
+        source: &temp_file,
+        file_path,
+        root_dir,
+        compile_commands: Some(&compile_path),
+        work_dir: base_dir.exists().then_some(base_dir.as_path()),
+    };
+    diags.extend(run_external_analyzers(settings, &ctx, resources, mode)?);
+
+    diags.retain(|d| paths_match(&d.file, file_path));
+    if let Some(ranges) = line_filter.as_deref() {
+        diags.retain(|d| in_line_ranges(d.range.start.line, ranges));
+    }
+    drop_ignored_checks(&mut diags, settings);
+    apply_severity_overrides(&mut diags, settings);
+    drop_unfixable(&mut diags, settings);
+    apply_diagnostic_caps(&mut diags, settings.maxDiagnosticsPerFile, settings.maxFixesPerFile);
+    if settings.suppressionFixes {
+        add_suppression_fixes(&mut diags, content);
+    }
+    let mut result: Vec<RpcDiagnostic> = diags.into_iter().map(to_rpc_diagnostic).collect();
+    result.extend(headers);
+    Ok(result)
+}
+
+fn parse_diagnostics(output: &str, root_dir: Option<&Path>, default_file: &Path) -> Vec<InternalDiagnostic> {
+    let re = Regex::new(r"^(?P<file>.+?):(?P<line>\d+):(?P<col>\d+): (?P<severity>warning|error|note): (?P<message>.*?)(?: \[(?P<code>.+?)\])?$").unwrap();
+    let mut diags = Vec::new();
+
+    for line in output.lines() {
+        if let Some(caps) = re.captures(line) {
+            let file_raw = caps.name("file").map(|m| m.as_str()).unwrap_or("");
+            let file_path = resolve_path(file_raw, root_dir).unwrap_or_else(|| default_file.to_path_buf());
+            let line_num: usize = caps.name("line").and_then(|m| m.as_str().parse().ok()).unwrap_or(1);
+            let col_num: usize = caps.name("col").and_then(|m| m.as_str().parse().ok()).unwrap_or(1);
+            let severity_raw = caps.name("severity").map(|m| m.as_str()).unwrap_or("warning");
+            let severity = normalize_severity(severity_raw);
+            let message = caps.name("message").map(|m| m.as_str()).unwrap_or("").to_string();
+            let code = caps.name("code").map(|m| m.as_str().to_string());
+
+            let range = range_from_line_col(line_num, col_num);
+            diags.push(InternalDiagnostic {
+                file: file_path,
+                range,
+                severity,
+                code,
+                message,
+                fixes: Vec::new(),
+                related: Vec::new(),
+            });
+        }
+    }
+
+    diags
+}
+
+fn parse_fixes(fixes_path: &Path, root_dir: Option<&Path>, target_file: &Path) -> Result<Vec<InternalDiagnostic>> {
+    if !fixes_path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = std::fs::read_to_string(fixes_path).unwrap_or_default();
+    if content.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let fixes: FixesFile = serde_yaml::from_str(&content).context("Failed to parse fixes YAML")?;
+    let file_text = std::fs::read_to_string(target_file).unwrap_or_default();
+    let line_starts = build_line_starts(&file_text);
+    let mut other_texts: HashMap<PathBuf, (String, Vec<usize>)> = HashMap::new();
+
+    let mut diags = Vec::new();
+
+    for diag in fixes.diagnostics {
+        let (message, file_path, file_offset, replacements) = diag.parts();
+
+        let diag_path = resolve_path(&file_path, root_dir).unwrap_or_else(|| target_file.to_path_buf());
+        if !paths_match(&diag_path, target_file) {
+            continue;
+        }
+
+        let mut edits = Vec::new();
+        let mut other_files: Vec<(PathBuf, Vec<TextEdit>)> = Vec::new();
+        for rep in replacements {
+            let rep_path = resolve_path(&rep.file_path, root_dir).unwrap_or_else(|| target_file.to_path_buf());
+            if paths_match(&rep_path, target_file) {
+                let range = offset_range(&file_text, &line_starts, rep.offset, rep.length);
+                edits.push(TextEdit {
+                    range,
+                    new_text: rep.replacement_text,
+                });
+                continue;
+            }
+            let (text, starts) = other_texts.entry(rep_path.clone()).or_insert_with(|| {
+                let text = std::fs::read_to_string(&rep_path).unwrap_or_default();
+                let starts = build_line_starts(&text);
+                (text, starts)
+            });
+            let edit = TextEdit {
+                range: offset_range(text, starts, rep.offset, rep.length),
+                new_text: rep.replacement_text,
+            };
+            match other_files.iter_mut().find(|(path, _)| *path == rep_path) {
+                Some((_, file_edits)) => file_edits.push(edit),
+                None => other_files.push((rep_path, vec![edit])),
+            }
+        }
+
+        if edits.is_empty() && other_files.is_empty() {
+            continue;
+        }
+        let document_changes: Vec<DocumentChange> = other_files
+            .into_iter()
+            .filter_map(|(path, file_edits)| {
+                let uri = Url::from_file_path(&path).ok()?;
+                Some(DocumentChange::Edit(TextDocumentEdit {
+                    text_document: VersionedTextDocumentIdentifier {
+                        uri: uri.to_string(),
+                        version: None,
+                    },
+                    edits: file_edits,
+                }))
+            })
+            .collect();
+
+        let range = offset_range(&file_text, &line_starts, file_offset, 1);
+        let (kind, safety) = classify_fix(diag.diagnostic_name.as_deref().map(canonical_check).as_deref());
+        let fix = Fix {
+            title: match &diag.diagnostic_name {
+                Some(name) if !name.is_empty() => format!("Apply clang-tidy fix ({name})"),
+                _ => "Apply clang-tidy fix".to_string(),
+            },
+            edits,
+            edit: (!document_changes.is_empty()).then_some(WorkspaceEdit { document_changes }),
+            kind: Some(kind.to_string()),
+            safety: Some(safety),
+            conflicts_with: Vec::new(),
+        };
+
+        diags.push(InternalDiagnostic {
+            file: diag_path,
+            range,
+            severity: "warning".to_string(),
+            code: diag.diagnostic_name.clone(),
+            message,
+            fixes: vec![fix],
+            related: Vec::new(),
+        });
+    }
+
+    Ok(diags)
+}
+
+/// Moves "in instantiation of ..." notes into the related locations of the
+/// diagnostic they follow instead of reporting them on their own.
+fn attach_instantiation_notes(diags: &mut Vec<InternalDiagnostic>) {
+    let mut result: Vec<InternalDiagnostic> = Vec::with_capacity(diags.len());
+    for diag in diags.drain(..) {
+        let is_instantiation_note = diag.severity == "info" && diag.code.is_none() && diag.message.starts_with("in instantiation of");
+        match result.last_mut() {
+            Some(primary) if is_instantiation_note => primary.related.push(RelatedNote {
+                file: diag.file,
+                range: diag.range,
+                message: diag.message,
+            }),
+            _ => result.push(diag),
+        }
+    }
+    *diags = result;
+}
+
+/// Moves the notes clang-tidy prints after a `clang-analyzer-*` finding (the
+/// path: "Assuming 'p' is null", "Calling 'f'", ...) into its related
+/// locations, in path order, instead of reporting each step on its own.
+fn attach_path_notes(diags: &mut Vec<InternalDiagnostic>) {
+    let mut result: Vec<InternalDiagnostic> = Vec::with_capacity(diags.len());
+    let mut in_path = false;
+    for diag in diags.drain(..) {
+        let is_note = diag.severity == "info" && diag.code.is_none();
+        match result.last_mut() {
+            Some(primary) if is_note && in_path => primary.related.push(RelatedNote {
+                file: diag.file,
+                range: diag.range,
+                message: diag.message,
+            }),
+            _ => {
+                in_path = diag.code.as_deref().is_some_and(|code| code.starts_with("clang-analyzer-"));
+                result.push(diag);
+            }
+        }
+    }
+    *diags = result;
+}
+
+/// Collapses diagnostics that one template produces at every instantiation:
+/// same file, position and check, at least one with instantiation notes. The
+/// survivor carries all instantiation sites as related information.
+fn group_instantiation_duplicates(diags: &mut Vec<InternalDiagnostic>) {
+    let mut groups: HashMap<(PathBuf, usize, usize, Option<String>), Vec<InternalDiagnostic>> = HashMap::new();
+    for d in diags.drain(..) {
+        let key = (d.file.clone(), d.range.start.line, d.range.start.character, d.code.clone());
+        groups.entry(key).or_default().push(d);
+    }
+    for (_, mut group) in groups {
+        if group.len() < 2 || group.iter().all(|d| d.related.is_empty()) {
+            diags.append(&mut group);
+            continue;
+        }
+        group.sort_by(|a, b| a.message.cmp(&b.message));
+        let count = group.len();
+        let mut primary = group.remove(0);
+        for other in group {
+            for fix in other.fixes {
+                if !primary.fixes.iter().any(|e| same_fix(e, &fix)) {
+                    primary.fixes.push(fix);
+                }
+            }
+            for note in other.related {
+                if !primary.related.contains(&note) {
+                    primary.related.push(note);
+                }
+            }
+        }
+        primary.message = format!("{} (reported for {count} instantiations)", primary.message);
+        diags.push(primary);
+    }
+}
+
+/// Merges fix-bearing diagnostics into `base`. Entries with the same key (which
+/// includes aliased duplicates once codes are normalized) collapse into one.
+fn merge_diagnostics(base: &mut Vec<InternalDiagnostic>, fixes: Vec<InternalDiagnostic>) {
+    let mut map: HashMap<String, InternalDiagnostic> = HashMap::new();
+    for d in base.drain(..) {
+        match map.get_mut(&diag_key(&d)) {
+            Some(existing) => {
+                for note in d.related {
+                    if !existing.related.contains(&note) {
+                        existing.related.push(note);
+                    }
+                }
+            }
+            None => {
+                map.insert(diag_key(&d), d);
+            }
+        }
+    }
+
+    for f in fixes {
+        let key = diag_key(&f);
+        if let Some(existing) = map.get_mut(&key) {
+            for fix in f.fixes {
+                if !existing.fixes.iter().any(|e| same_fix(e, &fix)) {
+                    existing.fixes.push(fix);
+                }
+            }
+        } else {
+            map.insert(key, f);
+        }
+    }
+
+    *base = map.into_values().collect();
+}
+
+/// Whether two fixes make the same edits, in this file and in any other file.
+fn same_fix(a: &Fix, b: &Fix) -> bool {
+    let other_files = |fix: &Fix| -> Vec<(String, Vec<TextEdit>)> {
+        fix.edit
+            .iter()
+            .flat_map(|edit| edit.document_changes.iter())
+            .filter_map(|change| match change {
+                DocumentChange::Edit(doc_edit) => Some((doc_edit.text_document.uri.clone(), doc_edit.edits.clone())),
+                DocumentChange::Resource(_) => None,
+            })
+            .collect()
+    };
+    let (a_other, b_other) = (other_files(a), other_files(b));
+    same_edits(&a.edits, &b.edits)
+        && a_other.len() == b_other.len()
+        && a_other.iter().zip(&b_other).all(|((a_uri, a_edits), (b_uri, b_edits))| a_uri == b_uri && same_edits(a_edits, b_edits))
+}
+
+fn same_edits(a: &[TextEdit], b: &[TextEdit]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(x, y)| {
+            x.new_text == y.new_text
+                && x.range.start.line == y.range.start.line
+                && x.range.start.character == y.range.start.character
+                && x.range.end.line == y.range.end.line
+                && x.range.end.character == y.range.end.character
+        })
+}
+
+/// Whether fixes of `check` may be offered: it matches `fixableChecks` (or
+/// that list is empty) and does not match `unfixableChecks`.
+fn check_fixable(settings: &Settings, check: &str) -> bool {
+    let matches = |globs: &[String]| globs.iter().filter_map(|glob| glob_to_regex(glob)).any(|re| re.is_match(check));
+    (settings.fixableChecks.is_empty() || matches(&settings.fixableChecks)) && !matches(&settings.unfixableChecks)
+}
+
+/// Findings clang-tidy reported while analyzing `file_path` in other files
+/// (headers its header filter lets through), converted the way the file's
+/// own are, with `file_uri` set. They carry no fixes. Headers must match
+/// `headerFilter`, or lie under the workspace root when it is empty, and must
+/// not match `excludeHeaderFilter`.
+fn header_diagnostics(diags: &[InternalDiagnostic], file_path: &Path, root_dir: Option<&Path>, settings: &Settings) -> Vec<RpcDiagnostic> {
+    let filter = |pattern: &str| if pattern.is_empty() { None } else { Regex::new(pattern).ok() };
+    let (include, exclude) = (filter(&settings.headerFilter), filter(&settings.excludeHeaderFilter));
+    let wanted = |header: &Path| {
+        let name = header.to_string_lossy();
+        let included = match include.as_ref() {
+            Some(re) => re.is_match(&name),
+            None => root_dir.is_none_or(|root| header.starts_with(root)),
+        };
+        included && !exclude.as_ref().is_some_and(|re| re.is_match(&name))
+    };
+    let mut by_file: BTreeMap<PathBuf, Vec<InternalDiagnostic>> = BTreeMap::new();
+    for diag in diags {
+        if paths_match(&diag.file, file_path) || !diag.file.is_absolute() || !wanted(&diag.file) {
+            continue;
+        }
+        let mut diag = diag.clone();
+        diag.fixes.clear();
+        by_file.entry(diag.file.clone()).or_default().push(diag);
+    }
+    let mut result = Vec::new();
+    for (header, mut diags) in by_file {
+        let Ok(uri) = Url::from_file_path(&header) else {
+            continue;
+        };
+        let text = std::fs::read_to_string(&header).unwrap_or_default();
+        byte_columns_to_utf16(&mut diags, &header, &text);
+        widen_diagnostic_ranges(&mut diags, &header, &text);
+        drop_ignored_checks(&mut diags, settings);
+        apply_severity_overrides(&mut diags, settings);
+        result.extend(diags.into_iter().map(|diag| RpcDiagnostic {
+            file_uri: Some(uri.to_string()),
+            ..to_rpc_diagnostic(diag)
+        }));
+    }
+    result
+}
+
+/// Replaces the header findings of translation unit `unit` with `headers`.
+/// Returns the URIs of the headers it had or now has findings in, whose
+/// merged findings need publishing again.
+fn record_header_findings(findings: &Mutex<HashMap<PathBuf, Vec<RpcDiagnostic>>>, unit: &Path, headers: Vec<RpcDiagnostic>) -> BTreeSet<String> {
+    let mut uris: BTreeSet<String> = headers.iter().filter_map(|diag| diag.file_uri.clone()).collect();
+    let mut findings = findings.lock().unwrap();
+    let previous = if headers.is_empty() { findings.remove(unit) } else { findings.insert(unit.to_path_buf(), headers) };
+    uris.extend(previous.into_iter().flatten().filter_map(|diag| diag.file_uri));
+    uris
+}
+
+/// Merges the header findings of every translation unit analyzed so far for
+/// the headers in `uris`: findings with the same range, check and message are
+/// published once, with `tu_count` set to how many units reported them.
+fn merge_header_findings(findings: &HashMap<PathBuf, Vec<RpcDiagnostic>>, uris: &BTreeSet<String>) -> BTreeMap<String, Vec<RpcDiagnostic>> {
+    type Key = (usize, usize, usize, usize, Option<String>, String);
+    let mut merged: BTreeMap<String, BTreeMap<Key, RpcDiagnostic>> = uris.iter().map(|uri| (uri.clone(), BTreeMap::new())).collect();
+    for diag in findings.values().flatten() {
+        let Some(diags) = diag.file_uri.as_ref().and_then(|uri| merged.get_mut(uri)) else {
+            continue;
+        };
+        let key = (
+            diag.range.start.line,
+            diag.range.start.character,
+            diag.range.end.line,
+            diag.range.end.character,
+            diag.code.clone(),
+            diag.message.clone(),
+        );
+        let merged = diags.entry(key).or_insert_with(|| RpcDiagnostic { file_uri: None, ..diag.clone() });
+        merged.tu_count = Some(merged.tu_count.unwrap_or_default() + 1);
+    }
+    merged.into_iter().map(|(uri, diags)| (uri, diags.into_values().collect())).collect()
+}
+
+/// Drops diagnostics whose check matches `ignoredChecks`; diagnostics without
+/// a check name are kept.
+fn drop_ignored_checks(diags: &mut Vec<InternalDiagnostic>, settings: &Settings) {
+    let ignored: Vec<Regex> = settings.ignoredChecks.iter().filter_map(|glob| glob_to_regex(glob)).collect();
+    if ignored.is_empty() {
+        return;
+    }
+    diags.retain(|d| d.code.as_deref().is_none_or(|check| !ignored.iter().any(|re| re.is_match(check))));
+}
+
+/// Sets the severity `severityOverrides` maps the check to; when several
+/// globs match, the longest wins. Entries naming an unknown severity are
+/// ignored.
+fn apply_severity_overrides(diags: &mut [InternalDiagnostic], settings: &Settings) {
+    let mut overrides: Vec<(&String, Regex, &str)> = settings
+        .severityOverrides
+        .iter()
+        .filter(|(_, severity)| SEVERITY_ORDER.contains(&severity.as_str()))
+        .filter_map(|(glob, severity)| Some((glob, glob_to_regex(glob)?, severity.as_str())))
+        .collect();
+    if overrides.is_empty() {
+        return;
+    }
+    overrides.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(b.0)));
+    for diag in diags.iter_mut() {
+        let Some(check) = diag.code.as_deref() else {
+            continue;
+        };
+        if let Some((_, _, severity)) = overrides.iter().find(|(_, re, _)| re.is_match(check)) {
+            diag.severity = severity.to_string();
+        }
+    }
+}
+
+/// Strips the fixes of checks that `check_fixable` rejects; the diagnostics
+/// themselves are kept.
+fn drop_unfixable(diags: &mut [InternalDiagnostic], settings: &Settings) {
+    if settings.fixableChecks.is_empty() && settings.unfixableChecks.is_empty() {
+        return;
+    }
+    for diag in diags.iter_mut() {
+        if !diag.fixes.is_empty() && !check_fixable(settings, diag.code.as_deref().unwrap_or_default()) {
+            diag.fixes.clear();
+        }
+    }
+}
+
+fn apply_diagnostic_caps(diags: &mut Vec<InternalDiagnostic>, max_diags: u32, max_fixes: u32) {
+    if max_diags > 0 && diags.len() > max_diags as usize {
+        diags.truncate(max_diags as usize);
+    }
+
+    if max_fixes == 0 {
+        return;
+    }
+
+    let mut remaining = max_fixes as usize;
+    for diag in diags.iter_mut() {
+        if diag.fixes.is_empty() {
+            continue;
+        }
+        if remaining == 0 {
+            diag.fixes.clear();
+            continue;
+        }
+        if diag.fixes.len() > remaining {
+            diag.fixes.truncate(remaining);
+            remaining = 0;
+        } else {
+            remaining -= diag.fixes.len();
+        }
+    }
+}
+
+fn throttle_publish(limiter: &Arc<Mutex<Instant>>, throttle_ms: u64) {
+    if throttle_ms == 0 {
+        return;
+    }
+    let mut last = limiter.lock().unwrap();
+    let elapsed = last.elapsed();
+    let interval = Duration::from_millis(throttle_ms);
+    if elapsed < interval {
+        thread::sleep(interval - elapsed);
+    }
+    *last = Instant::now();
+}
+
+fn run_command_with_timeout(cmd: &mut Command, timeout_ms: u64) -> Result<std::process::Output> {
+    run_command_with_input(cmd, None, timeout_ms)
+}
+
+/// Like `run_command_with_timeout`, feeding `input` to the command's stdin.
+/// Output is drained while the command runs, so it cannot block on a full pipe.
+fn run_command_with_input(cmd: &mut Command, input: Option<&str>, timeout_ms: u64) -> Result<std::process::Output> {
+    if timeout_ms == 0 && input.is_none() {
+        return Ok(cmd.output()?);
+    }
+
+    let name = Path::new(cmd.get_program()).file_name().unwrap_or_default().to_string_lossy().to_string();
+    if input.is_some() {
+        cmd.stdin(std::process::Stdio::piped());
+    }
+    cmd.stdout(std::process::Stdio::piped());
+    cmd.stderr(std::process::Stdio::piped());
+    let mut child = cmd.spawn().with_context(|| format!("Failed to run {name}"))?;
+    let writer = child.stdin.take().zip(input).map(|(mut stdin, input)| {
+        let input = input.to_string();
+        thread::spawn(move || stdin.write_all(input.as_bytes()))
+    });
+    let stdout = drain_pipe(child.stdout.take());
+    let stderr = drain_pipe(child.stderr.take());
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if timeout_ms > 0 && start.elapsed() >= Duration::from_millis(timeout_ms) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow::anyhow!("{name} timed out after {} ms", timeout_ms));
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+    Ok(std::process::Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+fn drain_pipe(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
+
+/// How the process died when it did not exit on its own: a signal (crash,
+/// OOM killer) on Unix, an NTSTATUS error code on Windows.
+fn crash_description(status: &std::process::ExitStatus) -> Option<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal().map(|signal| format!("was killed by signal {signal}"))
+    }
+    #[cfg(not(unix))]
+    {
+        status
+            .code()
+            .map(|code| code as u32)
+            .filter(|code| code & 0xC000_0000 == 0xC000_0000)
+            .map(|code| format!("crashed with status 0x{code:08X}"))
+    }
+}
+
+/// Runs clang-tidy like `run_command_with_timeout` and records wall time and,
+/// where the OS reports it, peak RSS and CPU time for `file_path`.
+fn run_measured(
+    cmd: &mut Command,
+    timeout_ms: u64,
+    resources: &Arc<Mutex<ResourceStats>>,
+    file_path: &Path,
+    mode: &str,
+) -> Result<std::process::Output> {
+    cmd.stdout(std::process::Stdio::piped());
+    cmd.stderr(std::process::Stdio::piped());
+    let start = Instant::now();
+    let name = Path::new(cmd.get_program()).file_name().unwrap_or_default().to_string_lossy().to_string();
+    let mut child = cmd.spawn().with_context(|| format!("Failed to start {name}"))?;
+    let stdout_reader = child.stdout.take().map(|mut out| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = out.read_to_end(&mut buf);
+            buf
+        })
+    });
+    let stderr_reader = child.stderr.take().map(|mut err| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = err.read_to_end(&mut buf);
+            buf
+        })
+    });
+
+    let (status, usage) = wait_with_usage(&mut child, timeout_ms)?;
+    let wall_ms = start.elapsed().as_millis() as u64;
+    let mut stats = resources.lock().unwrap();
+    stats.invocations += 1;
+    stats.wall_ms += wall_ms;
+    if let Some(usage) = usage {
+        stats.user_cpu_ms += usage.user_cpu_ms;
+        stats.system_cpu_ms += usage.system_cpu_ms;
+        stats.peak_rss_kb = stats.peak_rss_kb.max(usage.peak_rss_kb);
+    }
+    stats.files.insert(file_path.to_path_buf(), InvocationUsage { mode: mode.to_string(), wall_ms, usage });
+    drop(stats);
+
+    Ok(std::process::Output {
+        status,
+        stdout: stdout_reader.and_then(|h| h.join().ok()).unwrap_or_default(),
+        stderr: stderr_reader.and_then(|h| h.join().ok()).unwrap_or_default(),
+    })
+}
+
+#[cfg(unix)]
+fn wait_with_usage(child: &mut std::process::Child, timeout_ms: u64) -> Result<(std::process::ExitStatus, Option<ResourceUsage>)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let pid = child.id() as libc::pid_t;
+    let start = Instant::now();
+    let mut killed = false;
+    loop {
+        let mut status: libc::c_int = 0;
+        // SAFETY: rusage is plain data; wait4 fills it in for our own child.
+        let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+        let flags = if timeout_ms == 0 || killed { 0 } else { libc::WNOHANG };
+        // SAFETY: pid is a child of this process that has not been reaped yet.
+        let rc = unsafe { libc::wait4(pid, &mut status, flags, &mut rusage) };
+        if rc == pid {
+            if killed {
+                return Err(anyhow::anyhow!("clang-tidy timed out after {} ms", timeout_ms));
+            }
+            let to_ms = |tv: libc::timeval| tv.tv_sec as u64 * 1000 + tv.tv_usec as u64 / 1000;
+            // ru_maxrss is kilobytes on Linux and bytes on macOS.
+            let peak_rss_kb = if cfg!(target_os = "macos") { rusage.ru_maxrss as u64 / 1024 } else { rusage.ru_maxrss as u64 };
+            let usage = ResourceUsage {
+                peak_rss_kb,
+                user_cpu_ms: to_ms(rusage.ru_utime),
+                system_cpu_ms: to_ms(rusage.ru_stime),
+            };
+            return Ok((std::process::ExitStatus::from_raw(status), Some(usage)));
+        }
+        if rc < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err.into());
+        }
+        if start.elapsed() >= Duration::from_millis(timeout_ms) {
+            let _ = child.kill();
+            killed = true;
+            continue;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[cfg(not(unix))]
+fn wait_with_usage(child: &mut std::process::Child, timeout_ms: u64) -> Result<(std::process::ExitStatus, Option<ResourceUsage>)> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status, None));
+        }
+        if timeout_ms > 0 && start.elapsed() >= Duration::from_millis(timeout_ms) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow::anyhow!("clang-tidy timed out after {} ms", timeout_ms));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn resource_status(stats: &ResourceStats, limit: usize) -> Value {
+    let mut files: Vec<(&PathBuf, &InvocationUsage)> = stats.files.iter().collect();
+    files.sort_by(|a, b| {
+        let rss = |u: &InvocationUsage| u.usage.map(|r| r.peak_rss_kb).unwrap_or(0);
+        rss(b.1).cmp(&rss(a.1)).then(b.1.wall_ms.cmp(&a.1.wall_ms))
+    });
+    let files: Vec<Value> = files
+        .into_iter()
+        .take(limit)
+        .map(|(path, usage)| {
+            let mut entry = serde_json::to_value(usage).unwrap_or(Value::Null);
+            entry["fileUri"] = Value::String(Url::from_file_path(path).map(|u| u.to_string()).unwrap_or_default());
+            entry
+        })
+        .collect();
+    serde_json::json!({
+        "invocations": stats.invocations,
+        "wallMs": stats.wall_ms,
+        "userCpuMs": stats.user_cpu_ms,
+        "systemCpuMs": stats.system_cpu_ms,
+        "peakRssKb": stats.peak_rss_kb,
+        "files": files,
+    })
+}
+
+/// With `enableCheckProfile`, makes clang-tidy store per-check timings in a
+/// fresh temp dir, returned so it lives until the profile has been read.
+fn add_profile_args(cmd: &mut Command, settings: &Settings) -> Result<Option<tempfile::TempDir>> {
+    if !settings.enableCheckProfile {
+        return Ok(None);
+    }
+    let dir = tempfile::Builder::new()
+        .prefix(&temp_artifact_prefix())
+        .tempdir()
+        .context("Failed to create temp dir for check profile")?;
+    cmd.arg("--enable-check-profile");
+    cmd.arg(format!("--store-check-profile={}", dir.path().display()));
+    Ok(Some(dir))
+}
+
+/// Reads the `--store-check-profile` JSON files in `dir` (keys like
+/// `time.clang-tidy.<check>.wall`, in seconds) as the latest profile of
+/// `file_path`.
+fn record_check_profile(resources: &Arc<Mutex<ResourceStats>>, file_path: &Path, dir: &Path) {
+    let mut checks: HashMap<String, CheckTiming> = HashMap::new();
+    for entry in WalkDir::new(dir).into_iter().flatten() {
+        if entry.path().extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let Some(profile) = std::fs::read(entry.path())
+            .ok()
+            .and_then(|data| serde_json::from_slice::<Value>(&data).ok())
+            .and_then(|v| v.get("profile").and_then(|p| p.as_object()).cloned())
+        else {
+            continue;
+        };
+        for (key, value) in profile {
+            let Some((check, kind)) = key.strip_prefix("time.clang-tidy.").and_then(|k| k.rsplit_once('.')) else {
+                continue;
+            };
+            let ms = value.as_f64().unwrap_or(0.0) * 1000.0;
+            let timing = checks.entry(check.to_string()).or_insert(CheckTiming { files: 1, ..Default::default() });
+            match kind {
+                "wall" => timing.wall_ms += ms,
+                "user" => timing.user_ms += ms,
+                "sys" => timing.system_ms += ms,
+                _ => {}
+            }
+        }
+    }
+    if !checks.is_empty() {
+        resources.lock().unwrap().check_profiles.insert(file_path.to_path_buf(), checks);
+    }
+}
+
+/// Sums the latest profiles of `files` and keeps the result as the profile
+/// of project run `run_id`.
+fn record_run_profile(resources: &Arc<Mutex<ResourceStats>>, run_id: &str, files: &[PathBuf]) {
+    let mut stats = resources.lock().unwrap();
+    let mut checks: HashMap<String, CheckTiming> = HashMap::new();
+    let mut profiled = 0;
+    for file in files {
+        let Some(profile) = stats.check_profiles.get(file) else {
+            continue;
+        };
+        profiled += 1;
+        for (check, timing) in profile {
+            checks.entry(check.clone()).or_default().add(timing);
+        }
+    }
+    if profiled == 0 {
+        return;
+    }
+    stats.run_profiles.retain(|run| run.run_id != run_id);
+    if stats.run_profiles.len() >= RUN_PROFILE_HISTORY {
+        stats.run_profiles.pop_front();
+    }
+    stats.run_profiles.push_back(RunProfile { run_id: run_id.to_string(), files: profiled, checks });
+}
+
+/// The `limit` most expensive checks by wall time.
+fn check_profile_report(files: usize, checks: &HashMap<String, CheckTiming>, limit: usize) -> Value {
+    let total = checks.values().fold(0.0, |sum, t| sum + t.wall_ms);
+    let mut sorted: Vec<(&String, &CheckTiming)> = checks.iter().collect();
+    sorted.sort_by(|a, b| b.1.wall_ms.total_cmp(&a.1.wall_ms).then_with(|| a.0.cmp(b.0)));
+    let round = |ms: f64| (ms * 1000.0).round() / 1000.0;
+    let checks: Vec<Value> = sorted
+        .into_iter()
+        .take(limit)
+        .map(|(check, timing)| {
+            serde_json::json!({
+                "check": check,
+                "wallMs": round(timing.wall_ms),
+                "userMs": round(timing.user_ms),
+                "systemMs": round(timing.system_ms),
+                "files": timing.files,
+                "percent": if total > 0.0 { round(timing.wall_ms / total * 100.0) } else { 0.0 },
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "files": files,
+        "totalWallMs": round(total),
+        "checks": checks,
+    })
+}
+
+fn split_command(command: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_single = false;
+    let mut in_double = false;
+    let mut escape = false;
+
+    for ch in command.chars() {
+        if escape {
+            current.push(ch);
+            escape = false;
+            continue;
+        }
+        if ch == '\\' && !in_single {
+            escape = true;
+            continue;
+        }
+        if ch == '\'' && !in_double {
+            in_single = !in_single;
+            continue;
+        }
+        if ch == '"' && !in_single {
+            in_double = !in_double;
+            continue;
+        }
+        if ch.is_whitespace() && !in_single && !in_double {
+            if !current.is_empty() {
+                args.push(current.clone());
+                current.clear();
+            }
+            continue;
+        }
+        current.push(ch);
+    }
+    if !current.is_empty() {
+        args.push(current);
+    }
+    args
+}
+
+/// Splits `command` the way `CommandLineToArgvW` does: backslashes are
+/// literal unless they precede a quote, and quotes toggle quoting (`""`
+/// inside quotes is a literal quote).
+fn split_windows_command(command: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut has_arg = false;
+    let mut in_quotes = false;
+    let mut backslashes = 0;
+    let mut chars = command.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            backslashes += 1;
+            has_arg = true;
+            continue;
+        }
+        if ch == '"' {
+            current.push_str(&"\\".repeat(backslashes / 2));
+            if backslashes % 2 == 1 {
+                current.push('"');
+            } else if in_quotes && chars.peek() == Some(&'"') {
+                chars.next();
+                current.push('"');
+            } else {
+                in_quotes = !in_quotes;
+            }
+            backslashes = 0;
+            has_arg = true;
+            continue;
+        }
+        current.push_str(&"\\".repeat(backslashes));
+        backslashes = 0;
+        if ch.is_whitespace() && !in_quotes {
+            if has_arg {
+                args.push(std::mem::take(&mut current));
+                has_arg = false;
+            }
+            continue;
+        }
+        current.push(ch);
+        has_arg = true;
+    }
+    current.push_str(&"\\".repeat(backslashes));
+    if has_arg {
+        args.push(current);
+    }
+    args
+}
+
+/// Splits the `command` of a compile entry. MSVC-style commands, and every
+/// command on Windows, follow the Windows rules as in clang's own JSON
+/// compilation database reader, so backslashes in paths survive.
+fn split_compile_command(command: &str) -> Vec<String> {
+    let windows = split_windows_command(command);
+    let mut program = windows.clone();
+    strip_compiler_launchers(&mut program, &[]);
+    if cfg!(windows) || is_cl_driver(&program) {
+        windows
+    } else {
+        split_command(command)
+    }
+}
+
+/// Whether `args` (compiler first) use the MSVC syntax: `cl`, `clang-cl`, or
+/// any compiler run with `--driver-mode=cl`.
+fn is_cl_driver(args: &[String]) -> bool {
+    let program = args
+        .first()
+        .and_then(|program| program.rsplit(['/', '\\']).next())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    let program = program.strip_suffix(".exe").unwrap_or(&program);
+    program == "cl" || program.starts_with("clang-cl") || args.iter().any(|arg| arg == "--driver-mode=cl")
+}
+
+/// An MSVC option without its `/` or `-` prefix.
+fn cl_option(arg: &str) -> Option<&str> {
+    arg.strip_prefix('/').or_else(|| arg.strip_prefix('-'))
+}
+
+/// GNU spelling of the MSVC arguments the daemon reads itself: include
+/// directories, macros, forced includes, the language and its standard.
+/// `args` excludes the compiler; everything else, source files included, is
+/// dropped.
+fn translate_cl_args(args: &[String]) -> Vec<String> {
+    const WITH_VALUE: &[(&str, &str)] = &[
+        ("external:I", "-isystem"),
+        ("imsvc", "-isystem"),
+        ("FI", "-include"),
+        ("I", "-I"),
+        ("D", "-D"),
+        ("U", "-U"),
+    ];
+    let mut translated = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let Some(option) = cl_option(arg) else {
+            continue;
+        };
+        if let Some(standard) = option.strip_prefix("std:") {
+            let standard = match standard {
+                "c++latest" => "c++2b",
+                "clatest" => "c2x",
+                other => other,
+            };
+            translated.push(format!("-std={standard}"));
+        } else if option == "TP" {
+            translated.extend(["-x".to_string(), "c++".to_string()]);
+        } else if option == "TC" {
+            translated.extend(["-x".to_string(), "c".to_string()]);
+        } else if let Some(passed) = option.strip_prefix("clang:") {
+            translated.push(passed.to_string());
+        } else if let Some((flag, value)) =
+            WITH_VALUE.iter().find_map(|(name, flag)| option.strip_prefix(name).map(|value| (*flag, value)))
+        {
+            let value = if value.is_empty() {
+                match iter.next() {
+                    Some(next) => next.as_str(),
+                    None => break,
+                }
+            } else {
+                value
+            };
+            translated.extend([flag.to_string(), value.to_string()]);
+        }
+    }
+    translated
+}
+
+/// MSVC options naming outputs, matched as prefixes.
+const CL_OUTPUT_OPTIONS: &[&str] = &["Fo", "Fd", "Fe", "Fa", "Fi", "Fm", "FR", "Fr", "FS", "showIncludes"];
+
+/// MSVC options creating or using a precompiled header, matched as prefixes.
+const CL_PCH_OPTIONS: &[&str] = &["Yc", "Yu", "Fp"];
+
+/// Number of arguments an output or dependency file option spans (`-o x` is
+/// two, `-MD` one), or `None` for other arguments. `cl` adds the MSVC
+/// outputs.
+fn output_option_len(arg: &str, cl: bool) -> Option<usize> {
+    match arg {
+        "-MD" | "-MMD" | "-M" | "-MM" | "-MP" => Some(1),
+        "-o" | "-MF" | "-MT" | "-MQ" => Some(2),
+        a if a.starts_with("-o") || a.starts_with("-MF") || a.starts_with("-MT") || a.starts_with("-MQ") => Some(1),
+        a if cl && cl_option(a).is_some_and(|option| CL_OUTPUT_OPTIONS.iter().any(|o| option.starts_with(o))) => Some(1),
+        _ => None,
+    }
+}
+
+/// GCC's C++ modules options, which clang rejects; dropped unless
+/// `disableDefaultFlagDrops` is set.
+const DEFAULT_DROPPED_FLAGS: &[&str] = &["-fmodules-ts", "-fmodule-mapper=*", "-fdeps-format=*", "-fdeps-file=*", "-fdeps-target=*"];
+
+/// Keeps warning options newer than clang-tidy's clang from being reported,
+/// or failing the run under `-Werror`.
+const UNKNOWN_WARNING_FLAG: &str = "-Wno-unknown-warning-option";
+
+/// A `dropFlags` entry as a regex over whole arguments; `*` matches anything.
+fn flag_pattern(glob: &str) -> Option<Regex> {
+    let pattern: Vec<String> = glob.trim().split('*').map(regex::escape).collect();
+    Regex::new(&format!("^{}$", pattern.join(".*"))).ok()
+}
+
+/// Compile arguments (compiler first) as clang-tidy should get them. Output
+/// and dependency file options are always removed. Unless
+/// `disableDefaultFlagDrops` is set, so are precompiled headers that do not
+/// exist (e.g. after a clean build), MSVC precompiled header options and
+/// `DEFAULT_DROPPED_FLAGS`; `dropFlags` globs remove more. Also returns what
+/// was removed besides outputs, which clang-tidy ignores anyway.
+fn sanitize_compile_args(args: &[String], settings: &Settings, directory: &Path) -> (Vec<String>, Vec<String>) {
+    let cl = is_cl_driver(args);
+    let defaults = !settings.disableDefaultFlagDrops;
+    let mut patterns: Vec<Regex> = settings.dropFlags.iter().filter_map(|glob| flag_pattern(glob)).collect();
+    if defaults {
+        patterns.extend(DEFAULT_DROPPED_FLAGS.iter().filter_map(|glob| flag_pattern(glob)));
+    }
+    let missing = |file: Option<&String>| file.is_some_and(|file| !directory.join(file).exists());
+    let mut kept: Vec<String> = args.first().cloned().into_iter().collect();
+    let mut dropped = Vec::new();
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        // CMake passes its PCH to clang as `-Xclang -include-pch -Xclang <file>`.
+        let stale_pch = match (arg, args.get(i + 1).map(String::as_str), args.get(i + 2).map(String::as_str)) {
+            ("-include-pch", ..) if missing(args.get(i + 1)) => 2,
+            ("-Xclang", Some("-include-pch"), Some("-Xclang")) if missing(args.get(i + 3)) => 4,
+            _ => 0,
+        };
+        let removed = if defaults && stale_pch > 0 {
+            stale_pch
+        } else if (defaults && cl && cl_option(arg).is_some_and(|option| CL_PCH_OPTIONS.iter().any(|o| option.starts_with(o))))
+            || patterns.iter().any(|re| re.is_match(arg))
+        {
+            1
+        } else if let Some(len) = output_option_len(arg, cl) {
+            i += len;
+            continue;
+        } else {
+            kept.push(arg.to_string());
+            i += 1;
+            continue;
+        };
+        let end = (i + removed).min(args.len());
+        dropped.push(args[i..end].join(" "));
+        i = end;
+    }
+    (kept, dropped)
+}
+
+struct SanitizedCompileDb {
+    compile_commands: PathBuf,
+    compile_commands_mtime: u64,
+    dir: PathBuf,
+}
+
+/// Sanitized copies of compile databases by compile directory and the hash
+/// of the settings that shaped them.
+fn sanitized_compile_dbs() -> &'static Mutex<HashMap<(PathBuf, u64), SanitizedCompileDb>> {
+    static DBS: std::sync::OnceLock<Mutex<HashMap<(PathBuf, u64), SanitizedCompileDb>>> = std::sync::OnceLock::new();
+    DBS.get_or_init(Default::default)
+}
+
+fn sanitize_settings_hash(settings: &Settings) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    settings.dropFlags.hash(&mut hasher);
+    settings.disableDefaultFlagDrops.hash(&mut hasher);
+    settings.compilerLaunchers.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn sanitized_db_root() -> PathBuf {
+    std::env::temp_dir().join(format!("{}db", temp_artifact_prefix()))
+}
+
+/// Directory of the sanitized copy of the compile database in `compile_dir`,
+/// while that copy is current.
+fn sanitized_compile_db(compile_dir: &Path, settings: &Settings) -> Option<PathBuf> {
+    let dbs = sanitized_compile_dbs().lock().unwrap();
+    let db = dbs.get(&(compile_dir.to_path_buf(), sanitize_settings_hash(settings)))?;
+    (mtime_for_path(&db.compile_commands) == Some(db.compile_commands_mtime)).then(|| db.dir.clone())
+}
+
+/// Writes a copy of the compile database at `path` in which the commands
+/// `sanitize_compile_args` drops anything from are rewritten, for clang-tidy
+/// to read instead of the original. Returns the dropped arguments with the
+/// number of commands each was dropped from; without any, no copy is used.
+fn write_sanitized_compile_db(path: &Path, mtime: u64, entries: &[CompileCommand], settings: &Settings) -> BTreeMap<String, usize> {
+    let mut dropped: BTreeMap<String, usize> = BTreeMap::new();
+    let mut rewritten: HashMap<usize, Vec<String>> = HashMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let mut args = match &entry.arguments {
+            Some(args) => args.clone(),
+            None => entry.command.as_deref().map(split_compile_command).unwrap_or_default(),
+        };
+        strip_compiler_launchers(&mut args, &settings.compilerLaunchers);
+        let (sanitized, removed) = sanitize_compile_args(&args, settings, Path::new(&entry.directory));
+        if removed.is_empty() {
+            continue;
+        }
+        for arg in removed {
+            *dropped.entry(arg).or_default() += 1;
+        }
+        rewritten.insert(i, sanitized);
+    }
+    let Some(compile_dir) = path.parent() else {
+        return dropped;
+    };
+    let key = (compile_dir.to_path_buf(), sanitize_settings_hash(settings));
+    let mut dbs = sanitized_compile_dbs().lock().unwrap();
+    dbs.remove(&key);
+    if rewritten.is_empty() {
+        return dropped;
+    }
+    let copy: Vec<CompileCommand> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| match rewritten.remove(&i) {
+            Some(args) => CompileCommand { command: None, arguments: Some(args), ..entry.clone() },
+            None => entry.clone(),
+        })
+        .collect();
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let dir = sanitized_db_root().join(format!("{:016x}", hasher.finish()));
+    let staging = dir.join("compile_commands.json.tmp");
+    let written = std::fs::create_dir_all(&dir)
+        .and_then(|_| std::fs::write(&staging, serde_json::to_vec(&copy).unwrap_or_default()))
+        .and_then(|_| std::fs::rename(&staging, dir.join("compile_commands.json")));
+    if written.is_ok() {
+        dbs.insert(key, SanitizedCompileDb { compile_commands: path.to_path_buf(), compile_commands_mtime: mtime, dir });
+    }
+    dropped
+}
+
+fn replace_file_arg(args: &mut [String], raw_file: &str, original_path: &str, temp_path: &str) -> bool {
+    let mut replaced = false;
+    for arg in args.iter_mut() {
+        if arg == raw_file || arg == original_path {
+            *arg = temp_path.to_string();
+            replaced = true;
+        }
+        // MSVC names sources of an explicit language as `/Tp<file>` or `/Tc<file>`.
+        let explicit = ["/Tp", "/Tc", "-Tp", "-Tc"]
+            .into_iter()
+            .find(|prefix| arg.strip_prefix(prefix).is_some_and(|file| file == raw_file || file == original_path));
+        if let Some(prefix) = explicit {
+            *arg = format!("{prefix}{temp_path}");
+            replaced = true;
+        }
+    }
+    replaced
+}
+
+const KNOWN_COMPILER_LAUNCHERS: &[&str] = &["ccache", "sccache", "distcc", "icecc", "buildcache"];
+
+fn resolve_arguments(entry: &CompileCommandEntry, settings: &Settings) -> Option<Vec<String>> {
+    let mut args = match entry.arguments.clone() {
+        Some(args) => args,
+        None => split_compile_command(entry.command.as_ref()?),
+    };
+    strip_compiler_launchers(&mut args, &settings.compilerLaunchers);
+    Some(expand_response_files(args, Path::new(&entry.directory), &mut Vec::new()))
+}
+
+const MAX_RESPONSE_FILE_DEPTH: usize = 16;
+
+/// Replaces `@file` arguments with the arguments the response file holds,
+/// split the way the compiler would: Windows rules for MSVC-style commands,
+/// GNU rules otherwise. Top-level files are relative to `directory`, nested
+/// ones to the file naming them. An unreadable file is left as it is, like
+/// compilers do. Every file read is added to `read`.
+fn expand_response_files(args: Vec<String>, directory: &Path, read: &mut Vec<PathBuf>) -> Vec<String> {
+    let cl = is_cl_driver(&args);
+    let mut expanded = Vec::with_capacity(args.len());
+    let mut pending: Vec<(String, PathBuf, usize)> =
+        args.into_iter().rev().map(|arg| (arg, directory.to_path_buf(), 0)).collect();
+    while let Some((arg, base, depth)) = pending.pop() {
+        let Some(name) = arg.strip_prefix('@').filter(|_| depth < MAX_RESPONSE_FILE_DEPTH) else {
+            expanded.push(arg);
+            continue;
+        };
+        let path = base.join(name);
+        let Ok(text) = std::fs::read_to_string(&path) else {
+            expanded.push(arg);
+            continue;
+        };
+        let nested_base = path.parent().map(Path::to_path_buf).unwrap_or(base);
+        let inner = if cl { split_windows_command(&text) } else { split_command(&text) };
+        pending.extend(inner.into_iter().rev().map(|arg| (arg, nested_base.clone(), depth + 1)));
+        read.push(path);
+    }
+    expanded
+}
+
+/// Version of a compile database for cache keys: its mtime, combined with the
+/// mtimes of the response files its commands read when there are any. The
+/// response files are listed once per database mtime.
+fn compile_db_mtime(compile_commands: &Path) -> Option<u64> {
+    static RESPONSE_FILES: std::sync::OnceLock<Mutex<HashMap<PathBuf, ResponseFiles>>> = std::sync::OnceLock::new();
+    let mtime = mtime_for_path(compile_commands)?;
+    let mut known = RESPONSE_FILES.get_or_init(Default::default).lock().unwrap();
+    let files = match known.get(compile_commands).filter(|listed| listed.compile_commands_mtime == mtime) {
+        Some(listed) => listed.files.clone(),
+        None => {
+            let files = compile_db_response_files(compile_commands);
+            known.insert(compile_commands.to_path_buf(), ResponseFiles { compile_commands_mtime: mtime, files: files.clone() });
+            files
+        }
+    };
+    drop(known);
+    if files.is_empty() {
+        return Some(mtime);
+    }
+    let mut hasher = DefaultHasher::new();
+    mtime.hash(&mut hasher);
+    for file in &files {
+        file.hash(&mut hasher);
+        mtime_for_path(file).hash(&mut hasher);
+    }
+    Some(hasher.finish())
+}
+
+struct ResponseFiles {
+    compile_commands_mtime: u64,
+    files: Vec<PathBuf>,
+}
+
+fn compile_db_response_files(compile_commands: &Path) -> Vec<PathBuf> {
+    let Ok(content) = std::fs::read_to_string(compile_commands) else {
+        return Vec::new();
+    };
+    let Ok(entries) = serde_json::from_str::<Vec<CompileCommand>>(&content) else {
+        return Vec::new();
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let mut args = match entry.arguments {
+            Some(args) => args,
+            None => entry.command.as_deref().map(split_compile_command).unwrap_or_default(),
+        };
+        strip_compiler_launchers(&mut args, &[]);
+        if args.iter().any(|arg| arg.starts_with('@')) {
+            expand_response_files(args, Path::new(&entry.directory), &mut files);
+        }
+    }
+    files.sort();
+    files.dedup();
+    files
+}
+
+fn strip_compiler_launchers(args: &mut Vec<String>, extra_launchers: &[String]) {
+    let mut strip = 0;
+    while strip + 1 < args.len() {
+        let program = Path::new(&args[strip])
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let is_launcher = KNOWN_COMPILER_LAUNCHERS.contains(&program.as_str())
+            || extra_launchers.iter().any(|l| l == &program || l == &args[strip]);
+        if !is_launcher {
+            break;
+        }
+        strip += 1;
+    }
+    args.drain(..strip);
+}
+
+fn diag_key(d: &InternalDiagnostic) -> String {
+    format!(
+        "{}:{}:{}:{}:{}",
+        d.file.display(),
+        d.range.start.line,
+        d.range.start.character,
+        d.code.clone().unwrap_or_default(),
+        d.message
+    )
+}
+
+/// Check name prefixes whose findings mark code editors can render as
+/// deprecated (struck through) or unnecessary (faded).
+const DIAGNOSTIC_TAGS: &[(&str, &str)] = &[
+    ("modernize-deprecated-", "deprecated"),
+    ("clang-diagnostic-deprecated", "deprecated"),
+    ("misc-unused-", "unnecessary"),
+    ("readability-redundant-", "unnecessary"),
+    ("clang-diagnostic-unused-", "unnecessary"),
+];
+
+fn diagnostic_tag(check: &str) -> Option<&'static str> {
+    DIAGNOSTIC_TAGS.iter().find(|(prefix, _)| check.starts_with(prefix)).map(|(_, tag)| *tag)
+}
+
+fn to_rpc_diagnostic(d: InternalDiagnostic) -> RpcDiagnostic {
+    let mut hasher = DefaultHasher::new();
+    diag_key(&d).hash(&mut hasher);
+    let experimental = d.code.as_deref().is_some_and(is_alpha_check).then_some(true);
+    let tags = d.code.as_deref().and_then(diagnostic_tag).map(|tag| vec![tag.to_string()]);
+    RpcDiagnostic {
+        id: format!("{:016x}", hasher.finish()),
+        range: d.range,
+        severity: d.severity,
+        code: d.code,
+        message: d.message,
+        fixes: if d.fixes.is_empty() { None } else { Some(d.fixes) },
+        code_description: None,
+        related_information: if d.related.is_empty() {
+            None
+        } else {
+            Some(
+                d.related
+                    .into_iter()
+                    .map(|note| RelatedInformation {
+                        location: Location {
+                            uri: Url::from_file_path(&note.file).map(|u| u.to_string()).unwrap_or_default(),
+                            range: note.range,
+                        },
+                        message: note.message,
+                    })
+                    .collect(),
+            )
+        },
+        experimental,
+        tags,
+        inferred_flags: None,
+        file_uri: None,
+        tu_count: None,
+    }
+}
+
+const ALPHA_CHECK_PREFIX: &str = "clang-analyzer-alpha.";
+
+fn is_alpha_check(code: &str) -> bool {
+    code.starts_with(ALPHA_CHECK_PREFIX)
+}
+
+/// Per-file clang-tidy timeout. `deep` runs path-sensitive analysis and gets
+/// `deepTimeoutMs`, or four times `perFileTimeoutMs` when that is unset.
+fn file_timeout_ms(settings: &Settings, mode: &str) -> u64 {
+    match mode {
+        "deep" if settings.deepTimeoutMs > 0 => settings.deepTimeoutMs,
+        "deep" => settings.perFileTimeoutMs.saturating_mul(DEEP_TIMEOUT_FACTOR),
+        _ => settings.perFileTimeoutMs,
+    }
+}
+
+const DEEP_TIMEOUT_FACTOR: u64 = 4;
+
+/// Adds the check selection flags shared by both analysis paths: `checks`,
+/// after `clang-analyzer-*` in `deep` mode or `quickChecks` in `quick` mode,
+/// on top of the configured checks. Without `enableAlphaChecks`, alpha
+/// analyzer globs are dropped: clang-tidy refuses to enable them unless
+/// explicitly allowed.
+fn add_check_args(cmd: &mut Command, settings: &Settings, mode: &str) {
+    if settings.enableAlphaChecks {
+        cmd.arg("--allow-enabling-analyzer-alpha-checkers");
+    }
+    let mode_checks = match mode {
+        "deep" => "clang-analyzer-*",
+        "quick" => settings.quickChecks.as_str(),
+        _ => "",
+    };
+    let checks: Vec<&str> = mode_checks
+        .split(',')
+        .chain(settings.checks.split(','))
+        .map(str::trim)
+        .filter(|check| !check.is_empty())
+        .filter(|check| settings.enableAlphaChecks || !is_alpha_check(check))
+        .collect();
+    if !checks.is_empty() {
+        cmd.arg(format!("-checks={}", checks.join(",")));
+    }
+    if !settings.headerFilter.is_empty() {
+        cmd.arg(format!("--header-filter={}", settings.headerFilter));
+    }
+}
+
+fn diff_base_ref(settings: &Settings) -> &str {
+    let base = settings.diffBaseRef.trim();
+    if base.is_empty() { "HEAD" } else { base }
+}
+
+/// Lines of `file_path` (1-based, inclusive ranges) that differ from the file
+/// at `diffBaseRef`, comparing `content` instead of the file on disk when
+/// given. `None` when the file does not exist at the base ref, so all of it
+/// is new.
+fn changed_line_ranges(settings: &Settings, file_path: &Path, content: Option<&str>) -> Result<Option<Vec<(u32, u32)>>> {
+    let base = diff_base_ref(settings);
+    let dir = file_path.parent().context("file has no parent directory")?;
+    let name = file_path.file_name().context("file has no name")?.to_string_lossy();
+
+    let verify = Command::new("git")
+        .current_dir(dir)
+        .args(["rev-parse", "--verify", "--quiet"])
+        .arg(format!("{base}^{{commit}}"))
+        .output()
+        .context("Failed to run git")?;
+    if !verify.status.success() {
+        return Err(anyhow::anyhow!("`{base}` is not a commit in the git repository of {}", file_path.display()));
+    }
+    // `--filters` applies the same line-ending conversion as a checkout.
+    let show = Command::new("git")
+        .current_dir(dir)
+        .args(["cat-file", "--filters"])
+        .arg(format!("{base}:./{name}"))
+        .output()
+        .context("Failed to run git")?;
+    if !show.status.success() {
+        return Ok(None);
+    }
+
+    let temp_dir = tempfile::Builder::new()
+        .prefix(&temp_artifact_prefix())
+        .tempdir()
+        .context("Failed to create temp dir for diff")?;
+    let base_file = temp_dir.path().join("base");
+    std::fs::write(&base_file, &show.stdout).context("Failed to write base revision")?;
+    let current_file = match content {
+        Some(text) => {
+            let path = temp_dir.path().join("current");
+            std::fs::write(&path, text).context("Failed to write buffer content")?;
+            path
+        }
+        None => file_path.to_path_buf(),
+    };
+    let diff = Command::new("git")
+        .args(["diff", "--no-index", "--no-color", "--no-ext-diff", "-U0", "--"])
+        .arg(&base_file)
+        .arg(&current_file)
+        .output()
+        .context("Failed to run git diff")?;
+    // `--no-index` exits with 1 when the files differ.
+    if !matches!(diff.status.code(), Some(0 | 1)) {
+        return Err(anyhow::anyhow!("git diff failed: {}", String::from_utf8_lossy(&diff.stderr).trim()));
+    }
+    Ok(Some(parse_diff_hunks(&String::from_utf8_lossy(&diff.stdout))))
+}
+
+/// New-side line ranges of `-U0` hunk headers. A pure deletion marks the line
+/// it follows, so code next to removed lines is still checked.
+fn parse_diff_hunks(diff: &str) -> Vec<(u32, u32)> {
+    let re = Regex::new(r"^@@ -\d+(?:,\d+)? \+(\d+)(?:,(\d+))? @@").unwrap();
+    let mut ranges = Vec::new();
+    for line in diff.lines() {
+        let Some(caps) = re.captures(line) else {
+            continue;
+        };
+        let start: u32 = caps[1].parse().unwrap_or(1);
+        let count: u32 = caps.get(2).and_then(|m| m.as_str().parse().ok()).unwrap_or(1);
+        if count == 0 {
+            ranges.push((start.max(1), start.max(1)));
+        } else {
+            ranges.push((start, start + count - 1));
+        }
+    }
+    ranges
+}
+
+/// Files under `dir` that differ from `diffBaseRef`, including untracked ones.
+fn changed_files(base: &str, dir: &Path) -> Result<HashSet<PathBuf>> {
+    let mut files = HashSet::new();
+    let listings: [Vec<&str>; 2] = [
+        vec!["diff", "--name-only", "--relative", "--no-renames", "-z", base, "--"],
+        vec!["ls-files", "--others", "--exclude-standard", "-z"],
+    ];
+    for args in listings {
+        let output = Command::new("git").current_dir(dir).args(&args).output().context("Failed to run git")?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!("git {} failed: {}", args[0], String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        for name in String::from_utf8_lossy(&output.stdout).split('\0').filter(|name| !name.is_empty()) {
+            let path = dir.join(name);
+            files.insert(std::fs::canonicalize(&path).unwrap_or(path));
+        }
+    }
+    Ok(files)
+}
+
+/// Whether `dir` is inside a git work tree (and git can be run at all).
+fn in_git_work_tree(dir: &Path) -> bool {
+    Command::new("git")
+        .current_dir(dir)
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// C, C++ and Objective-C sources and headers, by extension.
+fn is_c_family_file(file_path: &Path) -> bool {
+    is_header(file_path)
+        || file_path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| matches!(e.to_ascii_lowercase().as_str(), "c" | "cc" | "cpp" | "cxx" | "c++" | "cp" | "m" | "mm" | "cu"))
+}
+
+/// Severities from least to most severe, for `failOn`.
+const SEVERITY_ORDER: &[&str] = &["hint", "info", "warning", "error"];
+
+/// Analyzes `files` like `analyzeFile` on up to `maxWorkers` threads,
+/// publishes their diagnostics under `run_id`, and summarizes the outcome.
+/// The run passes when no file failed and no diagnostic is at least as
+/// severe as `fail_on`.
+fn analyze_changed_files(state: &AppState, run_id: &str, mode: &str, files: &[PathBuf], fail_on: &str) -> Value {
+    let settings = state.settings.lock().unwrap().clone();
+    let publisher = Publisher::from_state(state).tracking_runs();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let pool = ThreadPool::new(settings.maxWorkers.max(1) as usize);
+    for file_path in files {
+        let Ok(file_uri) = Url::from_file_path(file_path).map(|u| u.to_string()) else {
+            continue;
+        };
+        let state = state.clone();
+        let file_path = file_path.clone();
+        let run_id = run_id.to_string();
+        let mode = mode.to_string();
+        let publisher = publisher.clone();
+        let sender = sender.clone();
+        let settings = settings.clone();
+        pool.execute(move || {
+            let outcome =
+                analyze_document(&state, &settings, &file_path, &file_uri, &mode, None, None, None).map_err(|err| err.to_string());
+            if let Some(analysis) = outcome.as_ref().ok().filter(|analysis| analysis.skipped.is_none()) {
+                record_latest(&state.latest, &file_path, &analysis.diagnostics);
+                let _ = publisher.publish(&run_id, &file_uri, None, &analysis.diagnostics);
+                publish_header_findings(&state, &publisher, &run_id, &analysis.headers);
+            }
+            let _ = sender.send((file_uri, outcome));
+        });
+    }
+    drop(sender);
+    let mut outcomes: Vec<(String, Result<DocumentAnalysis, String>)> = receiver.iter().collect();
+    outcomes.sort_by(|a, b| a.0.cmp(&b.0));
+    let threshold = SEVERITY_ORDER.iter().position(|s| *s == fail_on).unwrap_or(SEVERITY_ORDER.len() - 1);
+    let mut totals: BTreeMap<&str, usize> = SEVERITY_ORDER.iter().map(|s| (*s, 0)).collect();
+    let (mut analyzed, mut blocking) = (0, 0);
+    let (mut results, mut skipped, mut failed) = (Vec::new(), Vec::new(), Vec::new());
+    for (file_uri, outcome) in outcomes {
+        let analysis = match outcome {
+            Ok(analysis) => analysis,
+            Err(message) => {
+                failed.push(serde_json::json!({ "fileUri": file_uri, "message": message }));
+                continue;
+            }
+        };
+        if let Some(reason) = analysis.skipped {
+            skipped.push(serde_json::json!({ "fileUri": file_uri, "reason": reason }));
+            continue;
+        }
+        analyzed += 1;
+        let mut counts: BTreeMap<&str, usize> = SEVERITY_ORDER.iter().map(|s| (*s, 0)).collect();
+        for diag in &analysis.diagnostics {
+            let severity = SEVERITY_ORDER.iter().position(|s| *s == diag.severity).unwrap_or(0);
+            *counts.entry(SEVERITY_ORDER[severity]).or_default() += 1;
+            *totals.entry(SEVERITY_ORDER[severity]).or_default() += 1;
+            if severity >= threshold {
+                blocking += 1;
+            }
+        }
+        if !analysis.diagnostics.is_empty() {
+            let mut result = serde_json::json!({ "fileUri": file_uri });
+            for (severity, count) in counts {
+                result[severity] = serde_json::json!(count);
+            }
+            results.push(result);
+        }
+    }
+    serde_json::json!({
+        "files": files.len(),
+        "analyzed": analyzed,
+        "skipped": skipped,
+        "failed": failed,
+        "diagnostics": totals,
+        "results": results,
+        "passed": failed.is_empty() && blocking == 0,
+    })
+}
+
+/// Restricts clang-tidy's output to `ranges` of `file`; diagnostics in other
+/// files (headers) are suppressed as well.
+fn add_line_filter(cmd: &mut Command, file: &Path, ranges: &[(u32, u32)]) {
+    let lines: Vec<[u32; 2]> = ranges.iter().map(|&(start, end)| [start, end]).collect();
+    let filter = serde_json::json!([{ "name": file.to_string_lossy(), "lines": lines }]);
+    cmd.arg(format!("-line-filter={filter}"));
+}
+
+/// Whether 0-based `line` falls in one of the 1-based `ranges`. Used to
+/// narrow findings of analyzers without a line filter, and cached results.
+fn in_line_ranges(line: usize, ranges: &[(u32, u32)]) -> bool {
+    let line = line as u32 + 1;
+    ranges.iter().any(|&(start, end)| (start..=end).contains(&line))
+}
+
+/// Lines both in the changed lines and the requested range, when either
+/// applies.
+fn intersect_line_ranges(changed: Option<Vec<(u32, u32)>>, requested: Option<&[(u32, u32)]>) -> Option<Vec<(u32, u32)>> {
+    match (changed, requested) {
+        (None, None) => None,
+        (Some(changed), None) => Some(changed),
+        (None, Some(requested)) => Some(requested.to_vec()),
+        (Some(changed), Some(requested)) => Some(
+            changed
+                .iter()
+                .flat_map(|&(a, b)| requested.iter().map(move |&(c, d)| (a.max(c), b.min(d))))
+                .filter(|(start, end)| start <= end)
+                .collect(),
+        ),
+    }
+}
+
+/// `lineRange` of `analyzeFile`: `{"start", "end"}` (0-based, inclusive
+/// lines) or an array of them, as 1-based ranges for `-line-filter`.
+fn line_range_param(params: &Value) -> Result<Option<Vec<(u32, u32)>>> {
+    let Some(value) = params.get("lineRange").filter(|v| !v.is_null()) else {
+        return Ok(None);
+    };
+    let items = match value.as_array() {
+        Some(items) => items.clone(),
+        None => vec![value.clone()],
+    };
+    let mut ranges = Vec::new();
+    for item in &items {
+        let line = |key: &str| item.get(key).and_then(|v| v.as_u64()).map(|n| n as u32);
+        let (Some(start), Some(end)) = (line("start"), line("end")) else {
+            return Err(invalid_params("lineRange", "Expected {\"start\", \"end\"} line numbers".to_string()));
+        };
+        if start > end {
+            return Err(invalid_params("lineRange", format!("Range start {start} is after its end {end}")));
+        }
+        ranges.push((start + 1, end + 1));
+    }
+    if ranges.is_empty() {
+        return Err(invalid_params("lineRange", "Expected at least one range".to_string()));
+    }
+    Ok(Some(ranges))
+}
+
+/// Converts fixes into the representation negotiated at `initialize`. The cache
+/// stores plain `edits` for the file itself, plus an `edit` for replacements in
+/// other files; the full WorkspaceEdit form is built on the way out, along with
+/// the `conflictsWith` annotations.
+fn shape_fixes(diags: &mut [RpcDiagnostic], format: FixFormat, file_uri: &str, version: Option<i64>) {
+    annotate_fix_conflicts(diags, file_uri);
+    if format != FixFormat::WorkspaceEdit {
+        return;
+    }
+    for diag in diags.iter_mut() {
+        for fix in diag.fixes.iter_mut().flatten() {
+            *fix = Fix {
+                title: fix.title.clone(),
+                edits: Vec::new(),
+                edit: Some(fix_workspace_edit(fix, file_uri, version)),
+                kind: fix.kind.clone(),
+                safety: fix.safety,
+                conflicts_with: fix.conflicts_with.clone(),
+            };
+        }
+    }
+}
+
+/// Sets `conflictsWith` on every fix of `diags` to the other fixes it cannot be
+/// applied together with (see `edits_conflict`), including alternative fixes of
+/// the same diagnostic. Edits are compared per document.
+fn annotate_fix_conflicts(diags: &mut [RpcDiagnostic], file_uri: &str) {
+    type Edit = ((usize, usize), (usize, usize), String);
+    let mut all: Vec<(FixRef, Vec<(String, Edit)>)> = Vec::new();
+    for diag in diags.iter() {
+        for (fix_index, fix) in diag.fixes.iter().flatten().enumerate() {
+            let edits = fix_file_edits(fix, file_uri)
+                .into_iter()
+                .flat_map(|(uri, edits)| {
+                    edits.into_iter().map(move |edit| {
+                        let start = (edit.range.start.line, edit.range.start.character);
+                        let end = (edit.range.end.line, edit.range.end.character).max(start);
+                        (uri.clone(), (start, end, edit.new_text))
+                    })
+                })
+                .collect();
+            all.push((FixRef { diagnostic_id: diag.id.clone(), fix_index }, edits));
+        }
+    }
+    let mut conflicts: HashMap<(String, usize), Vec<FixRef>> = HashMap::new();
+    for (i, (a_ref, a_edits)) in all.iter().enumerate() {
+        for (b_ref, b_edits) in &all[i + 1..] {
+            let conflict = a_edits
+                .iter()
+                .any(|(a_uri, a)| b_edits.iter().any(|(b_uri, b)| a_uri == b_uri && edits_conflict(a, b)));
+            if conflict {
+                conflicts.entry((a_ref.diagnostic_id.clone(), a_ref.fix_index)).or_default().push(b_ref.clone());
+                conflicts.entry((b_ref.diagnostic_id.clone(), b_ref.fix_index)).or_default().push(a_ref.clone());
+            }
+        }
+    }
+    for diag in diags.iter_mut() {
+        for (fix_index, fix) in diag.fixes.iter_mut().flatten().enumerate() {
+            fix.conflicts_with = conflicts.remove(&(diag.id.clone(), fix_index)).unwrap_or_default();
+        }
+    }
+}
+
+/// Returns the fix as a WorkspaceEdit, whichever shape it was produced in.
+fn fix_workspace_edit(fix: &Fix, file_uri: &str, version: Option<i64>) -> WorkspaceEdit {
+    let mut edit = fix.edit.clone().unwrap_or_default();
+    if !fix.edits.is_empty() {
+        edit.document_changes.insert(
+            0,
+            DocumentChange::Edit(TextDocumentEdit {
+                text_document: VersionedTextDocumentIdentifier {
+                    uri: file_uri.to_string(),
+                    version,
+                },
+                edits: fix.edits.clone(),
+            }),
+        );
+    }
+    edit
+}
+
+fn clang_tidy_binary(settings: &Settings) -> String {
+    if settings.clangTidyPath.is_empty() {
+        "clang-tidy".to_string()
+    } else {
+        settings.clangTidyPath.clone()
+    }
+}
+
+/// Compiler used to build preamble PCHs: `clangPath`, else the `clang` next
+/// to clang-tidy so both read the same PCH format, else `clang` on `PATH`.
+fn clang_binary(settings: &Settings) -> String {
+    if !settings.clangPath.is_empty() {
+        return settings.clangPath.clone();
+    }
+    resolve_binary_path(&clang_tidy_binary(settings))
+        .and_then(|tidy| tidy.parent().map(|dir| dir.join("clang")))
+        .filter(|clang| clang.is_file())
+        .map(|clang| clang.to_string_lossy().to_string())
+        .unwrap_or_else(|| "clang".to_string())
+}
+
+/// `clangApplyReplacementsPath`, else the `clang-apply-replacements` next to
+/// clang-tidy, else the one on `PATH`.
+fn clang_apply_replacements_binary(settings: &Settings) -> String {
+    if !settings.clangApplyReplacementsPath.is_empty() {
+        return settings.clangApplyReplacementsPath.clone();
+    }
+    resolve_binary_path(&clang_tidy_binary(settings))
+        .and_then(|tidy| tidy.parent().map(|dir| dir.join("clang-apply-replacements")))
+        .filter(|tool| tool.is_file())
+        .map(|tool| tool.to_string_lossy().to_string())
+        .unwrap_or_else(|| "clang-apply-replacements".to_string())
+}
+
+fn cppcheck_binary(settings: &Settings) -> String {
+    if settings.cppcheckPath.is_empty() {
+        "cppcheck".to_string()
+    } else {
+        settings.cppcheckPath.clone()
+    }
+}
+
+/// Whether `analyzers` selects `name`; an empty list means clang-tidy only.
+fn analyzer_enabled(settings: &Settings, name: &str) -> bool {
+    if settings.analyzers.is_empty() {
+        return name == "clang-tidy";
+    }
+    settings.analyzers.iter().any(|a| a == name)
+}
+
+const CPPCHECK_CODE_PREFIX: &str = "cppcheck-";
+
+/// Runs cppcheck on the compile_commands entry for `source` and parses its
+/// findings (template output on stderr) as diagnostics for `file_path`, which
+/// differs from `source` when an unsaved buffer was written to a temp file.
+/// Codes are the cppcheck ids prefixed with `cppcheck-`.
+fn run_cppcheck(
+    settings: &Settings,
+    compile_commands: &Path,
+    source: &Path,
+    file_path: &Path,
+    resources: &Arc<Mutex<ResourceStats>>,
+    mode: &str,
+) -> Result<Vec<InternalDiagnostic>> {
+    let mut cmd = Command::new(cppcheck_binary(settings));
+    cmd.arg(format!("--project={}", compile_commands.display()))
+        .arg(format!("--file-filter={}", source.display()))
+        .arg("--template={file}:{line}:{column}: {severity}: {message} [{id}]")
+        .arg("--enable=warning,style,performance,portability")
+        .arg("--inline-suppr")
+        .arg("--quiet");
+    for arg in &settings.cppcheckArgs {
+        cmd.arg(arg);
+    }
+    if let Some(dir) = compile_commands.parent() {
+        cmd.current_dir(dir);
+    }
+    let output = run_measured(&mut cmd, settings.perFileTimeoutMs, resources, file_path, mode)
+        .context("Failed to run cppcheck")?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let line_re = Regex::new(r"^(?P<file>.+):(?P<line>\d+):(?P<col>\d+): (?P<severity>\w+): (?P<message>.*) \[(?P<id>[\w.-]+)\]$").unwrap();
+    let base_dir = compile_commands.parent();
+    let mut diags = Vec::new();
+    for line in stderr.lines() {
+        let Some(caps) = line_re.captures(line.trim_end()) else {
+            continue;
+        };
+        let file = PathBuf::from(&caps["file"]);
+        let file = match base_dir {
+            Some(dir) if file.is_relative() => dir.join(file),
+            _ => file,
+        };
+        if !paths_match(&file, source) {
+            continue;
+        }
+        let line: usize = caps["line"].parse().unwrap_or(1);
+        let col: usize = caps["col"].parse().unwrap_or(1);
+        diags.push(InternalDiagnostic {
+            file: file_path.to_path_buf(),
+            range: range_from_line_col(line, col),
+            severity: normalize_severity(&caps["severity"]),
+            code: Some(format!("{CPPCHECK_CODE_PREFIX}{}", &caps["id"])),
+            message: caps["message"].to_string(),
+            fixes: Vec::new(),
+            related: Vec::new(),
+        });
+    }
+    Ok(diags)
+}
+
+/// Paths an external analyzer invocation may refer to. `source` is the file
+/// handed to the tool, a temp copy for unsaved buffers; findings are
+/// reported for `file_path`.
+struct AdapterContext<'a> {
+    source: &'a Path,
+    file_path: &'a Path,
+    root_dir: Option<&'a Path>,
+    compile_commands: Option<&'a Path>,
+    work_dir: Option<&'a Path>,
+}
+
+/// One finding read from an external analyzer, before it is anchored.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExternalFinding {
+    #[serde(default)]
+    file: Option<String>,
+    line: usize,
+    #[serde(default)]
+    column: Option<usize>,
+    #[serde(default)]
+    end_line: Option<usize>,
+    #[serde(default)]
+    end_column: Option<usize>,
+    #[serde(default)]
+    severity: Option<String>,
+    message: String,
+    #[serde(default)]
+    code: Option<String>,
+}
+
+/// Runs the external analyzers whose `files` match, in declaration order.
+fn run_external_analyzers(
+    settings: &Settings,
+    ctx: &AdapterContext,
+    resources: &Arc<Mutex<ResourceStats>>,
+    mode: &str,
+) -> Result<Vec<InternalDiagnostic>> {
+    let mut diags = Vec::new();
+    for adapter in &settings.externalAnalyzers {
+        if adapter.command.is_empty() {
+            continue;
+        }
+        if !adapter.files.is_empty() && !path_matches_globs(&adapter.files, ctx.file_path, ctx.root_dir) {
+            continue;
+        }
+        diags.extend(run_external_analyzer(adapter, settings, ctx, resources, mode)?);
+    }
+    Ok(diags)
+}
+
+fn run_external_analyzer(
+    adapter: &ExternalAnalyzer,
+    settings: &Settings,
+    ctx: &AdapterContext,
+    resources: &Arc<Mutex<ResourceStats>>,
+    mode: &str,
+) -> Result<Vec<InternalDiagnostic>> {
+    let path_text = |p: Option<&Path>| p.map(|p| p.to_string_lossy().into_owned()).unwrap_or_default();
+    let substitute = |arg: &str| {
+        arg.replace("${file}", &path_text(Some(ctx.source)))
+            .replace("${root}", &path_text(ctx.root_dir))
+            .replace("${compileCommands}", &path_text(ctx.compile_commands))
+            .replace("${compileDir}", &path_text(ctx.compile_commands.and_then(Path::parent)))
+    };
+    let mut cmd = Command::new(substitute(&adapter.command[0]));
+    cmd.args(adapter.command[1..].iter().map(|arg| substitute(arg)));
+    if let Some(dir) = ctx.work_dir.or(ctx.root_dir) {
+        cmd.current_dir(dir);
+    }
+    let output = run_measured(&mut cmd, settings.perFileTimeoutMs, resources, ctx.file_path, mode)
+        .with_context(|| format!("Failed to run external analyzer `{}`", adapter.name))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let findings = match adapter.format.as_str() {
+        "sarif" => parse_sarif_findings(&stdout)?,
+        "json" => serde_json::from_str(&stdout)
+            .with_context(|| format!("External analyzer `{}` printed invalid JSON", adapter.name))?,
+        _ => {
+            let re = Regex::new(&adapter.pattern)
+                .with_context(|| format!("Invalid `pattern` for external analyzer `{}`", adapter.name))?;
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            parse_regex_findings(&re, &format!("{stdout}\n{stderr}"))
+        }
+    };
+
+    let base_dir = ctx.work_dir.or(ctx.root_dir);
+    let mut diags = Vec::new();
+    for finding in findings {
+        if let Some(raw) = finding.file.as_deref() {
+            let file = uri_to_path(raw).or_else(|| resolve_path(raw, base_dir));
+            if !file.is_some_and(|file| paths_match(&file, ctx.source)) {
+                continue;
+            }
+        }
+        let mut range = range_from_line_col(finding.line, finding.column.unwrap_or(1));
+        if let Some(end_line) = finding.end_line {
+            range.end = Position {
+                line: end_line.saturating_sub(1),
+                character: finding.end_column.unwrap_or(1).saturating_sub(1),
+            };
+        }
+        diags.push(InternalDiagnostic {
+            file: ctx.file_path.to_path_buf(),
+            range,
+            severity: normalize_severity(finding.severity.as_deref().unwrap_or("warning")),
+            code: Some(match finding.code {
+                Some(code) => format!("{}{EXTERNAL_CODE_SEPARATOR}{code}", adapter.name),
+                None => adapter.name.clone(),
+            }),
+            message: finding.message,
+            fixes: Vec::new(),
+            related: Vec::new(),
+        });
+    }
+    Ok(diags)
+}
+
+/// Separates an external analyzer's name from its rule id in diagnostic
+/// codes. No clang-tidy check name contains it.
+const EXTERNAL_CODE_SEPARATOR: char = ':';
+
+fn parse_regex_findings(re: &Regex, output: &str) -> Vec<ExternalFinding> {
+    output
+        .lines()
+        .filter_map(|line| re.captures(line.trim_end()))
+        .filter_map(|caps| {
+            let group = |name: &str| caps.name(name).map(|m| m.as_str().to_string());
+            Some(ExternalFinding {
+                file: group("file"),
+                line: group("line")?.parse().ok()?,
+                column: group("column").and_then(|c| c.parse().ok()),
+                end_line: None,
+                end_column: None,
+                severity: group("severity").map(|s| s.to_lowercase()),
+                message: group("message")?,
+                code: group("code"),
+            })
+        })
+        .collect()
+}
+
+/// Results of every run in a SARIF 2.1 log, at their first physical location.
+fn parse_sarif_findings(output: &str) -> Result<Vec<ExternalFinding>> {
+    let log: Value = serde_json::from_str(output).context("Invalid SARIF output")?;
+    let mut findings = Vec::new();
+    let runs = log.get("runs").and_then(|v| v.as_array()).into_iter().flatten();
+    for result in runs.filter_map(|run| run.get("results").and_then(|v| v.as_array())).flatten() {
+        let location = result.pointer("/locations/0/physicalLocation");
+        let region = location.and_then(|l| l.get("region"));
+        let number = |key: &str| region.and_then(|r| r.get(key)).and_then(|v| v.as_u64()).map(|n| n as usize);
+        findings.push(ExternalFinding {
+            file: location
+                .and_then(|l| l.pointer("/artifactLocation/uri"))
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string()),
+            line: number("startLine").unwrap_or(1),
+            column: number("startColumn"),
+            end_line: number("endLine"),
+            end_column: number("endColumn"),
+            severity: result.get("level").and_then(|v| v.as_str()).map(|s| s.to_string()),
+            message: result.pointer("/message/text").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+            code: result.get("ruleId").and_then(|v| v.as_str()).map(|s| s.to_string()),
+        });
+    }
+    Ok(findings)
+}
+
+fn clang_format_binary(settings: &Settings) -> String {
+    if settings.clangFormatPath.is_empty() {
+        "clang-format".to_string()
+    } else {
+        settings.clangFormatPath.clone()
+    }
+}
+
+/// Locates `binary` the way `Command` would (as given when it contains a path
+/// separator, otherwise on `PATH`) and resolves symlinks such as
+/// `clang-tidy -> clang-tidy-17`.
+fn resolve_binary_path(binary: &str) -> Option<PathBuf> {
+    let given = Path::new(binary);
+    if given.components().count() > 1 {
+        return std::fs::canonicalize(given).ok();
+    }
+    let path_var = std::env::var_os("PATH")?;
+    std::env::split_paths(&path_var).find_map(|dir| {
+        [binary.to_string(), format!("{binary}.exe")]
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|candidate| candidate.is_file())
+            .and_then(|candidate| std::fs::canonicalize(candidate).ok())
+    })
+}
+
+/// Hashes the resolved clang-tidy executable (path, mtime, size and its
+/// `--version`, probed once per signature through `input_hash`) so that cache
+/// entries produced by a different toolchain are not reused after an upgrade.
+fn hash_tool_identity(binary: &str, hasher: &mut DefaultHasher) {
+    match resolve_binary_path(binary) {
+        Some(resolved) => {
+            resolved.to_string_lossy().hash(hasher);
+            file_signature(&resolved).hash(hasher);
+            input_hash(&resolved, || detect_tool_version(&resolved.to_string_lossy())).hash(hasher);
+        }
+        None => binary.hash(hasher),
+    }
+}
+
+/// Returns the version reported by `clang-tidy --version`, probing each
+/// configured binary only once per process.
+fn clang_tidy_version(settings: &Settings, state: &AppState) -> Option<String> {
+    let binary = clang_tidy_binary(settings);
+    if let Some(known) = state.tool_versions.lock().unwrap().get(&binary) {
+        return known.clone();
+    }
+    let version = detect_tool_version(&binary);
+    state.tool_versions.lock().unwrap().insert(binary, version.clone());
+    version
+}
+
+fn detect_tool_version(binary: &str) -> Option<String> {
+    let output = run_command_with_timeout(Command::new(binary).arg("--version"), 10_000).ok()?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    let re = Regex::new(r"version (\d+\.\d+\.\d+[\w.-]*)").unwrap();
+    re.captures(&text).map(|caps| caps[1].to_string())
+}
+
+/// Documentation URL for a check. Released versions link to the matching
+/// releases.llvm.org page; development builds and unknown versions use the
+/// current docs on clang.llvm.org.
+fn check_docs_url(check: &str, version: Option<&str>) -> Option<String> {
+    if check.is_empty() || check.contains(char::is_whitespace) {
+        return None;
+    }
+    if check.starts_with(CPPCHECK_CODE_PREFIX) || check.contains(EXTERNAL_CODE_SEPARATOR) {
+        return None;
+    }
+    if let Some(flag) = check.strip_prefix("clang-diagnostic-") {
+        return Some(format!("https://clang.llvm.org/docs/DiagnosticsReference.html#w{flag}"));
+    }
+    let release = version.filter(|v| v.chars().all(|c| c.is_ascii_digit() || c == '.'));
+    let major: u32 = release
+        .and_then(|v| v.split('.').next())
+        .and_then(|m| m.parse().ok())
+        .unwrap_or(u32::MAX);
+    let page = if major < 15 {
+        check.to_string()
+    } else if let Some(rest) = check.strip_prefix("clang-analyzer-") {
+        format!("clang-analyzer/{rest}")
+    } else {
+        match check.split_once('-') {
+            Some((module, rest)) => format!("{module}/{rest}"),
+            None => check.to_string(),
+        }
+    };
+    Some(match release {
+        Some(v) => format!("https://releases.llvm.org/{v}/tools/clang/tools/extra/docs/clang-tidy/checks/{page}.html"),
+        None => format!("https://clang.llvm.org/extra/clang-tidy/checks/{page}.html"),
+    })
+}
+
+fn attach_code_descriptions(diags: &mut [RpcDiagnostic], version: Option<&str>) {
+    for diag in diags.iter_mut() {
+        if let Some(code) = diag.code.as_deref() {
+            diag.code_description = check_docs_url(code, version).map(|href| CodeDescription { href });
+        }
+    }
+}
+
+/// One-line summaries for frequently reported checks, used when the daemon has
+/// to describe a check without network access.
+const CHECK_SUMMARIES: &[(&str, &str)] = &[
+    ("bugprone-branch-clone", "Finds repeated branches in `if`/`else if` chains and `switch` statements."),
+    ("bugprone-easily-swappable-parameters", "Finds adjacent parameters of the same type that are easy to swap by mistake."),
+    ("bugprone-macro-parentheses", "Finds macros that can behave unexpectedly because arguments or the replacement list lack parentheses."),
+    ("bugprone-narrowing-conversions", "Flags implicit conversions that may lose precision or change value."),
+    ("bugprone-reserved-identifier", "Finds declarations that use identifiers reserved for the implementation."),
+    ("bugprone-suspicious-include", "Flags `#include` directives that include implementation files."),
+    ("bugprone-unused-return-value", "Warns when the result of a function whose return value must be checked is discarded."),
+    ("bugprone-use-after-move", "Warns when an object is used after it has been moved from."),
+    ("cppcoreguidelines-avoid-magic-numbers", "Flags numeric literals that should be named constants."),
+    ("cppcoreguidelines-init-variables", "Flags local variables declared without an initial value."),
+    ("cppcoreguidelines-owning-memory", "Enforces `gsl::owner<T*>` for raw pointers that own memory."),
+    ("cppcoreguidelines-pro-bounds-pointer-arithmetic", "Flags pointer arithmetic, which is prone to out-of-bounds access."),
+    ("cppcoreguidelines-pro-type-member-init", "Flags constructors that leave members or bases uninitialized."),
+    ("cppcoreguidelines-special-member-functions", "Requires classes that define one special member function to define all of them."),
+    ("google-explicit-constructor", "Requires single-argument constructors and conversion operators to be `explicit`."),
+    ("llvm-header-guard", "Checks that header guards follow the LLVM naming convention."),
+    ("misc-const-correctness", "Suggests adding `const` to local variables that are never modified."),
+    ("misc-include-cleaner", "Finds unused `#include` directives and symbols used without a direct include."),
+    ("misc-non-private-member-variables-in-classes", "Flags classes with public or protected data members."),
+    ("misc-static-assert", "Replaces `assert()` on compile-time constants with `static_assert`."),
+    ("misc-unused-parameters", "Finds unused function parameters."),
+    ("misc-unused-using-decls", "Finds `using` declarations that are never used."),
+    ("modernize-avoid-c-arrays", "Suggests `std::array` or `std::vector` instead of C-style arrays."),
+    ("modernize-deprecated-headers", "Replaces C compatibility headers such as `<stdio.h>` with their C++ equivalents."),
+    ("modernize-loop-convert", "Converts index- and iterator-based loops to range-based `for` loops."),
+    ("modernize-make-shared", "Replaces `std::shared_ptr<T>(new T(...))` with `std::make_shared<T>(...)`."),
+    ("modernize-make-unique", "Replaces `std::unique_ptr<T>(new T(...))` with `std::make_unique<T>(...)`."),
+    ("modernize-pass-by-value", "Passes sink parameters by value and moves them into place."),
+    ("modernize-use-auto", "Uses `auto` where the type is already spelled out in the initializer."),
+    ("modernize-use-emplace", "Replaces `push_back` of temporaries with `emplace_back`."),
+    ("modernize-use-nodiscard", "Adds `[[nodiscard]]` to const member functions with no side effects."),
+    ("modernize-use-nullptr", "Replaces `NULL` and `0` null pointer constants with `nullptr`."),
+    ("modernize-use-override", "Adds `override` to virtual functions that override a base class function."),
+    ("modernize-use-trailing-return-type", "Rewrites function declarations to use trailing return types."),
+    ("modernize-use-using", "Replaces `typedef` with `using` alias declarations."),
+    ("performance-for-range-copy", "Finds range-based loops that copy each element when a reference would do."),
+    ("performance-move-const-arg", "Finds `std::move` calls on const or trivially copyable values."),
+    ("performance-unnecessary-copy-initialization", "Finds local copies that could be const references."),
+    ("performance-unnecessary-value-param", "Finds expensive-to-copy parameters passed by value but only read."),
+    ("readability-braces-around-statements", "Requires braces around the bodies of control statements."),
+    ("readability-else-after-return", "Removes `else` after a branch that always returns, breaks or continues."),
+    ("readability-function-cognitive-complexity", "Flags functions whose cognitive complexity exceeds a threshold."),
+    ("readability-identifier-length", "Flags variable and parameter names that are too short."),
+    ("readability-identifier-naming", "Checks identifier names against the configured naming conventions."),
+    ("readability-implicit-bool-conversion", "Flags implicit conversions to and from `bool`."),
+    ("readability-magic-numbers", "Flags numeric literals that should be named constants."),
+    ("readability-uppercase-literal-suffix", "Requires integer and floating literal suffixes to be upper case."),
+];
+
+/// Module level fallbacks for checks without an entry in `CHECK_SUMMARIES`.
+const CHECK_MODULE_SUMMARIES: &[(&str, &str)] = &[
+    ("abseil-", "Checks related to the Abseil library."),
+    ("altera-", "Checks for OpenCL programming for FPGAs."),
+    ("android-", "Checks related to Android."),
+    ("boost-", "Checks related to the Boost library."),
+    ("bugprone-", "Checks that target bug-prone code constructs."),
+    ("cert-", "Checks related to the CERT Secure Coding Guidelines."),
+    ("clang-analyzer-", "Clang Static Analyzer checks."),
+    ("clang-diagnostic-", "Clang compiler warning."),
+    ("concurrency-", "Checks related to concurrent programming."),
+    ("cppcoreguidelines-", "Checks related to the C++ Core Guidelines."),
+    ("darwin-", "Checks related to Darwin coding conventions."),
+    ("fuchsia-", "Checks related to Fuchsia coding conventions."),
+    ("google-", "Checks related to Google coding conventions."),
+    ("hicpp-", "Checks related to the High Integrity C++ Coding Standard."),
+    ("linuxkernel-", "Checks related to the Linux kernel coding conventions."),
+    ("llvm-", "Checks related to the LLVM coding conventions."),
+    ("llvmlibc-", "Checks related to the LLVM-libc coding standards."),
+    ("misc-", "Checks that do not fit a more specific category."),
+    ("modernize-", "Checks that advocate usage of modern C++ language constructs."),
+    ("mpi-", "Checks related to MPI (Message Passing Interface)."),
+    ("objc-", "Checks related to Objective-C coding conventions."),
+    ("openmp-", "Checks related to OpenMP API."),
+    ("performance-", "Checks that target performance-related issues."),
+    ("portability-", "Checks that target portability-related issues."),
+    ("readability-", "Checks that target readability-related issues."),
+    ("zircon-", "Checks related to the Zircon kernel coding conventions."),
+];
+
+fn check_summary(check: &str) -> Option<&'static str> {
+    CHECK_SUMMARIES
+        .iter()
+        .find(|(name, _)| *name == check)
+        .or_else(|| CHECK_MODULE_SUMMARIES.iter().find(|(prefix, _)| check.starts_with(prefix)))
+        .map(|(_, summary)| *summary)
+}
+
+/// What the daemon knows about a check without running clang-tidy: the
+/// bundled summary (check level, else module level), the documentation page
+/// for the installed release and the aliases that report under it.
+fn explain_check(check: &str, version: Option<&str>) -> Value {
+    let canonical = canonical_check(check);
+    let (summary, summary_source) = match CHECK_SUMMARIES.iter().find(|(name, _)| *name == canonical) {
+        Some((_, summary)) => (Some(*summary), Some("check")),
+        None => match CHECK_MODULE_SUMMARIES.iter().find(|(prefix, _)| canonical.starts_with(prefix)) {
+            Some((_, summary)) => (Some(*summary), Some("module")),
+            None => (None, None),
+        },
+    };
+    let aliases: Vec<&str> = CHECK_ALIASES
+        .iter()
+        .filter(|(_, target)| *target == canonical)
+        .map(|(alias, _)| *alias)
+        .collect();
+    let (kind, safety) = classify_fix(Some(&canonical));
+    let mut result = serde_json::json!({
+        "check": canonical,
+        "summary": summary,
+        "summarySource": summary_source,
+        "url": check_docs_url(&canonical, version),
+        "aliases": aliases,
+        "fixKind": kind,
+        "fixSafety": safety,
+    });
+    if canonical != check {
+        result["requestedAlias"] = Value::String(check.to_string());
+    }
+    if is_alpha_check(&canonical) {
+        result["experimental"] = Value::Bool(true);
+    }
+    result
+}
+
+fn record_latest(latest: &Arc<Mutex<SpillStore<Vec<RpcDiagnostic>>>>, file_path: &Path, diags: &[RpcDiagnostic]) {
+    latest.lock().unwrap().insert(file_path.to_path_buf(), diags.to_vec());
+}
+
+/// Most recent diagnostics for a file: the last published set, else the cache.
+fn latest_diagnostics(state: &AppState, file_path: &Path) -> Vec<RpcDiagnostic> {
+    if let Some(diags) = state.latest.lock().unwrap().get(file_path) {
+        return diags;
+    }
+    state
+        .cache
+        .lock()
+        .unwrap()
+        .get(file_path)
+        .map(|entry| entry.diagnostics.clone())
+        .unwrap_or_default()
+}
+
+fn range_contains(range: &Range, pos: &Position) -> bool {
+    let after_start = (pos.line, pos.character) >= (range.start.line, range.start.character);
+    let before_end = (pos.line, pos.character) <= (range.end.line, range.end.character);
+    after_start && before_end
+}
+
+fn build_hover(
+    file_path: &Path,
+    file_uri: &str,
+    diags: &[RpcDiagnostic],
+    position: &Position,
+    version: Option<&str>,
+) -> Value {
+    let hits: Vec<&RpcDiagnostic> = diags.iter().filter(|d| range_contains(&d.range, position)).collect();
+    if hits.is_empty() {
+        return Value::Null;
+    }
+    let file_text = std::fs::read_to_string(file_path).unwrap_or_default();
+    let lines: Vec<&str> = file_text.lines().collect();
+
+    let mut sections = Vec::new();
+    for diag in &hits {
+        let mut md = String::new();
+        match diag.code.as_deref() {
+            Some(code) => md.push_str(&format!("**{}** `{}`\n\n{}\n", diag.severity, code, diag.message)),
+            None => md.push_str(&format!("**{}**\n\n{}\n", diag.severity, diag.message)),
+        }
+        if let Some(code) = diag.code.as_deref() {
+            if let Some(summary) = check_summary(code) {
+                md.push_str(&format!("\n{summary}"));
+            }
+            if let Some(url) = check_docs_url(code, version) {
+                md.push_str(&format!(" [Documentation]({url})"));
+            }
+            md.push('\n');
+        }
+        for fix in diag.fixes.iter().flatten() {
+            md.push_str(&format!("\n*{}*\n\n```diff\n", fix.title));
+            for edit in &own_file_edits(fix, file_uri) {
+                md.push_str(&edit_preview(&lines, edit));
+            }
+            md.push_str("```\n");
+        }
+        sections.push(md);
+    }
+
+    serde_json::json!({
+        "contents": {"kind": "markdown", "value": sections.join("\n---\n\n")},
+        "range": hits[0].range,
+    })
+}
+
+/// Renders an edit as `-`/`+` lines using the current file text.
+fn edit_preview(lines: &[&str], edit: &TextEdit) -> String {
+    let start = edit.range.start.line;
+    let end = edit.range.end.line;
+    let (Some(first), Some(last)) = (lines.get(start), lines.get(end)) else {
+        return format!("+ {}\n", edit.new_text);
+    };
+    let prefix = utf16_prefix(first, edit.range.start.character);
+    let suffix = utf16_suffix(last, edit.range.end.character);
+    let mut out = String::new();
+    for line in &lines[start..=end] {
+        out.push_str(&format!("- {line}\n"));
+    }
+    for line in format!("{prefix}{}{suffix}", edit.new_text).lines() {
+        out.push_str(&format!("+ {line}\n"));
+    }
+    out
+}
+
+fn utf16_byte_offset(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (idx, ch) in line.char_indices() {
+        if units >= character {
+            return idx;
+        }
+        units += ch.len_utf16();
+    }
+    line.len()
+}
+
+fn utf16_prefix(line: &str, character: usize) -> &str {
+    &line[..utf16_byte_offset(line, character)]
+}
+
+fn utf16_suffix(line: &str, character: usize) -> &str {
+    &line[utf16_byte_offset(line, character)..]
+}
+
+fn explain_fix(file_path: &Path, file_uri: &str, diag: &RpcDiagnostic, fix: &Fix) -> Value {
+    let file_text = std::fs::read_to_string(file_path).unwrap_or_default();
+    let lines: Vec<&str> = file_text.lines().collect();
+    let mut changes = Vec::new();
+    for edit in &own_file_edits(fix, file_uri) {
+        let old_text = edit_original_text(&lines, edit);
+        let line = edit.range.start.line + 1;
+        let description = if let Some(include) = edit.new_text.trim().strip_prefix("#include") {
+            format!("adds `{}` include", include.trim())
+        } else if old_text.is_empty() {
+            format!("inserts {} at line {line}", describe_snippet(&edit.new_text))
+        } else if edit.new_text.is_empty() {
+            format!("removes {} at line {line}", describe_snippet(&old_text))
+        } else {
+            format!(
+                "replaces {} with {} at line {line}",
+                describe_snippet(&old_text),
+                describe_snippet(&edit.new_text)
+            )
+        };
+        changes.push(serde_json::json!({ "line": line, "description": description }));
+    }
+
+    let mut summary = changes
+        .iter()
+        .filter_map(|c| c["description"].as_str())
+        .collect::<Vec<_>>()
+        .join("; ");
+    if let Some(first) = summary.get(..1) {
+        summary = format!("{}{}.", first.to_uppercase(), &summary[1..]);
+    }
+    if let Some(safety) = fix.safety {
+        summary.push_str(match safety {
+            FixSafety::Safe => " The change is considered safe.",
+            FixSafety::Cosmetic => " The change is cosmetic.",
+            FixSafety::BehaviorChanging => " The change may alter behavior; review it before applying.",
+        });
+    }
+
+    serde_json::json!({
+        "diagnosticId": diag.id,
+        "title": fix.title,
+        "check": diag.code,
+        "checkSummary": diag.code.as_deref().and_then(check_summary),
+        "kind": fix.kind,
+        "safety": fix.safety,
+        "summary": summary,
+        "changes": changes,
+    })
+}
+
+fn edit_original_text(lines: &[&str], edit: &TextEdit) -> String {
+    let (start, end) = (edit.range.start.line, edit.range.end.line);
+    if start > end || end >= lines.len() {
+        return String::new();
+    }
+    if start == end {
+        let line = lines[start];
+        let from = utf16_byte_offset(line, edit.range.start.character);
+        let to = utf16_byte_offset(line, edit.range.end.character).max(from);
+        return line[from..to].to_string();
+    }
+    let mut text = utf16_suffix(lines[start], edit.range.start.character).to_string();
+    for line in &lines[start + 1..end] {
+        text.push('\n');
+        text.push_str(line);
+    }
+    text.push('\n');
+    text.push_str(utf16_prefix(lines[end], edit.range.end.character));
+    text
+}
+
+fn describe_snippet(text: &str) -> String {
+    let line_count = text.trim_end_matches('\n').lines().count();
+    if line_count > 1 {
+        return format!("{line_count} lines");
+    }
+    let trimmed = text.trim();
+    if trimmed.is_empty() {
+        return "whitespace".to_string();
+    }
+    if trimmed.chars().count() > 40 {
+        let short: String = trimmed.chars().take(37).collect();
+        format!("`{short}...`")
+    } else {
+        format!("`{trimmed}`")
+    }
+}
+
+fn normalize_severity(raw: &str) -> String {
+    match raw {
+        "error" => "error",
+        "warning" => "warning",
+        _ => "info",
+    }
+    .to_string()
+}
+
+fn range_from_line_col(line: usize, col: usize) -> Range {
+    let line0 = line.saturating_sub(1);
+    let col0 = col.saturating_sub(1);
+    Range {
+        start: Position { line: line0, character: col0 },
+        end: Position { line: line0, character: col0 + 1 },
+    }
+}
+
+/// clang reports columns in bytes; positions count UTF-16 units. Converts the
+/// start column of the one-character ranges `parse_diagnostics` built for
+/// `file_path`, whose text is `text`. Fix edits need no conversion: `parse_fixes`
+/// maps their byte offsets straight to UTF-16 positions. Must run before
+/// `merge_diagnostics` and `widen_diagnostic_ranges`, which compare the two.
+fn byte_columns_to_utf16(diags: &mut [InternalDiagnostic], file_path: &Path, text: &str) {
+    let lines: Vec<&str> = text.lines().collect();
+    for diag in diags.iter_mut().filter(|d| is_single_char_range(&d.range) && paths_match(&d.file, file_path)) {
+        let Some(line) = lines.get(diag.range.start.line) else {
+            continue;
+        };
+        let byte = diag.range.start.character.min(line.len());
+        let Some(prefix) = line.get(..byte) else {
+            continue;
+        };
+        let character = prefix.encode_utf16().count();
+        diag.range = Range {
+            start: Position { line: diag.range.start.line, character },
+            end: Position { line: diag.range.start.line, character: character + 1 },
+        };
+    }
+}
+
+/// Widens the one-character ranges of diagnostics in `file_path` so editors
+/// underline the offending code: to the edit of a fix that replaces text at
+/// the diagnostic and ends on the same line, else to the token under it.
+/// Expects UTF-16 columns throughout; see `byte_columns_to_utf16`.
+fn widen_diagnostic_ranges(diags: &mut [InternalDiagnostic], file_path: &Path, text: &str) {
+    let lines: Vec<&str> = text.lines().collect();
+    for diag in diags.iter_mut().filter(|d| is_single_char_range(&d.range) && paths_match(&d.file, file_path)) {
+        let start = diag.range.start.clone();
+        let from_fix = diag
+            .fixes
+            .iter()
+            .flat_map(|fix| fix.edits.iter())
+            .filter(|edit| edit.range.start == start && edit.range.end.line == start.line && edit.range.end.character > start.character)
+            .map(|edit| edit.range.end.character)
+            .max();
+        let end = from_fix.or_else(|| {
+            let line = lines.get(start.line)?;
+            let byte = utf16_byte_offset(line, start.character);
+            let len = token_len(&line[byte..]);
+            (len > 0).then(|| start.character + line[byte..byte + len].encode_utf16().count())
+        });
+        if let Some(end) = end {
+            diag.range.end = Position { line: start.line, character: end };
+        }
+    }
+}
+
+fn is_single_char_range(range: &Range) -> bool {
+    range.start.line == range.end.line && range.end.character == range.start.character + 1
+}
+
+/// Punctuators longer than one character, longest first.
+const MULTI_CHAR_PUNCTUATORS: &[&str] = &[
+    "<<=", ">>=", "->*", "...", "<=>", "::", "->", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=",
+    "*=", "/=", "%=", "&=", "|=", "^=", ".*", "##",
+];
+
+/// Byte length of the token at the start of `rest`: an identifier or number,
+/// a string or character literal (with an encoding prefix), or a punctuator.
+fn token_len(rest: &str) -> usize {
+    let Some(first) = rest.chars().next() else {
+        return 0;
+    };
+    let word = |c: char| c.is_alphanumeric() || c == '_';
+    let literal_len = |quoted: &str| -> Option<usize> {
+        let quote = quoted.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let mut escaped = false;
+        for (idx, c) in quoted.char_indices().skip(1) {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == quote {
+                return Some(idx + 1);
+            }
+        }
+        None
+    };
+    if word(first) {
+        let number = first.is_ascii_digit();
+        let len = rest
+            .char_indices()
+            .find(|(_, c)| !(word(*c) || number && (*c == '.' || *c == '\'')))
+            .map_or(rest.len(), |(idx, _)| idx);
+        if matches!(&rest[..len], "L" | "u" | "U" | "u8") {
+            if let Some(literal) = literal_len(&rest[len..]) {
+                return len + literal;
+            }
+        }
+        return len;
+    }
+    if let Some(literal) = literal_len(rest) {
+        return literal;
+    }
+    MULTI_CHAR_PUNCTUATORS.iter().find(|p| rest.starts_with(**p)).map_or(first.len_utf8(), |p| p.len())
+}
+
+fn build_line_starts(text: &str) -> Vec<usize> {
+    let mut starts = vec![0];
+    for (i, b) in text.bytes().enumerate() {
+        if b == b'\n' {
+            starts.push(i + 1);
+        }
+    }
+    starts
+}
+
+fn offset_range(text: &str, line_starts: &[usize], offset: usize, length: usize) -> Range {
+    let (start_line, start_col) = offset_to_line_col(text, line_starts, offset);
+    let (end_line, end_col) = offset_to_line_col(text, line_starts, offset + length);
+    Range {
+        start: Position { line: start_line, character: start_col },
+        end: Position { line: end_line, character: end_col },
+    }
+}
+
+fn position_to_offset(text: &str, line_starts: &[usize], pos: &Position) -> usize {
+    let Some(&start) = line_starts.get(pos.line) else {
+        return text.len();
+    };
+    let end = line_starts.get(pos.line + 1).copied().unwrap_or(text.len());
+    start + utf16_byte_offset(&text[start..end], pos.character)
+}
+
+fn offset_to_line_col(text: &str, line_starts: &[usize], offset: usize) -> (usize, usize) {
+    let offset = offset.min(text.len());
+    let mut line = 0;
+    for (idx, &start) in line_starts.iter().enumerate() {
+        if start > offset {
+            break;
+        }
+        line = idx;
+    }
+    let line_start = line_starts.get(line).cloned().unwrap_or(0);
+    let slice = &text[line_start..offset];
+    let col = slice.encode_utf16().count();
+    (line, col)
+}
+
+/// One `<replacement>` from `clang-format --output-replacements-xml`: the
+/// byte range `offset..offset + length` of the input text and what replaces it.
+struct FormatReplacement {
+    offset: usize,
+    length: usize,
+    text: String,
+}
+
+const CLANG_FORMAT_TIMEOUT_MS: u64 = 30_000;
+
+/// Runs clang-format on `text` as if it were `file_path`, so the nearest
+/// `.clang-format` applies, restricted to the byte `ranges` and the 1-based
+/// inclusive `lines` when given. Returns the replacements and whether
+/// clang-format reported the input as only partially formattable (usually a
+/// syntax error).
+fn run_clang_format(
+    settings: &Settings,
+    file_path: &Path,
+    text: &str,
+    ranges: &[(usize, usize)],
+    lines: &[(usize, usize)],
+) -> Result<(Vec<FormatReplacement>, bool)> {
+    let mut cmd = Command::new(clang_format_binary(settings));
+    cmd.arg("--style=file")
+        .arg("--output-replacements-xml")
+        .arg(format!("--assume-filename={}", file_path.display()));
+    for (offset, length) in ranges {
+        cmd.arg(format!("--offset={offset}")).arg(format!("--length={length}"));
+    }
+    for (first, last) in lines {
+        cmd.arg(format!("--lines={first}:{last}"));
+    }
+    if let Some(dir) = file_path.parent() {
+        cmd.current_dir(dir);
+    }
+    let output = run_command_with_input(&mut cmd, Some(text), CLANG_FORMAT_TIMEOUT_MS)?;
+    if !output.status.success() {
+        anyhow::bail!("clang-format failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let xml = String::from_utf8_lossy(&output.stdout);
+    let incomplete = xml.contains("incomplete_format='true'");
+    let replacement = Regex::new(r"<replacement offset='(\d+)' length='(\d+)'>([^<]*)</replacement>").unwrap();
+    let replacements = replacement
+        .captures_iter(&xml)
+        .map(|caps| FormatReplacement {
+            offset: caps[1].parse().unwrap_or(0),
+            length: caps[2].parse().unwrap_or(0),
+            text: xml_unescape(&caps[3]),
+        })
+        .collect();
+    Ok((replacements, incomplete))
+}
+
+fn xml_unescape(text: &str) -> String {
+    let entity = Regex::new(r"&(#x[0-9A-Fa-f]+|#[0-9]+|lt|gt|amp|apos|quot);").unwrap();
+    entity
+        .replace_all(text, |caps: &regex::Captures| {
+            let name = &caps[1];
+            let decoded = match name {
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "amp" => Some('&'),
+                "apos" => Some('\''),
+                "quot" => Some('"'),
+                _ if name.starts_with("#x") => u32::from_str_radix(&name[2..], 16).ok().and_then(char::from_u32),
+                _ => name[1..].parse().ok().and_then(char::from_u32),
+            };
+            decoded.map(String::from).unwrap_or_else(|| caps[0].to_string())
+        })
+        .into_owned()
+}
+
+/// Formatting deviations reported like clang-tidy findings, one per
+/// replacement, each carrying its own edit as a cosmetic fix.
+fn format_diagnostics(file_path: &Path, edits: Vec<TextEdit>) -> Vec<RpcDiagnostic> {
+    edits
+        .into_iter()
+        .map(|edit| {
+            to_rpc_diagnostic(InternalDiagnostic {
+                file: file_path.to_path_buf(),
+                range: edit.range.clone(),
+                severity: "info".to_string(),
+                code: Some("clang-format".to_string()),
+                message: "Formatting does not match .clang-format".to_string(),
+                fixes: vec![Fix {
+                    title: "Apply clang-format".to_string(),
+                    edits: vec![edit],
+                    edit: None,
+                    kind: Some("quickfix".to_string()),
+                    safety: Some(FixSafety::Cosmetic),
+                    conflicts_with: Vec::new(),
+                }],
+                related: Vec::new(),
+            })
+        })
+        .collect()
+}
+
+fn resolve_path(path_str: &str, root_dir: Option<&Path>) -> Option<PathBuf> {
+    if path_str.is_empty() {
+        return None;
+    }
+    let p = PathBuf::from(path_str);
+    if p.is_absolute() {
+        Some(p)
+    } else if let Some(root) = root_dir {
+        Some(root.join(p))
+    } else {
+        Some(p)
+    }
+}
+
+/// mtime in nanoseconds and size of `path`.
+fn file_signature(path: &Path) -> Option<(u64, u64)> {
+    let meta = std::fs::metadata(path).ok()?;
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos() as u64;
+    Some((mtime, meta.len()))
+}
+
+/// xxh3 hash of the content of `path`, the cache's notion of file identity.
+fn file_content_hash(path: &Path) -> Option<u64> {
+    std::fs::read(path).ok().map(|data| xxh3_64(&data))
+}
+
+fn mtime_for_path(path: &Path) -> Option<u64> {
+    let meta = std::fs::metadata(path).ok()?;
+    meta.modified().ok()?.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+fn clang_tidy_config_mtime(root_dir: Option<&Path>, compile_dir: Option<&Path>) -> Option<u64> {
+    mtime_for_path(&clang_tidy_config_path(root_dir, compile_dir)?)
+}
+
+/// The `.clang-tidy` next to the compile database, else in the root.
+fn clang_tidy_config_path(root_dir: Option<&Path>, compile_dir: Option<&Path>) -> Option<PathBuf> {
+    [compile_dir, root_dir].into_iter().flatten().map(|dir| dir.join(".clang-tidy")).find(|p| p.exists())
+}
+
+/// `checkOptions` with every matching `checkOptionsOverrides` entry applied in
+/// order, so later overrides win.
+fn effective_check_options(settings: &Settings, file_path: &Path, root_dir: Option<&Path>) -> BTreeMap<String, String> {
+    let mut options = settings.checkOptions.clone();
+    if settings.checkOptionsOverrides.is_empty() {
+        return options;
+    }
+    for entry in &settings.checkOptionsOverrides {
+        if path_matches_globs(&entry.paths, file_path, root_dir) {
+            options.extend(entry.options.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+    }
+    options
+}
+
+/// Matches `file_path` against globs written relative to the workspace root
+/// or as absolute paths.
+fn path_matches_globs(globs: &[String], file_path: &Path, root_dir: Option<&Path>) -> bool {
+    let relative = root_dir
+        .and_then(|root| file_path.strip_prefix(root).ok())
+        .unwrap_or(file_path)
+        .to_string_lossy()
+        .replace('\\', "/");
+    let absolute = file_path.to_string_lossy().replace('\\', "/");
+    globs
+        .iter()
+        .filter_map(|glob| glob_to_regex(glob))
+        .any(|re| re.is_match(&relative) || re.is_match(&absolute))
+}
+
+fn glob_to_regex(glob: &str) -> Option<Regex> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.trim().trim_start_matches("./").chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    pattern.push_str("(.*/)?");
+                } else {
+                    pattern.push_str(".*");
+                }
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            _ => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).ok()
+}
+
+/// Inline `--config` that keeps the `.clang-tidy` files clang-tidy discovers
+/// for the source and layers the given options on top.
+fn inline_check_config(options: &BTreeMap<String, String>) -> String {
+    let check_options: Vec<Value> = options
+        .iter()
+        .map(|(key, value)| serde_json::json!({ "key": key, "value": value }))
+        .collect();
+    serde_json::json!({ "InheritParentConfig": true, "CheckOptions": check_options }).to_string()
+}
+
+/// Writes `base` (if any) with `options` merged into its `CheckOptions` to
+/// `dir`, for runs that pass an explicit `--config-file`.
+fn write_merged_config(base: Option<&Path>, options: &BTreeMap<String, String>, dir: &Path) -> Result<PathBuf> {
+    let mut config = match base {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+            serde_yaml::from_str::<serde_yaml::Value>(&text).unwrap_or(serde_yaml::Value::Null)
+        }
+        None => serde_yaml::Value::Null,
+    };
+    if !config.is_mapping() {
+        config = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+    }
+
+    let mut merged: BTreeMap<String, String> = BTreeMap::new();
+    let yaml_string = |v: &serde_yaml::Value| match v {
+        serde_yaml::Value::String(s) => Some(s.clone()),
+        serde_yaml::Value::Bool(b) => Some(b.to_string()),
+        serde_yaml::Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    };
+    match config.get("CheckOptions") {
+        Some(serde_yaml::Value::Sequence(items)) => {
+            for item in items {
+                if let (Some(key), Some(value)) = (item.get("key").and_then(yaml_string), item.get("value").and_then(yaml_string)) {
+                    merged.insert(key, value);
+                }
+            }
+        }
+        Some(serde_yaml::Value::Mapping(map)) => {
+            for (key, value) in map {
+                if let (Some(key), Some(value)) = (yaml_string(key), yaml_string(value)) {
+                    merged.insert(key, value);
+                }
+            }
+        }
+        _ => {}
+    }
+    merged.extend(options.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+    let check_options: Vec<serde_yaml::Value> = merged
+        .into_iter()
+        .map(|(key, value)| {
+            let mut entry = serde_yaml::Mapping::new();
+            entry.insert("key".into(), key.into());
+            entry.insert("value".into(), value.into());
+            serde_yaml::Value::Mapping(entry)
+        })
+        .collect();
+    if let serde_yaml::Value::Mapping(map) = &mut config {
+        map.insert("CheckOptions".into(), serde_yaml::Value::Sequence(check_options));
+    }
+
+    let path = dir.join(".clang-tidy");
+    std::fs::write(&path, serde_yaml::to_string(&config)?).context("Failed to write merged clang-tidy config")?;
+    Ok(path)
+}
+
+/// Directory that receives a run's exported fixes: `requested` (relative paths
+/// resolve against the workspace root) or `.vscode/clang-tidy-fixes/<runId>`.
+/// Files from an earlier export into the same directory are removed.
+fn prepare_export_dir(requested: &str, root_dir: Option<&Path>, run_id: &str) -> Result<PathBuf> {
+    let requested = requested.trim();
+    let dir = if !requested.is_empty() {
+        let path = PathBuf::from(requested);
+        match root_dir {
+            Some(root) if path.is_relative() => root.join(path),
+            _ => path,
+        }
+    } else {
+        let run_dir: String = run_id
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        root_dir
+            .context("exportFixes needs exportFixesDir or a workspace root")?
+            .join(".vscode")
+            .join("clang-tidy-fixes")
+            .join(run_dir)
+    };
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let exported_name = Regex::new(r"^[0-9a-f]{16}\.yaml$").unwrap();
+    if let Ok(entries) = std::fs::read_dir(&dir) {
+        for entry in entries.flatten() {
+            if exported_name.is_match(&entry.file_name().to_string_lossy()) {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+    Ok(dir)
+}
+
+/// Writes the fixes of `diags` as a clang-apply-replacements compatible YAML
+/// file. Returns `false` when the file has nothing to fix.
+fn write_exported_fixes(dir: &Path, file_path: &Path, diags: &[RpcDiagnostic]) -> Result<bool> {
+    let text = std::fs::read_to_string(file_path).unwrap_or_default();
+    let line_starts = build_line_starts(&text);
+    let to_offset = |pos: &Position| position_to_offset(&text, &line_starts, pos);
+    let file_name = file_path.to_string_lossy().to_string();
+    let file_uri = Url::from_file_path(file_path).map(|u| u.to_string()).unwrap_or_default();
+    let mut other_texts: HashMap<PathBuf, (String, Vec<usize>)> = HashMap::new();
+
+    let mut entries = Vec::new();
+    for diag in diags {
+        for fix in diag.fixes.iter().flatten().filter(|fix| !is_suppression_fix(fix)) {
+            let mut replacements: Vec<Value> = Vec::new();
+            for (uri, edits) in fix_file_edits(fix, &file_uri) {
+                let Some(path) = uri_to_path(&uri) else {
+                    continue;
+                };
+                let (text, starts) = if uri == file_uri {
+                    (&text, &line_starts)
+                } else {
+                    let (text, starts) = other_texts.entry(path.clone()).or_insert_with(|| {
+                        let text = std::fs::read_to_string(&path).unwrap_or_default();
+                        let starts = build_line_starts(&text);
+                        (text, starts)
+                    });
+                    (&*text, &*starts)
+                };
+                for edit in edits {
+                    let offset = position_to_offset(text, starts, &edit.range.start);
+                    let end = position_to_offset(text, starts, &edit.range.end).max(offset);
+                    replacements.push(serde_json::json!({
+                        "FilePath": path.to_string_lossy(),
+                        "Offset": offset,
+                        "Length": end - offset,
+                        "ReplacementText": edit.new_text,
+                    }));
+                }
+            }
+            if replacements.is_empty() {
+                continue;
+            }
+            entries.push(serde_json::json!({
+                "DiagnosticName": diag.code.clone().unwrap_or_default(),
+                "DiagnosticMessage": {
+                    "Message": diag.message,
+                    "FilePath": file_name,
+                    "FileOffset": to_offset(&diag.range.start),
+                    "Replacements": replacements,
+                },
+                "Level": "Warning",
+            }));
+        }
+    }
+    if entries.is_empty() {
+        return Ok(false);
+    }
+
+    let document = serde_json::json!({
+        "MainSourceFile": file_name,
+        "Diagnostics": entries,
+    });
+    let path = dir.join(format!("{:016x}.yaml", cache_key_for_path(file_path)));
+    std::fs::write(&path, serde_yaml::to_string(&document)?).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(true)
 }
 
-#[derive(Debug, Clone)]
-struct CompileCommandEntry {
-    file: String,
-    directory: String,
-    command: Option<String>,
-    arguments: Option<Vec<String>>,
+/// Options of an `applyFixesProject` run.
+struct FixCampaign {
+    run_id: String,
+    filters: Vec<Regex>,
+    include_behavior_changing: bool,
+    dry_run: bool,
+    /// clang-apply-replacements binary the merge and write is delegated to;
+    /// `None` merges in the daemon.
+    replacements_tool: Option<String>,
+    cancel: Arc<AtomicBool>,
 }
 
-fn main() -> Result<()> {
-    let stdin = io::stdin();
-    let stdout = Arc::new(Mutex::new(io::stdout()));
+/// One fix collected by a fix campaign. Its replacements can span several
+/// files (e.g. a rename reaching into a header).
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct ProjectFix {
+    /// (file, start, end, replacement); byte offsets, sorted.
+    edits: Vec<(PathBuf, usize, usize, String)>,
+    check: String,
+    message: String,
+    file: PathBuf,
+    offset: usize,
+}
 
-    let state = AppState {
-        settings: Arc::new(Mutex::new(Settings::default())),
-        root_dir: Arc::new(Mutex::new(None)),
-        compile_commands: Arc::new(Mutex::new(None)),
-        compile_index: Arc::new(Mutex::new(None)),
-        stdout: stdout.clone(),
-        cancel_map: Arc::new(Mutex::new(HashMap::new())),
-        cache: Arc::new(Mutex::new(HashMap::new())),
+/// Every fix in a clang-tidy export-fixes file, with paths resolved against
+/// `base_dir` and canonicalized so fixes from different TUs compare equal.
+fn read_project_fixes(fixes_path: &Path, base_dir: Option<&Path>, keep_aliases: bool) -> Result<Vec<ProjectFix>> {
+    let content = std::fs::read_to_string(fixes_path).unwrap_or_default();
+    if content.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let fixes: FixesFile = serde_yaml::from_str(&content).context("Failed to parse fixes YAML")?;
+    let resolve = |raw: &str| {
+        resolve_path(raw, base_dir).map(|path| std::fs::canonicalize(&path).unwrap_or(path))
     };
-
-    for line in stdin.lock().lines() {
-        let line = match line {
-            Ok(l) => l,
-            Err(_) => continue,
+    let mut result = Vec::new();
+    for diag in &fixes.diagnostics {
+        let (message, file_path, offset, replacements) = diag.parts();
+        let Some(file) = resolve(&file_path) else {
+            continue;
         };
-        if line.trim().is_empty() {
+        let mut edits: Vec<(PathBuf, usize, usize, String)> = replacements
+            .into_iter()
+            .map(|rep| {
+                let path = resolve(&rep.file_path).unwrap_or_else(|| file.clone());
+                (path, rep.offset, rep.offset + rep.length, rep.replacement_text)
+            })
+            .collect();
+        if edits.is_empty() {
             continue;
         }
+        edits.sort();
+        let name = diag.diagnostic_name.clone().unwrap_or_default();
+        result.push(ProjectFix {
+            edits,
+            check: if keep_aliases { name } else { canonical_check(&name) },
+            message,
+            file,
+            offset,
+        });
+    }
+    Ok(result)
+}
 
-        let value: Value = match serde_json::from_str(&line) {
-            Ok(v) => v,
-            Err(err) => {
-                send_notification(&stdout, "log", LogParams {
-                    level: "error",
-                    message: format!("Failed to parse JSON: {err}"),
-                })?;
-                continue;
-            }
-        };
-
-        let method = value.get("method").and_then(|m| m.as_str()).map(|s| s.to_string());
-        let id = value.get("id").cloned();
-        let params = value.get("params").cloned().unwrap_or(Value::Null);
-
-        if let Some(method) = method {
-            if let Some(id) = id {
-                let response = handle_request(&method, params, &state);
-                match response {
-                    Ok(result) => {
-                        let resp = JsonRpcResponse { jsonrpc: "2.0", id, result };
-                        write_json(&stdout, &resp)?;
+/// Runs clang-tidy on every TU and collects their fixes, like
+/// `clang-apply-replacements` over a directory of exported fixes: duplicates
+/// (the same replacements reached through several TUs or alias checks) are
+/// applied once, and conflicting fixes are resolved as in
+/// `resolve_fix_conflicts`, in order of file, position and check name. Each
+/// touched file is written atomically unless it changed after clang-tidy read
+/// it; fixes touching such a file are skipped entirely.
+#[allow(clippy::too_many_arguments)]
+fn run_fix_campaign(
+    settings: &Settings,
+    root_dir: Option<&Path>,
+    compile_commands: &Path,
+    files: &[PathBuf],
+    campaign: &FixCampaign,
+    writes: &Mutex<FixJournal>,
+    scheduler: &Scheduler,
+    resources: &Arc<Mutex<ResourceStats>>,
+    progress: &(dyn Fn(String, Option<u32>) + Sync),
+) -> Value {
+    let total = files.len();
+    let collected: Mutex<Vec<ProjectFix>> = Mutex::new(Vec::new());
+    let signatures: Mutex<HashMap<PathBuf, Option<(u64, u64)>>> = Mutex::new(HashMap::new());
+    let failures = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let compile_dir = compile_commands.parent();
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..settings.maxWorkers.max(1).min(total.max(1) as u32) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= total || campaign.cancel.load(Ordering::Relaxed) {
+                    break;
+                }
+                let file_path = &files[i];
+                // Project runs and the editor's analyses share the slots.
+                let permit = scheduler.acquire_for(Priority::Batch, &campaign.run_id);
+                if campaign.cancel.load(Ordering::Relaxed) {
+                    break;
+                }
+                let result = new_fixes_file().map_err(anyhow::Error::from).and_then(|temp| {
+                    let mut cmd =
+                        tidy_command(&clang_tidy_binary(settings), file_path, settings, root_dir, compile_dir, temp.path(), "full");
+                    run_measured(&mut cmd, file_timeout_ms(settings, "full"), resources, file_path, "full")?;
+                    read_project_fixes(temp.path(), compile_dir.or(root_dir), settings.keepCheckAliases)
+                });
+                drop(permit);
+                match result {
+                    Ok(fixes) => {
+                        // Offsets are only valid for the text clang-tidy read.
+                        let mut seen = signatures.lock().unwrap();
+                        for (path, ..) in fixes.iter().flat_map(|fix| fix.edits.iter()) {
+                            seen.entry(path.clone()).or_insert_with(|| file_signature(path));
+                        }
+                        drop(seen);
+                        collected.lock().unwrap().extend(fixes);
                     }
-                    Err(err) => {
-                        let resp = JsonRpcErrorResponse {
-                            jsonrpc: "2.0",
-                            id,
-                            error: JsonRpcError { code: -32000, message: err.to_string() },
-                        };
-                        write_json(&stdout, &resp)?;
+                    Err(_) => {
+                        failures.fetch_add(1, Ordering::Relaxed);
                     }
                 }
+                let finished = done.fetch_add(1, Ordering::Relaxed) + 1;
+                if finished == total || finished.is_multiple_of(10) {
+                    let percent = (finished as f64 / total as f64 * 100.0) as u32;
+                    progress(format!("Collected fixes from {finished}/{total} files"), Some(percent));
+                }
+            });
+        }
+    });
+
+    let mut summary = serde_json::json!({
+        "files": total,
+        "failures": failures.load(Ordering::Relaxed),
+        "dryRun": campaign.dry_run,
+    });
+    if campaign.cancel.load(Ordering::Relaxed) {
+        summary["cancelled"] = Value::Bool(true);
+        return summary;
+    }
+
+    let mut fixes = collected.into_inner().unwrap();
+    fixes.retain(|fix| {
+        check_fixable(settings, &fix.check)
+            && (campaign.filters.is_empty() || campaign.filters.iter().any(|re| re.is_match(&fix.check)))
+            && (campaign.include_behavior_changing || classify_fix(Some(&fix.check)).1 != FixSafety::BehaviorChanging)
+    });
+    fixes.sort();
+    let found = fixes.len();
+    fixes.dedup_by(|later, earlier| later.edits == earlier.edits);
+    let duplicates = found - fixes.len();
+    fixes.sort_by(|a, b| {
+        (&a.edits[0].0, a.edits[0].1, &a.check, &a.message).cmp(&(&b.edits[0].0, b.edits[0].1, &b.check, &b.message))
+    });
+
+    let signatures = signatures.into_inner().unwrap();
+    let modified: HashSet<&PathBuf> = signatures.iter().filter(|(path, sig)| file_signature(path) != **sig).map(|(path, _)| path).collect();
+    let describe = |fix: &ProjectFix| {
+        serde_json::json!({
+            "check": fix.check,
+            "message": fix.message,
+            "fileUri": Url::from_file_path(&fix.file).map(|u| u.to_string()).unwrap_or_default(),
+            "offset": fix.offset,
+        })
+    };
+    if let (Some(tool), false) = (&campaign.replacements_tool, campaign.dry_run) {
+        let mut skipped = Vec::new();
+        let mut passed: Vec<&ProjectFix> = Vec::new();
+        for fix in &fixes {
+            if fix.edits.iter().any(|(path, ..)| modified.contains(path)) {
+                let mut entry = describe(fix);
+                entry["reason"] = Value::String("fileModified".to_string());
+                skipped.push(entry);
             } else {
-                handle_notification(&method, params, &state)?;
+                passed.push(fix);
             }
         }
+        summary["fixes"] = serde_json::json!(fixes.len());
+        summary["duplicates"] = serde_json::json!(duplicates);
+        summary["applyWith"] = Value::String("clang-apply-replacements".to_string());
+        summary["skipped"] = Value::Array(skipped);
+        match apply_with_replacements_tool(tool, &passed, settings.formatAfterFix, progress) {
+            Ok(run) => {
+                summary["changedFiles"] = run
+                    .changed
+                    .iter()
+                    .filter_map(|file| Url::from_file_path(&file.path).ok())
+                    .map(|u| Value::String(u.to_string()))
+                    .collect();
+                summary["exitCode"] = serde_json::json!(run.exit_code);
+                summary["messages"] = serde_json::json!(run.messages);
+                writes.lock().unwrap().record(&campaign.run_id, run.changed);
+            }
+            Err(err) => {
+                summary["changedFiles"] = Value::Array(Vec::new());
+                summary["writeErrors"] = serde_json::json!([{ "message": format!("{err:#}") }]);
+            }
+        }
+        return summary;
     }
 
-    Ok(())
-}
+    let mut accepted: Vec<&ProjectFix> = Vec::new();
+    let mut taken: HashMap<&PathBuf, Vec<(usize, usize, String)>> = HashMap::new();
+    let mut skipped = Vec::new();
+    for fix in &fixes {
+        if fix.edits.iter().any(|(path, ..)| modified.contains(path)) {
+            let mut entry = describe(fix);
+            entry["reason"] = Value::String("fileModified".to_string());
+            skipped.push(entry);
+            continue;
+        }
+        let conflict = fix.edits.iter().find_map(|(path, start, end, text)| {
+            let edit = (*start, *end, text.clone());
+            let position = taken.get(path)?.iter().position(|other| edits_conflict(other, &edit))?;
+            Some((path, position))
+        });
+        if let Some((path, position)) = conflict {
+            let (start, end, text) = &taken[path][position];
+            let winner = accepted.iter().find(|taken| taken.edits.iter().any(|e| (&e.0, e.1, e.2, &e.3) == (path, *start, *end, text)));
+            let mut entry = describe(fix);
+            entry["reason"] = Value::String("conflict".to_string());
+            entry["conflictsWith"] = winner.map(|winner| describe(winner)).unwrap_or(Value::Null);
+            skipped.push(entry);
+            continue;
+        }
+        for (path, start, end, text) in &fix.edits {
+            taken.entry(path).or_default().push((*start, *end, text.clone()));
+        }
+        accepted.push(fix);
+    }
 
-fn handle_request(method: &str, params: Value, state: &AppState) -> Result<Value> {
-    match method {
-        "initialize" => {
-            if let Some(root_uri) = params.get("rootUri").and_then(|v| v.as_str()) {
-                if let Some(path) = uri_to_path(root_uri) {
-                    *state.root_dir.lock().unwrap() = Some(path);
-                }
-            }
-            if let Some(s) = params.get("settings") {
-                if let Ok(parsed) = serde_json::from_value::<Settings>(s.clone()) {
-                    *state.settings.lock().unwrap() = parsed;
-                }
+    let mut changed_files = Vec::new();
+    let mut updates = Vec::new();
+    let mut write_errors = Vec::new();
+    let mut paths: Vec<&&PathBuf> = taken.keys().collect();
+    paths.sort();
+    for path in paths {
+        let mut edits = taken[*path].clone();
+        edits.sort();
+        edits.dedup();
+        let uri = Url::from_file_path(path).map(|u| u.to_string()).unwrap_or_default();
+        if campaign.dry_run {
+            changed_files.push(Value::String(uri));
+            continue;
+        }
+        let updated = std::fs::read_to_string(path).map_err(anyhow::Error::from).and_then(|text| {
+            if edits.iter().any(|(start, end, _)| *end > text.len() || !text.is_char_boundary(*start) || !text.is_char_boundary(*end)) {
+                anyhow::bail!("replacement outside the file");
             }
-            *state.compile_commands.lock().unwrap() = None;
-            *state.compile_index.lock().unwrap() = None;
-            let result = serde_json::json!({
-                "server": {"name": "clang-tidy-daemon", "version": "0.1.0"},
-                "capabilities": {"analyzeFile": true, "analyzeProject": true, "cancel": true},
-                "pid": std::process::id(),
-            });
-            Ok(result)
+            let spans: Vec<(usize, usize, &str)> = edits.iter().map(|(start, end, new_text)| (*start, *end, new_text.as_str())).collect();
+            let (updated, edited) = splice_edits(&text, &spans);
+            Ok((text, format_fixed_text(settings, path, updated, &edited)))
+        });
+        match updated {
+            Ok((text, updated)) => updates.push(((*path).clone(), uri, text, updated)),
+            Err(err) => write_errors.push(serde_json::json!({ "fileUri": uri, "message": err.to_string() })),
         }
-        "shutdown" => Ok(serde_json::json!({})),
-        "ping" => Ok(serde_json::json!({ "ok": true })),
-        "analyzeFile" => {
-            let run_id = params.get("runId").cloned().unwrap_or(Value::String("unknown".to_string()));
-            let file_uri = params.get("fileUri").and_then(|v| v.as_str()).unwrap_or("");
-            let mode = params.get("mode").and_then(|v| v.as_str()).unwrap_or("full").to_string();
-            let file_content = params.get("fileContent").and_then(|v| v.as_str()).map(|s| s.to_string());
-            let file_path = uri_to_path(file_uri).context("Invalid fileUri")?;
+    }
 
-            let settings = state.settings.lock().unwrap().clone();
-            let root_dir = state.root_dir.lock().unwrap().clone();
-            let compile_commands = resolve_compile_commands_path(&settings, root_dir.as_deref(), state);
-            let compile_index = match compile_commands.as_deref() {
-                Some(path) => match get_compile_index(path, state) {
-                    Ok(index) => Some(index),
-                    Err(err) => {
-                        let _ = send_notification(&state.stdout, "log", LogParams {
-                            level: "warn",
-                            message: format!("Failed to load compile_commands.json index: {err}"),
-                        });
-                        None
-                    }
-                },
-                None => None,
-            };
+    // Journaled before any write, so the files written before a failure can be reverted.
+    writes.lock().unwrap().record(
+        &campaign.run_id,
+        updates
+            .iter()
+            .map(|(path, _, before, updated)| JournalFile { path: path.clone(), before: before.clone(), after: xxh3_64(updated.as_bytes()) })
+            .collect(),
+    );
+    for (path, uri, before, updated) in updates {
+        // Locked per file, so other fix writes and reverts get in between;
+        // a file one of them changed since it was read is left alone.
+        let mut journal = writes.lock().unwrap();
+        if file_content_hash(&path) != Some(xxh3_64(before.as_bytes())) {
+            journal.forget_file(&campaign.run_id, &path);
+            write_errors.push(serde_json::json!({ "fileUri": uri, "message": "File changed while the fixes were collected" }));
+            continue;
+        }
+        match write_file_atomic(&path, updated.as_bytes()) {
+            Ok(()) => changed_files.push(Value::String(uri)),
+            Err(err) => write_errors.push(serde_json::json!({ "fileUri": uri, "message": err.to_string() })),
+        }
+    }
+    if !write_errors.is_empty() {
+        writes.lock().unwrap().discard(&campaign.run_id);
+    }
+    summary["fixes"] = serde_json::json!(fixes.len());
+    summary["applied"] = serde_json::json!(accepted.len());
+    summary["duplicates"] = serde_json::json!(duplicates);
+    summary["changedFiles"] = Value::Array(changed_files);
+    summary["skipped"] = Value::Array(skipped);
+    if !write_errors.is_empty() {
+        summary["writeErrors"] = Value::Array(write_errors);
+    }
+    summary
+}
 
-            if let Some(index) = compile_index.as_ref() {
-                if !file_in_index(&file_path, index) {
-                    let result = serde_json::json!({
-                        "runId": run_id,
-                        "fileUri": file_uri,
-                        "diagnostics": [],
-                    });
-                    return Ok(result);
+/// Outcome of a `clang-apply-replacements` run.
+struct ReplacementsRun {
+    changed: Vec<JournalFile>,
+    exit_code: Option<i32>,
+    messages: Vec<String>,
+}
+
+/// Exports `fixes` as one YAML file per diagnosed file into a temp directory
+/// and runs `clang-apply-replacements` over it, which merges them, drops
+/// duplicates, reports conflicts and rewrites the files. Each line it prints
+/// is reported as progress. Changed files are found by comparing contents,
+/// since the tool does not list them. `format` has the tool run clang-format
+/// over the replaced code.
+fn apply_with_replacements_tool(
+    tool: &str,
+    fixes: &[&ProjectFix],
+    format: bool,
+    progress: &(dyn Fn(String, Option<u32>) + Sync),
+) -> Result<ReplacementsRun> {
+    let dir = tempfile::Builder::new()
+        .prefix(&temp_artifact_prefix())
+        .tempdir()
+        .context("Failed to create fixes export directory")?;
+    let mut by_file: BTreeMap<&PathBuf, Vec<&ProjectFix>> = BTreeMap::new();
+    for fix in fixes {
+        by_file.entry(&fix.file).or_default().push(fix);
+    }
+    for (i, (file, file_fixes)) in by_file.iter().enumerate() {
+        let file_name = file.to_string_lossy();
+        let entries: Vec<Value> = file_fixes
+            .iter()
+            .map(|fix| {
+                let replacements: Vec<Value> = fix
+                    .edits
+                    .iter()
+                    .map(|(path, start, end, text)| {
+                        serde_json::json!({
+                            "FilePath": path.to_string_lossy(),
+                            "Offset": start,
+                            "Length": end - start,
+                            "ReplacementText": text,
+                        })
+                    })
+                    .collect();
+                serde_json::json!({
+                    "DiagnosticName": fix.check,
+                    "DiagnosticMessage": {
+                        "Message": fix.message,
+                        "FilePath": file_name,
+                        "FileOffset": fix.offset,
+                        "Replacements": replacements,
+                    },
+                    "Level": "Warning",
+                })
+            })
+            .collect();
+        let document = serde_json::json!({ "MainSourceFile": file_name, "Diagnostics": entries });
+        let path = dir.path().join(format!("{i}.yaml"));
+        std::fs::write(&path, serde_yaml::to_string(&document)?).with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+
+    let mut paths: Vec<&PathBuf> = fixes.iter().flat_map(|fix| fix.edits.iter().map(|(path, ..)| path)).collect();
+    paths.sort();
+    paths.dedup();
+    let before: Vec<Option<String>> = paths.iter().map(|path| std::fs::read_to_string(path).ok()).collect();
+
+    progress(format!("Applying {} fixes with clang-apply-replacements", fixes.len()), None);
+    let mut cmd = Command::new(tool);
+    if format {
+        cmd.arg("--format").arg("--style=file");
+    }
+    let mut child = cmd
+        .arg(dir.path())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {tool}"))?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let messages: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        let forward = |stream: Box<dyn Read + Send>| {
+            for line in io::BufReader::new(stream).lines().map_while(|line| line.ok()) {
+                if line.trim().is_empty() {
+                    continue;
                 }
+                progress(line.clone(), None);
+                messages.lock().unwrap().push(line);
             }
-            let diags = if let Some(content) = file_content {
-                analyze_file_with_content(
-                    &file_path,
-                    &content,
-                    &settings,
-                    root_dir.as_deref(),
-                    compile_commands.as_deref(),
-                    compile_index.as_deref(),
-                    mode.as_str(),
-                )
-                .unwrap_or_else(|_| {
-                    analyze_file(
-                        &file_path,
-                        &settings,
-                        root_dir.as_deref(),
-                        compile_commands.as_deref(),
-                        mode.as_str(),
-                        &state.cache,
-                    )
-                    .unwrap_or_default()
-                })
-            } else {
-                analyze_file(
-                    &file_path,
-                    &settings,
-                    root_dir.as_deref(),
-                    compile_commands.as_deref(),
-                    mode.as_str(),
-                    &state.cache,
-                )?
-            };
-            let result = serde_json::json!({
-                "runId": run_id,
-                "fileUri": file_uri,
-                "diagnostics": diags,
-            });
-            Ok(result)
+        };
+        if let Some(out) = stdout {
+            scope.spawn(move || forward(Box::new(out)));
         }
-        "analyzeProject" => {
-            let run_id = params.get("runId").and_then(|v| v.as_str()).unwrap_or("unknown").to_string();
-            let mode = params.get("mode").and_then(|v| v.as_str()).unwrap_or("full").to_string();
-            let incremental = params.get("incremental").and_then(|v| v.as_bool()).unwrap_or(true);
-            let batch_size = params.get("batchSize").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
-            let settings = state.settings.lock().unwrap().clone();
-            let root_dir = state.root_dir.lock().unwrap().clone();
-            let stdout = state.stdout.clone();
-            let compile_commands = resolve_compile_commands_path(&settings, root_dir.as_deref(), state);
-            let cache = state.cache.clone();
-            let compile_index = match compile_commands.as_deref() {
-                Some(path) => match get_compile_index(path, state) {
-                    Ok(index) => Some(index),
-                    Err(err) => {
-                        let _ = send_notification(&stdout, "log", LogParams {
-                            level: "error",
-                            message: format!("Failed to load compile_commands.json index: {err}"),
-                        });
-                        None
-                    }
-                },
-                None => None,
-            };
-
-            let cancel_flag = Arc::new(AtomicBool::new(false));
-            state.cancel_map.lock().unwrap().insert(run_id.clone(), cancel_flag.clone());
+        if let Some(err) = stderr {
+            scope.spawn(move || forward(Box::new(err)));
+        }
+    });
+    let status = child.wait().with_context(|| format!("Failed to wait for {tool}"))?;
+
+    let changed = paths
+        .iter()
+        .zip(before)
+        .filter_map(|(path, before)| {
+            let after = std::fs::read_to_string(path).ok()?;
+            let before = before?;
+            (after != before).then(|| JournalFile { path: (*path).clone(), before, after: xxh3_64(after.as_bytes()) })
+        })
+        .collect();
+    Ok(ReplacementsRun {
+        changed,
+        exit_code: status.code(),
+        messages: messages.into_inner().unwrap(),
+    })
+}
 
-            let run_id_thread = run_id.clone();
-            thread::spawn(move || {
-                let run_id_for_tasks = run_id_thread.clone();
-                let _ = send_notification(&stdout, "progress", serde_json::json!({
-                    "runId": run_id_thread,
-                    "kind": "begin",
-                    "message": format!("Starting project analysis ({mode})")
-                }));
+/// Include graph for the current compile database, rebuilt when the database
+/// changed since the last build.
+fn current_include_graph(state: &AppState) -> Result<Arc<IncludeGraph>> {
+    let settings = state.settings.lock().unwrap().clone();
+    let root_dir = state.root_dir.lock().unwrap().clone();
+    let compile_commands =
+        resolve_compile_commands_path(&settings, root_dir.as_deref(), state).context("compile_commands.json not found")?;
+    let index = get_compile_index(&compile_commands, state)?;
+    if let Some(graph) = state.include_graph.lock().unwrap().as_ref() {
+        if graph.index_path == index.path && graph.index_mtime == index.mtime {
+            return Ok(graph.clone());
+        }
+    }
+    let graph = Arc::new(build_include_graph(&index, &settings));
+    *state.include_graph.lock().unwrap() = Some(graph.clone());
+    Ok(graph)
+}
 
-                let compile_commands = match compile_commands {
-                    Some(p) => p,
-                    None => {
-                        let _ = send_notification(&stdout, "log", LogParams {
-                            level: "error",
-                            message: "compile_commands.json not found".to_string(),
-                        });
-                        return;
-                    }
-                };
+fn build_include_graph(index: &CompileCommandsIndex, settings: &Settings) -> IncludeGraph {
+    let include_re = Regex::new(r#"(?m)^\s*#\s*include\s*([<"])([^>"]+)[>"]"#).unwrap();
+    let mut graph = IncludeGraph {
+        index_path: index.path.clone(),
+        index_mtime: index.mtime,
+        ..IncludeGraph::default()
+    };
 
-                let mut files: Vec<PathBuf> = if let Some(list) = params.get("files").and_then(|v| v.as_array()) {
-                    let mut override_files = Vec::new();
-                    for entry in list {
-                        if let Some(raw) = entry.as_str() {
-                            if let Ok(url) = Url::parse(raw) {
-                                if let Ok(path) = url.to_file_path() {
-                                    override_files.push(path);
-                                    continue;
-                                }
-                            }
-                            override_files.push(PathBuf::from(raw));
-                        }
-                    }
-                    override_files
-                } else if let Some(index) = compile_index.as_ref() {
-                    index.files.clone()
-                } else {
-                    match load_project_files(&compile_commands) {
-                        Ok(v) => v,
-                        Err(err) => {
-                            let _ = send_notification(&stdout, "log", LogParams {
-                                level: "error",
-                                message: format!("Failed to load compile_commands.json: {err}"),
-                            });
-                            return;
+    for tu in &index.files {
+        let Some(entry) = index.commands.get(tu) else {
+            continue;
+        };
+        let (quote_dirs, angle_dirs) = include_dirs(entry, settings);
+        let mut seen: HashSet<PathBuf> = HashSet::new();
+        let mut stack = vec![tu.clone()];
+        while let Some(file) = stack.pop() {
+            if !seen.insert(file.clone()) {
+                continue;
+            }
+            if file != *tu {
+                *graph.fan_in.entry(file.clone()).or_default() += 1;
+            }
+            if !graph.includes.contains_key(&file) {
+                let text = std::fs::read_to_string(&file).unwrap_or_default();
+                let mut targets = Vec::new();
+                for caps in include_re.captures_iter(&text) {
+                    let name = &caps[2];
+                    let quoted = &caps[1] == "\"";
+                    let local = file.parent().filter(|_| quoted).map(Path::to_path_buf);
+                    let candidates = local.iter().chain(quoted.then_some(&quote_dirs).into_iter().flatten()).chain(angle_dirs.iter());
+                    if let Some(found) = candidates.map(|dir| dir.join(name)).find(|p| p.is_file()) {
+                        let found = std::fs::canonicalize(&found).unwrap_or(found);
+                        if !targets.contains(&found) {
+                            targets.push(found);
                         }
                     }
-                };
-
-                if incremental {
-                    let compile_dir = compile_commands.parent();
-                    let compile_commands_mtime = mtime_for_path(&compile_commands);
-                    let config_mtime = clang_tidy_config_mtime(root_dir.as_deref(), compile_dir);
-                    let settings_hash = settings_fingerprint(&settings, Some(&compile_commands), compile_commands_mtime, config_mtime, mode.as_str());
-                    let cache_dir = resolve_cache_dir(&settings, root_dir.as_deref(), compile_dir);
-                    files.retain(|file_path| !is_cached(file_path, &cache, settings_hash, cache_dir.as_deref()));
                 }
-
-                let total = files.len();
-                if total == 0 {
-                    let _ = send_notification(&stdout, "progress", serde_json::json!({
-                        "runId": run_id_thread,
-                        "kind": "end",
-                        "message": if incremental { "No changed files to analyze" } else { "No files found in compile_commands.json" }
-                    }));
-                    return;
+                for target in &targets {
+                    graph.includers.entry(target.clone()).or_default().push(file.clone());
                 }
+                graph.includes.insert(file.clone(), targets);
+            }
+            stack.extend(graph.includes[&file].iter().cloned());
+        }
+    }
+    graph
+}
 
-                let throttle_ms = settings.publishDiagnosticsThrottleMs;
-                let limiter = Arc::new(Mutex::new(Instant::now() - Duration::from_millis(throttle_ms)));
+/// Include search paths of a compile entry: (`-iquote` dirs, `-I`/`-isystem`
+/// dirs), resolved against the entry's directory.
+fn include_dirs(entry: &CompileCommandEntry, settings: &Settings) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut args = resolve_arguments(entry, settings).unwrap_or_default();
+    if is_cl_driver(&args) {
+        args = translate_cl_args(args.get(1..).unwrap_or_default());
+    }
+    let base = PathBuf::from(&entry.directory);
+    let mut quote_dirs = Vec::new();
+    let mut angle_dirs = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let (flag, value) = ["-iquote", "-isystem", "-I", "/I"]
+            .iter()
+            .find_map(|flag| arg.strip_prefix(flag).map(|rest| (*flag, rest)))
+            .unwrap_or(("", ""));
+        if flag.is_empty() {
+            continue;
+        }
+        let value = if value.is_empty() {
+            match iter.next() {
+                Some(next) => next.as_str(),
+                None => break,
+            }
+        } else {
+            value
+        };
+        let dir = base.join(value);
+        if flag == "-iquote" {
+            quote_dirs.push(dir);
+        } else {
+            angle_dirs.push(dir);
+        }
+    }
+    (quote_dirs, angle_dirs)
+}
 
-                let pool = ThreadPool::new(settings.maxWorkers.max(1) as usize);
-                let done = Arc::new(AtomicUsize::new(0));
-                let effective_batch = if batch_size == 0 { total } else { batch_size.max(1) };
-                for chunk in files.chunks(effective_batch) {
-                    for file_path in chunk.iter().cloned() {
-                        let cancel = cancel_flag.clone();
-                        let stdout = stdout.clone();
-                        let settings = settings.clone();
-                        let root_dir = root_dir.clone();
-                        let compile_commands = compile_commands.clone();
-                        let done = done.clone();
-                        let run_id = run_id_for_tasks.clone();
-                        let cache = cache.clone();
-                        let mode = mode.clone();
-                        let limiter = limiter.clone();
+/// Scores translation units by the fan-in of the most widely included header
+/// they reach.
+fn include_fan_in_scores(files: &[PathBuf], graph: &IncludeGraph) -> HashMap<PathBuf, usize> {
+    files
+        .iter()
+        .map(|file| {
+            let score = walk_include_edges(&graph.includes, file, true)
+                .iter()
+                .filter_map(|header| graph.fan_in.get(header))
+                .copied()
+                .max()
+                .unwrap_or(0);
+            (file.clone(), score)
+        })
+        .collect()
+}
+
+/// Moves `priority` files (open editors) to the front in their given order,
+/// followed by the other files in their directories. The rest keeps its
+/// order. Only files already in `files` are scheduled.
+fn order_by_priority_files(files: &mut [PathBuf], priority: &[PathBuf]) {
+    let mut ranks: HashMap<PathBuf, usize> = HashMap::new();
+    let mut dirs: HashSet<PathBuf> = HashSet::new();
+    for (rank, file) in priority.iter().enumerate() {
+        let canonical = std::fs::canonicalize(file).ok();
+        for path in std::iter::once(normalize_path(file)).chain(canonical) {
+            if let Some(dir) = path.parent() {
+                dirs.insert(dir.to_path_buf());
+            }
+            ranks.entry(path).or_insert(rank);
+        }
+    }
+    files.sort_by_cached_key(|file| {
+        let file = normalize_path(file);
+        match ranks.get(&file) {
+            Some(rank) => (0, *rank),
+            None if file.parent().is_some_and(|dir| dirs.contains(dir)) => (1, 0),
+            None => (2, 0),
+        }
+    });
+}
+
+/// Files reachable from `start` along `edges` (one step unless `transitive`).
+fn walk_include_edges(edges: &HashMap<PathBuf, Vec<PathBuf>>, start: &Path, transitive: bool) -> Vec<PathBuf> {
+    let mut result = Vec::new();
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    let mut stack: Vec<PathBuf> = edges.get(start).cloned().unwrap_or_default();
+    stack.reverse();
+    while let Some(file) = stack.pop() {
+        if file == start || !seen.insert(file.clone()) {
+            continue;
+        }
+        result.push(file.clone());
+        if transitive {
+            if let Some(next) = edges.get(&file) {
+                stack.extend(next.iter().rev().cloned());
+            }
+        }
+    }
+    result
+}
+
+/// Text edits of a fix per document URI (the file itself as `file_uri`),
+/// regardless of the negotiated fix format.
+fn fix_file_edits(fix: &Fix, file_uri: &str) -> Vec<(String, Vec<TextEdit>)> {
+    let mut files: Vec<(String, Vec<TextEdit>)> = Vec::new();
+    for change in fix_workspace_edit(fix, file_uri, None).document_changes {
+        let DocumentChange::Edit(doc_edit) = change else {
+            continue;
+        };
+        match files.iter_mut().find(|(uri, _)| *uri == doc_edit.text_document.uri) {
+            Some((_, edits)) => edits.extend(doc_edit.edits),
+            None => files.push((doc_edit.text_document.uri, doc_edit.edits)),
+        }
+    }
+    files.retain(|(_, edits)| !edits.is_empty());
+    files
+}
+
+/// Content hashes of the files other than `file_path` that fixes of
+/// `diagnostics` edit.
+fn fix_file_hashes(file_path: &Path, diagnostics: &[RpcDiagnostic]) -> Vec<(PathBuf, u64)> {
+    let file_uri = Url::from_file_path(file_path).map(|u| u.to_string()).unwrap_or_default();
+    let mut paths: Vec<PathBuf> = diagnostics
+        .iter()
+        .flat_map(|d| d.fixes.iter().flatten())
+        .flat_map(|fix| fix_file_edits(fix, &file_uri))
+        .filter_map(|(uri, _)| uri_to_path(&uri))
+        .filter(|path| path != file_path)
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths.into_iter().filter_map(|path| file_content_hash(&path).map(|hash| (path, hash))).collect()
+}
 
-                        pool.execute(move || {
-                            if cancel.load(Ordering::Relaxed) {
-                                return;
-                            }
+/// Edits of a fix to the file itself (`file_uri`), in either fix format.
+fn own_file_edits(fix: &Fix, file_uri: &str) -> Vec<TextEdit> {
+    fix_file_edits(fix, file_uri)
+        .into_iter()
+        .filter(|(uri, _)| uri == file_uri)
+        .flat_map(|(_, edits)| edits)
+        .collect()
+}
 
-                            let diags = match analyze_file(
-                                &file_path,
-                                &settings,
-                                root_dir.as_deref(),
-                                Some(&compile_commands),
-                                mode.as_str(),
-                                &cache,
-                            ) {
-                                Ok(d) => d,
-                                Err(err) => {
-                                    let _ = send_notification(&stdout, "log", LogParams {
-                                        level: "error",
-                                        message: format!("clang-tidy failed for {}: {err}", file_path.display()),
-                                    });
-                                    Vec::new()
-                                }
-                            };
+/// Applies `edits` (all relative to `text`). Overlapping edits are rejected.
+/// Also returns the byte ranges of the replacements in the updated text.
+fn apply_text_edits(text: &str, edits: &[TextEdit]) -> Result<(String, Vec<(usize, usize)>)> {
+    let line_starts = build_line_starts(text);
+    let mut spans: Vec<(usize, usize, &str)> = Vec::new();
+    for edit in edits {
+        let start = position_to_offset(text, &line_starts, &edit.range.start);
+        let end = position_to_offset(text, &line_starts, &edit.range.end);
+        if end < start {
+            anyhow::bail!("Edit range ends before it starts");
+        }
+        spans.push((start, end, &edit.new_text));
+    }
+    spans.sort_by_key(|(start, end, _)| (*start, *end));
+    if spans.windows(2).any(|pair| pair[1].0 < pair[0].1) {
+        anyhow::bail!("Fix has overlapping edits");
+    }
+    Ok(splice_edits(text, &spans))
+}
 
-                            let file_uri = match Url::from_file_path(&file_path) {
-                                Ok(u) => u.to_string(),
-                                Err(_) => return,
-                            };
+/// Replaces the sorted, non-overlapping byte `spans` of `text`. Returns the
+/// new text and where each replacement ended up in it.
+fn splice_edits(text: &str, spans: &[(usize, usize, &str)]) -> (String, Vec<(usize, usize)>) {
+    let mut updated = String::with_capacity(text.len());
+    let mut edited = Vec::with_capacity(spans.len());
+    let mut copied = 0;
+    for (start, end, new_text) in spans {
+        updated.push_str(&text[copied..*start]);
+        edited.push((updated.len(), updated.len() + new_text.len()));
+        updated.push_str(new_text);
+        copied = *end;
+    }
+    updated.push_str(&text[copied..]);
+    (updated, edited)
+}
 
-                            throttle_publish(&limiter, throttle_ms);
-                            let _ = send_notification(&stdout, "publishDiagnostics", serde_json::json!({
-                                "runId": run_id,
-                                "fileUri": file_uri,
-                                "diagnostics": diags,
-                            }));
-
-                            let finished = done.fetch_add(1, Ordering::Relaxed) + 1;
-                            let percent = (finished as f64 / total as f64 * 100.0) as u32;
-                            if finished == total || finished % 10 == 0 {
-                                let _ = send_notification(&stdout, "progress", serde_json::json!({
-                                    "runId": run_id,
-                                    "kind": "report",
-                                    "message": format!("Analyzed {finished}/{total} files"),
-                                    "percent": percent
-                                }));
-                            }
-                        });
-                    }
+/// With `formatAfterFix`, runs clang-format over the lines of `text` touched
+/// by the `edited` byte ranges so machine-applied fixes follow the project
+/// style. Formatting is best effort: the text is returned as is when
+/// clang-format fails.
+fn format_fixed_text(settings: &Settings, path: &Path, text: String, edited: &[(usize, usize)]) -> String {
+    if !settings.formatAfterFix || edited.is_empty() {
+        return text;
+    }
+    let line_starts = build_line_starts(&text);
+    let line_of = |offset: usize| line_starts.partition_point(|start| *start <= offset);
+    let mut lines: Vec<(usize, usize)> = edited.iter().map(|(start, end)| (line_of(*start), line_of((*end).max(*start + 1) - 1))).collect();
+    lines.sort();
+    lines.dedup();
+    let Ok((replacements, _)) = run_clang_format(settings, path, &text, &[], &lines) else {
+        return text;
+    };
+    let mut formatted = text;
+    for r in replacements.iter().rev() {
+        let end = r.offset + r.length;
+        if end > formatted.len() || !formatted.is_char_boundary(r.offset) || !formatted.is_char_boundary(end) {
+            continue;
+        }
+        formatted.replace_range(r.offset..end, &r.text);
+    }
+    formatted
+}
 
-                    pool.join();
-                }
+/// Replaces `path` through a temp file in the same directory, keeping its
+/// permissions, so readers see either the old or the new content.
+fn write_file_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    stage_file_write(path, data)?.persist(path).map_err(|err| err.error).context("Failed to replace file")?;
+    Ok(())
+}
 
-                let _ = send_notification(&stdout, "progress", serde_json::json!({
-                    "runId": run_id_thread,
-                    "kind": "end",
-                    "message": "Project analysis completed"
-                }));
-            });
+/// A temp file next to `path` holding `data` with the permissions of `path`,
+/// ready to be persisted over it.
+fn stage_file_write(path: &Path, data: &[u8]) -> Result<NamedTempFile> {
+    let dir = path.parent().context("file has no parent directory")?;
+    let permissions = std::fs::metadata(path).context("Failed to stat file")?.permissions();
+    let mut tmp = NamedTempFile::new_in(dir).context("Failed to create temp file")?;
+    tmp.write_all(data).context("Failed to write file")?;
+    tmp.flush()?;
+    std::fs::set_permissions(tmp.path(), permissions).context("Failed to set file permissions")?;
+    Ok(tmp)
+}
 
-            Ok(serde_json::json!({ "runId": run_id }))
-        }
-        "cancel" => {
-            if let Some(run_id) = params.get("runId").and_then(|v| v.as_str()) {
-                let map = state.cancel_map.lock().unwrap();
-                if run_id == "*" {
-                    for (_, flag) in map.iter() {
-                        flag.store(true, Ordering::Relaxed);
-                    }
-                } else if let Some(flag) = map.get(run_id) {
-                    flag.store(true, Ordering::Relaxed);
-                }
+/// Replaces several files together: every new text is staged in a temp file
+/// before any file is replaced, and the files already replaced are restored
+/// to `before` when a later one fails.
+fn write_files_atomic(files: &[(PathBuf, String, String)]) -> Result<()> {
+    let staged = files
+        .iter()
+        .map(|(path, _, after)| stage_file_write(path, after.as_bytes()).with_context(|| format!("Failed to write {}", path.display())))
+        .collect::<Result<Vec<_>>>()?;
+    for (i, tmp) in staged.into_iter().enumerate() {
+        let path = &files[i].0;
+        if let Err(err) = tmp.persist(path) {
+            for (path, before, _) in &files[..i] {
+                let _ = write_file_atomic(path, before.as_bytes());
             }
-            Ok(serde_json::json!({}))
+            return Err(anyhow::Error::from(err.error).context(format!("Failed to replace {}", path.display())));
         }
-        _ => Ok(serde_json::json!({})),
     }
+    Ok(())
 }
 
-fn handle_notification(method: &str, params: Value, state: &AppState) -> Result<()> {
-    if method == "configChanged" {
-        if let Some(s) = params.get("settings") {
-            if let Ok(parsed) = serde_json::from_value::<Settings>(s.clone()) {
-                *state.settings.lock().unwrap() = parsed;
-                *state.compile_commands.lock().unwrap() = None;
-                *state.compile_index.lock().unwrap() = None;
+/// Every fix of `diags` (filtered by check name globs) as byte ranges into
+/// `text`, in file order. Fixes that also edit other files are left out, since
+/// applying only their part in this file would leave the code broken, and so
+/// are `NOLINT` suppressions and, unless `include_behavior_changing`, fixes
+/// that may change behavior.
+fn collect_fixes(
+    text: &str,
+    file_uri: &str,
+    diags: &[RpcDiagnostic],
+    filters: &[Regex],
+    include_behavior_changing: bool,
+) -> Vec<SessionFix> {
+    let line_starts = build_line_starts(text);
+    let mut pending: Vec<SessionFix> = Vec::new();
+    for diag in diags {
+        let code = diag.code.clone().unwrap_or_default();
+        if !filters.is_empty() && !filters.iter().any(|re| re.is_match(&code)) {
+            continue;
+        }
+        for fix in diag.fixes.iter().flatten().filter(|fix| !is_suppression_fix(fix)) {
+            let safety = fix.safety.unwrap_or_else(|| classify_fix(diag.code.as_deref()).1);
+            if safety == FixSafety::BehaviorChanging && !include_behavior_changing {
+                continue;
+            }
+            let files = fix_file_edits(fix, file_uri);
+            let [(uri, file_edits)] = files.as_slice() else {
+                continue;
+            };
+            if uri != file_uri {
+                continue;
+            }
+            let mut edits: Vec<(usize, usize, String)> = file_edits
+                .iter()
+                .map(|edit| {
+                    let start = position_to_offset(text, &line_starts, &edit.range.start);
+                    let end = position_to_offset(text, &line_starts, &edit.range.end).max(start);
+                    (start, end, edit.new_text.clone())
+                })
+                .collect();
+            if edits.is_empty() {
+                continue;
             }
+            edits.sort_by_key(|(start, _, _)| *start);
+            pending.push(SessionFix {
+                diagnostic_id: diag.id.clone(),
+                code: diag.code.clone(),
+                message: diag.message.clone(),
+                title: fix.title.clone(),
+                safety: fix.safety,
+                edits,
+            });
         }
-        send_notification(&state.stdout, "log", LogParams {
-            level: "info",
-            message: "Settings updated".to_string(),
-        })?;
     }
-    Ok(())
+    pending.sort_by_key(|fix| fix.edits[0].0);
+    pending
 }
 
-fn analyze_file(
-    file_path: &Path,
-    settings: &Settings,
-    root_dir: Option<&Path>,
-    compile_commands: Option<&Path>,
-    mode: &str,
-    cache: &Arc<Mutex<HashMap<PathBuf, CacheEntry>>>,
-) -> Result<Vec<RpcDiagnostic>> {
-    let clang_tidy = if settings.clangTidyPath.is_empty() {
-        "clang-tidy".to_string()
-    } else {
-        settings.clangTidyPath.clone()
+/// Queues every fix of `diags` (filtered by check name globs) in file order.
+fn start_fix_session(
+    text: String,
+    file_path: PathBuf,
+    file_uri: &str,
+    diags: &[RpcDiagnostic],
+    filters: &[Regex],
+    include_behavior_changing: bool,
+) -> FixSession {
+    let pending = collect_fixes(&text, file_uri, diags, filters, include_behavior_changing);
+    FixSession {
+        file_path,
+        used: Instant::now(),
+        text,
+        pending: pending.into(),
+        current: None,
+        accepted: 0,
+        skipped: 0,
+        conflicting: 0,
+    }
+}
+
+/// Whether two edits cannot both be applied: their ranges overlap, or they
+/// insert different text at the same point. Identical edits do not conflict.
+/// Works on byte offsets as well as (line, character) positions.
+fn edits_conflict<T: Ord>(a: &(T, T, String), b: &(T, T, String)) -> bool {
+    if a == b {
+        return false;
+    }
+    (a.0 < b.1 && b.0 < a.1) || (a.0 == b.0 && a.1 == b.1)
+}
+
+/// Picks the fixes to apply together. In order of first edit, check name and
+/// diagnostic id, a fix is taken unless one of its edits conflicts with an
+/// edit already taken; it is then skipped along with the id of the diagnostic
+/// it conflicts with. The order makes the choice independent of the order
+/// clang-tidy reported the diagnostics in.
+fn resolve_fix_conflicts(mut fixes: Vec<SessionFix>) -> (Vec<SessionFix>, Vec<(SessionFix, String)>) {
+    fixes.sort_by(|a, b| {
+        (a.edits[0].0, &a.code, &a.diagnostic_id, &a.title).cmp(&(b.edits[0].0, &b.code, &b.diagnostic_id, &b.title))
+    });
+    let mut accepted: Vec<SessionFix> = Vec::new();
+    let mut skipped = Vec::new();
+    for fix in fixes {
+        let conflict = accepted
+            .iter()
+            .find(|taken| taken.edits.iter().any(|edit| fix.edits.iter().any(|other| edits_conflict(edit, other))))
+            .map(|taken| taken.diagnostic_id.clone());
+        match conflict {
+            Some(conflicts_with) => skipped.push((fix, conflicts_with)),
+            None => accepted.push(fix),
+        }
+    }
+    (accepted, skipped)
+}
+
+fn session_fix_edits(text: &str, fix: &SessionFix) -> Vec<TextEdit> {
+    let line_starts = build_line_starts(text);
+    fix.edits
+        .iter()
+        .map(|(start, end, new_text)| TextEdit {
+            range: offset_range(text, &line_starts, *start, end - start),
+            new_text: new_text.clone(),
+        })
+        .collect()
+}
+
+fn next_session_fix(session: &mut FixSession) -> Value {
+    if session.current.is_none() {
+        session.current = session.pending.pop_front();
+    }
+    let Some(fix) = session.current.as_ref() else {
+        return serde_json::json!({ "done": true });
     };
+    serde_json::json!({
+        "done": false,
+        "remaining": session.pending.len(),
+        "diagnosticId": fix.diagnostic_id,
+        "code": fix.code,
+        "message": fix.message,
+        "title": fix.title,
+        "safety": fix.safety,
+        "edits": session_fix_edits(&session.text, fix),
+    })
+}
 
-    let compile_dir = compile_commands.and_then(|p| p.parent());
-    let base_dir = compile_dir.or(root_dir);
+/// Applies the current fix to the session text, shifts the offsets of later
+/// fixes and drops those that overlap what was just changed. The returned
+/// edits are relative to the text before this fix.
+fn accept_session_fix(session: &mut FixSession) -> Result<Value> {
+    let fix = session.current.take().context("No current fix; call nextFix first")?;
+    let edits = session_fix_edits(&session.text, &fix);
+    for (start, end, new_text) in fix.edits.iter().rev() {
+        if *end <= session.text.len() && session.text.is_char_boundary(*start) && session.text.is_char_boundary(*end) {
+            session.text.replace_range(*start..*end, new_text);
+        }
+    }
 
-    let file_sig = file_signature(file_path);
-    let compile_commands_mtime = compile_commands.and_then(mtime_for_path);
-    let config_mtime = clang_tidy_config_mtime(root_dir, compile_dir);
-    let settings_hash = settings_fingerprint(settings, compile_commands, compile_commands_mtime, config_mtime, mode);
-    let cache_dir = resolve_cache_dir(settings, root_dir, compile_dir);
+    let before = session.pending.len();
+    session.pending.retain(|other| {
+        !other.edits.iter().any(|(o_start, o_end, _)| {
+            fix.edits
+                .iter()
+                .any(|(start, end, _)| o_start < end && start < o_end || (o_start == start && o_end == end))
+        })
+    });
+    let conflicts = before - session.pending.len();
+    session.conflicting += conflicts;
+    for other in session.pending.iter_mut() {
+        for (o_start, o_end, _) in other.edits.iter_mut() {
+            let shift: isize = fix
+                .edits
+                .iter()
+                .filter(|(_, end, _)| *end <= *o_start)
+                .map(|(start, end, new_text)| new_text.len() as isize - (end - start) as isize)
+                .sum();
+            *o_start = (*o_start as isize + shift) as usize;
+            *o_end = (*o_end as isize + shift) as usize;
+        }
+    }
+    session.accepted += 1;
+    Ok(serde_json::json!({ "applied": true, "edits": edits, "conflicts": conflicts }))
+}
 
-    if let Some((mtime, size)) = file_sig {
-        if let Some(entry) = cache.lock().unwrap().get(file_path) {
-            if entry.mtime == mtime && entry.size == size && entry.settings_hash == settings_hash {
-                return Ok(entry.diagnostics.clone());
-            }
+/// Packages what is needed to reproduce the analysis of `file_path` into a
+/// zip archive. Text entries go through the log redactor; the preprocessed
+/// source (opt-in) is stored as produced by the compiler.
+fn create_repro(
+    state: &AppState,
+    file_path: &Path,
+    mode: &str,
+    include_preprocessed: bool,
+    output_dir: Option<PathBuf>,
+) -> Result<(PathBuf, Vec<String>)> {
+    let settings = state.settings.lock().unwrap().clone();
+    let root_dir = state.root_dir.lock().unwrap().clone();
+    let redactor = state.stdout.redactor.lock().unwrap().clone();
+    let compile_commands = resolve_compile_commands_path(&settings, root_dir.as_deref(), state);
+    let compile_dir = compile_commands.as_deref().and_then(|p| p.parent());
+    let entry = compile_commands
+        .as_deref()
+        .and_then(|path| get_compile_index(path, state).ok())
+        .and_then(|index| find_compile_entry(&index, file_path));
+    let clang_tidy = clang_tidy_binary(&settings);
+
+    let mut bundle = ReproBundle { redactor, files: Vec::new() };
+    bundle.add_json(
+        "manifest.json",
+        serde_json::json!({
+            "daemonVersion": env!("CARGO_PKG_VERSION"),
+            "platform": format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+            "file": file_path.to_string_lossy(),
+            "mode": mode,
+            "rootDir": root_dir.as_ref().map(|p| p.to_string_lossy().to_string()),
+            "compileCommands": compile_commands.as_ref().map(|p| p.to_string_lossy().to_string()),
+            "createdAt": std::time::SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        }),
+    )?;
+    let mut shared_settings = settings.clone();
+    if !shared_settings.remoteCacheToken.is_empty() {
+        shared_settings.remoteCacheToken = "<redacted>".to_string();
+    }
+    bundle.add_json("settings.json", serde_json::to_value(&shared_settings)?)?;
+
+    let version = run_command_with_timeout(Command::new(&clang_tidy).arg("--version"), 10_000)
+        .map(|out| String::from_utf8_lossy(&out.stdout).into_owned())
+        .unwrap_or_else(|err| format!("failed to run {clang_tidy} --version: {err}\n"));
+    bundle.add_text("clang-tidy-version.txt", &version);
+
+    let compile_entry = match &entry {
+        Some(entry) => serde_json::json!({
+            "directory": entry.directory,
+            "file": entry.file,
+            "arguments": resolve_arguments(entry, &settings),
+            "output": entry.output,
+        }),
+        None => Value::Null,
+    };
+    bundle.add_json("compile_command.json", compile_entry)?;
+
+    // --dump-config resolves the .clang-tidy chain plus the options the daemon adds.
+    let mut dump = Command::new(&clang_tidy);
+    dump.arg("--dump-config");
+    let check_options = effective_check_options(&settings, file_path, root_dir.as_deref());
+    if !check_options.is_empty() {
+        dump.arg(format!("--config={}", inline_check_config(&check_options)));
+    }
+    add_check_args(&mut dump, &settings, mode);
+    dump.arg(file_path);
+    if let Some(dir) = compile_dir.or(root_dir.as_deref()) {
+        dump.current_dir(dir);
+    }
+    match run_command_with_timeout(&mut dump, 10_000) {
+        Ok(out) if out.status.success() && String::from_utf8_lossy(&out.stdout).contains("Checks:") => {
+            bundle.add_text(".clang-tidy", &String::from_utf8_lossy(&out.stdout));
         }
-        if let Some(dir) = cache_dir.as_deref() {
-            if let Some(diags) = read_disk_cache(dir, file_path, mtime, size, settings_hash) {
-                cache.lock().unwrap().insert(
-                    file_path.to_path_buf(),
-                    CacheEntry {
-                        mtime,
-                        size,
-                        settings_hash,
-                        diagnostics: diags.clone(),
-                    },
-                );
-                return Ok(diags);
+        _ => {
+            if let Some(config) = find_clang_tidy_config(file_path, root_dir.as_deref(), compile_dir) {
+                bundle.add_text(".clang-tidy", &std::fs::read_to_string(&config).unwrap_or_default());
             }
         }
     }
 
-    let temp = NamedTempFile::new().context("Failed to create temp file for fixes")?;
-
-    let mut cmd = Command::new(clang_tidy);
-    cmd.arg(file_path);
-    if let Some(dir) = compile_dir {
-        cmd.arg("-p").arg(dir);
-        cmd.current_dir(dir);
-    } else if let Some(dir) = root_dir {
-        cmd.current_dir(dir);
+    let fixes = new_fixes_file().context("Failed to create temp file for fixes")?;
+    let mut cmd = tidy_command(&clang_tidy, file_path, &settings, root_dir.as_deref(), compile_dir, fixes.path(), mode);
+    let command_line = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|a| a.to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let output = match run_command_with_timeout(&mut cmd, file_timeout_ms(&settings, mode)) {
+        Ok(out) => format!(
+            "$ {command_line}\n{}\n\n--- stdout ---\n{}\n--- stderr ---\n{}",
+            out.status,
+            String::from_utf8_lossy(&out.stdout),
+            String::from_utf8_lossy(&out.stderr)
+        ),
+        Err(err) => format!("$ {command_line}\nfailed: {err}\n"),
+    };
+    bundle.add_text("clang-tidy-output.txt", &output);
+    if let Ok(yaml) = std::fs::read_to_string(fixes.path()) {
+        if !yaml.trim().is_empty() {
+            bundle.add_text("fixes.yaml", &yaml);
+        }
     }
-    cmd.arg("-export-fixes").arg(temp.path());
-    cmd.arg("--quiet");
-    cmd.arg("-extra-arg=-fno-color-diagnostics");
-    if mode == "quick" && !settings.quickChecks.trim().is_empty() {
-        cmd.arg(format!("-checks={}", settings.quickChecks.trim()));
+
+    if include_preprocessed {
+        let entry = entry.as_ref().context("compile command not found for file")?;
+        let source = preprocess_source(entry, &settings)?;
+        let name = file_path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        bundle.files.push((format!("{name}.ii"), source));
     }
-    for arg in &settings.extraArgs {
-        cmd.arg(arg);
+
+    let dir = output_dir.unwrap_or_else(std::env::temp_dir);
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let stem = file_path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let stamp = std::time::SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let zip_path = dir.join(format!("clang-tidy-repro-{stem}-{stamp}.zip"));
+    let mut zip = zip::ZipWriter::new(std::fs::File::create(&zip_path).context("Failed to create repro archive")?);
+    let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    for (name, data) in &bundle.files {
+        zip.start_file(name.as_str(), options)?;
+        zip.write_all(data)?;
     }
+    zip.finish().context("Failed to write repro archive")?;
+    Ok((zip_path, bundle.files.into_iter().map(|(name, _)| name).collect()))
+}
 
-    let output = run_command_with_timeout(&mut cmd, settings.perFileTimeoutMs)
-        .context("Failed to run clang-tidy")?;
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let stderr = String::from_utf8_lossy(&output.stderr);
-    let combined = format!("{}\n{}", stdout, stderr);
+struct ReproBundle {
+    redactor: Arc<Redactor>,
+    files: Vec<(String, Vec<u8>)>,
+}
 
-    let mut diags = parse_diagnostics(&combined, base_dir, file_path);
-    let fix_diags = parse_fixes(temp.path(), base_dir, file_path);
+impl ReproBundle {
+    fn add_text(&mut self, name: &str, text: &str) {
+        self.files.push((name.to_string(), self.redactor.redact(text).into_bytes()));
+    }
 
-    if let Ok(fixes) = fix_diags {
-        merge_diagnostics(&mut diags, fixes);
+    /// JSON is redacted value by value so that a rule cannot swallow quotes.
+    fn add_json(&mut self, name: &str, value: Value) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&redact_json(value, &self.redactor))?;
+        self.files.push((name.to_string(), data));
+        Ok(())
     }
+}
 
-    diags.retain(|d| paths_match(&d.file, file_path));
-    apply_diagnostic_caps(&mut diags, settings.maxDiagnosticsPerFile, settings.maxFixesPerFile);
-    let result: Vec<RpcDiagnostic> = diags.into_iter().map(to_rpc_diagnostic).collect();
+fn redact_json(value: Value, redactor: &Redactor) -> Value {
+    match value {
+        Value::String(text) => Value::String(redactor.redact(&text)),
+        Value::Array(items) => Value::Array(items.into_iter().map(|v| redact_json(v, redactor)).collect()),
+        Value::Object(map) => Value::Object(map.into_iter().map(|(k, v)| (k, redact_json(v, redactor))).collect()),
+        other => other,
+    }
+}
 
-    if let Some((mtime, size)) = file_sig {
-        cache.lock().unwrap().insert(
-            file_path.to_path_buf(),
-            CacheEntry {
-                mtime,
-                size,
-                settings_hash,
-                diagnostics: result.clone(),
+/// Compiler arguments without the compile step, output and dependency file
+/// options, so another output can be requested from the same command. `cl`
+/// also drops the MSVC output and precompiled header options.
+fn compile_only_flags(args: &[String], cl: bool) -> Vec<&str> {
+    let mut filtered: Vec<&str> = Vec::new();
+    let mut skip_next = false;
+    for arg in args {
+        if skip_next {
+            skip_next = false;
+            continue;
+        }
+        match arg.as_str() {
+            "-c" => {}
+            "/c" if cl => {}
+            a if cl && cl_option(a).is_some_and(|option| CL_PCH_OPTIONS.iter().any(|o| option.starts_with(o))) => {}
+            a => match output_option_len(a, cl) {
+                Some(len) => skip_next = len > 1,
+                None => filtered.push(a),
             },
-        );
-        if let Some(dir) = cache_dir.as_deref() {
-            let _ = write_disk_cache(dir, file_path, mtime, size, settings_hash, &result);
         }
     }
+    filtered
+}
 
-    Ok(result)
+/// Runs the compile entry's compiler with `-E` and returns the output.
+/// Object and dependency file outputs are dropped from the command line.
+fn preprocess_source(entry: &CompileCommandEntry, settings: &Settings) -> Result<Vec<u8>> {
+    let args = resolve_arguments(entry, settings).context("compile command missing arguments")?;
+    let (compiler, rest) = args.split_first().context("compile command is empty")?;
+    let out = NamedTempFile::with_prefix(temp_artifact_prefix()).context("Failed to create temp file")?;
+    let cl = is_cl_driver(&args);
+    let filtered = compile_only_flags(rest, cl);
+    let mut cmd = Command::new(compiler);
+    cmd.args(&filtered).current_dir(&entry.directory);
+    // cl and clang-cl write `/E` output to stdout.
+    if cl {
+        cmd.arg("/E");
+    } else {
+        cmd.arg("-E").arg("-o").arg(out.path());
+    }
+    let timeout = if settings.perFileTimeoutMs > 0 { settings.perFileTimeoutMs } else { 60_000 };
+    let output = run_command_with_timeout(&mut cmd, timeout).context("Failed to run the compiler")?;
+    if !output.status.success() {
+        anyhow::bail!("preprocessing failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    if cl {
+        return Ok(output.stdout);
+    }
+    std::fs::read(out.path()).context("Failed to read preprocessed output")
 }
 
-fn analyze_file_with_content(
-    file_path: &Path,
-    content: &str,
+const DEFAULT_BENCH_SAMPLE: usize = 8;
+
+/// One benchmark configuration. `cache: true` measures a second pass over
+/// the sample after an untimed first pass has filled the memory cache.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BenchConfig {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    max_workers: u32,
+    #[serde(default)]
+    mode: String,
+    #[serde(default)]
+    cache: bool,
+}
+
+/// Evenly spaced files from the compile database so the sample spans the
+/// whole project rather than its first directory.
+fn sample_files(files: &[PathBuf], sample: usize) -> Vec<PathBuf> {
+    if sample == 0 || files.len() <= sample {
+        return files.to_vec();
+    }
+    let step = files.len() as f64 / sample as f64;
+    (0..sample).map(|i| files[(i as f64 * step) as usize].clone()).collect()
+}
+
+/// Worker counts of 1, half and all cores on cold full runs, plus a warm
+/// cache run and a quick-mode run when `quickChecks` is set.
+fn default_bench_configs(settings: &Settings) -> Vec<BenchConfig> {
+    let cores = thread::available_parallelism().map(|n| n.get() as u32).unwrap_or(1);
+    let mut workers = vec![1, (cores / 2).max(1), cores];
+    workers.dedup();
+    let config = |max_workers: u32, mode: &str, cache: bool| BenchConfig {
+        name: format!("{mode}, workers={max_workers}{}", if cache { ", cached" } else { "" }),
+        max_workers,
+        mode: mode.to_string(),
+        cache,
+    };
+    let mut configs: Vec<BenchConfig> = workers.iter().map(|&n| config(n, "full", false)).collect();
+    if !settings.quickChecks.trim().is_empty() {
+        configs.push(config(cores, "quick", false));
+    }
+    configs.push(config(cores, "full", true));
+    configs
+}
+
+fn run_benchmark(
     settings: &Settings,
     root_dir: Option<&Path>,
-    compile_commands: Option<&Path>,
-    compile_index: Option<&CompileCommandsIndex>,
+    compile_commands: &Path,
+    files: &[PathBuf],
+    configs: &[BenchConfig],
+    progress: &dyn Fn(String),
+) -> Value {
+    let mut results = Vec::new();
+    for config in configs {
+        let mut settings = settings.clone();
+        settings.maxWorkers = config.max_workers.max(1);
+        settings.daemonCacheOnDisk = false;
+        let mode = if config.mode.is_empty() { "full" } else { config.mode.as_str() };
+        let cache = Arc::new(Mutex::new(SpillStore::<CacheEntry>::default()));
+        if config.cache {
+            bench_pass(&settings, root_dir, compile_commands, files, mode, &cache);
+        }
+        progress(format!("Benchmarking {}", config.name));
+        let (wall, mut latencies, failures) = bench_pass(&settings, root_dir, compile_commands, files, mode, &cache);
+        latencies.sort_unstable();
+        let percentile = |p: usize| latencies.get((latencies.len() * p / 100).min(latencies.len().saturating_sub(1))).copied();
+        let wall_ms = wall.as_millis() as u64;
+        results.push(serde_json::json!({
+            "name": config.name,
+            "maxWorkers": settings.maxWorkers,
+            "mode": mode,
+            "cache": config.cache,
+            "files": files.len(),
+            "failures": failures,
+            "wallMs": wall_ms,
+            "filesPerSecond": files.len() as f64 / wall.as_secs_f64().max(1e-6),
+            "latencyMs": {
+                "p50": percentile(50),
+                "p95": percentile(95),
+                "max": latencies.last().copied(),
+            },
+        }));
+    }
+    serde_json::json!({
+        "files": files.iter().map(|f| f.to_string_lossy().to_string()).collect::<Vec<_>>(),
+        "results": results,
+        "recommendation": bench_recommendation(&results),
+    })
+}
+
+/// Analyzes `files` with a pool of `settings.maxWorkers` threads and returns
+/// the wall time, per-file latencies in milliseconds and the failure count.
+fn bench_pass(
+    settings: &Settings,
+    root_dir: Option<&Path>,
+    compile_commands: &Path,
+    files: &[PathBuf],
     mode: &str,
-) -> Result<Vec<RpcDiagnostic>> {
-    let _ = compile_commands.context("compile_commands.json not found")?;
-    let index = compile_index.context("compile_commands index missing")?;
-    let entry = find_compile_entry(index, file_path).context("compile command not found for file")?;
+    cache: &Arc<Mutex<SpillStore<CacheEntry>>>,
+) -> (Duration, Vec<u64>, usize) {
+    let pool = ThreadPool::new(settings.maxWorkers.max(1) as usize);
+    let latencies = Arc::new(Mutex::new(Vec::new()));
+    let failures = Arc::new(AtomicUsize::new(0));
+    let resources = Arc::new(Mutex::new(ResourceStats::default()));
+    let start = Instant::now();
+    for file_path in files {
+        let file_path = file_path.clone();
+        let settings = settings.clone();
+        let root_dir = root_dir.map(Path::to_path_buf);
+        let compile_commands = compile_commands.to_path_buf();
+        let mode = mode.to_string();
+        let cache = cache.clone();
+        let resources = resources.clone();
+        let latencies = latencies.clone();
+        let failures = failures.clone();
+        pool.execute(move || {
+            let begin = Instant::now();
+            let result = analyze_file(
+                &file_path,
+                &settings,
+                root_dir.as_deref(),
+                Some(&compile_commands),
+                &mode,
+                None,
+                &cache,
+                None,
+                &resources,
+                &|_| {},
+            );
+            if result.is_err() {
+                failures.fetch_add(1, Ordering::Relaxed);
+            }
+            latencies.lock().unwrap().push(begin.elapsed().as_millis() as u64);
+        });
+    }
+    pool.join();
+    let latencies = std::mem::take(&mut *latencies.lock().unwrap());
+    (start.elapsed(), latencies, failures.load(Ordering::Relaxed))
+}
 
-    let temp_dir = TempDir::new().context("Failed to create temp dir for unsaved buffer")?;
-    let filename = file_path.file_name().unwrap_or_else(|| std::ffi::OsStr::new("file.cpp"));
-    let temp_file = temp_dir.path().join(filename);
-    std::fs::write(&temp_file, content).context("Failed to write temp file content")?;
+/// Suggests the worker count of the fastest cold full run (ties go to fewer
+/// workers) and a per-file timeout with headroom over the slowest file.
+fn bench_recommendation(results: &[Value]) -> Value {
+    let cold_full = results.iter().filter(|r| r["mode"] == "full" && r["cache"] == false && r["failures"] == 0);
+    let best = cold_full.min_by_key(|r| (r["wallMs"].as_u64().unwrap_or(u64::MAX), r["maxWorkers"].as_u64().unwrap_or(0)));
+    let slowest = results
+        .iter()
+        .filter(|r| r["cache"] == false)
+        .filter_map(|r| r["latencyMs"]["max"].as_u64())
+        .max();
+    serde_json::json!({
+        "maxWorkers": best.and_then(|r| r["maxWorkers"].as_u64()),
+        "perFileTimeoutMs": slowest.map(|ms| (ms * 3).max(10_000).div_ceil(1000) * 1000),
+    })
+}
 
-    let mut args = resolve_arguments(&entry).context("compile command missing arguments")?;
-    let original_path = file_path.to_string_lossy().to_string();
-    let temp_path = temp_file.to_string_lossy().to_string();
-    let replaced = replace_file_arg(&mut args, &entry.file, &original_path, &temp_path);
-    if !replaced {
-        return Err(anyhow::anyhow!("compile command does not reference file path"));
+/// `clang-tidy-daemon bench`: runs the benchmark outside an editor session
+/// and prints the report as JSON.
+fn run_bench_cli(args: &[String]) -> Result<()> {
+    let mut settings = Settings::default();
+    let mut root_dir = std::env::current_dir().ok();
+    let mut sample = DEFAULT_BENCH_SAMPLE;
+    let mut configs: Option<Vec<BenchConfig>> = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().with_context(|| format!("{arg} expects a value"));
+        match arg.as_str() {
+            "--root" => root_dir = Some(PathBuf::from(value()?)),
+            "--compile-commands" => settings.compileCommandsPath = value()?.clone(),
+            "--clang-tidy" => settings.clangTidyPath = value()?.clone(),
+            "--sample" => sample = value()?.parse().context("--sample expects a number")?,
+            "--settings" => {
+                let text = std::fs::read_to_string(value()?).context("Failed to read settings file")?;
+                settings = serde_json::from_str(&text).context("Invalid settings file")?;
+            }
+            "--configurations" => {
+                let text = std::fs::read_to_string(value()?).context("Failed to read configurations file")?;
+                configs = Some(serde_json::from_str(&text).context("Invalid configurations file")?);
+            }
+            other => anyhow::bail!(
+                "unknown argument {other}\nusage: clang-tidy-daemon bench [--root DIR] [--compile-commands PATH] \
+                 [--clang-tidy PATH] [--sample N] [--settings FILE] [--configurations FILE]"
+            ),
+        }
+    }
+
+    let compile_commands = if settings.compileCommandsPath.is_empty() {
+        root_dir.as_deref().and_then(find_compile_commands)
+    } else {
+        resolve_path(&settings.compileCommandsPath, root_dir.as_deref())
+    }
+    .context("compile_commands.json not found")?;
+    let files = sample_files(&load_project_files(&compile_commands)?, sample);
+    let configs = configs.unwrap_or_else(|| default_bench_configs(&settings));
+    let report = run_benchmark(&settings, root_dir.as_deref(), &compile_commands, &files, &configs, &|message| {
+        eprintln!("{message}");
+    });
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+/// `export-cache` / `import-cache`: the `exportCache` / `importCache` RPCs for
+/// CI scripts, on the disk cache the given settings point at.
+fn run_cache_archive_cli(command: &str, args: &[String]) -> Result<()> {
+    let mut settings = Settings::default();
+    let mut root_dir = std::env::current_dir()?;
+    let mut archive: Option<PathBuf> = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().with_context(|| format!("{arg} expects a value"));
+        match arg.as_str() {
+            "--root" => root_dir = PathBuf::from(value()?),
+            "--compile-commands" => settings.compileCommandsPath = value()?.clone(),
+            "--clang-tidy" => settings.clangTidyPath = value()?.clone(),
+            "--settings" => {
+                let text = std::fs::read_to_string(value()?).context("Failed to read settings file")?;
+                settings = serde_json::from_str(&text).context("Invalid settings file")?;
+            }
+            "--output-dir" if command == "export-cache" => archive = Some(PathBuf::from(value()?)),
+            "--input" if command == "import-cache" => archive = Some(PathBuf::from(value()?)),
+            other => anyhow::bail!(
+                "unknown argument {other}\nusage: clang-tidy-daemon {command} [--root DIR] [--compile-commands PATH] \
+                 [--clang-tidy PATH] [--settings FILE] {}",
+                if command == "export-cache" { "[--output-dir DIR]" } else { "--input ARCHIVE|DIR" }
+            ),
+        }
+    }
+    settings.daemonCacheOnDisk = true;
+    let root_dir = std::path::absolute(&root_dir)?;
+    let compile_commands = if settings.compileCommandsPath.is_empty() {
+        find_compile_commands(&root_dir)
+    } else {
+        resolve_path(&settings.compileCommandsPath, Some(&root_dir))
+            .map(|p| if p.is_dir() { p.join("compile_commands.json") } else { p })
+    }
+    .context("compile_commands.json not found")?;
+    let files = load_project_files(&compile_commands)?;
+    let remote = RemoteCache::default();
+    let scope = CacheArchiveScope {
+        settings: &settings,
+        root_dir: &root_dir,
+        compile_commands: &compile_commands,
+        files: &files,
+        memory: None,
+        remote: &remote,
+    };
+    let report = if command == "export-cache" {
+        let (path, manifest) = export_cache_archive(&scope, &archive.unwrap_or_else(|| root_dir.clone()))?;
+        serde_json::json!({ "path": path.to_string_lossy(), "commit": manifest.commit, "entries": manifest.entries })
+    } else {
+        let import = import_cache_archive(&scope, &archive.context("--input is required")?)?;
+        serde_json::json!({
+            "commit": import.manifest.commit,
+            "headCommit": head_commit(&root_dir),
+            "imported": import.imported,
+            "alreadyCached": import.present,
+        })
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+/// Runs `clang-tidy --verify-config` on `config_path` and anchors each
+/// complaint at the offending name in the file. "Did you mean" suggestions
+/// become fixes.
+fn verify_config(settings: &Settings, config_path: &Path) -> Result<Vec<RpcDiagnostic>> {
+    let text = std::fs::read_to_string(config_path).context("Failed to read .clang-tidy")?;
+    let mut cmd = Command::new(clang_tidy_binary(settings));
+    cmd.arg("--verify-config").arg(format!("--config-file={}", config_path.display()));
+    if let Some(dir) = config_path.parent() {
+        cmd.current_dir(dir);
+    }
+    let output = run_command_with_timeout(&mut cmd, 30_000)?;
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    if combined.contains("Unknown command line argument '--verify-config'") {
+        anyhow::bail!("clang-tidy does not support --verify-config (LLVM 16 or newer is required)");
+    }
+
+    let line_starts = build_line_starts(&text);
+    let line_re = Regex::new(r"(?:^|: )(?P<severity>warning|error): (?P<message>.*?)(?: \[-verify-config\])?$").unwrap();
+    let name_re = Regex::new(r"'(?P<name>[^']+)'(?:; did you mean '(?P<suggestion>[^']+)')?").unwrap();
+    let mut diags = Vec::new();
+    for line in combined.lines() {
+        let Some(caps) = line_re.captures(line.trim_end()) else {
+            continue;
+        };
+        let message = caps["message"].to_string();
+        let names = name_re.captures(&message);
+        let name = names.as_ref().and_then(|n| n.name("name")).map(|m| m.as_str().to_string());
+        let suggestion = names.as_ref().and_then(|n| n.name("suggestion")).map(|m| m.as_str().to_string());
+        let range = match name.as_deref().and_then(|name| text.find(name).map(|offset| (offset, name.len()))) {
+            Some((offset, length)) => offset_range(&text, &line_starts, offset, length),
+            None => range_from_line_col(1, 1),
+        };
+        let fixes = match suggestion {
+            Some(suggestion) => vec![Fix {
+                title: format!("Replace with '{suggestion}'"),
+                edits: vec![TextEdit { range: range.clone(), new_text: suggestion }],
+                edit: None,
+                kind: Some("quickfix".to_string()),
+                safety: Some(FixSafety::Safe),
+                conflicts_with: Vec::new(),
+            }],
+            None => Vec::new(),
+        };
+        diags.push(to_rpc_diagnostic(InternalDiagnostic {
+            file: config_path.to_path_buf(),
+            range,
+            severity: normalize_severity(&caps["severity"]),
+            code: Some("clang-tidy-config".to_string()),
+            message,
+            fixes,
+            related: Vec::new(),
+        }));
+    }
+    Ok(diags)
+}
+
+fn find_clang_tidy_config(file_path: &Path, root_dir: Option<&Path>, compile_dir: Option<&Path>) -> Option<PathBuf> {
+    let mut current = file_path.parent();
+    while let Some(dir) = current {
+        let candidate = dir.join(".clang-tidy");
+        if candidate.exists() {
+            return Some(candidate);
+        }
+        if root_dir.is_some() && root_dir == Some(dir) {
+            break;
+        }
+        if compile_dir.is_some() && compile_dir == Some(dir) {
+            break;
+        }
+        current = dir.parent();
+    }
+
+    if let Some(dir) = compile_dir {
+        let candidate = dir.join(".clang-tidy");
+        if candidate.exists() {
+            return Some(candidate);
+        }
+    }
+    if let Some(dir) = root_dir {
+        let candidate = dir.join(".clang-tidy");
+        if candidate.exists() {
+            return Some(candidate);
+        }
     }
 
-    let compile_entry = serde_json::json!({
-        "directory": entry.directory,
-        "file": temp_path,
-        "arguments": args,
-    });
-    let compile_path = temp_dir.path().join("compile_commands.json");
-    std::fs::write(&compile_path, serde_json::to_vec(&vec![compile_entry])?)
-        .context("Failed to write temp compile_commands.json")?;
-
-    let clang_tidy = if settings.clangTidyPath.is_empty() {
-        "clang-tidy".to_string()
-    } else {
-        settings.clangTidyPath.clone()
-    };
-
-    let base_dir = PathBuf::from(&entry.directory);
-    let base_dir_ref = if base_dir.exists() { Some(base_dir.as_path()) } else { root_dir };
-    let config_path = find_clang_tidy_config(file_path, root_dir, base_dir_ref);
+    None
+}
 
-    let temp = NamedTempFile::new().context("Failed to create temp file for fixes")?;
-    let mut cmd = Command::new(clang_tidy);
-    cmd.arg(&temp_file);
-    cmd.arg("-p").arg(temp_dir.path());
-    if base_dir.exists() {
-        cmd.current_dir(&base_dir);
-    } else if let Some(dir) = root_dir {
-        cmd.current_dir(dir);
+fn settings_fingerprint(settings: &Settings, root_dir: Option<&Path>, compile_commands: Option<&Path>, mode: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    settings.clangTidyPath.hash(&mut hasher);
+    hash_tool_identity(&clang_tidy_binary(settings), &mut hasher);
+    settings.maxWorkers.hash(&mut hasher);
+    if mode == "changed" {
+        diff_base_ref(settings).hash(&mut hasher);
     }
-    cmd.arg("-export-fixes").arg(temp.path());
-    cmd.arg("--quiet");
-    cmd.arg("-extra-arg=-fno-color-diagnostics");
-    if let Some(config) = config_path {
-        cmd.arg(format!("--config-file={}", config.display()));
+    if analyzer_enabled(settings, "cppcheck") {
+        settings.cppcheckPath.hash(&mut hasher);
     }
-    if mode == "quick" && !settings.quickChecks.trim().is_empty() {
-        cmd.arg(format!("-checks={}", settings.quickChecks.trim()));
+    hash_result_settings(settings, mode, &mut hasher);
+    let compile_dir = compile_commands.and_then(Path::parent);
+    match portable_cache_root(settings, root_dir) {
+        // Shared with checkouts at other paths: the compile database and
+        // `.clang-tidy` count by content, with the root abstracted.
+        Some(root) => {
+            if let Some(p) = compile_commands {
+                p.strip_prefix(root).unwrap_or(p).to_string_lossy().hash(&mut hasher);
+                input_hash(p, || std::fs::read_to_string(p).ok().map(|text| to_portable_paths(&text, root))).hash(&mut hasher);
+            }
+            clang_tidy_config_path(root_dir, compile_dir).and_then(|p| file_content_hash(&p)).hash(&mut hasher);
+        }
+        None => {
+            if let Some(p) = compile_commands {
+                p.to_string_lossy().hash(&mut hasher);
+            }
+            compile_commands.and_then(compile_db_mtime).hash(&mut hasher);
+            clang_tidy_config_mtime(root_dir, compile_dir).hash(&mut hasher);
+        }
     }
-    for arg in &settings.extraArgs {
-        cmd.arg(arg);
+    hasher.finish()
+}
+
+/// Hashes the settings that shape results wherever the daemon runs; part of
+/// both the local and the remote cache keys.
+fn hash_result_settings(settings: &Settings, mode: &str, hasher: &mut impl Hasher) {
+    settings.extraArgs.hash(hasher);
+    settings.quickChecks.hash(hasher);
+    settings.checks.hash(hasher);
+    settings.maxDiagnosticsPerFile.hash(hasher);
+    settings.maxFixesPerFile.hash(hasher);
+    settings.fixableChecks.hash(hasher);
+    settings.unfixableChecks.hash(hasher);
+    settings.ignoredChecks.hash(hasher);
+    settings.headerFilter.hash(hasher);
+    settings.excludeHeaderFilter.hash(hasher);
+    settings.severityOverrides.hash(hasher);
+    settings.suppressionFixes.hash(hasher);
+    settings.perFileTimeoutMs.hash(hasher);
+    if mode == "deep" {
+        settings.deepTimeoutMs.hash(hasher);
+    }
+    settings.keepCheckAliases.hash(hasher);
+    settings.checkOptions.hash(hasher);
+    settings.checkOptionsOverrides.hash(hasher);
+    settings.disableInstantiationGrouping.hash(hasher);
+    settings.enableAlphaChecks.hash(hasher);
+    // Profiling does not change results, but a profiled run must not be
+    // served from an unprofiled cache.
+    settings.enableCheckProfile.hash(hasher);
+    settings.analyzers.hash(hasher);
+    settings.externalAnalyzers.hash(hasher);
+    settings.dropFlags.hash(hasher);
+    settings.disableDefaultFlagDrops.hash(hasher);
+    settings.compilerLaunchers.hash(hasher);
+    settings.duplicateEntryPolicy.hash(hasher);
+    if analyzer_enabled(settings, "cppcheck") {
+        settings.cppcheckArgs.hash(hasher);
     }
+    mode.hash(hasher);
+}
 
-    let output = run_command_with_timeout(&mut cmd, settings.perFileTimeoutMs)
-        .context("Failed to run clang-tidy")?;
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let stderr = String::from_utf8_lossy(&output.stderr);
-    let combined = format!("{}\n{}", stdout, stderr);
+fn cache_key_for_path(path: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    path.to_string_lossy().hash(&mut hasher);
+    hasher.finish()
+}
 
-    let mut diags = parse_diagnostics(&combined, base_dir_ref, &temp_file);
-    for diag in diags.iter_mut() {
-        diag.file = file_path.to_path_buf();
+fn cache_file_name(key: u64, content_hash: u64, settings_hash: u64) -> String {
+    format!("{:016x}-{:016x}-{:016x}.json", key, content_hash, settings_hash)
+}
+
+fn resolve_cache_dir(settings: &Settings, root_dir: Option<&Path>, compile_dir: Option<&Path>) -> Option<PathBuf> {
+    if !settings.daemonCacheOnDisk {
+        return None;
     }
-    let fix_diags = parse_fixes(temp.path(), base_dir_ref, &temp_file);
 
-    if let Ok(mut fixes) = fix_diags {
-        for fix in fixes.iter_mut() {
-            fix.file = file_path.to_path_buf();
+    let trimmed = settings.daemonCacheDir.trim();
+    let mut base = if !trimmed.is_empty() {
+        PathBuf::from(trimmed)
+    } else if settings.daemonCacheScope == "global" {
+        os_cache_dir()?.join("clang-tidy-daemon")
+    } else if let Some(root) = root_dir {
+        root.join(".vscode").join("clang-tidy-daemon-cache")
+    } else if let Some(dir) = compile_dir {
+        dir.join(".vscode").join("clang-tidy-daemon-cache")
+    } else {
+        return None;
+    };
+
+    if base.is_relative() {
+        if let Some(root) = root_dir {
+            base = root.join(base);
+        } else if let Some(dir) = compile_dir {
+            base = dir.join(base);
         }
-        merge_diagnostics(&mut diags, fixes);
     }
 
-    diags.retain(|d| paths_match(&d.file, file_path));
-    apply_diagnostic_caps(&mut diags, settings.maxDiagnosticsPerFile, settings.maxFixesPerFile);
-    let result: Vec<RpcDiagnostic> = diags.into_iter().map(to_rpc_diagnostic).collect();
-    Ok(result)
+    if std::fs::create_dir_all(&base).is_err() {
+        return None;
+    }
+
+    Some(base)
 }
 
-fn parse_diagnostics(output: &str, root_dir: Option<&Path>, default_file: &Path) -> Vec<InternalDiagnostic> {
-    let re = Regex::new(r"^(?P<file>.+?):(?P<line>\d+):(?P<col>\d+): (?P<severity>warning|error|note): (?P<message>.*?)(?: \[(?P<code>.+?)\])?$").unwrap();
-    let mut diags = Vec::new();
+/// The per-user cache directory of the OS: `%LOCALAPPDATA%` on Windows,
+/// `~/Library/Caches` on macOS and `$XDG_CACHE_HOME` or `~/.cache` elsewhere.
+fn os_cache_dir() -> Option<PathBuf> {
+    let non_empty = |name: &str| std::env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+    if cfg!(windows) {
+        non_empty("LOCALAPPDATA")
+    } else if cfg!(target_os = "macos") {
+        non_empty("HOME").map(|home| home.join("Library").join("Caches"))
+    } else {
+        non_empty("XDG_CACHE_HOME").or_else(|| non_empty("HOME").map(|home| home.join(".cache")))
+    }
+}
 
-    for line in output.lines() {
-        if let Some(caps) = re.captures(line) {
-            let file_raw = caps.name("file").map(|m| m.as_str()).unwrap_or("");
-            let file_path = resolve_path(file_raw, root_dir).unwrap_or_else(|| default_file.to_path_buf());
-            let line_num: usize = caps.name("line").and_then(|m| m.as_str().parse().ok()).unwrap_or(1);
-            let col_num: usize = caps.name("col").and_then(|m| m.as_str().parse().ok()).unwrap_or(1);
-            let severity_raw = caps.name("severity").map(|m| m.as_str()).unwrap_or("warning");
-            let severity = normalize_severity(severity_raw);
-            let message = caps.name("message").map(|m| m.as_str()).unwrap_or("").to_string();
-            let code = caps.name("code").map(|m| m.as_str().to_string());
+/// Workspace root the disk cache abstracts from its keys and entries: with
+/// `daemonCacheScope: "global"` entries are keyed by the workspace-relative
+/// path, so checkouts at different paths share them.
+fn portable_cache_root<'a>(settings: &Settings, root_dir: Option<&'a Path>) -> Option<&'a Path> {
+    root_dir.filter(|_| settings.daemonCacheScope == "global")
+}
 
-            let range = range_from_line_col(line_num, col_num);
-            diags.push(InternalDiagnostic {
-                file: file_path,
-                range,
-                severity,
-                code,
-                message,
-                fixes: Vec::new(),
-            });
+/// Name of the disk cache entry of `file_path`; see `portable_cache_root`.
+fn disk_cache_file_name(portable_root: Option<&Path>, file_path: &Path, content_hash: u64, settings_hash: u64) -> String {
+    cache_file_name(disk_cache_key(portable_root, file_path), content_hash, settings_hash)
+}
+
+fn disk_cache_key(portable_root: Option<&Path>, file_path: &Path) -> u64 {
+    match portable_root.and_then(|root| file_path.strip_prefix(root).ok()) {
+        Some(relative) => {
+            let mut hasher = DefaultHasher::new();
+            relative.components().map(|c| c.as_os_str().to_string_lossy().to_string()).collect::<Vec<_>>().hash(&mut hasher);
+            hasher.finish()
         }
+        None => cache_key_for_path(file_path),
     }
-
-    diags
 }
 
-fn parse_fixes(fixes_path: &Path, root_dir: Option<&Path>, target_file: &Path) -> Result<Vec<InternalDiagnostic>> {
-    if !fixes_path.exists() {
-        return Ok(Vec::new());
+fn read_disk_cache(
+    cache_dir: &Path,
+    portable_root: Option<&Path>,
+    file_path: &Path,
+    content_hash: u64,
+    settings_hash: u64,
+) -> Option<DiskCacheEntry> {
+    let filename = disk_cache_file_name(portable_root, file_path, content_hash, settings_hash);
+    let full_path = cache_dir.join(filename);
+    let data = std::fs::read(&full_path).ok()?;
+    let text = String::from_utf8_lossy(&data);
+    let parsed = match portable_root {
+        Some(root) => serde_json::from_str::<Value>(&from_portable_paths(&text, root)),
+        None => serde_json::from_str::<Value>(&text),
+    };
+    let Ok(value) = parsed else {
+        remove_cache_file(cache_dir, &full_path);
+        return None;
+    };
+    // Left in place: the directory may be shared with daemons of other versions.
+    if value.get("version").and_then(Value::as_u64) != Some(DISK_CACHE_VERSION as u64) {
+        return None;
     }
-    let content = std::fs::read_to_string(fixes_path).unwrap_or_default();
-    if content.trim().is_empty() {
-        return Ok(Vec::new());
+    let Ok(entry) = serde_json::from_value::<DiskCacheEntry>(value) else {
+        remove_cache_file(cache_dir, &full_path);
+        return None;
+    };
+    if entry.filePath != file_path.to_string_lossy() {
+        return None;
     }
+    if entry.contentHash != content_hash || entry.settingsHash != settings_hash {
+        return None;
+    }
+    // The mtime orders entries for `enforce_disk_cache_limit`.
+    let _ = std::fs::File::options()
+        .write(true)
+        .open(&full_path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    Some(entry)
+}
 
-    let fixes: FixesFile = serde_yaml::from_str(&content).context("Failed to parse fixes YAML")?;
-    let file_text = std::fs::read_to_string(target_file).unwrap_or_default();
-    let line_starts = build_line_starts(&file_text);
-
-    let mut diags = Vec::new();
+fn write_disk_cache(cache_dir: &Path, portable_root: Option<&Path>, file_path: &Path, cached: &CacheEntry) -> Result<()> {
+    let key = disk_cache_key(portable_root, file_path);
+    let filename = cache_file_name(key, cached.content_hash, cached.settings_hash);
+    let full_path = cache_dir.join(&filename);
 
-    for diag in fixes.diagnostics {
-        let (message, file_path, file_offset, replacements) = if let Some(dm) = diag.diagnostic_message.as_ref() {
-            (
-                dm.message.clone().or(diag.message.clone()).unwrap_or_default(),
-                dm.file_path.clone().or(diag.file_path.clone()).unwrap_or_default(),
-                dm.file_offset.or(diag.file_offset).unwrap_or(0),
-                dm.replacements.clone().or(diag.replacements.clone()).unwrap_or_default(),
-            )
-        } else {
-            (
-                diag.message.clone().unwrap_or_default(),
-                diag.file_path.clone().unwrap_or_default(),
-                diag.file_offset.unwrap_or(0),
-                diag.replacements.clone().unwrap_or_default(),
-            )
-        };
+    let entry = DiskCacheEntry {
+        version: DISK_CACHE_VERSION,
+        filePath: file_path.to_string_lossy().to_string(),
+        mtime: cached.mtime,
+        size: cached.size,
+        contentHash: cached.content_hash,
+        settingsHash: cached.settings_hash,
+        diagnostics: cached.diagnostics.clone(),
+        fixFiles: cached.fix_files.clone(),
+    };
+    let mut data = serde_json::to_vec(&entry)?;
+    if let Some(root) = portable_root {
+        data = to_portable_paths(&String::from_utf8_lossy(&data), root).into_bytes();
+    }
 
-        let diag_path = resolve_path(&file_path, root_dir).unwrap_or_else(|| target_file.to_path_buf());
-        if !paths_match(&diag_path, target_file) {
-            continue;
-        }
+    append_cache_journal(cache_dir, "begin", &filename)?;
+    let mut tmp = NamedTempFile::new_in(cache_dir)?;
+    tmp.write_all(&data)?;
+    tmp.flush()?;
+    tmp.persist(&full_path).map_err(|err| err.error)?;
+    append_cache_journal(cache_dir, "commit", &filename)?;
 
-        let mut edits = Vec::new();
-        for rep in replacements {
-            let rep_path = resolve_path(&rep.file_path, root_dir).unwrap_or_else(|| target_file.to_path_buf());
-            if !paths_match(&rep_path, target_file) {
+    // Older results of the file are replaced, unless other checkouts may still
+    // be at that content.
+    if let (Ok(entries), None) = (std::fs::read_dir(cache_dir), portable_root) {
+        let prefix = format!("{:016x}-", key);
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name == filename {
                 continue;
             }
-            let range = offset_range(&file_text, &line_starts, rep.offset, rep.length);
-            edits.push(TextEdit {
-                range,
-                new_text: rep.replacement_text,
-            });
+            if name.starts_with(&prefix) {
+                remove_cache_file(cache_dir, &entry.path());
+            }
         }
+    }
 
-        if edits.is_empty() {
-            continue;
-        }
+    Ok(())
+}
 
-        let range = offset_range(&file_text, &line_starts, file_offset, 1);
-        let fix = Fix {
-            title: match &diag.diagnostic_name {
-                Some(name) if !name.is_empty() => format!("Apply clang-tidy fix ({name})"),
-                _ => "Apply clang-tidy fix".to_string(),
-            },
-            edits,
-        };
+const DEFAULT_REMOTE_CACHE_TIMEOUT_MS: u64 = 2000;
+/// How long the remote cache is left alone after a transport error.
+const REMOTE_CACHE_RETRY: Duration = Duration::from_secs(60);
+/// Stands for the workspace root in remote cache entries, so they can be
+/// shared between checkouts at different paths.
+const REMOTE_ROOT_PLACEHOLDER: &str = "${workspaceRoot}";
+
+/// HTTP cache shared between machines (`remoteCacheUrl`): entries are fetched
+/// with `GET <url>/<key>` and, with `remoteCacheWrite`, stored with `PUT`.
+/// Keys only depend on inputs that are the same on every checkout of a
+/// commit, and entries hold paths relative to the workspace root.
+#[derive(Default)]
+struct RemoteCache {
+    agent: Mutex<Option<(u64, ureq::Agent)>>,
+    /// Set after a transport error; the cache is skipped until then.
+    offline_until: Mutex<Option<Instant>>,
+    stats: Mutex<RemoteCacheStats>,
+    /// Queue of the upload thread, started by the first `queue_store`.
+    uploads: Mutex<Option<std::sync::mpsc::SyncSender<RemoteUpload>>>,
+}
 
-        diags.push(InternalDiagnostic {
-            file: diag_path,
-            range,
-            severity: "warning".to_string(),
-            code: diag.diagnostic_name.clone(),
-            message,
-            fixes: vec![fix],
-        });
-    }
+/// Uploads waiting for the upload thread; results beyond it are not uploaded.
+const REMOTE_CACHE_UPLOAD_QUEUE: usize = 64;
 
-    Ok(diags)
+struct RemoteUpload {
+    settings: Settings,
+    key: String,
+    root: PathBuf,
+    diagnostics: Vec<RpcDiagnostic>,
 }
 
-fn merge_diagnostics(base: &mut Vec<InternalDiagnostic>, fixes: Vec<InternalDiagnostic>) {
-    let mut map: HashMap<String, InternalDiagnostic> = HashMap::new();
-    for d in base.drain(..) {
-        map.insert(diag_key(&d), d);
-    }
+/// Hash derived from a file, valid while its signature is unchanged.
+struct InputHash {
+    signature: Option<(u64, u64)>,
+    hash: u64,
+}
 
-    for mut f in fixes {
-        let key = diag_key(&f);
-        if let Some(existing) = map.get_mut(&key) {
-            existing.fixes.append(&mut f.fixes);
-        } else {
-            map.insert(key, f);
+/// Hash of what `describe` reports for `path`, computed once per file
+/// signature; portable hashes of the clang-tidy version and compile database.
+fn input_hash(path: &Path, describe: impl FnOnce() -> Option<String>) -> Option<u64> {
+    static INPUT_HASHES: std::sync::OnceLock<Mutex<HashMap<PathBuf, InputHash>>> = std::sync::OnceLock::new();
+    let known = INPUT_HASHES.get_or_init(Default::default);
+    let signature = file_signature(path);
+    if let Some(entry) = known.lock().unwrap().get(path) {
+        if entry.signature == signature {
+            return Some(entry.hash);
         }
     }
-
-    *base = map.into_values().collect();
+    let hash = xxh3_64(describe()?.as_bytes());
+    known.lock().unwrap().insert(path.to_path_buf(), InputHash { signature, hash });
+    Some(hash)
 }
 
-fn apply_diagnostic_caps(diags: &mut Vec<InternalDiagnostic>, max_diags: u32, max_fixes: u32) {
-    if max_diags > 0 && diags.len() > max_diags as usize {
-        diags.truncate(max_diags as usize);
-    }
-
-    if max_fixes == 0 {
-        return;
-    }
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RemoteCacheStats {
+    hits: u64,
+    misses: u64,
+    uploads: u64,
+    errors: u64,
+}
 
-    let mut remaining = max_fixes as usize;
-    for diag in diags.iter_mut() {
-        if diag.fixes.is_empty() {
-            continue;
-        }
-        if remaining == 0 {
-            diag.fixes.clear();
-            continue;
-        }
-        if diag.fixes.len() > remaining {
-            diag.fixes.truncate(remaining);
-            remaining = 0;
-        } else {
-            remaining -= diag.fixes.len();
-        }
-    }
+#[derive(Debug, Deserialize, Serialize)]
+struct RemoteCacheEntry {
+    version: u32,
+    diagnostics: Vec<RpcDiagnostic>,
 }
 
-fn throttle_publish(limiter: &Arc<Mutex<Instant>>, throttle_ms: u64) {
-    if throttle_ms == 0 {
-        return;
-    }
-    let mut last = limiter.lock().unwrap();
-    let elapsed = last.elapsed();
-    let interval = Duration::from_millis(throttle_ms);
-    if elapsed < interval {
-        thread::sleep(interval - elapsed);
+impl RemoteCache {
+    fn enabled(&self, settings: &Settings) -> bool {
+        !settings.remoteCacheUrl.trim().is_empty()
+            && self.offline_until.lock().unwrap().is_none_or(|until| Instant::now() >= until)
     }
-    *last = Instant::now();
-}
 
-fn run_command_with_timeout(cmd: &mut Command, timeout_ms: u64) -> Result<std::process::Output> {
-    if timeout_ms == 0 {
-        return Ok(cmd.output()?);
+    fn status(&self) -> Value {
+        let mut status = serde_json::to_value(self.stats.lock().unwrap().clone()).unwrap_or(Value::Null);
+        let offline = self.offline_until.lock().unwrap().is_some_and(|until| Instant::now() < until);
+        status["offline"] = Value::Bool(offline);
+        status
     }
 
-    cmd.stdout(std::process::Stdio::piped());
-    cmd.stderr(std::process::Stdio::piped());
-    let mut child = cmd.spawn().context("Failed to run clang-tidy")?;
-    let start = Instant::now();
-    loop {
-        if let Some(status) = child.try_wait()? {
-            let mut stdout = String::new();
-            let mut stderr = String::new();
-            if let Some(mut out) = child.stdout.take() {
-                let _ = out.read_to_string(&mut stdout);
-            }
-            if let Some(mut err) = child.stderr.take() {
-                let _ = err.read_to_string(&mut stderr);
-            }
-            return Ok(std::process::Output {
-                status,
-                stdout: stdout.into_bytes(),
-                stderr: stderr.into_bytes(),
-            });
-        }
-        if start.elapsed() >= Duration::from_millis(timeout_ms) {
-            let _ = child.kill();
-            let _ = child.wait();
-            return Err(anyhow::anyhow!("clang-tidy timed out after {} ms", timeout_ms));
+    fn agent(&self, settings: &Settings) -> ureq::Agent {
+        let timeout_ms = match settings.remoteCacheTimeoutMs {
+            0 => DEFAULT_REMOTE_CACHE_TIMEOUT_MS,
+            ms => ms,
+        };
+        let mut agent = self.agent.lock().unwrap();
+        match agent.as_ref() {
+            Some((ms, agent)) if *ms == timeout_ms => agent.clone(),
+            _ => {
+                let built = ureq::AgentBuilder::new().timeout(Duration::from_millis(timeout_ms)).build();
+                *agent = Some((timeout_ms, built.clone()));
+                built
+            }
         }
-        thread::sleep(Duration::from_millis(50));
     }
-}
-
-fn split_command(command: &str) -> Vec<String> {
-    let mut args = Vec::new();
-    let mut current = String::new();
-    let mut in_single = false;
-    let mut in_double = false;
-    let mut escape = false;
 
-    for ch in command.chars() {
-        if escape {
-            current.push(ch);
-            escape = false;
-            continue;
-        }
-        if ch == '\\' && !in_single {
-            escape = true;
-            continue;
-        }
-        if ch == '\'' && !in_double {
-            in_single = !in_single;
-            continue;
+    fn request(&self, settings: &Settings, method: &str, key: &str) -> ureq::Request {
+        let url = format!("{}/{key}", settings.remoteCacheUrl.trim().trim_end_matches('/'));
+        let request = self.agent(settings).request(method, &url);
+        match settings.remoteCacheToken.trim() {
+            "" => request,
+            token => request.set("Authorization", &format!("Bearer {token}")),
         }
-        if ch == '"' && !in_single {
-            in_double = !in_double;
-            continue;
+    }
+
+    /// Counts a failed request. Transport errors also take the cache offline
+    /// for `REMOTE_CACHE_RETRY`, so analysis falls back to the local cache
+    /// without waiting on every file.
+    fn failed(&self, err: &ureq::Error) {
+        self.stats.lock().unwrap().errors += 1;
+        if matches!(err, ureq::Error::Transport(_)) {
+            *self.offline_until.lock().unwrap() = Some(Instant::now() + REMOTE_CACHE_RETRY);
         }
-        if ch.is_whitespace() && !in_single && !in_double {
-            if !current.is_empty() {
-                args.push(current.clone());
-                current.clear();
+    }
+
+    fn fetch(&self, settings: &Settings, key: &str, root: &Path) -> Option<Vec<RpcDiagnostic>> {
+        let body = match self.request(settings, "GET", key).call() {
+            Ok(response) => response.into_string().ok(),
+            Err(ureq::Error::Status(404, _)) => None,
+            Err(err) => {
+                self.failed(&err);
+                return None;
+            }
+        };
+        let entry = body
+            .and_then(|body| serde_json::from_str::<RemoteCacheEntry>(&from_portable_paths(&body, root)).ok())
+            .filter(|entry| entry.version == DISK_CACHE_VERSION);
+        let mut stats = self.stats.lock().unwrap();
+        match entry {
+            Some(mut entry) => {
+                stats.hits += 1;
+                drop_untrusted_fixes(&mut entry.diagnostics);
+                Some(entry.diagnostics)
+            }
+            None => {
+                stats.misses += 1;
+                None
             }
-            continue;
         }
-        current.push(ch);
     }
-    if !current.is_empty() {
-        args.push(current);
+
+    /// Hands `upload` to the upload thread without waiting. When the queue is
+    /// full, e.g. while the server is slow, the result is not uploaded.
+    fn queue_store(self: &Arc<Self>, upload: RemoteUpload) {
+        let mut uploads = self.uploads.lock().unwrap();
+        let sender = uploads.get_or_insert_with(|| {
+            let (sender, receiver) = std::sync::mpsc::sync_channel::<RemoteUpload>(REMOTE_CACHE_UPLOAD_QUEUE);
+            let cache = Arc::downgrade(self);
+            thread::spawn(move || {
+                for upload in receiver {
+                    let Some(cache) = cache.upgrade() else {
+                        break;
+                    };
+                    cache.store(&upload.settings, &upload.key, &upload.root, &upload.diagnostics);
+                }
+            });
+            sender
+        });
+        let _ = sender.try_send(upload);
     }
-    args
-}
 
-fn replace_file_arg(args: &mut [String], raw_file: &str, original_path: &str, temp_path: &str) -> bool {
-    let mut replaced = false;
-    for arg in args.iter_mut() {
-        if arg == raw_file || arg == original_path {
-            *arg = temp_path.to_string();
-            replaced = true;
+    fn store(&self, settings: &Settings, key: &str, root: &Path, diagnostics: &[RpcDiagnostic]) {
+        let entry = RemoteCacheEntry { version: DISK_CACHE_VERSION, diagnostics: diagnostics.to_vec() };
+        let Ok(body) = serde_json::to_string(&entry) else {
+            return;
+        };
+        match self
+            .request(settings, "PUT", key)
+            .set("Content-Type", "application/json")
+            .send_string(&to_portable_paths(&body, root))
+        {
+            Ok(_) => self.stats.lock().unwrap().uploads += 1,
+            Err(err) => self.failed(&err),
         }
     }
-    replaced
+
+    /// Key of the result for `file_path`: its path relative to `root`, its
+    /// content, the result-shaping settings, the clang-tidy version and the
+    /// `.clang-tidy` and compile database contents with `root` abstracted.
+    /// `None` for files outside the workspace.
+    #[allow(clippy::too_many_arguments)]
+    fn key(
+        &self,
+        settings: &Settings,
+        root: &Path,
+        compile_commands: Option<&Path>,
+        compile_dir: Option<&Path>,
+        file_path: &Path,
+        content_hash: u64,
+        mode: &str,
+    ) -> Option<String> {
+        let relative = file_path.strip_prefix(root).ok()?;
+        let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        DISK_CACHE_VERSION.hash(&mut hasher);
+        relative.components().map(|c| c.as_os_str().to_string_lossy().to_string()).collect::<Vec<_>>().hash(&mut hasher);
+        content_hash.hash(&mut hasher);
+        hash_result_settings(settings, mode, &mut hasher);
+        let clang_tidy = resolve_binary_path(&clang_tidy_binary(settings))?;
+        input_hash(&clang_tidy, || detect_tool_version(&clang_tidy.to_string_lossy())).hash(&mut hasher);
+        if let Some(path) = compile_commands {
+            input_hash(path, || std::fs::read_to_string(path).ok().map(|text| to_portable_paths(&text, root))).hash(&mut hasher);
+        }
+        clang_tidy_config_path(Some(root), compile_dir).and_then(|p| file_content_hash(&p)).hash(&mut hasher);
+        Some(format!("{:016x}", hasher.finish()))
+    }
 }
 
-fn resolve_arguments(entry: &CompileCommandEntry) -> Option<Vec<String>> {
-    if let Some(args) = entry.arguments.clone() {
-        return Some(args);
+/// Removes the fixes of results from a remote cache or a cache archive. Their
+/// edits were made elsewhere and cannot be checked against the local files,
+/// so they are not offered or applied.
+fn drop_untrusted_fixes(diagnostics: &mut [RpcDiagnostic]) {
+    for diag in diagnostics {
+        diag.fixes = None;
     }
-    entry.command.as_ref().map(|cmd| split_command(cmd))
 }
 
-fn diag_key(d: &InternalDiagnostic) -> String {
-    format!(
-        "{}:{}:{}:{}:{}",
-        d.file.display(),
-        d.range.start.line,
-        d.range.start.character,
-        d.code.clone().unwrap_or_default(),
-        d.message
-    )
+/// Replaces the workspace root in JSON text, as a URI and as a path, with
+/// `REMOTE_ROOT_PLACEHOLDER`.
+fn to_portable_paths(text: &str, root: &Path) -> String {
+    root_spellings(root)
+        .into_iter()
+        .fold(text.to_string(), |text, (spelling, placeholder)| text.replace(&spelling, &placeholder))
 }
 
-fn to_rpc_diagnostic(d: InternalDiagnostic) -> RpcDiagnostic {
-    RpcDiagnostic {
-        range: d.range,
-        severity: d.severity,
-        code: d.code,
-        message: d.message,
-        fixes: if d.fixes.is_empty() { None } else { Some(d.fixes) },
-    }
+fn from_portable_paths(text: &str, root: &Path) -> String {
+    root_spellings(root)
+        .into_iter()
+        .fold(text.to_string(), |text, (spelling, placeholder)| text.replace(&placeholder, &spelling))
 }
 
-fn normalize_severity(raw: &str) -> String {
-    match raw {
-        "error" => "error",
-        "warning" => "warning",
-        _ => "info",
+/// The root as it appears in JSON text, paired with its placeholder; the URI
+/// comes first since it contains the path.
+fn root_spellings(root: &Path) -> Vec<(String, String)> {
+    let mut spellings = Vec::new();
+    if let Ok(uri) = Url::from_directory_path(root) {
+        spellings.push((uri.to_string(), format!("file://{REMOTE_ROOT_PLACEHOLDER}/")));
     }
-    .to_string()
+    if let Ok(path) = serde_json::to_string(&root.to_string_lossy()) {
+        spellings.push((path.trim_matches('"').to_string(), REMOTE_ROOT_PLACEHOLDER.to_string()));
+    }
+    spellings
 }
 
-fn range_from_line_col(line: usize, col: usize) -> Range {
-    let line0 = line.saturating_sub(1);
-    let col0 = col.saturating_sub(1);
-    Range {
-        start: Position { line: line0, character: col0 },
-        end: Position { line: line0, character: col0 + 1 },
-    }
+/// Modes whose results `exportCache` collects; `changed` results depend on the
+/// local diff.
+const CACHE_ARCHIVE_MODES: [&str; 3] = ["quick", "full", "deep"];
+
+/// The compile database files of a workspace and the caches that hold their
+/// results, for `exportCache` and `importCache`.
+struct CacheArchiveScope<'a> {
+    settings: &'a Settings,
+    root_dir: &'a Path,
+    compile_commands: &'a Path,
+    files: &'a [PathBuf],
+    memory: Option<&'a Arc<Mutex<SpillStore<CacheEntry>>>>,
+    remote: &'a RemoteCache,
 }
 
-fn build_line_starts(text: &str) -> Vec<usize> {
-    let mut starts = vec![0];
-    for (i, b) in text.bytes().enumerate() {
-        if b == b'\n' {
-            starts.push(i + 1);
-        }
-    }
-    starts
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheArchiveManifest {
+    version: u32,
+    daemon_version: String,
+    commit: Option<String>,
+    created_at: u64,
+    entries: usize,
 }
 
-fn offset_range(text: &str, line_starts: &[usize], offset: usize, length: usize) -> Range {
-    let (start_line, start_col) = offset_to_line_col(text, line_starts, offset);
-    let (end_line, end_col) = offset_to_line_col(text, line_starts, offset + length);
-    Range {
-        start: Position { line: start_line, character: start_col },
-        end: Position { line: end_line, character: end_col },
+impl CacheArchiveScope<'_> {
+    /// The local settings hash and the portable key (as used by the remote
+    /// cache) of `file_path` in each archived mode.
+    fn keys(&self, file_path: &Path, content_hash: u64) -> Vec<(u64, String)> {
+        let compile_dir = self.compile_commands.parent();
+        CACHE_ARCHIVE_MODES
+            .iter()
+            .filter_map(|mode| {
+                let settings_hash = settings_fingerprint(self.settings, Some(self.root_dir), Some(self.compile_commands), mode);
+                let key = self.remote.key(
+                    self.settings,
+                    self.root_dir,
+                    Some(self.compile_commands),
+                    compile_dir,
+                    file_path,
+                    content_hash,
+                    mode,
+                )?;
+                Some((settings_hash, key))
+            })
+            .collect()
+    }
+
+    fn cache_dir(&self) -> Option<PathBuf> {
+        resolve_cache_dir(self.settings, Some(self.root_dir), self.compile_commands.parent())
     }
 }
 
-fn offset_to_line_col(text: &str, line_starts: &[usize], offset: usize) -> (usize, usize) {
-    let offset = offset.min(text.len());
-    let mut line = 0;
-    for (idx, &start) in line_starts.iter().enumerate() {
-        if start > offset {
-            break;
+/// `git rev-parse HEAD` in `dir`.
+fn head_commit(dir: &Path) -> Option<String> {
+    let output = Command::new("git").current_dir(dir).args(["rev-parse", "HEAD"]).output().ok()?;
+    let commit = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !commit.is_empty()).then_some(commit)
+}
+
+fn cache_archive_name(commit: Option<&str>) -> String {
+    format!("clang-tidy-cache-{}.zip", commit.unwrap_or("workspace"))
+}
+
+/// Writes the cached results of every file in `scope`, in the archived
+/// modes, to `clang-tidy-cache-<commit>.zip` in `output_dir`. Entries are
+/// stored under their portable keys with workspace-relative paths, so the
+/// archive can be imported into another checkout of the same commit.
+fn export_cache_archive(scope: &CacheArchiveScope, output_dir: &Path) -> Result<(PathBuf, CacheArchiveManifest)> {
+    let cache_dir = scope.cache_dir();
+    let portable_root = portable_cache_root(scope.settings, Some(scope.root_dir));
+    let mut entries: Vec<(String, String)> = Vec::new();
+    for file_path in scope.files {
+        let Some(content_hash) = file_content_hash(file_path) else {
+            continue;
+        };
+        let in_memory = scope.memory.and_then(|memory| memory.lock().unwrap().get(file_path));
+        for (settings_hash, key) in scope.keys(file_path, content_hash) {
+            let diagnostics = in_memory
+                .as_ref()
+                .filter(|entry| entry.settings_hash == settings_hash && entry.content_hash == content_hash)
+                .map(|entry| entry.diagnostics.clone())
+                .or_else(|| read_disk_cache(cache_dir.as_deref()?, portable_root, file_path, content_hash, settings_hash).map(|entry| entry.diagnostics));
+            let Some(diagnostics) = diagnostics else {
+                continue;
+            };
+            let entry = RemoteCacheEntry { version: DISK_CACHE_VERSION, diagnostics };
+            entries.push((format!("entries/{key}.json"), to_portable_paths(&serde_json::to_string(&entry)?, scope.root_dir)));
         }
-        line = idx;
     }
-    let line_start = line_starts.get(line).cloned().unwrap_or(0);
-    let slice = &text[line_start..offset];
-    let col = slice.encode_utf16().count();
-    (line, col)
-}
 
-fn resolve_path(path_str: &str, root_dir: Option<&Path>) -> Option<PathBuf> {
-    if path_str.is_empty() {
-        return None;
+    let commit = head_commit(scope.root_dir);
+    let manifest = CacheArchiveManifest {
+        version: DISK_CACHE_VERSION,
+        daemon_version: env!("CARGO_PKG_VERSION").to_string(),
+        commit: commit.clone(),
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        entries: entries.len(),
+    };
+    std::fs::create_dir_all(output_dir).with_context(|| format!("Failed to create {}", output_dir.display()))?;
+    let path = output_dir.join(cache_archive_name(commit.as_deref()));
+    let mut tmp = NamedTempFile::new_in(output_dir)?;
+    {
+        let mut zip = zip::ZipWriter::new(tmp.as_file_mut());
+        let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        zip.start_file("manifest.json", options)?;
+        zip.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+        for (name, text) in &entries {
+            zip.start_file(name.as_str(), options)?;
+            zip.write_all(text.as_bytes())?;
+        }
+        zip.finish().context("Failed to write cache archive")?;
     }
-    let p = PathBuf::from(path_str);
-    if p.is_absolute() {
-        Some(p)
-    } else if let Some(root) = root_dir {
-        Some(root.join(p))
+    tmp.persist(&path).map_err(|err| err.error)?;
+    Ok((path, manifest))
+}
+
+/// Result of `import_cache_archive`.
+struct CacheImport {
+    manifest: CacheArchiveManifest,
+    /// Archive entries written to the local caches.
+    imported: usize,
+    /// Results already cached locally.
+    present: usize,
+}
+
+/// Looks up every file in `scope` in an archive from `export_cache_archive`
+/// and stores the results it holds for the file's current content in the
+/// disk cache, or in memory when the disk cache is off. `path` may be the
+/// archive or a directory holding `clang-tidy-cache-<HEAD>.zip`.
+fn import_cache_archive(scope: &CacheArchiveScope, path: &Path) -> Result<CacheImport> {
+    let path = if path.is_dir() {
+        path.join(cache_archive_name(head_commit(scope.root_dir).as_deref()))
     } else {
-        Some(p)
+        path.to_path_buf()
+    };
+    let file = std::fs::File::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut zip = zip::ZipArchive::new(file).context("Invalid cache archive")?;
+    let read = |zip: &mut zip::ZipArchive<std::fs::File>, name: &str| -> Option<String> {
+        let mut text = String::new();
+        zip.by_name(name).ok()?.read_to_string(&mut text).ok()?;
+        Some(text)
+    };
+    let manifest: CacheArchiveManifest = read(&mut zip, "manifest.json")
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .context("Cache archive has no valid manifest.json")?;
+    if manifest.version != DISK_CACHE_VERSION {
+        anyhow::bail!("Cache archive has format version {}, expected {}", manifest.version, DISK_CACHE_VERSION);
+    }
+
+    let cache_dir = scope.cache_dir();
+    let portable_root = portable_cache_root(scope.settings, Some(scope.root_dir));
+    let mut import = CacheImport { manifest, imported: 0, present: 0 };
+    for file_path in scope.files {
+        let (Some((mtime, size)), Some(content_hash)) = (file_signature(file_path), file_content_hash(file_path)) else {
+            continue;
+        };
+        for (settings_hash, key) in scope.keys(file_path, content_hash) {
+            let cached = match cache_dir.as_deref() {
+                Some(dir) => dir.join(disk_cache_file_name(portable_root, file_path, content_hash, settings_hash)).exists(),
+                None => scope.memory.is_some_and(|memory| {
+                    memory
+                        .lock()
+                        .unwrap()
+                        .get(file_path)
+                        .is_some_and(|entry| entry.settings_hash == settings_hash && entry.content_hash == content_hash)
+                }),
+            };
+            if cached {
+                import.present += 1;
+                continue;
+            }
+            let Some(entry) = read(&mut zip, &format!("entries/{key}.json"))
+                .and_then(|text| serde_json::from_str::<RemoteCacheEntry>(&from_portable_paths(&text, scope.root_dir)).ok())
+            else {
+                continue;
+            };
+            let mut diagnostics = entry.diagnostics;
+            drop_untrusted_fixes(&mut diagnostics);
+            let entry = CacheEntry { mtime, size, content_hash, settings_hash, diagnostics, fix_files: Vec::new() };
+            match (cache_dir.as_deref(), scope.memory) {
+                (Some(dir), _) => write_disk_cache(dir, portable_root, file_path, &entry)?,
+                (None, Some(memory)) => memory.lock().unwrap().insert(file_path.clone(), entry),
+                (None, None) => continue,
+            }
+            import.imported += 1;
+        }
     }
+    Ok(import)
 }
 
-fn file_signature(path: &Path) -> Option<(u64, u64)> {
-    let meta = std::fs::metadata(path).ok()?;
-    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
-    Some((mtime, meta.len()))
+/// Where the memory cache of a workspace is kept between sessions when there
+/// is no disk cache: the per-user cache directory, or the temp directory.
+fn cache_snapshot_path(root: &Path) -> PathBuf {
+    os_cache_dir()
+        .map(|dir| dir.join("clang-tidy-daemon"))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("sessions")
+        .join(format!("{:016x}.json", cache_key_for_path(root)))
 }
 
-fn mtime_for_path(path: &Path) -> Option<u64> {
-    let meta = std::fs::metadata(path).ok()?;
-    meta.modified().ok()?.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+/// Whether the memory cache is saved at `shutdown` and restored at
+/// `initialize`; with a disk cache the results survive restarts anyway.
+fn cache_snapshot_enabled(settings: &Settings) -> bool {
+    !settings.daemonCacheOnDisk && !settings.disableCacheSnapshot
 }
 
-fn clang_tidy_config_mtime(root_dir: Option<&Path>, compile_dir: Option<&Path>) -> Option<u64> {
-    let mut candidates = Vec::new();
-    if let Some(dir) = compile_dir {
-        candidates.push(dir.to_path_buf());
+/// Writes every memory cache entry, in the disk cache entry format, to the
+/// workspace's snapshot file.
+fn save_cache_snapshot(state: &AppState, settings: &Settings) {
+    let Some(root) = state.root_dir.lock().unwrap().clone() else {
+        return;
+    };
+    if !cache_snapshot_enabled(settings) {
+        return;
     }
-    if let Some(dir) = root_dir {
-        candidates.push(dir.to_path_buf());
+    let entries: Vec<DiskCacheEntry> = state
+        .cache
+        .lock()
+        .unwrap()
+        .snapshot()
+        .into_iter()
+        // The cache is shared with the other `--socket`/`--listen` sessions;
+        // their workspaces keep their own snapshots.
+        .filter(|(path, _)| state.owns_process || path.starts_with(&root))
+        .map(|(path, cached)| DiskCacheEntry {
+            version: DISK_CACHE_VERSION,
+            filePath: path.to_string_lossy().to_string(),
+            mtime: cached.mtime,
+            size: cached.size,
+            contentHash: cached.content_hash,
+            settingsHash: cached.settings_hash,
+            diagnostics: cached.diagnostics,
+            fixFiles: cached.fix_files,
+        })
+        .collect();
+    let path = cache_snapshot_path(&root);
+    let write = || -> Result<()> {
+        let dir = path.parent().context("snapshot path has no parent")?;
+        std::fs::create_dir_all(dir)?;
+        let mut tmp = NamedTempFile::new_in(dir)?;
+        serde_json::to_writer(io::BufWriter::new(tmp.as_file_mut()), &entries)?;
+        tmp.persist(&path).map_err(|err| err.error)?;
+        Ok(())
+    };
+    if let Err(err) = write() {
+        let _ = send_log(&state.stdout, "warn", format!("Failed to save the memory cache to {}: {err}", path.display()));
     }
+}
 
-    for dir in candidates {
-        let p = dir.join(".clang-tidy");
-        if p.exists() {
-            return mtime_for_path(&p);
+/// Loads the snapshot written by the previous session into the memory cache.
+/// Entries are checked like any other on use, so results for files edited in
+/// between, or made with other settings, are not served.
+fn restore_cache_snapshot(state: &AppState, settings: &Settings) {
+    let Some(root) = state.root_dir.lock().unwrap().clone() else {
+        return;
+    };
+    if !cache_snapshot_enabled(settings) {
+        return;
+    }
+    let Some(entries) = std::fs::read(cache_snapshot_path(&root))
+        .ok()
+        .and_then(|data| serde_json::from_slice::<Vec<DiskCacheEntry>>(&data).ok())
+    else {
+        return;
+    };
+    let mut cache = state.cache.lock().unwrap();
+    let mut restored = 0;
+    for entry in entries.into_iter().filter(|e| e.version == DISK_CACHE_VERSION) {
+        let path = PathBuf::from(&entry.filePath);
+        if cache.contains_key(&path) || !path.exists() {
+            continue;
         }
+        cache.insert(
+            path,
+            CacheEntry {
+                mtime: entry.mtime,
+                size: entry.size,
+                content_hash: entry.contentHash,
+                settings_hash: entry.settingsHash,
+                diagnostics: entry.diagnostics,
+                fix_files: entry.fixFiles,
+            },
+        );
+        restored += 1;
+    }
+    drop(cache);
+    if restored > 0 {
+        let _ = send_log(&state.stdout, "info", format!("Restored {restored} cached results from the previous session"));
     }
-    None
 }
 
-fn find_clang_tidy_config(file_path: &Path, root_dir: Option<&Path>, compile_dir: Option<&Path>) -> Option<PathBuf> {
-    let mut current = file_path.parent();
-    while let Some(dir) = current {
-        let candidate = dir.join(".clang-tidy");
-        if candidate.exists() {
-            return Some(candidate);
+fn append_cache_journal(cache_dir: &Path, op: &str, file: &str) -> io::Result<()> {
+    let record = CacheJournalRecord { op: op.to_string(), file: file.to_string() };
+    let mut line = serde_json::to_string(&record).map_err(io::Error::other)?;
+    line.push('\n');
+    let mut journal = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(cache_dir.join(CACHE_JOURNAL_FILE))?;
+    journal.write_all(line.as_bytes())
+}
+
+fn remove_cache_file(cache_dir: &Path, path: &Path) {
+    let Some(name) = path.file_name().map(|n| n.to_string_lossy().to_string()) else {
+        return;
+    };
+    if append_cache_journal(cache_dir, "evict", &name).is_err() {
+        return;
+    }
+    if std::fs::remove_file(path).is_ok() || !path.exists() {
+        let _ = append_cache_journal(cache_dir, "removed", &name);
+    }
+}
+
+/// Replays the journal left by previous runs: deletes entries whose write never
+/// committed, finishes interrupted evictions, removes unreadable or outdated
+/// entries and abandoned temp files, then truncates the journal.
+fn recover_disk_cache(cache_dir: &Path) -> CacheRecovery {
+    let mut recovery = CacheRecovery::default();
+    let journal_path = cache_dir.join(CACHE_JOURNAL_FILE);
+    let mut pending_writes: HashSet<String> = HashSet::new();
+    let mut pending_evictions: HashSet<String> = HashSet::new();
+    if let Ok(text) = std::fs::read_to_string(&journal_path) {
+        // A torn final line is ignored; the entry it named is validated below.
+        for record in text.lines().filter_map(|l| serde_json::from_str::<CacheJournalRecord>(l).ok()) {
+            match record.op.as_str() {
+                "begin" => {
+                    pending_writes.insert(record.file);
+                }
+                "commit" => {
+                    pending_writes.remove(&record.file);
+                }
+                "evict" => {
+                    pending_evictions.insert(record.file);
+                }
+                "removed" => {
+                    pending_evictions.remove(&record.file);
+                }
+                _ => {}
+            }
         }
-        if root_dir.is_some() && root_dir == Some(dir) {
-            break;
+    }
+    for name in pending_writes {
+        if std::fs::remove_file(cache_dir.join(&name)).is_ok() {
+            recovery.incomplete += 1;
         }
-        if compile_dir.is_some() && compile_dir == Some(dir) {
-            break;
+    }
+    for name in pending_evictions {
+        if std::fs::remove_file(cache_dir.join(&name)).is_ok() {
+            recovery.evictions += 1;
         }
-        current = dir.parent();
     }
 
-    if let Some(dir) = compile_dir {
-        let candidate = dir.join(".clang-tidy");
-        if candidate.exists() {
-            return Some(candidate);
+    if let Ok(entries) = std::fs::read_dir(cache_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with(".tmp") {
+                // Only temp files old enough not to belong to a write in progress.
+                let abandoned = entry
+                    .metadata()
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|t| t.elapsed().ok())
+                    .is_some_and(|age| age > Duration::from_secs(60));
+                if abandoned && std::fs::remove_file(&path).is_ok() {
+                    recovery.temp_files += 1;
+                }
+                continue;
+            }
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            // Entries of other format versions belong to other daemons sharing the directory.
+            let readable = std::fs::read(&path).ok().and_then(|data| serde_json::from_slice::<Value>(&data).ok()).is_some_and(|value| {
+                value.get("version").and_then(Value::as_u64) != Some(DISK_CACHE_VERSION as u64)
+                    || serde_json::from_value::<DiskCacheEntry>(value).is_ok()
+            });
+            if readable {
+                recovery.kept += 1;
+            } else if std::fs::remove_file(&path).is_ok() {
+                recovery.unreadable += 1;
+            }
         }
     }
-    if let Some(dir) = root_dir {
-        let candidate = dir.join(".clang-tidy");
-        if candidate.exists() {
-            return Some(candidate);
+
+    let _ = std::fs::write(&journal_path, b"");
+    recovery
+}
+
+const TEMP_ARTIFACT_PREFIX: &str = "clang-tidy-daemon-tmp-";
+const DEFAULT_JANITOR_INTERVAL_MS: u64 = 30 * 60 * 1000;
+const DEFAULT_COMPILE_DB_WATCH_INTERVAL_MS: u64 = 2000;
+
+/// Temp files and dirs are named after the owning pid so that the janitor can
+/// tell artifacts of crashed daemons from those of running ones.
+fn temp_artifact_prefix() -> String {
+    format!("{TEMP_ARTIFACT_PREFIX}{}-", std::process::id())
+}
+
+fn new_fixes_file() -> io::Result<NamedTempFile> {
+    tempfile::Builder::new()
+        .prefix(&temp_artifact_prefix())
+        .suffix(".yaml")
+        .tempfile()
+}
+
+/// Leading lines of `content` that only hold includes, macro definitions,
+/// pragmas and comments. `None` without an include, since a PCH would not
+/// save anything.
+fn preamble_text(content: &str) -> Option<&str> {
+    let mut end = 0;
+    let mut includes = false;
+    let mut in_comment = false;
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if in_comment || trimmed.starts_with("/*") {
+            in_comment = !trimmed.contains("*/");
+        } else if let Some(directive) = trimmed.strip_prefix('#').map(str::trim_start) {
+            if directive.starts_with("include") || directive.starts_with("import") {
+                includes = true;
+            } else if !(directive.starts_with("define") || directive.starts_with("undef") || directive.starts_with("pragma")) {
+                break;
+            }
+            // A continued directive would be cut in half.
+            if trimmed.ends_with('\\') {
+                break;
+            }
+        } else if !(trimmed.is_empty() || trimmed.starts_with("//")) {
+            break;
         }
+        end += line.len();
+    }
+    if in_comment || !includes {
+        return None;
     }
+    Some(&content[..end])
+}
 
-    None
+fn preamble_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("{}pch", temp_artifact_prefix()))
 }
 
-fn settings_fingerprint(
-    settings: &Settings,
-    compile_commands: Option<&Path>,
-    compile_commands_mtime: Option<u64>,
-    config_mtime: Option<u64>,
-    mode: &str,
-) -> u64 {
+/// PCH of the preamble of `content`, compiled with the flags of its compile
+/// command, when one is up to date. Otherwise a build is started in the
+/// background for the next run and `None` returned. PCHs are keyed by the
+/// preamble text, the flags and the compiler, and are stale once any header
+/// they were built from is newer than they are.
+fn preamble_pch(settings: &Settings, directory: &str, args: &[String], source: &str, file_path: &Path, content: &str) -> Option<PathBuf> {
+    // The PCH is built by the GNU-style driver; clang-cl mode would reject it.
+    if is_cl_driver(args) {
+        return None;
+    }
+    let preamble = preamble_text(content)?.to_string();
+    let flags: Vec<String> = compile_only_flags(args.get(1..)?, false)
+        .into_iter()
+        .filter(|arg| *arg != source)
+        .map(str::to_string)
+        .collect();
+    // Projects that already force-include a header or PCH are left alone.
+    if flags.iter().any(|arg| arg.starts_with("-include") || arg == "--include") {
+        return None;
+    }
+    let language = header_language(file_path);
+    let clang = clang_binary(settings);
     let mut hasher = DefaultHasher::new();
-    settings.clangTidyPath.hash(&mut hasher);
-    settings.extraArgs.hash(&mut hasher);
-    settings.maxWorkers.hash(&mut hasher);
-    settings.quickChecks.hash(&mut hasher);
-    settings.maxDiagnosticsPerFile.hash(&mut hasher);
-    settings.maxFixesPerFile.hash(&mut hasher);
-    settings.perFileTimeoutMs.hash(&mut hasher);
-    mode.hash(&mut hasher);
-    if let Some(p) = compile_commands {
-        p.to_string_lossy().hash(&mut hasher);
+    preamble.hash(&mut hasher);
+    flags.hash(&mut hasher);
+    language.hash(&mut hasher);
+    directory.hash(&mut hasher);
+    hash_tool_identity(&clang, &mut hasher);
+    let dir = preamble_dir();
+    let base = dir.join(format!("{:016x}", hasher.finish()));
+    let pch = base.with_extension("pch");
+
+    if preamble_up_to_date(&pch, &base.with_extension("d")) {
+        return Some(pch);
     }
-    compile_commands_mtime.hash(&mut hasher);
-    config_mtime.hash(&mut hasher);
-    hasher.finish()
+    // The marker claims the build. It stays behind when clang fails, so a
+    // preamble that does not compile is not retried on every keystroke.
+    std::fs::create_dir_all(&dir).ok()?;
+    std::fs::OpenOptions::new().write(true).create_new(true).open(base.with_extension("building")).ok()?;
+    let timeout = if settings.perFileTimeoutMs > 0 { settings.perFileTimeoutMs } else { 60_000 };
+    let directory = directory.to_string();
+    thread::spawn(move || {
+        let header = base.with_extension("h");
+        let staging = base.with_extension("pch.tmp");
+        if std::fs::write(&header, preamble).is_err() {
+            return;
+        }
+        let mut cmd = Command::new(clang);
+        cmd.args(&flags)
+            .arg("-x")
+            .arg(language)
+            .arg(&header)
+            .arg("-o")
+            .arg(&staging)
+            .arg("-MD")
+            .arg("-MF")
+            .arg(base.with_extension("d"))
+            .current_dir(&directory);
+        let built = run_command_with_timeout(&mut cmd, timeout).is_ok_and(|output| output.status.success());
+        if built && std::fs::rename(&staging, base.with_extension("pch")).is_ok() {
+            let _ = std::fs::remove_file(base.with_extension("building"));
+        } else {
+            let _ = std::fs::remove_file(&staging);
+        }
+    });
+    None
 }
 
-fn cache_key_for_path(path: &Path) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    path.to_string_lossy().hash(&mut hasher);
-    hasher.finish()
+/// Whether `pch` exists and is newer than every input listed in its
+/// make-style dependency file.
+fn preamble_up_to_date(pch: &Path, deps: &Path) -> bool {
+    let Some(built) = mtime_for_path(pch) else {
+        return false;
+    };
+    let Ok(text) = std::fs::read_to_string(deps) else {
+        return false;
+    };
+    let inputs = text.split_once(": ").map(|(_, inputs)| inputs).unwrap_or("");
+    inputs
+        .split_whitespace()
+        .filter(|input| *input != "\\")
+        .all(|input| mtime_for_path(Path::new(input)).is_some_and(|mtime| mtime <= built))
 }
 
-fn cache_file_name(key: u64, mtime: u64, size: u64, settings_hash: u64) -> String {
-    format!("{:016x}-{}-{}-{:016x}.json", key, mtime, size, settings_hash)
+/// clang rejects a PCH built by another compiler version or with different
+/// flags; the run is then repeated without it.
+fn preamble_rejected(output: &std::process::Output) -> bool {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    [stderr, stdout].iter().any(|text| text.contains("PCH file") || text.contains("precompiled header"))
 }
 
-fn resolve_cache_dir(settings: &Settings, root_dir: Option<&Path>, compile_dir: Option<&Path>) -> Option<PathBuf> {
-    if !settings.daemonCacheOnDisk {
-        return None;
-    }
+/// Drops a rejected PCH, keeping its build marker so it is not rebuilt.
+fn discard_preamble(pch: &Path) {
+    let _ = std::fs::remove_file(pch);
+    let _ = std::fs::write(pch.with_extension("building"), b"");
+}
 
-    let trimmed = settings.daemonCacheDir.trim();
-    let mut base = if !trimmed.is_empty() {
-        PathBuf::from(trimmed)
-    } else if let Some(root) = root_dir {
-        root.join(".vscode").join("clang-tidy-daemon-cache")
-    } else if let Some(dir) = compile_dir {
-        dir.join(".vscode").join("clang-tidy-daemon-cache")
-    } else {
-        return None;
+/// Removes temp files and dirs left in the system temp directory by daemons
+/// that are no longer running.
+fn clean_orphaned_temp_artifacts() -> usize {
+    let Ok(entries) = std::fs::read_dir(std::env::temp_dir()) else {
+        return 0;
     };
-
-    if base.is_relative() {
-        if let Some(root) = root_dir {
-            base = root.join(base);
-        } else if let Some(dir) = compile_dir {
-            base = dir.join(base);
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(pid) = name
+            .strip_prefix(TEMP_ARTIFACT_PREFIX)
+            .and_then(|rest| rest.split_once('-'))
+            .and_then(|(pid, _)| pid.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        if pid == std::process::id() || process_alive(pid) {
+            continue;
+        }
+        let path = entry.path();
+        let result = if path.is_dir() { std::fs::remove_dir_all(&path) } else { std::fs::remove_file(&path) };
+        if result.is_ok() {
+            removed += 1;
         }
     }
+    removed
+}
 
-    if std::fs::create_dir_all(&base).is_err() {
-        return None;
+/// Evicts disk cache entries whose source file was deleted or, when the compile
+/// database is loaded, is no longer part of it.
+fn clean_stale_cache_entries(cache_dir: &Path, index: Option<&CompileCommandsIndex>) -> usize {
+    let Ok(entries) = std::fs::read_dir(cache_dir) else {
+        return 0;
+    };
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let Some(cached) = std::fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<DiskCacheEntry>(&data).ok())
+        else {
+            continue;
+        };
+        // Entries of a global cache name the file relative to its workspace.
+        if cached.filePath.starts_with(REMOTE_ROOT_PLACEHOLDER) {
+            continue;
+        }
+        let source = PathBuf::from(&cached.filePath);
+        let orphaned = !source.exists() || index.is_some_and(|index| !file_in_index(&source, index));
+        if orphaned {
+            remove_cache_file(cache_dir, &path);
+            removed += 1;
+        }
     }
-
-    Some(base)
+    removed
 }
 
-fn read_disk_cache(
-    cache_dir: &Path,
-    file_path: &Path,
-    mtime: u64,
-    size: u64,
-    settings_hash: u64,
-) -> Option<Vec<RpcDiagnostic>> {
-    let key = cache_key_for_path(file_path);
-    let filename = cache_file_name(key, mtime, size, settings_hash);
-    let full_path = cache_dir.join(filename);
-    let data = std::fs::read(full_path).ok()?;
-    let entry: DiskCacheEntry = serde_json::from_slice(&data).ok()?;
-    if entry.version != DISK_CACHE_VERSION {
-        return None;
+/// Removes the least recently used disk cache entries until the directory
+/// holds at most `max_bytes` of them; 0 is unlimited. Reads refresh an entry's
+/// mtime, so the oldest mtime goes first.
+fn enforce_disk_cache_limit(cache_dir: &Path, max_bytes: u64) -> usize {
+    if max_bytes == 0 {
+        return 0;
     }
-    if entry.filePath != file_path.to_string_lossy() {
-        return None;
+    let Ok(entries) = std::fs::read_dir(cache_dir) else {
+        return 0;
+    };
+    let mut files: Vec<(SystemTime, u64, PathBuf)> = entries
+        .flatten()
+        .filter(|entry| entry.path().extension().and_then(|e| e.to_str()) == Some("json"))
+        .filter_map(|entry| {
+            let meta = entry.metadata().ok()?;
+            Some((meta.modified().unwrap_or(UNIX_EPOCH), meta.len(), entry.path()))
+        })
+        .collect();
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    files.sort();
+    let mut removed = 0;
+    for (_, len, path) in files {
+        if total <= max_bytes {
+            break;
+        }
+        remove_cache_file(cache_dir, &path);
+        total = total.saturating_sub(len);
+        removed += 1;
     }
-    if entry.mtime != mtime || entry.size != size || entry.settingsHash != settings_hash {
-        return None;
+    removed
+}
+
+/// Applies `cacheMaxBytes` to the configured disk cache directory and counts
+/// the evictions.
+fn trim_disk_cache(state: &AppState) -> usize {
+    let settings = state.settings.lock().unwrap().clone();
+    if settings.cacheMaxBytes == 0 {
+        return 0;
     }
-    Some(entry.diagnostics)
+    let root_dir = state.root_dir.lock().unwrap().clone();
+    let index = state.compile_index.lock().unwrap().clone();
+    let compile_dir = index.as_ref().and_then(|i| i.path.parent().map(Path::to_path_buf));
+    let removed = resolve_cache_dir(&settings, root_dir.as_deref(), compile_dir.as_deref())
+        .map(|dir| enforce_disk_cache_limit(&dir, settings.cacheMaxBytes))
+        .unwrap_or(0);
+    state.disk_cache_evictions.fetch_add(removed as u64, Ordering::Relaxed);
+    removed
 }
 
-fn write_disk_cache(
-    cache_dir: &Path,
-    file_path: &Path,
-    mtime: u64,
-    size: u64,
-    settings_hash: u64,
-    diagnostics: &[RpcDiagnostic],
-) -> Result<()> {
-    let key = cache_key_for_path(file_path);
-    let filename = cache_file_name(key, mtime, size, settings_hash);
-    let full_path = cache_dir.join(&filename);
+const CACHE_WARMING_RUN_ID: &str = "cacheWarming";
 
-    let entry = DiskCacheEntry {
-        version: DISK_CACHE_VERSION,
-        filePath: file_path.to_string_lossy().to_string(),
-        mtime,
-        size,
-        settingsHash: settings_hash,
-        diagnostics: diagnostics.to_vec(),
+/// Starts the `cacheWarming` pass: analyzes the compile database files that
+/// are not cached yet, in the mode `didOpen` uses, on `cacheWarmingWorkers`
+/// threads at background priority. Results are cached, not published. A pass
+/// still running from an earlier `initialize` is cancelled.
+fn start_cache_warming(state: &AppState) {
+    let cancel = Arc::new(AtomicBool::new(false));
+    if let Some(previous) = state.cancel_map.lock().unwrap().insert(CACHE_WARMING_RUN_ID.to_string(), cancel.clone()) {
+        previous.store(true, Ordering::Relaxed);
+    }
+    let settings = state.settings.lock().unwrap().clone();
+    let root_dir = state.root_dir.lock().unwrap().clone();
+    let Some(compile_commands) = resolve_compile_commands_path(&settings, root_dir.as_deref(), state) else {
+        return;
     };
-    let data = serde_json::to_vec(&entry)?;
+    let index = match get_compile_index(&compile_commands, state) {
+        Ok(index) => index,
+        Err(err) => {
+            let _ = send_log(&state.stdout, "warn", format!("Cache warming skipped: {err}"));
+            return;
+        }
+    };
+    let mode = trigger_mode(&settings, "didOpen").unwrap_or_else(|| "quick".to_string());
+    let state = state.clone();
+    let active = state.active_runs.enter();
+    thread::spawn(move || {
+        let _active = active;
+        let compile_dir = compile_commands.parent();
+        let settings_hash = settings_fingerprint(&settings, root_dir.as_deref(), Some(&compile_commands), &mode);
+        let cache_dir = resolve_cache_dir(&settings, root_dir.as_deref(), compile_dir);
+        let files: Vec<PathBuf> = index
+            .files
+            .iter()
+            .filter(|file_path| glob_exclusion(&settings, file_path, root_dir.as_deref()).is_none())
+            .filter(|file_path| !is_cached(file_path, &state.cache, settings_hash, cache_dir.as_deref(), portable_cache_root(&settings, root_dir.as_deref())))
+            .cloned()
+            .collect();
+        let warming = state.cache_warming.clone();
+        {
+            let mut progress = warming.progress.lock().unwrap();
+            (progress.running, progress.done, progress.total) = (true, 0, files.len());
+        }
+        let total = files.len();
+        let pool = ThreadPool::new(settings.cacheWarmingWorkers.max(1) as usize);
+        for file_path in files {
+            let (state, settings, root_dir, compile_commands, mode, cancel, cache_dir) = (
+                state.clone(),
+                settings.clone(),
+                root_dir.clone(),
+                compile_commands.clone(),
+                mode.clone(),
+                cancel.clone(),
+                cache_dir.clone(),
+            );
+            pool.execute(move || {
+                let warming = &state.cache_warming;
+                if !warming.wait_resumed(&cancel) {
+                    return;
+                }
+                let _permit = state.scheduler.acquire(Priority::Background);
+                if cancel.load(Ordering::Relaxed) {
+                    return;
+                }
+                // Opened in the meantime, or analyzed by a project run.
+                if !is_cached(&file_path, &state.cache, settings_hash, cache_dir.as_deref(), portable_cache_root(&settings, root_dir.as_deref())) {
+                    let _ = analyze_file(
+                        &file_path,
+                        &settings,
+                        root_dir.as_deref(),
+                        Some(&compile_commands),
+                        &mode,
+                        None,
+                        &state.cache,
+                        Some(&state.remote_cache),
+                        &state.resources,
+                        &|message| {
+                            let _ = send_log(&state.stdout, "warn", message);
+                        },
+                    );
+                }
+                if !cancel.load(Ordering::Relaxed) {
+                    warming.progress.lock().unwrap().done += 1;
+                }
+            });
+        }
+        pool.join();
+        // A newer pass owns the progress once it has replaced this one.
+        let mut cancel_map = state.cancel_map.lock().unwrap();
+        if !cancel_map.get(CACHE_WARMING_RUN_ID).is_some_and(|flag| Arc::ptr_eq(flag, &cancel)) {
+            return;
+        }
+        cancel_map.remove(CACHE_WARMING_RUN_ID);
+        drop(cancel_map);
+        let done = {
+            let mut progress = warming.progress.lock().unwrap();
+            progress.running = false;
+            progress.done
+        };
+        if total > 0 {
+            let _ = send_log(&state.stdout, "info", format!("Cache warming analyzed {done}/{total} files ({mode})"));
+        }
+    });
+}
 
-    let mut tmp = NamedTempFile::new_in(cache_dir)?;
-    tmp.write_all(&data)?;
-    tmp.flush()?;
-    tmp.persist(&full_path).map_err(|err| err.error)?;
+fn run_janitor(state: &AppState) {
+    let temp_removed = clean_orphaned_temp_artifacts();
+    let settings = state.settings.lock().unwrap().clone();
+    let root_dir = state.root_dir.lock().unwrap().clone();
+    let index = state.compile_index.lock().unwrap().clone();
+    let compile_dir = index.as_ref().and_then(|i| i.path.parent().map(Path::to_path_buf));
+    let cache_removed = resolve_cache_dir(&settings, root_dir.as_deref(), compile_dir.as_deref())
+        // A global cache also holds entries of other workspaces.
+        .map(|dir| clean_stale_cache_entries(&dir, index.as_deref().filter(|_| settings.daemonCacheScope != "global")))
+        .unwrap_or(0);
+    state
+        .cache
+        .lock()
+        .unwrap()
+        .retain(|path| path.exists() && index.as_ref().is_none_or(|i| file_in_index(path, i)));
+    let evicted = trim_disk_cache(state);
+    if temp_removed + cache_removed + evicted > 0 {
+        let _ = send_log(&state.stdout, "info", format!(
+            "Cleanup removed {temp_removed} orphaned temp artifacts, {cache_removed} cache entries for missing files and {evicted} cache entries over cacheMaxBytes"
+        ));
+    }
+}
 
-    if let Ok(entries) = std::fs::read_dir(cache_dir) {
-        let prefix = format!("{:016x}-", key);
-        for entry in entries.flatten() {
-            let name = entry.file_name();
-            let name = name.to_string_lossy();
-            if name == filename {
-                continue;
-            }
-            if name.starts_with(&prefix) {
-                let _ = std::fs::remove_file(entry.path());
-            }
+/// Runs journal recovery for the cache directory configured in `settings` and
+/// logs what was repaired.
+/// The directory may be shared with other daemons (`daemonCacheScope:
+/// "global"`, or several windows on one workspace): recovery is skipped while
+/// another one holds the directory lock, and the shared lock is kept afterwards.
+fn recover_cache_dir(state: &AppState, settings: &Settings) {
+    let root_dir = state.root_dir.lock().unwrap().clone();
+    let compile_commands = resolve_compile_commands_path(settings, root_dir.as_deref(), state);
+    let compile_dir = compile_commands.as_deref().and_then(Path::parent);
+    let mut held = state.cache_dir_lock.lock().unwrap();
+    held.take();
+    let Some(cache_dir) = resolve_cache_dir(settings, root_dir.as_deref(), compile_dir) else {
+        return;
+    };
+    let lock = std::fs::File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(cache_dir.join(CACHE_LOCK_FILE));
+    // Without a lock file, recover as if the directory were not shared.
+    let exclusive = lock.as_ref().map_or(true, |file| file.try_lock().is_ok());
+    let r = if exclusive { recover_disk_cache(&cache_dir) } else { CacheRecovery::default() };
+    if let Ok(file) = lock {
+        let _ = file.unlock();
+        if file.lock_shared().is_ok() {
+            *held = Some(file);
         }
     }
-
-    Ok(())
+    drop(held);
+    if r.incomplete + r.unreadable + r.evictions + r.temp_files == 0 {
+        return;
+    }
+    let _ = send_log(&state.stdout, "info", format!(
+        "Disk cache recovery in {}: {} entries kept, {} incomplete writes discarded, {} unreadable entries removed, {} evictions completed, {} temp files removed",
+        cache_dir.display(),
+        r.kept,
+        r.incomplete,
+        r.unreadable,
+        r.evictions,
+        r.temp_files
+    ));
 }
 
 fn is_cached(
     file_path: &Path,
-    cache: &Arc<Mutex<HashMap<PathBuf, CacheEntry>>>,
+    cache: &Arc<Mutex<SpillStore<CacheEntry>>>,
     settings_hash: u64,
     cache_dir: Option<&Path>,
+    portable_root: Option<&Path>,
 ) -> bool {
-    let sig = file_signature(file_path);
-    if let Some((mtime, size)) = sig {
-        if let Some(entry) = cache.lock().unwrap().get(file_path) {
-            return entry.mtime == mtime && entry.size == size && entry.settings_hash == settings_hash;
+    if let Some(entry) = cache.lock().unwrap().get(file_path) {
+        return entry.settings_hash == settings_hash && entry.matches_file(file_path, file_signature(file_path));
+    }
+    match (cache_dir, file_content_hash(file_path)) {
+        (Some(dir), Some(content_hash)) => {
+            dir.join(disk_cache_file_name(portable_root, file_path, content_hash, settings_hash)).exists()
         }
-        if let Some(dir) = cache_dir {
-            let key = cache_key_for_path(file_path);
-            let filename = cache_file_name(key, mtime, size, settings_hash);
-            return dir.join(filename).exists();
+        _ => false,
+    }
+}
+
+/// The diagnostics cached for the current content of `file_path`, from
+/// memory or else from the disk cache.
+fn cached_diagnostics(
+    file_path: &Path,
+    cache: &Arc<Mutex<SpillStore<CacheEntry>>>,
+    settings_hash: u64,
+    cache_dir: Option<&Path>,
+    portable_root: Option<&Path>,
+) -> Option<Vec<RpcDiagnostic>> {
+    if let Some(entry) = cache.lock().unwrap().get(file_path) {
+        if entry.settings_hash == settings_hash && entry.matches_file(file_path, file_signature(file_path)) {
+            return Some(entry.diagnostics);
         }
     }
-    false
+    let content_hash = file_content_hash(file_path)?;
+    read_disk_cache(cache_dir?, portable_root, file_path, content_hash, settings_hash).map(|entry| entry.diagnostics)
 }
 
 fn paths_match(a: &Path, b: &Path) -> bool {
@@ -1378,16 +11797,45 @@ fn resolve_compile_commands_path(settings: &Settings, root_dir: Option<&Path>, s
 }
 
 fn find_compile_commands(root: &Path) -> Option<PathBuf> {
-    for entry in WalkDir::new(root).max_depth(4).follow_links(false) {
-        if let Ok(ent) = entry {
-            if ent.file_name() == "compile_commands.json" {
-                return Some(ent.path().to_path_buf());
-            }
+    for ent in WalkDir::new(root).max_depth(4).follow_links(false).into_iter().flatten() {
+        if ent.file_name() == "compile_commands.json" {
+            return Some(ent.path().to_path_buf());
         }
     }
     None
 }
 
+/// Settings for one request: the global settings with its `settingsOverride`
+/// merged over them, setting by setting. Nothing is stored.
+fn run_settings(state: &AppState, params: &Value) -> Result<Settings> {
+    let settings = state.settings.lock().unwrap().clone();
+    let Some(overrides) = params.get("settingsOverride").filter(|v| !v.is_null()) else {
+        return Ok(settings);
+    };
+    let overrides = overrides
+        .as_object()
+        .ok_or_else(|| invalid_params("settingsOverride", "`settingsOverride` must be an object".to_string()))?;
+    let mut merged = serde_json::to_value(&settings)?;
+    for (key, value) in overrides {
+        if merged.get(key).is_none() {
+            return Err(invalid_params("settingsOverride", format!("Unknown setting `{key}` in `settingsOverride`")));
+        }
+        merged[key] = value.clone();
+    }
+    serde_json::from_value(merged).map_err(|err| invalid_params("settingsOverride", format!("Invalid `settingsOverride`: {err}")))
+}
+
+/// Paths from a list of `file://` URIs or plain paths; other values are ignored.
+fn file_list_param(list: &[Value]) -> Vec<PathBuf> {
+    list.iter()
+        .filter_map(|entry| entry.as_str())
+        .map(|raw| match Url::parse(raw).ok().and_then(|url| url.to_file_path().ok()) {
+            Some(path) => path,
+            None => PathBuf::from(raw),
+        })
+        .collect()
+}
+
 fn load_project_files(compile_commands: &Path) -> Result<Vec<PathBuf>> {
     let content = std::fs::read_to_string(compile_commands).context("Failed to read compile_commands.json")?;
     let entries: Vec<CompileCommand> = serde_json::from_str(&content).context("Invalid compile_commands.json")?;
@@ -1406,6 +11854,8 @@ fn load_project_files(compile_commands: &Path) -> Result<Vec<PathBuf>> {
     Ok(files.into_iter().collect())
 }
 
+const MAX_LOGGED_DROPS: usize = 10;
+
 fn get_compile_index(path: &Path, state: &AppState) -> Result<Arc<CompileCommandsIndex>> {
     let mtime = mtime_for_path(path).unwrap_or(0);
     if let Some(existing) = state.compile_index.lock().unwrap().as_ref() {
@@ -1414,11 +11864,26 @@ fn get_compile_index(path: &Path, state: &AppState) -> Result<Arc<CompileCommand
         }
     }
 
+    let settings = state.settings.lock().unwrap().clone();
+    let policy = settings.duplicateEntryPolicy.clone();
     let content = std::fs::read_to_string(path).context("Failed to read compile_commands.json")?;
     let entries: Vec<CompileCommand> = serde_json::from_str(&content).context("Invalid compile_commands.json")?;
+    let dropped = write_sanitized_compile_db(path, mtime, &entries, &settings);
+    if !dropped.is_empty() {
+        let mut listed: Vec<String> = dropped
+            .iter()
+            .take(MAX_LOGGED_DROPS)
+            .map(|(arg, commands)| format!("{arg} ({commands} commands)"))
+            .collect();
+        if dropped.len() > MAX_LOGGED_DROPS {
+            listed.push(format!("{} more", dropped.len() - MAX_LOGGED_DROPS));
+        }
+        let _ = send_log(&state.stdout, "info", format!("Dropped from compile commands for clang-tidy: {}", listed.join(", ")));
+    }
     let mut files = Vec::new();
     let mut file_set = HashSet::new();
     let mut commands: HashMap<PathBuf, CompileCommandEntry> = HashMap::new();
+    let mut by_name: HashMap<std::ffi::OsString, Vec<PathBuf>> = HashMap::new();
 
     for entry in entries {
         let file_path = PathBuf::from(&entry.file);
@@ -1427,15 +11892,26 @@ fn get_compile_index(path: &Path, state: &AppState) -> Result<Arc<CompileCommand
         } else {
             PathBuf::from(&entry.directory).join(file_path)
         };
-        let canonical = std::fs::canonicalize(&full).unwrap_or(full);
-        file_set.insert(canonical.clone());
-        files.push(canonical.clone());
-        commands.entry(canonical.clone()).or_insert(CompileCommandEntry {
+        let key = normalize_path(&full);
+        if file_set.insert(key.clone()) {
+            files.push(key.clone());
+            if let Some(name) = key.file_name() {
+                by_name.entry(name.to_os_string()).or_default().push(key.clone());
+            }
+        }
+        let candidate = CompileCommandEntry {
             file: entry.file,
             directory: entry.directory,
             command: entry.command,
             arguments: entry.arguments,
-        });
+            output: entry.output,
+        };
+        match commands.get(&key) {
+            Some(existing) if !prefer_duplicate_entry(existing, &candidate, &policy) => {}
+            _ => {
+                commands.insert(key, candidate);
+            }
+        }
     }
 
     let index = Arc::new(CompileCommandsIndex {
@@ -1444,30 +11920,397 @@ fn get_compile_index(path: &Path, state: &AppState) -> Result<Arc<CompileCommand
         files,
         file_set,
         commands,
+        by_name,
+        aliases: Mutex::new(HashMap::new()),
+        canonicalized: AtomicBool::new(false),
     });
+    canonicalize_compile_index(&index);
 
-    *state.compile_index.lock().unwrap() = Some(index.clone());
+    let previous = state.compile_index.lock().unwrap().replace(index.clone());
+    if let Some(previous) = previous.filter(|p| p.path == path) {
+        notify_compile_db_changed(&previous, &index, state);
+    }
     Ok(index)
 }
 
+/// Watches the loaded compile_commands.json so a rebuilt database is picked up
+/// without waiting for the next request. A new mtime is only reindexed once it
+/// held for a whole interval, so a build system still writing the file is not
+/// read half-way; `pending` carries that mtime between polls.
+fn poll_compile_db(state: &AppState, pending: &mut Option<u64>) {
+    let Some(index) = state.compile_index.lock().unwrap().clone() else {
+        *pending = None;
+        return;
+    };
+    let mtime = match mtime_for_path(&index.path) {
+        Some(mtime) if mtime != index.mtime => mtime,
+        _ => {
+            *pending = None;
+            return;
+        }
+    };
+    if pending.replace(mtime) != Some(mtime) {
+        return;
+    }
+    // An unparsable database keeps `pending` set and is retried on the next poll.
+    if get_compile_index(&index.path, state).is_ok() {
+        *pending = None;
+    }
+}
+
+/// Compares a reindexed compile database with the previous index, drops cached
+/// results for files whose flags changed and tells the client about them.
+fn notify_compile_db_changed(previous: &CompileCommandsIndex, current: &CompileCommandsIndex, state: &AppState) {
+    let mut changed = Vec::new();
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    for file in &current.files {
+        match (previous.commands.get(file), current.commands.get(file)) {
+            (None, _) => added.push(file.clone()),
+            (Some(old), Some(new)) if old.directory != new.directory || compile_flags(old) != compile_flags(new) => {
+                changed.push(file.clone());
+            }
+            _ => {}
+        }
+    }
+    for file in &previous.files {
+        if !current.file_set.contains(file) {
+            removed.push(file.clone());
+        }
+    }
+    if changed.is_empty() && added.is_empty() && removed.is_empty() {
+        return;
+    }
+
+    let mut invalidated = Vec::new();
+    {
+        let mut cache = state.cache.lock().unwrap();
+        let latest = state.latest.lock().unwrap();
+        for file in changed.iter().chain(removed.iter()) {
+            let had_cache = cache.remove(file);
+            if had_cache || latest.contains_key(file) {
+                invalidated.push(file.clone());
+            }
+        }
+    }
+
+    let to_uris = |files: &[PathBuf]| -> Vec<String> {
+        files
+            .iter()
+            .filter_map(|f| Url::from_file_path(f).ok())
+            .map(|u| u.to_string())
+            .collect()
+    };
+    let _ = send_notification(&state.stdout, "compileDbChanged", serde_json::json!({
+        "compileCommands": Url::from_file_path(&current.path).map(|u| u.to_string()).unwrap_or_default(),
+        "changed": to_uris(&changed),
+        "added": to_uris(&added),
+        "removed": to_uris(&removed),
+        "invalidated": to_uris(&invalidated),
+    }));
+}
+
+fn compile_flags(entry: &CompileCommandEntry) -> Vec<String> {
+    entry
+        .arguments
+        .clone()
+        .or_else(|| entry.command.as_deref().map(split_compile_command))
+        .unwrap_or_default()
+}
+
+const DEFAULT_COMPILE_DB_PROBLEM_LIMIT: usize = 200;
+
+/// Something in compile_commands.json that keeps a file from being analyzed as
+/// the build compiles it. `entries` are 0-based indices into the database.
+#[derive(Debug, Serialize)]
+struct CompileDbProblem {
+    kind: &'static str,
+    entries: Vec<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    message: String,
+}
+
+/// Checks the text of a compile database and returns its number of entries
+/// with the problems found: invalid JSON or entries, directories and files
+/// that do not resolve or exist, commands that cannot be split or read, and
+/// files listed more than once with different flags.
+fn validate_compile_db(content: &str, settings: &Settings) -> (usize, Vec<CompileDbProblem>) {
+    let problem = |kind, entries, file: Option<&str>, message| CompileDbProblem { kind, entries, file: file.map(str::to_string), message };
+    let values: Vec<Value> = match serde_json::from_str(content) {
+        Ok(values) => values,
+        Err(err) => return (0, vec![problem("invalidJson", Vec::new(), None, format!("Not a JSON array of entries: {err}"))]),
+    };
+    let mut problems = Vec::new();
+    let mut by_file: HashMap<PathBuf, Vec<(usize, CompileCommandEntry)>> = HashMap::new();
+    let mut order = Vec::new();
+    for (i, value) in values.iter().enumerate() {
+        let entry = match CompileCommand::deserialize(value) {
+            Ok(entry) => entry,
+            Err(err) => {
+                let file = value.get("file").and_then(|v| v.as_str());
+                problems.push(problem("invalidEntry", vec![i], file, format!("Invalid entry: {err}")));
+                continue;
+            }
+        };
+        let file = Some(entry.file.as_str());
+        let directory = PathBuf::from(&entry.directory);
+        let file_path = Path::new(&entry.file);
+        let directory_ok = if !directory.is_absolute() {
+            let message = format!("`directory` `{}` is not an absolute path", entry.directory);
+            problems.push(problem("unresolvedPath", vec![i], file, message));
+            false
+        } else if !directory.is_dir() {
+            let message = format!("`directory` `{}` does not exist", entry.directory);
+            problems.push(problem("unresolvedPath", vec![i], file, message));
+            false
+        } else {
+            true
+        };
+        let full = directory.join(file_path);
+        if (directory_ok || file_path.is_absolute()) && !full.exists() {
+            problems.push(problem("missingFile", vec![i], file, format!("`{}` does not exist", full.display())));
+        }
+        let unparsable = match (&entry.arguments, entry.command.as_deref()) {
+            (Some(args), _) if args.is_empty() => Some("`arguments` is empty"),
+            (None, None) => Some("Entry has neither `command` nor `arguments`"),
+            (None, Some(command)) if split_compile_command(command).is_empty() => Some("`command` is empty"),
+            (None, Some(command)) if has_unterminated_quote(command) => Some("`command` has an unterminated quote"),
+            _ => None,
+        };
+        if let Some(message) = unparsable {
+            problems.push(problem("unparsableCommand", vec![i], file, message.to_string()));
+            continue;
+        }
+        let entry = CompileCommandEntry {
+            file: entry.file,
+            directory: entry.directory,
+            command: entry.command,
+            arguments: entry.arguments,
+            output: entry.output,
+        };
+        if directory_ok {
+            let mut args = compile_flags(&entry);
+            strip_compiler_launchers(&mut args, &settings.compilerLaunchers);
+            for arg in args.iter().filter_map(|arg| arg.strip_prefix('@')) {
+                if !directory.join(arg).is_file() {
+                    let message = format!("Response file `{arg}` does not exist");
+                    problems.push(problem("unparsableCommand", vec![i], Some(entry.file.as_str()), message));
+                }
+            }
+        }
+        let canonical = std::fs::canonicalize(&full).unwrap_or(full);
+        let duplicates = by_file.entry(canonical.clone()).or_default();
+        if duplicates.is_empty() {
+            order.push(canonical);
+        }
+        duplicates.push((i, entry));
+    }
+    for file in order {
+        let duplicates = &by_file[&file];
+        let flags = |entry: &CompileCommandEntry| {
+            let mut args = compile_flags(entry);
+            strip_compiler_launchers(&mut args, &settings.compilerLaunchers);
+            let cl = is_cl_driver(&args);
+            let file_path = Path::new(&entry.directory).join(&entry.file);
+            compile_only_flags(&args, cl)
+                .into_iter()
+                .filter(|arg| *arg != entry.file && Path::new(&entry.directory).join(arg) != file_path)
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        let first = flags(&duplicates[0].1);
+        if duplicates.iter().skip(1).all(|(_, entry)| flags(entry) == first) {
+            continue;
+        }
+        let mut used = &duplicates[0];
+        for candidate in &duplicates[1..] {
+            if prefer_duplicate_entry(&used.1, &candidate.1, &settings.duplicateEntryPolicy) {
+                used = candidate;
+            }
+        }
+        let indices: Vec<usize> = duplicates.iter().map(|(i, _)| *i).collect();
+        let message = format!(
+            "`{}` has {} entries with different flags; entry {} is used (duplicateEntryPolicy)",
+            file.display(),
+            indices.len(),
+            used.0
+        );
+        problems.push(problem("conflictingDuplicate", indices, Some(used.1.file.as_str()), message));
+    }
+    (values.len(), problems)
+}
+
+/// Whether a compile `command` ends inside a quoted string, under the same
+/// splitting rules as `split_compile_command`.
+fn has_unterminated_quote(command: &str) -> bool {
+    let mut program = split_windows_command(command);
+    strip_compiler_launchers(&mut program, &[]);
+    let windows = cfg!(windows) || is_cl_driver(&program);
+    let mut in_single = false;
+    let mut in_double = false;
+    let mut backslashes = 0;
+    for ch in command.chars() {
+        match ch {
+            '\\' if !in_single => {
+                backslashes += 1;
+                continue;
+            }
+            '"' if backslashes % 2 == 0 => in_double = !in_double,
+            '\'' if !windows && !in_double && (in_single || backslashes % 2 == 0) => in_single = !in_single,
+            _ => {}
+        }
+        backslashes = 0;
+    }
+    in_single || in_double
+}
+
+/// Decides whether `candidate` should replace `existing` when a source file appears
+/// more than once in compile_commands.json. Policies: "first", "last", and the
+/// default "object" which prefers ordinary object compiles over PCH/module builds.
+fn prefer_duplicate_entry(existing: &CompileCommandEntry, candidate: &CompileCommandEntry, policy: &str) -> bool {
+    match policy {
+        "first" => false,
+        "last" => true,
+        _ => compile_entry_rank(candidate) > compile_entry_rank(existing),
+    }
+}
+
+fn compile_entry_rank(entry: &CompileCommandEntry) -> u8 {
+    let args = compile_flags(entry);
+    let output = entry.output.clone().or_else(|| {
+        args.iter()
+            .position(|a| a == "-o")
+            .and_then(|i| args.get(i + 1).cloned())
+            .or_else(|| args.iter().find_map(|a| a.strip_prefix("/Fo").map(|s| s.to_string())))
+    });
+    let builds_pch = args
+        .iter()
+        .any(|a| a == "-emit-pch" || a == "c++-header" || a == "c-header" || a.starts_with("/Yc"));
+    if builds_pch {
+        return 0;
+    }
+    let ext = output
+        .as_deref()
+        .and_then(|o| Path::new(o).extension())
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("o") | Some("obj") => 3,
+        None => 2,
+        Some("pch") | Some("gch") | Some("pcm") => 0,
+        Some(_) => 1,
+    }
+}
+
 fn file_in_index(file_path: &Path, index: &CompileCommandsIndex) -> bool {
-    let candidate = std::fs::canonicalize(file_path).unwrap_or_else(|_| file_path.to_path_buf());
-    index.file_set.contains(&candidate)
+    index.key_for(file_path).is_some()
 }
 
 fn find_compile_entry(index: &CompileCommandsIndex, file_path: &Path) -> Option<CompileCommandEntry> {
-    let candidate = std::fs::canonicalize(file_path).unwrap_or_else(|_| file_path.to_path_buf());
-    index.commands.get(&candidate).cloned()
+    index.entry(file_path).cloned()
+}
+
+/// Largest `Content-Length` accepted; longer bodies are skipped unread.
+const MAX_MESSAGE_BYTES: usize = 256 * 1024 * 1024;
+
+/// Reads the next message body, or `None` at end of input. Malformed headers
+/// are reported as `InvalidData` and the reader moves on to the next message.
+fn read_message(reader: &mut impl BufRead, framing: &mut Framing) -> io::Result<Option<String>> {
+    if *framing == Framing::Auto {
+        loop {
+            let buf = reader.fill_buf()?;
+            let Some(&first) = buf.first() else {
+                return Ok(None);
+            };
+            if first.is_ascii_whitespace() {
+                reader.consume(1);
+                continue;
+            }
+            *framing = if first.eq_ignore_ascii_case(&b'c') { Framing::Header } else { Framing::Ndjson };
+            break;
+        }
+    }
+
+    if *framing == Framing::Ndjson {
+        let mut line = String::new();
+        return match reader.read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line)),
+        };
+    }
+
+    let mut content_length: Option<usize> = None;
+    let mut saw_header = false;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end_matches(['\r', '\n']);
+        if header.is_empty() {
+            if saw_header {
+                break;
+            }
+            continue;
+        }
+        saw_header = true;
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok();
+            }
+        }
+    }
+    let length = content_length
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing or invalid Content-Length header"))?;
+    if length > MAX_MESSAGE_BYTES {
+        io::copy(&mut reader.take(length as u64), &mut io::sink())?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Content-Length {length} exceeds the {MAX_MESSAGE_BYTES} byte limit"),
+        ));
+    }
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
 }
 
-fn write_json<T: Serialize>(stdout: &Arc<Mutex<io::Stdout>>, value: &T) -> Result<()> {
-    let mut out = stdout.lock().unwrap();
-    writeln!(out, "{}", serde_json::to_string(value)?)?;
+fn write_json<T: Serialize>(stdout: &Arc<Output>, value: &T) -> Result<()> {
+    let body = serde_json::to_string(value)?;
+    let mut out = stdout.writer.lock().unwrap();
+    if stdout.header_framed.load(Ordering::Relaxed) {
+        write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    } else {
+        writeln!(out, "{}", body)?;
+    }
     out.flush()?;
     Ok(())
 }
 
-fn send_notification<T: Serialize>(stdout: &Arc<Mutex<io::Stdout>>, method: &'static str, params: T) -> Result<()> {
+fn send_notification<T: Serialize>(stdout: &Arc<Output>, method: &'static str, params: T) -> Result<()> {
     let note = Notification { jsonrpc: "2.0", method, params };
     write_json(stdout, &note)
 }
+
+fn send_log(stdout: &Arc<Output>, level: &'static str, message: String) -> Result<()> {
+    let message = stdout.redactor.lock().unwrap().redact(&message);
+    send_notification(stdout, "log", LogParams { level, message })
+}
+
+fn apply_memory_limits(state: &AppState, settings: &Settings) {
+    state.scheduler.set_limit(settings.maxWorkers);
+    let mut cache = state.cache.lock().unwrap();
+    cache.set_limit(settings.memoryCacheMaxDiagnostics);
+    cache.set_max_entries(settings.cacheMaxEntries);
+    drop(cache);
+    state.latest.lock().unwrap().set_limit(settings.memoryCacheMaxDiagnostics);
+}
+
+fn apply_redaction_settings(state: &AppState, settings: &Settings) {
+    let (redactor, errors) = Redactor::from_settings(settings);
+    *state.stdout.redactor.lock().unwrap() = Arc::new(redactor);
+    for err in errors {
+        let _ = send_log(&state.stdout, "warn", err);
+    }
+}
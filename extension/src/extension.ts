@@ -23,11 +23,14 @@ interface RpcFix {
 }
 
 interface RpcDiagnostic {
+  id?: string;
   range: RpcRange;
-  severity: "info" | "warning" | "error";
+  severity: "hint" | "info" | "warning" | "error";
   code?: string;
   message: string;
   fixes?: RpcFix[];
+  codeDescription?: { href: string };
+  tags?: ("deprecated" | "unnecessary")[];
 }
 
 interface PublishDiagnosticsParams {
@@ -36,6 +39,16 @@ interface PublishDiagnosticsParams {
   diagnostics: RpcDiagnostic[];
 }
 
+// publishDiagnostics as sent with the diagnosticsDelta capability: a full set
+// or the changes against the set of `delta.baseVersion`.
+interface VersionedPublishDiagnosticsParams {
+  runId: string | number;
+  fileUri: string;
+  version?: number;
+  diagnostics?: RpcDiagnostic[];
+  delta?: { baseVersion: number; added: RpcDiagnostic[]; removed: string[] };
+}
+
 interface AnalyzeFileResult {
   runId: string | number;
   fileUri: string;
@@ -178,6 +191,7 @@ const findingsPageState = new Map<string, number>();
 let findingsFilter: FindingsFilter | null = null;
 let summaryStatusBar: vscode.StatusBarItem | null = null;
 const lastDiagnosticsStore = new Map<string, RpcDiagnostic[]>();
+const publishedVersions = new Map<string, { version: number; diagnostics: RpcDiagnostic[] }>();
 const persistedIndices = new Map<string, PersistedIndex>();
 const pendingPersist = new Map<string, PersistedFilePayload>();
 let persistTimer: NodeJS.Timeout | null = null;
@@ -462,7 +476,17 @@ function toVsDiagnostic(fileUri: string, d: RpcDiagnostic): vscode.Diagnostic {
   const range = rpcRangeToVs(d.range);
   const diag = new vscode.Diagnostic(range, d.message, severityToVs(d.severity));
   diag.source = "clang-tidy";
-  if (d.code) diag.code = d.code;
+  if (d.code) {
+    // A code with a target renders as a link to the check's documentation.
+    diag.code = d.codeDescription
+      ? { value: d.code, target: vscode.Uri.parse(d.codeDescription.href) }
+      : d.code;
+  }
+  if (d.tags) {
+    diag.tags = d.tags.map((tag) =>
+      tag === "deprecated" ? vscode.DiagnosticTag.Deprecated : vscode.DiagnosticTag.Unnecessary
+    );
+  }
   const category = resolveCategory(d.code);
   const related: vscode.DiagnosticRelatedInformation[] = [];
   try {
@@ -496,6 +520,8 @@ function severityToVs(sev: RpcDiagnostic["severity"]): vscode.DiagnosticSeverity
       return vscode.DiagnosticSeverity.Error;
     case "warning":
       return vscode.DiagnosticSeverity.Warning;
+    case "hint":
+      return vscode.DiagnosticSeverity.Hint;
     default:
       return vscode.DiagnosticSeverity.Information;
   }
@@ -633,7 +659,8 @@ async function startDaemon(context: vscode.ExtensionContext): Promise<void> {
   });
   connection.onNotification((method, params) => {
     if (method === "publishDiagnostics") {
-      handlePublishDiagnostics(params as PublishDiagnosticsParams);
+      const resolved = resolvePublishedDiagnostics(params as VersionedPublishDiagnosticsParams);
+      if (resolved) handlePublishDiagnostics(resolved);
     } else if (method === "progress") {
       const p = params as { runId: string | number; kind: string; message?: string; percent?: number };
       if (p.message) output.appendLine(`[progress] ${p.message}`);
@@ -649,7 +676,7 @@ async function startDaemon(context: vscode.ExtensionContext): Promise<void> {
     await connection.sendRequest("initialize", {
       rootUri,
       client: { name: "clang-tidy-vscode", version: "0.1.0" },
-      capabilities: { supportsProgress: true },
+      capabilities: { supportsProgress: true, diagnosticsDelta: true },
       settings: getSettings(),
     });
     daemonRestartAttempts = 0;
@@ -888,6 +915,7 @@ function resetRuntimeForDaemonRestart(): void {
   projectRunById.clear();
   projectActiveWorkspaceKey = null;
   runIdToDocVersion.clear();
+  publishedVersions.clear();
   updateStatusBar();
 }
 
@@ -2992,6 +3020,39 @@ function renderChecksHtml(
   </html>`;
 }
 
+// Rebuilds the full diagnostics of a delta publish from the set of its base
+// version. A delta against a version we do not have asks the daemon to resend
+// the full set and is dropped.
+function resolvePublishedDiagnostics(
+  params: VersionedPublishDiagnosticsParams
+): PublishDiagnosticsParams | undefined {
+  const { runId, fileUri, version } = params;
+  if (params.diagnostics) {
+    if (version !== undefined) {
+      publishedVersions.set(fileUri, { version, diagnostics: params.diagnostics });
+    }
+    return { runId, fileUri, diagnostics: params.diagnostics };
+  }
+  if (!params.delta || version === undefined) return undefined;
+  const base = publishedVersions.get(fileUri);
+  if (!base || base.version !== params.delta.baseVersion) {
+    publishedVersions.delete(fileUri);
+    connection?.sendRequest("resyncDiagnostics", { fileUri }).catch(() => undefined);
+    return undefined;
+  }
+  // An added diagnostic replaces a cached one with its id, so a changed range,
+  // severity or fix never leaves the stale payload behind.
+  const removed = new Set(params.delta.removed);
+  for (const d of params.delta.added) {
+    if (d.id !== undefined) removed.add(d.id);
+  }
+  const diagnostics = base.diagnostics
+    .filter((d) => d.id === undefined || !removed.has(d.id))
+    .concat(params.delta.added);
+  publishedVersions.set(fileUri, { version, diagnostics });
+  return { runId, fileUri, diagnostics };
+}
+
 function handlePublishDiagnostics(params: PublishDiagnosticsParams) {
   const maxPerFile = getDiagnosticsCapPerFile();
   const trimmedDiagnostics =
@@ -3534,7 +3595,7 @@ class FixCodeActionProvider implements vscode.CodeActionProvider {
     const actions: vscode.CodeAction[] = [];
     for (const diag of context.diagnostics) {
       if (diag.source !== "clang-tidy") continue;
-      const key = `${diag.range.start.line}:${diag.range.start.character}-${diag.range.end.line}:${diag.range.end.character}|${diag.code ?? ""}|${diag.message}`;
+      const key = `${diag.range.start.line}:${diag.range.start.character}-${diag.range.end.line}:${diag.range.end.character}|${diagnosticCodeToString(diag.code) ?? ""}|${diag.message}`;
       const fixes = perFile.get(key);
       if (!fixes) continue;
 